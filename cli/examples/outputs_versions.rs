@@ -1,6 +1,6 @@
 #![allow(non_snake_case)]
 
-use blocks_iterator::{Config, PeriodCounter};
+use blocks_iterator::{Config, ForkFlags, PeriodCounter};
 use clap::Parser;
 use env_logger::Env;
 use log::info;
@@ -16,7 +16,9 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut config = Config::parse();
     config.skip_prevout = true;
+    let network = config.network;
     let iter = blocks_iterator::iter(config);
+    let mut active_forks = ForkFlags::empty();
     let mut counters = [0usize; 17];
     let mut output_file = File::create("outputs_versions.log").unwrap();
     for block_extra in iter {
@@ -29,12 +31,16 @@ fn main() -> Result<(), Box<dyn Error>> {
             );
         }
 
-        if block_extra.height() == 481824 {
-            info!("segwit locked in");
-        }
-        if block_extra.height() == 687456 {
-            info!("taproot locked in");
+        let forks = block_extra.active_forks(network);
+        for (flag, name) in [
+            (ForkFlags::SEGWIT, "segwit"),
+            (ForkFlags::TAPROOT, "taproot"),
+        ] {
+            if forks.contains(flag) && !active_forks.contains(flag) {
+                info!("{} active", name);
+            }
         }
+        active_forks = forks;
 
         for (txid, tx) in block_extra.iter_tx() {
             for (i, output) in tx.output.iter().enumerate() {
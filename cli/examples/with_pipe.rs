@@ -19,27 +19,21 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut heaviest: (Txid, Weight) = (Txid::all_zeros(), Weight::ZERO);
 
     for block_extra in iter {
-        let txs_fee = block_extra.fee().expect("launch without `--skip-prevout`");
+        let breakdown = block_extra
+            .coinbase_breakdown()
+            .expect("launch without `--skip-prevout`");
         let block = block_extra.block();
-        let coinbase = &block.txdata[0];
-        let coinbase_sum_outputs: u64 = coinbase
-            .output
-            .iter()
-            .map(|output| output.value.to_sat())
-            .sum();
-        let base_reward = block_extra.base_reward();
-        let missing_reward = base_reward + txs_fee - coinbase_sum_outputs;
 
-        if missing_reward != 0 {
+        if breakdown.burned != 0 {
             blocks_missing_reward += 1;
-            total_missing_reward += missing_reward;
+            total_missing_reward += breakdown.burned;
             warn!(
                 "block {} at height {} tx_fees:{}, coinbase_outputs:{}, missing_reward:{}",
                 block.block_hash(),
                 block_extra.height(),
-                txs_fee,
-                coinbase_sum_outputs,
-                missing_reward
+                breakdown.fees,
+                breakdown.claimed,
+                breakdown.burned
             );
         }
 
@@ -1,12 +1,21 @@
-use bitcoin::consensus::encode::MAX_VEC_SIZE;
+use bitcoin::consensus::encode::serialize_hex;
 use bitcoin::consensus::Encodable;
-use blocks_iterator::Config;
+use bitcoin::hex::DisplayHex;
+use bitcoin::p2p::Magic;
+use bitcoin::BlockHash;
+use blocks_iterator::{write_undo, BlockExtra, Config, OutputFormat, PrevoutsRecord};
 use clap::Parser;
 use env_logger::Env;
 use log::info;
+use mmap_output::MmapOutput;
 use std::error::Error;
+use std::fmt;
+use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+mod mmap_output;
 
 fn main() -> Result<(), Box<dyn Error>> {
     init_logging();
@@ -14,16 +23,222 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let config = Config::parse();
 
-    let blocks_iter = blocks_iterator::iter(config);
-    let mut buffer = [0u8; MAX_VEC_SIZE];
-    for block_extra in blocks_iter {
-        let size = block_extra.consensus_encode(&mut &mut buffer[..]).unwrap();
-        io::stdout().write_all(&buffer[..size])?;
+    if config.check {
+        let report = check_blocks(config)?;
+        print!("{}", report);
+        info!("end");
+        return Ok(());
+    }
+
+    if let Some(dir) = config.output_dir.clone() {
+        let shards = write_shards(config, &dir)?;
+        info!("written {} shards to {:?}", shards, dir);
+        info!("end");
+        return Ok(());
+    }
+
+    match config.output_mmap.clone() {
+        Some((path, estimated_size)) => {
+            let output = MmapOutput::create(&path, estimated_size)?;
+            let output = write_output(config, output)?;
+            info!("written {} bytes to {:?}", output.len(), path);
+            output.finish()?;
+        }
+        None => {
+            write_output(config, io::stdout())?;
+        }
     }
     info!("end");
     Ok(())
 }
 
+/// Write the blocks to `writer`, compressed if requested, returning it at the end
+fn write_output<W: Write>(config: Config, writer: W) -> io::Result<W> {
+    let mut block_writer = BlockWriter::new(&config)?;
+    let writer = block_writer.write_compressed(blocks_iterator::iter(config), writer)?;
+    block_writer.finish()?;
+    Ok(writer)
+}
+
+/// Write the blocks to files of `shard_blocks` blocks each in `dir`, created if missing, every
+/// file like the output of [`write_output`]. Returns the number of files written
+fn write_shards(config: Config, dir: &Path) -> io::Result<usize> {
+    std::fs::create_dir_all(dir)?;
+    let shard_blocks = config.shard_blocks.max(1) as usize;
+    let mut block_writer = BlockWriter::new(&config)?;
+    let mut blocks_iter = blocks_iterator::iter(config).peekable();
+    let mut shards = 0;
+    while blocks_iter.peek().is_some() {
+        let file = File::create(dir.join(format!("shard-{:06}.dat", shards)))?;
+        let shard = blocks_iter.by_ref().take(shard_blocks);
+        let writer = block_writer.write_compressed(shard, BufWriter::new(file))?;
+        writer.into_inner().map_err(|e| e.into_error())?;
+        shards += 1;
+    }
+    block_writer.finish()?;
+    Ok(shards)
+}
+
+/// Write the blocks one by one in the format chosen by the config, and their previous outputs
+/// to the sidecar file and their undo data to the undo file if requested
+struct BlockWriter {
+    output_format: OutputFormat,
+    magic: Magic,
+    #[cfg(feature = "compression")]
+    compress: Option<blocks_iterator::Compression>,
+    sidecar: Option<BufWriter<File>>,
+    undo: Option<BufWriter<File>>,
+    /// reused across blocks, it grows to the size of the biggest one
+    buffer: Vec<u8>,
+}
+
+impl BlockWriter {
+    fn new(config: &Config) -> io::Result<Self> {
+        let sidecar = match config.prevouts_sidecar.as_ref() {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        let undo = match config.undo_file.as_ref() {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        Ok(BlockWriter {
+            output_format: config.output_format,
+            magic: config.magic(),
+            #[cfg(feature = "compression")]
+            compress: config.compress,
+            sidecar,
+            undo,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Write `blocks` to `writer`, compressed if requested, returning it at the end
+    fn write_compressed<W: Write>(
+        &mut self,
+        blocks: impl Iterator<Item = BlockExtra>,
+        mut writer: W,
+    ) -> io::Result<W> {
+        #[cfg(feature = "compression")]
+        if let Some(compression) = self.compress {
+            let mut encoder = compression.encoder(writer)?;
+            for block_extra in blocks {
+                self.write(&mut encoder, &block_extra)?;
+            }
+            return encoder.finish();
+        }
+        for block_extra in blocks {
+            self.write(&mut writer, &block_extra)?;
+        }
+        Ok(writer)
+    }
+
+    fn write<W: Write>(&mut self, mut writer: W, block_extra: &BlockExtra) -> io::Result<()> {
+        let magic = self.magic;
+        if let Some(undo) = self.undo.as_mut() {
+            write_undo(undo, magic, block_extra)?;
+        }
+        if let Some(sidecar) = self.sidecar.as_mut() {
+            PrevoutsRecord::from(block_extra).consensus_encode(sidecar)?;
+            return match self.output_format {
+                OutputFormat::Binary => writer.write_all(block_extra.block_bytes()),
+                OutputFormat::Hex => writeln!(
+                    writer,
+                    "{}",
+                    block_extra.block_bytes().to_lower_hex_string()
+                ),
+                OutputFormat::Bootstrap => write_bootstrap(&mut writer, magic, block_extra),
+                OutputFormat::Undo => write_undo(&mut writer, magic, block_extra),
+            };
+        }
+        match self.output_format {
+            OutputFormat::Binary => {
+                self.buffer.clear();
+                block_extra.consensus_encode(&mut self.buffer)?;
+                writer.write_all(&self.buffer)
+            }
+            OutputFormat::Hex => writeln!(writer, "{}", serialize_hex(block_extra)),
+            OutputFormat::Bootstrap => write_bootstrap(&mut writer, magic, block_extra),
+            OutputFormat::Undo => write_undo(&mut writer, magic, block_extra),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        if let Some(mut sidecar) = self.sidecar {
+            sidecar.flush()?;
+        }
+        if let Some(mut undo) = self.undo {
+            undo.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the block bytes framed like in the block files: magic, size and the block
+fn write_bootstrap<W: Write>(
+    mut writer: W,
+    magic: Magic,
+    block_extra: &BlockExtra,
+) -> io::Result<()> {
+    writer.write_all(&magic.to_bytes())?;
+    writer.write_all(&(block_extra.block_bytes().len() as u32).to_le_bytes())?;
+    writer.write_all(block_extra.block_bytes())
+}
+
+/// The result of iterating the blocks with `--check`
+#[derive(Debug, Default, PartialEq, Eq)]
+struct CheckReport {
+    blocks: u64,
+    heights: Option<(u32, u32)>,
+    gaps: Vec<(u32, u32)>,
+    forks: u64,
+    tip: Option<BlockHash>,
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "blocks: {}", self.blocks)?;
+        match self.heights {
+            Some((first, last)) => writeln!(f, "heights: {}..={}", first, last)?,
+            None => writeln!(f, "heights: none")?,
+        }
+        writeln!(f, "gaps: {}", self.gaps.len())?;
+        for (from, to) in self.gaps.iter() {
+            writeln!(f, "  missing heights {}..{}", from, to)?;
+        }
+        writeln!(f, "forks: {}", self.forks)?;
+        match (self.heights, self.tip) {
+            (Some((_, height)), Some(hash)) => writeln!(f, "tip: {} {}", height, hash),
+            _ => writeln!(f, "tip: none"),
+        }
+    }
+}
+
+/// Iterate the blocks without writing them, failing if the iteration didn't complete
+fn check_blocks(config: Config) -> Result<CheckReport, Box<dyn Error>> {
+    let mut report = CheckReport::default();
+    let mut blocks_iter = blocks_iterator::try_iter(config);
+    for block_extra in &mut blocks_iter {
+        let height = block_extra.height();
+        report.heights = match report.heights {
+            Some((first, last)) => {
+                if height != last + 1 {
+                    report.gaps.push((last + 1, height));
+                }
+                Some((first, height))
+            }
+            None => Some((height, height)),
+        };
+        report.blocks += 1;
+        report.forks += block_extra.forks().len() as u64;
+        report.tip = Some(block_extra.block_hash());
+    }
+    match blocks_iter.error() {
+        Some(e) => Err(format!("check failed after {} blocks: {}", report.blocks, e).into()),
+        None => Ok(report),
+    }
+}
+
 fn init_logging() {
     let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
     if let Ok(s) = std::env::var("RUST_LOG_STYLE") {
@@ -43,3 +258,290 @@ fn init_logging() {
 
     builder.init();
 }
+
+#[cfg(test)]
+mod test {
+    use super::{check_blocks, write_output};
+    use bitcoin::hashes::hex::FromHex;
+    use blocks_iterator::{Config, OutputFormat};
+
+    #[test]
+    fn test_write_blocks_hex() {
+        let mut config = Config::new("../blocks", bitcoin::Network::Testnet);
+        config.skip_prevout = true;
+        config.stop_at_height = Some(20);
+        let mut binary = vec![];
+        write_output(config.clone(), &mut binary).unwrap();
+
+        config.output_format = OutputFormat::Hex;
+        let mut hex = vec![];
+        write_output(config, &mut hex).unwrap();
+        let hex = String::from_utf8(hex).unwrap();
+        assert!(hex.ends_with('\n'));
+        assert_eq!(hex.lines().count(), 21);
+        let decoded: Vec<u8> = hex
+            .lines()
+            .flat_map(|line| Vec::<u8>::from_hex(line).unwrap())
+            .collect();
+        assert_eq!(decoded, binary);
+    }
+
+    #[test]
+    fn test_write_blocks_bootstrap() {
+        use bitcoin::consensus::deserialize;
+        use bitcoin::{Block, Network};
+
+        let mut config = Config::new("../blocks", Network::Testnet);
+        config.skip_prevout = true;
+        config.stop_at_height = Some(20);
+        let expected: Vec<_> = blocks_iterator::iter(config.clone()).collect();
+
+        config.output_format = OutputFormat::Bootstrap;
+        let mut bootstrap = vec![];
+        write_output(config.clone(), &mut bootstrap).unwrap();
+
+        let mut rest = &bootstrap[..];
+        for block_extra in expected.iter() {
+            assert_eq!(rest[..4], Network::Testnet.magic().to_bytes());
+            let size = deserialize::<u32>(&rest[4..8]).unwrap() as usize;
+            assert_eq!(size, block_extra.block_bytes().len());
+            let block: Block = deserialize(&rest[8..8 + size]).unwrap();
+            assert_eq!(block.block_hash(), block_extra.block_hash());
+            rest = &rest[8 + size..];
+        }
+        assert!(rest.is_empty());
+
+        // the framing is the one of the block files, thus it can be iterated again
+        let tempdir = tempfile::TempDir::new().unwrap();
+        std::fs::write(tempdir.path().join("blk00000.dat"), &bootstrap).unwrap();
+        config.blocks_dir = tempdir.path().to_path_buf();
+        config.stop_at_height = None;
+        let hashes: Vec<_> = blocks_iterator::iter(config)
+            .map(|b| b.block_hash())
+            .collect();
+        // the last `max_reorg` blocks don't have enough followers to be emitted
+        let expected: Vec<_> = expected.iter().map(|b| b.block_hash()).collect();
+        assert_eq!(hashes, expected[..expected.len() - 6]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_write_output_compressed() {
+        use blocks_iterator::{decoder, read_block_extras, Compression};
+        use std::io::Cursor;
+
+        let mut config = Config::new("../blocks", bitcoin::Network::Testnet);
+        config.stop_at_height = Some(20);
+        let binary = write_output(config.clone(), vec![]).unwrap();
+        let expected: Vec<_> = read_block_extras(Cursor::new(binary.clone()))
+            .map(|b| b.unwrap().block_hash())
+            .collect();
+        assert_eq!(expected.len(), 21);
+
+        for compression in [Compression::Snappy, Compression::Xz, Compression::Zstd] {
+            config.compress = Some(compression);
+            let compressed = write_output(config.clone(), vec![]).unwrap();
+            assert!(compressed.len() < binary.len());
+            let reader = decoder(Cursor::new(compressed)).unwrap();
+            let hashes: Vec<_> = read_block_extras(reader)
+                .map(|b| b.unwrap().block_hash())
+                .collect();
+            assert_eq!(hashes, expected);
+        }
+    }
+
+    #[test]
+    fn test_write_shards() {
+        use super::write_shards;
+        use blocks_iterator::read_block_extras;
+        use std::io::Cursor;
+
+        let mut config = Config::new("../blocks", bitcoin::Network::Testnet);
+        config.stop_at_height = Some(20);
+        let binary = write_output(config.clone(), vec![]).unwrap();
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let dir = tempdir.path().join("shards");
+        config.shard_blocks = 8;
+        assert_eq!(write_shards(config.clone(), &dir).unwrap(), 3);
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["shard-000000.dat", "shard-000001.dat", "shard-000002.dat"]
+        );
+        let mut concatenated = vec![];
+        let mut heights = vec![];
+        for name in names.iter() {
+            let shard = std::fs::read(dir.join(name)).unwrap();
+            let blocks: Vec<_> = read_block_extras(Cursor::new(shard.clone()))
+                .map(|b| b.unwrap().height())
+                .collect();
+            heights.push((blocks[0], blocks.len()));
+            concatenated.extend(shard);
+        }
+        assert_eq!(heights, vec![(0, 8), (8, 8), (16, 5)]);
+        assert_eq!(concatenated, binary);
+
+        #[cfg(feature = "compression")]
+        {
+            config.compress = Some(blocks_iterator::Compression::Zstd);
+            let dir = tempdir.path().join("compressed");
+            assert_eq!(write_shards(config, &dir).unwrap(), 3);
+            let shard = std::fs::File::open(dir.join("shard-000001.dat")).unwrap();
+            let reader = blocks_iterator::decoder(shard).unwrap();
+            let heights: Vec<_> = read_block_extras(reader)
+                .map(|b| b.unwrap().height())
+                .collect();
+            assert_eq!(heights, (8..16).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_write_blocks_prevouts_sidecar() {
+        use blocks_iterator::{read_block_extras, zip_prevouts, PrevoutsRecord};
+        use std::io::Cursor;
+
+        let mut config = Config::new("../blocks", bitcoin::Network::Testnet);
+        config.stop_at_height = Some(200);
+        let mut binary = vec![];
+        write_output(config.clone(), &mut binary).unwrap();
+        let expected: Vec<_> = read_block_extras(Cursor::new(binary))
+            .map(|b| b.unwrap())
+            .collect();
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("prevouts");
+        config.prevouts_sidecar = Some(path.clone());
+        let mut blocks = vec![];
+        write_output(config, &mut blocks).unwrap();
+        let prevouts = std::fs::read(path).unwrap();
+        let zipped: Vec<_> = zip_prevouts(Cursor::new(blocks), Cursor::new(prevouts))
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(zipped.len(), expected.len());
+        for ((block, record), block_extra) in zipped.iter().zip(expected.iter()) {
+            assert_eq!(block, block_extra.block());
+            assert_eq!(record, &PrevoutsRecord::from(block_extra));
+        }
+    }
+
+    #[test]
+    fn test_write_undo() {
+        use bitcoin::consensus::deserialize;
+
+        let mut config = Config::new("../blocks", bitcoin::Network::Testnet);
+        config.stop_at_height = Some(200);
+        config.output_format = OutputFormat::Undo;
+        let undo = write_output(config.clone(), vec![]).unwrap();
+
+        // the same records next to the bootstrap output, one for every block but the genesis
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("rev00000.dat");
+        config.output_format = OutputFormat::Bootstrap;
+        config.undo_file = Some(path.clone());
+        write_output(config, vec![]).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), undo);
+
+        let mut rest = &undo[..];
+        let mut records = 0;
+        while !rest.is_empty() {
+            assert_eq!(rest[..4], bitcoin::Network::Testnet.magic().to_bytes());
+            let size = deserialize::<u32>(&rest[4..8]).unwrap() as usize;
+            rest = &rest[8 + size + 32..];
+            records += 1;
+        }
+        assert_eq!(records, 200);
+
+        let mut config = Config::new("../blocks", bitcoin::Network::Testnet);
+        config.stop_at_height = Some(20);
+        config.skip_prevout = true;
+        config.output_format = OutputFormat::Undo;
+        assert!(write_output(config, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_check_blocks() {
+        let mut config = Config::new("../blocks", bitcoin::Network::Testnet);
+        config.skip_prevout = true;
+        config.check = true;
+        let report = check_blocks(config.clone()).unwrap();
+        assert_eq!(report.blocks, 395);
+        assert_eq!(report.heights, Some((0, 394)));
+        assert!(report.gaps.is_empty());
+        assert_eq!(report.forks, 0);
+        let last = blocks_iterator::iter(config.clone()).last().unwrap();
+        assert_eq!(report.tip, Some(last.block_hash()));
+        let printed = report.to_string();
+        assert!(printed.contains("blocks: 395\n"));
+        assert!(printed.contains(&format!("tip: 394 {}\n", last.block_hash())));
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        config.blocks_dir = tempdir.path().to_path_buf();
+        let report = check_blocks(config.clone()).unwrap();
+        assert_eq!(report.blocks, 0);
+        assert_eq!(report.to_string().lines().last(), Some("tip: none"));
+
+        config.blocks_dir = "../not_existing".into();
+        let error = check_blocks(config).unwrap_err().to_string();
+        assert!(error.contains("not found"), "{}", error);
+    }
+
+    #[test]
+    fn test_write_blocks_oversized() {
+        use bitcoin::blockdata::constants::genesis_block;
+        use bitcoin::consensus::encode::{serialize, MAX_VEC_SIZE};
+        use bitcoin::{Block, Network, OutPoint, ScriptBuf, Transaction, TxIn};
+
+        // blocks near `MAX_VEC_SIZE`, the second spending the outputs of the first thus having a
+        // `BlockExtra` twice as big. They don't need to be valid
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for i in 0..8u8 {
+            let prev = chain.last().unwrap();
+            let mut coinbase = prev.txdata[0].clone();
+            coinbase.input[0].script_sig = ScriptBuf::from(vec![i; 8]);
+            let mut txdata = vec![];
+            if i < 2 {
+                coinbase.output[0].script_pubkey = ScriptBuf::from(vec![0x51; MAX_VEC_SIZE / 7]);
+                coinbase.output = vec![coinbase.output[0].clone(); 5];
+            }
+            if i == 1 {
+                let txid = prev.txdata[0].compute_txid();
+                let mut spending = Transaction {
+                    input: vec![],
+                    ..coinbase.clone()
+                };
+                spending.output.truncate(1);
+                for vout in 0..5 {
+                    spending.input.push(TxIn {
+                        previous_output: OutPoint::new(txid, vout),
+                        ..TxIn::default()
+                    });
+                }
+                txdata.push(spending);
+            }
+            txdata.insert(0, coinbase);
+            let mut block = Block {
+                header: prev.header,
+                txdata,
+            };
+            block.header.prev_blockhash = prev.block_hash();
+            block.header.time += 1;
+            block.header.merkle_root = block.compute_merkle_root().unwrap();
+            assert!(serialize(&block).len() < MAX_VEC_SIZE);
+            chain.push(block);
+        }
+        let config = Config::from_blocks(chain, Network::Regtest);
+        let sizes: Vec<_> = blocks_iterator::iter(config.clone())
+            .map(|b| serialize(&b).len())
+            .collect();
+        assert!(sizes[2] > MAX_VEC_SIZE);
+
+        let mut binary = vec![];
+        write_output(config, &mut binary).unwrap();
+        assert_eq!(binary.len(), sizes.iter().sum::<usize>());
+    }
+}
@@ -0,0 +1,128 @@
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A [`Write`] to a memory-mapped file, avoiding a syscall per write when exporting many records,
+/// see `Config::output_mmap`.
+///
+/// The file is pre-sized to the estimated size given at creation and remapped with double the
+/// size when the estimate is exceeded. Call [`MmapOutput::finish`] to truncate the file to the
+/// bytes actually written and to get any error, otherwise it is done on drop ignoring errors.
+pub struct MmapOutput {
+    file: File,
+    mmap: Option<MmapMut>,
+    len: usize,
+}
+
+impl MmapOutput {
+    /// Create or truncate the file at `path`, sized to `estimated_size` bytes
+    pub fn create(path: &Path, estimated_size: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mmap = map(&file, estimated_size.max(1))?;
+        Ok(MmapOutput {
+            file,
+            mmap: Some(mmap),
+            len: 0,
+        })
+    }
+
+    /// Number of bytes written
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Flush the mapped memory and truncate the file to the bytes written
+    pub fn finish(mut self) -> io::Result<()> {
+        self.truncate()
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        if let Some(mmap) = self.mmap.take() {
+            mmap.flush()?;
+            drop(mmap);
+            self.file.set_len(self.len as u64)?;
+        }
+        Ok(())
+    }
+
+    fn reserve(&mut self, additional: usize) -> io::Result<&mut MmapMut> {
+        let mmap = self
+            .mmap
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "output already finished"))?;
+        let required = self.len + additional;
+        let mmap = if required > mmap.len() {
+            mmap.flush()?;
+            let capacity = required.max(mmap.len() * 2);
+            drop(mmap);
+            map(&self.file, capacity)?
+        } else {
+            mmap
+        };
+        Ok(self.mmap.insert(mmap))
+    }
+}
+
+fn map(file: &File, len: usize) -> io::Result<MmapMut> {
+    file.set_len(len as u64)?;
+    // SAFETY: the file has just been created by us and it's not expected to be modified by other
+    // processes while mapped
+    unsafe { MmapMut::map_mut(file) }
+}
+
+impl Write for MmapOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = self.len;
+        let mmap = self.reserve(buf.len())?;
+        mmap[start..start + buf.len()].copy_from_slice(buf);
+        self.len += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.mmap.as_ref() {
+            Some(mmap) => mmap.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MmapOutput {
+    fn drop(&mut self) {
+        let _ = self.truncate();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MmapOutput;
+    use std::io::Write;
+
+    #[test]
+    fn test_mmap_output() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("output");
+        let mut expected = vec![];
+        // a small estimate, to exercise remapping
+        let mut output = MmapOutput::create(&path, 100).unwrap();
+        for i in 0..50u8 {
+            let record = vec![i; i as usize * 7];
+            output.write_all(&record).unwrap();
+            expected.extend(record);
+        }
+        assert_eq!(output.len(), expected.len());
+        output.finish().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        let output = MmapOutput::create(&path, 1_000).unwrap();
+        assert_eq!(output.len(), 0);
+        drop(output);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,58 @@
+//! Serve the height of the latest block iterated over HTTP from an async runtime, while the
+//! iteration goes on.
+//!
+//! ```sh
+//! cargo run --release --example async_latest_height --features async -- \
+//!     ~/.bitcoin/testnet3/blocks testnet 127.0.0.1:3000
+//! curl http://127.0.0.1:3000/
+//! ```
+
+use blocks_iterator::bitcoin::Network;
+use blocks_iterator::{async_iter, Config};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_stream::StreamExt;
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let blocks_dir = args.next().expect("missing blocks dir");
+    let network = Network::from_str(&args.next().expect("missing network")).expect("network");
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:3000".to_string());
+
+    let mut config = Config::new(blocks_dir, network);
+    config.skip_prevout = true;
+
+    // -1 until the first block is iterated
+    let latest = Arc::new(AtomicI64::new(-1));
+    let latest_iterated = latest.clone();
+    tokio::spawn(async move {
+        let mut blocks = async_iter(config);
+        while let Some(block_extra) = blocks.next().await {
+            latest_iterated.store(block_extra.height() as i64, Ordering::Relaxed);
+        }
+        println!("iteration ended");
+    });
+
+    let listener = TcpListener::bind(&addr).await?;
+    println!("serving the latest height at http://{}/", addr);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let height = latest.load(Ordering::Relaxed);
+        tokio::spawn(async move {
+            // the request is not parsed, every path returns the height
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+            let body = height.to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -0,0 +1,83 @@
+//! Iterate blocks whose `blk*.dat` files are served over HTTP, for example by an object storage.
+//!
+//! Blocks are fetched with HTTP range requests, so the server must support the `Range` header.
+//!
+//! ```sh
+//! cargo run --release --example http_block_source --features minreq -- \
+//!     http://localhost:8000/blocks/ testnet blk00000.dat blk00001.dat
+//! ```
+
+use blocks_iterator::bitcoin::Network;
+use blocks_iterator::{BlockSource, Config};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct HttpBlockSource {
+    base_url: String,
+    names: Vec<String>,
+}
+
+fn get(url: &str, range: Option<(usize, usize)>) -> io::Result<minreq::Response> {
+    let mut request = minreq::get(url);
+    if let Some((start, end)) = range {
+        request = request.with_header("Range", format!("bytes={}-{}", start, end - 1));
+    }
+    let response = request
+        .send()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    match response.status_code {
+        200 | 206 | 416 => Ok(response),
+        code => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("GET {} returned {}", url, code),
+        )),
+    }
+}
+
+impl BlockSource for HttpBlockSource {
+    fn files(&self) -> io::Result<Vec<String>> {
+        Ok(self.names.clone())
+    }
+
+    fn read_file(&self, name: &str, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let response = get(&format!("{}{}", self.base_url, name), None)?;
+        buffer.extend(response.into_bytes());
+        Ok(())
+    }
+
+    fn read_range(&self, name: &str, start: usize, end: usize) -> io::Result<Vec<u8>> {
+        if start == end {
+            return Ok(vec![]);
+        }
+        let response = get(&format!("{}{}", self.base_url, name), Some((start, end)))?;
+        Ok(match response.status_code {
+            // the server ignored the range and returned the whole file
+            200 => {
+                let bytes = response.into_bytes();
+                bytes[start.min(bytes.len())..end.min(bytes.len())].to_vec()
+            }
+            // the range starts after the end of the file
+            416 => vec![],
+            _ => response.into_bytes(),
+        })
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let base_url = args.next().expect("missing base url");
+    let network = Network::from_str(&args.next().expect("missing network")).expect("network");
+    let names: Vec<String> = args.collect();
+
+    let mut config = Config::new("", network);
+    config.skip_prevout = true;
+    config.block_files_source = Some(Arc::new(HttpBlockSource { base_url, names }));
+
+    let mut last = None;
+    for block_extra in blocks_iterator::iter(config) {
+        last = Some((block_extra.height(), block_extra.block_hash()));
+    }
+    println!("last block: {:?}", last);
+}
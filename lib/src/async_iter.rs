@@ -0,0 +1,59 @@
+use crate::{try_iter, BlockExtra, Config};
+use log::{debug, error};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+
+/// Return a [`Stream`] of [`BlockExtra`] like the Iterator returned by [`crate::iter`], for async
+/// runtimes.
+///
+/// The blocking iteration runs on its own thread, which forwards the blocks through a
+/// [`tokio::sync::mpsc`] channel of `channels_size` elements, thus polling the stream never
+/// blocks the executor and it can be created outside of a runtime. Dropping the stream stops the
+/// iteration, like dropping the Iterator.
+///
+/// The stream ends also if the iteration stops because of an error, which is logged, use
+/// [`crate::try_iter`] to know the error
+pub fn async_iter(config: Config) -> impl Stream<Item = BlockExtra> {
+    let (sender, receiver) = mpsc::channel(config.channels_size.max(1).into());
+    std::thread::spawn(move || {
+        let mut blocks = try_iter(config);
+        for block_extra in &mut blocks {
+            if sender.blocking_send(block_extra).is_err() {
+                debug!("async receiver disconnected");
+                return;
+            }
+        }
+        if let Some(e) = blocks.error() {
+            error!("async iteration stopped: {}", e);
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use super::async_iter;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_async_iter() {
+        let expected: Vec<_> = iter(test_conf()).map(|b| b.block_hash()).collect();
+        let hashes: Vec<_> = async_iter(test_conf())
+            .map(|b| b.block_hash())
+            .collect()
+            .await;
+        assert_eq!(hashes, expected);
+
+        let mut blocks = async_iter(test_conf());
+        let first = blocks.next().await.unwrap();
+        assert_eq!(first.height(), 0);
+        drop(blocks);
+
+        let mut conf = test_conf();
+        conf.blocks_dir = "../not_existing".into();
+        assert!(async_iter(conf).next().await.is_none());
+    }
+}
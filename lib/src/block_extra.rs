@@ -1,17 +1,28 @@
-use crate::bitcoin::consensus::{encode, Decodable, Encodable};
-use crate::bitcoin::{Block, BlockHash, OutPoint, Transaction, TxOut};
-use crate::FsBlock;
-use bitcoin::consensus::serialize;
+use crate::bitcoin::bip158::{self, BlockFilter};
+use crate::bitcoin::block::Header;
+use crate::bitcoin::blockdata::constants::{DIFFCHANGE_INTERVAL, DIFFCHANGE_TIMESPAN};
+use crate::bitcoin::blockdata::locktime::absolute::LOCK_TIME_THRESHOLD;
+use crate::bitcoin::consensus::encode::{Decodable, VarInt};
+use crate::bitcoin::script::Instruction;
+use crate::bitcoin::WitnessVersion;
+use crate::bitcoin::{
+    Amount, Block, BlockHash, Network, OutPoint, PublicKey, Script, ScriptBuf, Transaction, TxOut,
+    Work,
+};
+use crate::{BlockLocation, CoinbaseSpend, Error, ForkFlags, FsBlock, MuHash, ScriptType};
 use bitcoin::Txid;
-use log::debug;
-use std::collections::HashMap;
+use bitcoin_slices::{bsl, Visit, Visitor};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::io::{Read, Seek, SeekFrom};
-use std::ops::DerefMut;
-use std::sync::OnceLock;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, OnceLock};
 
 /// The bitcoin block and additional metadata returned by the [crate::iter()] method
-#[derive(Debug, Eq, PartialEq)]
+///
+/// The `Debug` implementation prints only a summary, use [`BlockExtra::full_debug()`] to print
+/// every field, block bytes included
+#[derive(Eq, PartialEq)]
 pub struct BlockExtra {
     /// Serialization format version
     pub(crate) version: u8,
@@ -20,9 +31,11 @@ pub struct BlockExtra {
     ///
     /// We store only the bytes because users can potentially avoid instantiating the [`bitcoin::Block`]
     /// avoiding the performance costs and use visitor directly on the bytes with [`bitcoin_slices`]
-    block_bytes: Vec<u8>,
+    ///
+    /// Bytes are reference counted so that they can be shared without copying
+    pub(crate) block_bytes: Arc<[u8]>,
 
-    block: OnceLock<Block>,
+    pub(crate) block: OnceLock<Block>,
 
     /// The bitcoin block hash, same as `block.block_hash()` but result from hashing is cached
     pub(crate) block_hash: BlockHash,
@@ -40,6 +53,8 @@ pub struct BlockExtra {
     /// All the previous outputs of this block. Allowing to validate the script or computing the fee
     /// Note that when configuration `skip_script_pub_key` is true, the script is empty,
     /// when `skip_prevout` is true, this map is empty.
+    /// It also contains the coinbase sentinel unless `insert_coinbase_sentinel` is false, see
+    /// [`BlockExtra::outpoint_values()`]
     pub(crate) outpoint_values: OnceLock<HashMap<OutPoint, TxOut>>,
 
     /// When deserializing we populate this vec and instantiate the map at first access
@@ -58,28 +73,92 @@ pub struct BlockExtra {
     ///
     /// This field is usize because it's not serialized, it's derived from the lenght of txids
     pub(crate) block_total_txs: usize,
+
+    /// Bytes of segwit data (marker, flag and witnesses) in the block, computed together with the
+    /// txids or at first access
+    pub(crate) witness_size: OnceLock<u32>,
+
+    /// Byte range of every transaction in `block_bytes`, computed together with the txids when
+    /// `compute_tx_offsets` is used or at first access
+    pub(crate) tx_offsets: OnceLock<Vec<(usize, usize)>>,
+
+    /// Where the block bytes have been read from, not available when deserialized
+    pub(crate) location: Option<BlockLocation>,
+
+    /// The coinbase of this block overwrote unspent outputs of a previous coinbase with the same
+    /// txid (BIP30), not available when deserialized
+    pub(crate) bip30_duplicate: bool,
+
+    /// Sum of the work of the blocks from the first one iterated up to this one included, computed
+    /// when `track_chainwork` is used, not available when deserialized
+    pub(crate) chain_work: Option<Work>,
+
+    /// Seconds between the first and the last block of the previous difficulty epoch, set on
+    /// retarget blocks when `track_chainwork` is used, not available when deserialized
+    pub(crate) retarget_timespan: Option<i64>,
+
+    /// Children of this block not followed by the iteration, not available when deserialized
+    pub(crate) forks: Vec<BlockHash>,
+
+    /// The schedule used by [`BlockExtra::base_reward()`], not serialized
+    pub(crate) subsidy_schedule: SubsidySchedule,
+
+    /// Data attached by [`crate::Config::enrich`], not serialized
+    pub(crate) extra: HashMap<String, Vec<u8>>,
+
+    /// The coinbase outputs spent in this block, computed when `track_coinbase_maturity` is used,
+    /// not serialized
+    pub(crate) coinbase_spends: Vec<CoinbaseSpend>,
+
+    /// The change of the number of unspent outputs caused by this block, computed when
+    /// `track_utxo_delta` is used, not serialized
+    pub(crate) utxo_delta: Option<i64>,
+
+    /// The hash accumulator of the UTXO set after this block, computed when `compute_utxo_hash`
+    /// is used, not serialized
+    pub(crate) utxo_muhash: Option<Box<MuHash>>,
+
+    /// `(height << 1) | is_coinbase` of the outputs spent in this block, in inputs order, computed
+    /// when `track_undo_data` is used, not serialized
+    pub(crate) prevout_codes: Option<Vec<u32>>,
+
+    /// The sum of the coinbase output values, set when the previous outputs are computed also
+    /// without the coinbase sentinel, which is serialized in its place
+    pub(crate) coinbase_value: Option<u64>,
 }
 
 impl TryFrom<FsBlock> for BlockExtra {
-    type Error = String;
+    type Error = Error;
 
+    /// Read the block bytes, the file may have been truncated since the block was detected, for
+    /// example by a running node, in that case [`Error::TruncatedBlock`] is returned
     fn try_from(fs_block: FsBlock) -> Result<Self, Self::Error> {
-        let err = |e: String, f: &FsBlock| -> String { format!("{:?} {:?}", e, f) };
-        let mut guard = fs_block
-            .file
-            .lock()
-            .map_err(|e| err(e.to_string(), &fs_block))?;
-        let file = guard.deref_mut();
-        file.seek(SeekFrom::Start(fs_block.start as u64))
-            .map_err(|e| err(e.to_string(), &fs_block))?;
-        debug!("going to read: {:?}", file);
-        let mut block_bytes = vec![0u8; fs_block.end - fs_block.start];
-        file.read_exact(&mut block_bytes)
-            .map_err(|e| err(e.to_string(), &fs_block))?;
-
-        Ok(BlockExtra {
+        let block_bytes = fs_block.read_bytes()?;
+        Ok(BlockExtra::from_fs_block(fs_block, block_bytes))
+    }
+}
+
+impl BlockExtra {
+    /// Creates the [`BlockExtra`] of `fs_block` given its bytes, see [`FsBlock::read_bytes()`]
+    pub(crate) fn from_fs_block(fs_block: FsBlock, block_bytes: Vec<u8>) -> Self {
+        let location = match &fs_block.path {
+            Some(path) => BlockLocation::File {
+                path: Arc::clone(path),
+                start: fs_block.start,
+                end: fs_block.end,
+                xor_key: fs_block.xor_key,
+            },
+            None => BlockLocation::Shared {
+                file: Arc::clone(&fs_block.file),
+                start: fs_block.start,
+                end: fs_block.end,
+                xor_key: fs_block.xor_key,
+            },
+        };
+
+        BlockExtra {
             version: fs_block.serialization_version,
-            block_bytes,
+            block_bytes: block_bytes.into(),
             block: OnceLock::new(),
             block_hash: fs_block.hash,
             size: (fs_block.end - fs_block.start) as u32,
@@ -91,7 +170,21 @@ impl TryFrom<FsBlock> for BlockExtra {
             block_total_outputs: fs_block.block_total_outputs,
             txids: vec![],
             block_total_txs: fs_block.block_total_txs as usize,
-        })
+            witness_size: OnceLock::new(),
+            tx_offsets: OnceLock::new(),
+            location: Some(location),
+            bip30_duplicate: false,
+            chain_work: None,
+            retarget_timespan: None,
+            forks: vec![],
+            subsidy_schedule: SubsidySchedule::default(),
+            extra: HashMap::new(),
+            coinbase_spends: vec![],
+            utxo_delta: None,
+            utxo_muhash: None,
+            prevout_codes: None,
+            coinbase_value: None,
+        }
     }
 }
 
@@ -100,18 +193,83 @@ impl BlockExtra {
         self.version
     }
 
+    /// Drop the block bytes and the data derived from them, keeping the metadata. The bytes can be
+    /// read again with [`BlockExtra::reload()`]
+    pub(crate) fn unload(&mut self) {
+        self.block_bytes = Arc::new([]);
+        self.block = OnceLock::new();
+        self.txids = vec![];
+    }
+
+    /// Read again the block bytes from `self.location` and recompute the txids
+    pub(crate) fn reload(&mut self) -> Result<(), String> {
+        let location = self.location.as_ref().ok_or("block location unknown")?;
+        let block_bytes = location
+            .read()
+            .map_err(|e| format!("{:?} {:?}", e, location))?;
+        self.block_bytes = block_bytes.into();
+        self.compute_txids(false);
+        Ok(())
+    }
+
     /// Returns the block from the bytes
     ///
-    /// This is an expensive operation, re-use the results instead of calling it multiple times
+    /// The block is decoded at the first call and cached, following calls are cheap. The decoded
+    /// block is kept alongside the bytes for the lifetime of this struct, roughly doubling its
+    /// memory, consumers holding many blocks may prefer visiting [`BlockExtra::block_bytes()`]
+    /// with [`bitcoin_slices`]. Without the block bytes, see [`BlockExtra::has_block_bytes()`],
+    /// the block has no transactions
     pub fn block(&self) -> &Block {
         self.block
             .get_or_init(|| Block::consensus_decode(&mut &self.block_bytes[..]).unwrap())
     }
 
+    /// Returns the owned block, moved out of the cache if [`BlockExtra::block()`] has already
+    /// been called, decoded from the bytes otherwise
+    pub fn into_block(self) -> Block {
+        match self.block.into_inner() {
+            Some(block) => block,
+            None => Block::consensus_decode(&mut &self.block_bytes[..]).unwrap(),
+        }
+    }
+
     pub fn block_bytes(&self) -> &[u8] {
         &self.block_bytes
     }
 
+    /// Returns false if the transactions of the block are not available, like when deserialized
+    /// from the compact serialization version 2 which keeps only the header and the metadata. In
+    /// that case the block bytes are the header followed by a zero transactions count, the
+    /// methods computing fees return `None` and the ones iterating transactions find none
+    pub fn has_block_bytes(&self) -> bool {
+        // compact records are decoded as a block without transactions, a valid block has the coinbase
+        !(self.version == 2 && self.block_bytes.len() == Header::SIZE + 1)
+    }
+
+    /// Returns the data attached to the block by [`crate::Config::enrich`], empty when
+    /// deserialized
+    pub fn extra(&self) -> &HashMap<String, Vec<u8>> {
+        &self.extra
+    }
+
+    /// Returns the data attached to the block, mutable, used by [`crate::Config::enrich`] to
+    /// attach its own
+    pub fn extra_mut(&mut self) -> &mut HashMap<String, Vec<u8>> {
+        &mut self.extra
+    }
+
+    /// Returns the block bytes as a reference counted slice, allowing to keep them beyond the
+    /// lifetime of this [`BlockExtra`] without copying
+    pub fn block_bytes_shared(&self) -> Arc<[u8]> {
+        Arc::clone(&self.block_bytes)
+    }
+
+    /// Returns the serialized block header, the 80 bytes hashed to get the block hash, for example
+    /// to verify the proof of work with external tools
+    pub fn header_bytes(&self) -> &[u8] {
+        &self.block_bytes[..Header::SIZE]
+    }
+
     pub fn block_hash(&self) -> BlockHash {
         self.block_hash
     }
@@ -124,10 +282,33 @@ impl BlockExtra {
         &self.next
     }
 
+    /// Returns the hashes of the children of this block known when it was emitted but not
+    /// followed by the iteration, the first blocks of stale branches. Empty if there was no fork
+    /// and for deserialized blocks
+    pub fn forks(&self) -> &[BlockHash] {
+        &self.forks
+    }
+
     pub fn height(&self) -> u32 {
         self.height
     }
 
+    /// Returns the consensus features active at the height of this block on `network`, to not
+    /// hardcode activation heights in the analysis
+    pub fn active_forks(&self, network: Network) -> ForkFlags {
+        ForkFlags::at_height(network, self.height)
+    }
+
+    /// Returns the previous outputs spent in this block.
+    ///
+    /// Other than the real prevouts, the map contains a sentinel entry with the null
+    /// `OutPoint::default()` (the one referenced by the coinbase input) mapped to a `TxOut` with an
+    /// empty script and the sum of the coinbase output values, so that the fee of the coinbase
+    /// computes to zero. Use [`BlockExtra::real_prevouts()`] to iterate without the sentinel, and
+    /// [`BlockExtra::coinbase_output_value()`] to access its value. The sentinel is not inserted
+    /// when [`crate::Config::insert_coinbase_sentinel`] is false.
+    ///
+    /// The map is empty when `skip_prevout` is used or the block is below `start_at_height`.
     pub fn outpoint_values(&self) -> &HashMap<OutPoint, TxOut> {
         self.outpoint_values.get_or_init(|| {
             self.outpoint_values_vec
@@ -137,6 +318,111 @@ impl BlockExtra {
         })
     }
 
+    /// Returns true if the coinbase of this block has the same txid of a previous coinbase with
+    /// unspent outputs, which are overwritten and become unspendable (BIP30). This happened on
+    /// mainnet at heights 91842 and 91880.
+    ///
+    /// Available only when the prevouts are computed and the UTXO set is built during the
+    /// iteration, it's always false otherwise
+    pub fn bip30_duplicate(&self) -> bool {
+        self.bip30_duplicate
+    }
+
+    /// Returns the coinbase outputs spent in this block in inputs order, with their creation height
+    /// and whether they reached maturity.
+    ///
+    /// Empty unless `track_coinbase_maturity` or `check_coinbase_maturity` is used, and for
+    /// deserialized blocks. Coinbases created in a previous run, like with `since_file_state` or
+    /// `resume_from_utxo_db`, are unknown thus their spends are not returned
+    pub fn coinbase_spends(&self) -> &[CoinbaseSpend] {
+        &self.coinbase_spends
+    }
+
+    /// Returns the number of outputs created by this block minus the number of inputs it spends,
+    /// provably unspendable outputs and the coinbase input excluded, thus the change of the size of
+    /// the UTXO set. The running sum from the genesis gives the size of the UTXO set over time.
+    ///
+    /// `None` unless `track_utxo_delta` is used, and with `skip_prevout`. A coinbase overwriting
+    /// an unspent output, see [`BlockExtra::bip30_duplicate()`], is counted as created
+    pub fn utxo_delta(&self) -> Option<i64> {
+        self.utxo_delta
+    }
+
+    /// Returns the hash of the UTXO set after this block, like the `muhash` of `gettxoutsetinfo`
+    /// of Bitcoin Core at this height, which shows it in reverse byte order.
+    ///
+    /// `None` unless `compute_utxo_hash` is used. It's computed on every call, some milliseconds
+    pub fn utxo_set_hash(&self) -> Option<[u8; 32]> {
+        self.utxo_muhash.as_ref().map(|muhash| muhash.finalize())
+    }
+
+    /// Returns the undo data of this block like Bitcoin Core stores it in the `rev*.dat` files,
+    /// the outputs spent by every transaction but the coinbase with the height of the block
+    /// creating them, see [`crate::write_undo()`] for the framing of the files.
+    ///
+    /// `None` unless `track_undo_data` is used, and if an output spent was created in a previous
+    /// run, like with `since_file_state`
+    pub fn undo_data(&self) -> Option<Vec<u8>> {
+        self.prevout_codes
+            .as_ref()
+            .map(|codes| crate::undo::serialize_block_undo(self, codes))
+    }
+
+    /// Returns the previous outputs spent in this block in block order, excluding the coinbase
+    /// sentinel contained in [`BlockExtra::outpoint_values()`]
+    pub fn real_prevouts(&self) -> impl Iterator<Item = &(OutPoint, TxOut)> {
+        self.outpoint_values_vec
+            .iter()
+            .filter(|(out_point, _)| !out_point.is_null())
+    }
+
+    /// True if the previous outputs have been computed, also if the block spends nothing and the
+    /// coinbase sentinel is not inserted
+    pub(crate) fn has_prevouts(&self) -> bool {
+        self.coinbase_value.is_some() || !self.outpoint_values_vec.is_empty()
+    }
+
+    /// Returns every input of the block, excluding the coinbase, paired with the output it spends
+    /// taken from [`BlockExtra::outpoint_values()`], in inputs order.
+    ///
+    /// The iterator is empty when the previous outputs are not available, like when
+    /// `skip_prevout` is used
+    pub fn input_prevout_pairs(&self) -> impl Iterator<Item = (&OutPoint, &TxOut)> {
+        let outpoint_values = self.outpoint_values();
+        let txs = if !self.has_prevouts() {
+            &[][..]
+        } else {
+            &self.block().txdata[1..]
+        };
+        txs.iter()
+            .flat_map(|tx| tx.input.iter())
+            .filter_map(move |input| {
+                let prevout = outpoint_values.get(&input.previous_output)?;
+                Some((&input.previous_output, prevout))
+            })
+    }
+
+    /// Returns the sum of the output values of the coinbase transaction in satoshi, which is the
+    /// base reward plus the fee unless the miner claimed less.
+    ///
+    /// Returns `None` without the block bytes, see [`BlockExtra::has_block_bytes()`]
+    pub fn coinbase_output_value(&self) -> Option<u64> {
+        if let Some(value) = self.coinbase_value {
+            return Some(value);
+        }
+        match self
+            .outpoint_values_vec
+            .iter()
+            .find(|(out_point, _)| out_point.is_null())
+        {
+            Some((_, tx_out)) => Some(tx_out.value.to_sat()),
+            None => {
+                let coinbase = self.block().txdata.first()?;
+                Some(coinbase.output.iter().map(|o| o.value.to_sat()).sum())
+            }
+        }
+    }
+
     pub fn block_total_inputs(&self) -> usize {
         self.block_total_inputs as usize
     }
@@ -145,248 +431,2188 @@ impl BlockExtra {
         self.block_total_outputs as usize
     }
 
+    /// Returns the number of inputs in the block, coinbase included. Unlike
+    /// [`BlockExtra::block_total_inputs()`], when the stored count is not populated, like in a
+    /// [`BlockExtra`] built from a [`Block`], the inputs are counted visiting the block bytes
+    pub fn input_count(&self) -> usize {
+        self.counts().0
+    }
+
+    /// Returns the number of outputs in the block. Unlike
+    /// [`BlockExtra::block_total_outputs()`], when the stored count is not populated the outputs
+    /// are counted visiting the block bytes
+    pub fn output_count(&self) -> usize {
+        self.counts().1
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        // a block has at least the coinbase input and output, zero means not populated
+        if (self.block_total_inputs > 0 && self.block_total_outputs > 0) || !self.has_block_bytes()
+        {
+            return (self.block_total_inputs(), self.block_total_outputs());
+        }
+        let mut visitor = InputsOutputsCounter::default();
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        (visitor.inputs, visitor.outputs)
+    }
+
     pub fn txids(&self) -> &Vec<Txid> {
         &self.txids
     }
 
-    /// Returns the average transaction fee in the block
+    /// Returns the size of the block without segwit data, as seen by pre-segwit nodes (BIP141)
+    pub fn stripped_size(&self) -> u32 {
+        self.size - self.witness_size()
+    }
+
+    /// Returns true if any transaction in the block contains segwit data
+    pub fn has_witness(&self) -> bool {
+        self.witness_size() > 0
+    }
+
+    fn witness_size(&self) -> u32 {
+        *self.witness_size.get_or_init(|| {
+            let mut visitor = WitnessSizeVisitor(0);
+            bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+            visitor.0
+        })
+    }
+
+    /// Returns the block header, decoding only its bytes if the block is not decoded yet
+    pub(crate) fn header(&self) -> Header {
+        match self.block.get() {
+            Some(block) => block.header,
+            None => Header::consensus_decode(&mut &self.block_bytes[..]).expect("valid header"),
+        }
+    }
+
+    /// Returns the timestamp of the block header, in seconds since the Unix epoch. Only the header
+    /// is decoded if the block is not decoded yet
+    pub fn time(&self) -> u32 {
+        self.header().time
+    }
+
+    /// Returns the difficulty of the block computed from the header `bits`, as a multiple of the
+    /// minimum difficulty of mainnet
+    pub fn difficulty(&self) -> f64 {
+        self.header().difficulty_float()
+    }
+
+    /// Returns the cumulative work of the chain up to this block included, starting from the
+    /// genesis block.
+    ///
+    /// Available only when [`crate::Config::track_chainwork`] is used and the iteration started
+    /// from the genesis block, it's `None` for blocks emitted by a run resumed from
+    /// `since_file_state` and for deserialized blocks.
+    pub fn chain_work(&self) -> Option<Work> {
+        self.chain_work
+    }
+
+    /// Returns true if the difficulty can change at this block, the first of an epoch of
+    /// [`DIFFCHANGE_INTERVAL`] blocks, genesis excluded
+    pub fn is_retarget(&self) -> bool {
+        self.height != 0 && self.height % DIFFCHANGE_INTERVAL == 0
+    }
+
+    /// Returns the actual and the expected ([`DIFFCHANGE_TIMESPAN`]) seconds of the previous
+    /// epoch, which the difficulty retarget at this block is based on. The actual timespan is
+    /// measured like Bitcoin Core, from the time of the first block of the epoch to the time of
+    /// the last one, it may be negative since block times aren't monotonic.
+    ///
+    /// Available only on retarget blocks, when [`crate::Config::track_chainwork`] is used and
+    /// the iteration started from the genesis block, like [`BlockExtra::chain_work()`]
+    pub fn retarget_timespan(&self) -> Option<(i64, u32)> {
+        self.retarget_timespan
+            .map(|actual| (actual, DIFFCHANGE_TIMESPAN))
+    }
+
+    /// Returns the txid of every transaction in the block together with the `start` and `end` of
+    /// its bytes in [`BlockExtra::block_bytes()`], allowing to slice out a raw transaction without
+    /// decoding the block.
+    ///
+    /// Offsets are computed during the iteration when [`crate::Config::compute_tx_offsets`] is
+    /// used, otherwise at first access
+    pub fn tx_offsets(&self) -> Vec<(Txid, usize, usize)> {
+        self.txids
+            .iter()
+            .zip(self.offsets().iter())
+            .map(|(txid, (start, end))| (*txid, *start, *end))
+            .collect()
+    }
+
+    /// Returns the raw bytes of the transaction at position `index` in the block, `raw_tx(0)` is
+    /// the coinbase. The slice points into [`BlockExtra::block_bytes()`], nothing is decoded nor
+    /// copied besides computing the offsets at first access, see [`BlockExtra::tx_offsets()`]
+    pub fn raw_tx(&self, index: usize) -> Option<&[u8]> {
+        let (start, end) = *self.offsets().get(index)?;
+        Some(&self.block_bytes[start..end])
+    }
+
+    fn offsets(&self) -> &[(usize, usize)] {
+        self.tx_offsets.get_or_init(|| {
+            let mut visitor = TxOffsetsVisitor::default();
+            bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+            visitor.offsets
+        })
+    }
+
+    /// Returns the txids of the transactions of the block with their weight in weight units
+    /// (BIP141), in block order. Computed from the block bytes without decoding the transactions,
+    /// it's the denominator of the fee rate
+    pub fn tx_weights(&self) -> Vec<(Txid, u64)> {
+        let txids = self.txids_or_compute();
+        let mut visitor = TxWeightsVisitor(Vec::with_capacity(txids.len()));
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        txids.iter().copied().zip(visitor.0).collect()
+    }
+
+    /// Returns the average transaction fee in the block, `None` if the fee is not available or the
+    /// number of transactions is unknown
     pub fn average_fee(&self) -> Option<f64> {
+        if self.block_total_txs == 0 {
+            return None;
+        }
         Some(self.fee()? as f64 / self.block_total_txs as f64)
     }
 
-    /// Returns the total fee of the block
+    /// Returns the total fee of the block, `None` if previous outputs or the block bytes are not
+    /// available
     pub fn fee(&self) -> Option<u64> {
+        if !self.has_block_bytes() {
+            return None;
+        }
         let mut total = 0u64;
         for tx in self.block().txdata.iter() {
-            total += self.tx_fee(tx)?;
+            total = total.checked_add(self.tx_fee(tx)?)?;
         }
         Some(total)
     }
 
-    /// Returns the fee of a transaction contained in the block
+    /// Returns the fee of a transaction contained in the block.
+    ///
+    /// Returns `None` if a previous output is missing, or if the outputs exceed the inputs, which
+    /// happens only with inconsistent previous outputs
     pub fn tx_fee(&self, tx: &Transaction) -> Option<u64> {
-        let output_total: u64 = tx.output.iter().map(|el| el.value.to_sat()).sum();
-        let mut input_total = 0u64;
+        if tx.is_coinbase() && self.coinbase_value.is_some() {
+            // the sentinel may be missing, the coinbase fee is zero anyway
+            return Some(0);
+        }
+        let mut output_total = Amount::ZERO;
+        for output in tx.output.iter() {
+            output_total = output_total.checked_add(output.value)?;
+        }
+        let mut input_total = Amount::ZERO;
         for input in tx.input.iter() {
-            input_total += self
-                .outpoint_values()
-                .get(&input.previous_output)?
-                .value
-                .to_sat();
+            let prevout = self.outpoint_values().get(&input.previous_output)?;
+            input_total = input_total.checked_add(prevout.value)?;
+        }
+        Some(input_total.checked_sub(output_total)?.to_sat())
+    }
+
+    /// Returns the txids of the transactions of the block with their fee rate in sat/vB, sorted by
+    /// descending fee rate, the coinbase excluded. Transactions with the same fee rate keep the
+    /// block order. Useful to compare the block with a greedy fee maximizing template.
+    ///
+    /// Returns an empty vector if the fee of any transaction is not available, like when
+    /// `skip_prevout` is used
+    pub fn txs_by_feerate(&self) -> Vec<(Txid, f64)> {
+        let block = self.block();
+        let mut feerates = Vec::with_capacity(block.txdata.len().saturating_sub(1));
+        let txids = self.txids_or_compute();
+        for (txid, tx) in txids.iter().zip(block.txdata.iter()).skip(1) {
+            match self.tx_fee(tx) {
+                Some(fee) => feerates.push((*txid, fee as f64 / tx.vsize() as f64)),
+                None => return vec![],
+            }
         }
-        Some(input_total - output_total)
+        feerates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        feerates
     }
 
-    /// Return the base block reward in satoshi
+    /// Return the base block reward in satoshi, according to the [`SubsidySchedule`] of the
+    /// iterated chain, the bitcoin one for deserialized blocks
     pub fn base_reward(&self) -> u64 {
-        let initial = 50 * 100_000_000u64;
-        let division = self.height as u64 / 210_000u64;
-        initial >> division
+        self.subsidy_schedule.subsidy(self.height)
     }
 
-    /// Iterate transactions of blocks together with their txids
+    /// Returns an approximation of the block fee as the coinbase output value minus the subsidy,
+    /// without needing the previous outputs, thus available with [`crate::Config::skip_prevout`]
+    /// and much cheaper than [`BlockExtra::fee()`].
     ///
-    /// requires serializing the block bytes, consider using a visitor on the bytes for performance
-    pub fn iter_tx(&self) -> impl Iterator<Item = (&Txid, &Transaction)> {
-        self.txids.iter().zip(self.block().txdata.iter())
+    /// The result is lower than the real fee when the miner doesn't claim the whole reward, see
+    /// [`BlockExtra::coinbase_breakdown()`], and may be negative. Returns `None` without the block
+    /// bytes
+    pub fn fee_from_coinbase(&self) -> Option<i64> {
+        Some(self.coinbase_output_value()? as i64 - self.base_reward() as i64)
+    }
+
+    /// Returns how the coinbase reward splits between the subsidy and the fees, and how much of it
+    /// the miner didn't claim, thus burned.
+    ///
+    /// Returns `None` if previous outputs are not available, or if the coinbase claims more than
+    /// the subsidy plus the fees, which happens only in invalid blocks
+    pub fn coinbase_breakdown(&self) -> Option<CoinbaseBreakdown> {
+        let subsidy = self.base_reward();
+        let fees = self.fee()?;
+        let claimed = self.coinbase_output_value()?;
+        let burned = subsidy.checked_add(fees)?.checked_sub(claimed)?;
+        Some(CoinbaseBreakdown {
+            subsidy,
+            fees,
+            claimed,
+            burned,
+        })
+    }
+
+    /// Returns the BIP158 basic block filter of this block, containing the output scripts and the
+    /// scripts of the spent previous outputs.
+    ///
+    /// Returns `None` if previous outputs are not available, like when `skip_prevout` is used, or
+    /// without the block bytes, see [`BlockExtra::has_block_bytes()`]
+    pub fn build_bip158_filter(&self) -> Option<BlockFilter> {
+        if !self.has_block_bytes() {
+            return None;
+        }
+        BlockFilter::new_script_filter(self.block(), |outpoint| {
+            self.outpoint_values()
+                .get(outpoint)
+                .map(|tx_out| tx_out.script_pubkey.as_script())
+                .ok_or(bip158::Error::UtxoMissing(*outpoint))
+        })
+        .ok()
     }
-}
 
-impl Encodable for BlockExtra {
-    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
-        &self,
-        writer: &mut W,
-    ) -> Result<usize, bitcoin::io::Error> {
-        let mut written = 0;
-        written += self.version.consensus_encode(writer)?;
-        if self.version == 1 {
-            written += self.size.consensus_encode(writer)?;
-        }
-        writer.write_all(&self.block_bytes)?;
-        written += self.block_bytes.len();
-        written += self.block_hash.consensus_encode(writer)?;
-        if self.version == 0 {
-            written += self.size.consensus_encode(writer)?;
-        }
-        written += self.next.consensus_encode(writer)?;
-        written += self.height.consensus_encode(writer)?;
-        written += (self.outpoint_values_vec.len() as u32).consensus_encode(writer)?;
-        for (out_point, tx_out) in self.outpoint_values_vec.iter() {
-            written += out_point.consensus_encode(writer)?;
-            written += tx_out.consensus_encode(writer)?;
-        }
-        written += self.block_total_inputs.consensus_encode(writer)?;
-        written += self.block_total_outputs.consensus_encode(writer)?;
-        written += (self.txids.len() as u32).consensus_encode(writer)?;
-        for txid in self.txids.iter() {
-            written += txid.consensus_encode(writer)?;
-        }
-        Ok(written)
-    }
-}
-
-impl Decodable for BlockExtra {
-    fn consensus_decode<D: bitcoin::io::Read + ?Sized>(d: &mut D) -> Result<Self, encode::Error> {
-        let version = Decodable::consensus_decode(d)?;
-        let (size, block_bytes, block_hash) = match version {
-            0 => {
-                let block = Block::consensus_decode(d)?;
-                let block_bytes = serialize(&block);
-                let block_hash = Decodable::consensus_decode(d)?;
-                let size = Decodable::consensus_decode(d)?;
-                (size, block_bytes, block_hash)
+    /// Returns the transactions of this block paying to, or spending from, any of the `scripts`.
+    ///
+    /// Inputs are matched only if the previous outputs are available, see
+    /// [`BlockExtra::outpoint_values()`]
+    pub fn watch_hits(&self, scripts: &HashSet<ScriptBuf>) -> Vec<WatchHit> {
+        let mut hits = vec![];
+        let txids = self.txids_or_compute();
+        for (txid, tx) in txids.iter().zip(self.block().txdata.iter()) {
+            let prevouts = tx
+                .input
+                .iter()
+                .filter_map(|input| self.outpoint_values().get(&input.previous_output));
+            let mut matched: Vec<ScriptBuf> = vec![];
+            for script in prevouts.chain(tx.output.iter()).map(|o| &o.script_pubkey) {
+                if scripts.contains(script) && !matched.contains(script) {
+                    matched.push(script.clone());
+                }
             }
-            1 => {
-                let size = Decodable::consensus_decode(d)?;
-                let mut block_bytes = vec![0u8; size as usize];
-                d.read_exact(&mut block_bytes)?;
-                let block_hash = Decodable::consensus_decode(d)?;
-                (size, block_bytes, block_hash)
+            if !matched.is_empty() {
+                hits.push(WatchHit {
+                    height: self.height,
+                    txid: *txid,
+                    matched,
+                });
             }
-            _ => {
-                return Err(encode::Error::ParseFailed(
-                    "Only version 0 and 1 are supported",
-                ));
+        }
+        hits
+    }
+
+    /// Returns a value debug printing every field, while the `Debug` of [`BlockExtra`] is a summary
+    pub fn full_debug(&self) -> FullDebug<'_> {
+        FullDebug(self)
+    }
+
+    /// Returns the outputs created in this block which are spent by a following transaction in
+    /// the same block, in creation order
+    pub fn intra_block_spends(&self) -> Vec<OutPoint> {
+        let txids = self.txids_or_compute();
+        let txdata = &self.block().txdata;
+        let created: HashSet<&Txid> = txids.iter().collect();
+        let spent: HashSet<&OutPoint> = txdata
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.input.iter())
+            .map(|input| &input.previous_output)
+            .filter(|outpoint| created.contains(&outpoint.txid))
+            .collect();
+
+        let mut result = Vec::with_capacity(spent.len());
+        for (txid, tx) in txids.iter().zip(txdata.iter()) {
+            for vout in 0..tx.output.len() as u32 {
+                let outpoint = OutPoint::new(*txid, vout);
+                if spent.contains(&outpoint) {
+                    result.push(outpoint);
+                }
             }
+        }
+        result
+    }
+
+    /// Iterate transactions of blocks together with their txids
+    ///
+    /// requires serializing the block bytes, consider using a visitor on the bytes for performance.
+    /// Iterates nothing when the txids are not computed, see [`crate::Config::compute_txids`]
+    pub fn iter_tx(&self) -> impl Iterator<Item = (&Txid, &Transaction)> {
+        self.txids.iter().zip(self.block().txdata.iter())
+    }
+
+    /// Returns the txids of the transactions signaling replaceability as defined in BIP125, having
+    /// at least an input with sequence lower than `0xfffffffe`. The coinbase is never included
+    pub fn rbf_signaling_txids(&self) -> Vec<Txid> {
+        let mut visitor = RbfVisitor {
+            current: 0,
+            signaling: false,
+            found: vec![],
         };
-        let mut b = BlockExtra {
-            version,
-            block_bytes,
-            block: OnceLock::new(),
-            block_hash,
-            size,
-            next: Decodable::consensus_decode(d)?,
-            height: Decodable::consensus_decode(d)?,
-            outpoint_values: OnceLock::new(),
-            outpoint_values_vec: {
-                let len = u32::consensus_decode(d)?;
-                let mut m = Vec::with_capacity(len as usize);
-                for _ in 0..len {
-                    m.push((
-                        Decodable::consensus_decode(d)?,
-                        Decodable::consensus_decode(d)?,
-                    ));
-                }
-                m
-            },
-            block_total_inputs: Decodable::consensus_decode(d)?,
-            block_total_outputs: Decodable::consensus_decode(d)?,
-            txids: {
-                let len = u32::consensus_decode(d)?;
-                let mut v = Vec::with_capacity(len as usize);
-                for _ in 0..len {
-                    v.push(Decodable::consensus_decode(d)?);
-                }
-                v
-            },
-            block_total_txs: 0, // To be initialized
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        let txids = self.txids_or_compute();
+        visitor
+            .found
+            .into_iter()
+            .filter(|i| *i > 0)
+            .map(|i| txids[i])
+            .collect()
+    }
+
+    /// Returns the transaction at position `index` in the block, `nth_tx(0)` is the coinbase.
+    ///
+    /// Only the requested transaction is decoded, unless the block is already decoded by a
+    /// previous call to [`BlockExtra::block()`]
+    pub fn nth_tx(&self, index: usize) -> Option<Transaction> {
+        if let Some(block) = self.block.get() {
+            return block.txdata.get(index).cloned();
+        }
+        let mut visitor = NthTxVisitor {
+            index,
+            current: 0,
+            tx: None,
         };
-        b.block_total_txs = b.txids.len();
-        Ok(b)
+        // the visit is interrupted when the transaction is found, thus the result is an error
+        let _ = bsl::Block::visit(&self.block_bytes, &mut visitor);
+        visitor.tx
     }
-}
 
-#[cfg(test)]
-pub mod test {
-    use crate::bitcoin::consensus::serialize;
-    use crate::bitcoin::{Block, OutPoint, TxOut};
-    use crate::BlockExtra;
-    use bitcoin::block::{Header, Version};
-    use bitcoin::consensus::encode::serialize_hex;
-    use bitcoin::consensus::{deserialize, Decodable};
-    use bitcoin::hash_types::TxMerkleNode;
-    use bitcoin::hashes::Hash;
-    use bitcoin::{BlockHash, CompactTarget};
-    use std::sync::OnceLock;
+    /// Returns the public keys appearing in the block, in order of appearance and with
+    /// duplicates. Covered are:
+    /// - pay to public key outputs, compressed or uncompressed
+    /// - the key revealed in the script sig spending pay to public key hash
+    /// - witness elements which are valid compressed keys, like the one revealed spending pay to
+    ///   witness public key hash
+    ///
+    /// Keys inside p2sh and p2wsh scripts, like multisig ones, and taproot x-only keys are not
+    /// returned. The block is visited without decoding it
+    pub fn extract_pubkeys(&self) -> Vec<PublicKey> {
+        let mut visitor = PubkeysVisitor(vec![]);
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        visitor.0
+    }
 
-    #[test]
-    fn block_extra_round_trip() {
-        let be = block_extra();
-        let ser = serialize(&be);
-        let deser = deserialize(&ser).unwrap();
-        assert_eq!(be, deser);
+    /// Returns the sum in satoshi of the output values of the block, coinbase included. The block
+    /// is visited without decoding it
+    pub fn total_output_value(&self) -> u64 {
+        let mut visitor = TotalValueVisitor(0);
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        visitor.0
+    }
 
-        let mut be1 = be;
-        be1.version = 1;
-        let ser = serialize(&be1);
-        let deser = deserialize(&ser).unwrap();
-        assert_eq!(be1, deser);
+    /// Returns the sum in satoshi of the output values of the block, excluding the provably
+    /// unspendable `OP_RETURN` outputs. Together with the subsidy, useful to reconcile the
+    /// theoretical supply with the spendable one. The block is visited without decoding it
+    pub fn spendable_output_value(&self) -> u64 {
+        let mut visitor = SpendableValueVisitor(0);
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        visitor.0
     }
 
-    pub fn block_extra() -> BlockExtra {
-        let block = Block {
-            header: Header {
-                version: Version::from_consensus(0),
-                prev_blockhash: BlockHash::all_zeros(),
-                merkle_root: TxMerkleNode::all_zeros(),
-                time: 0,
-                bits: CompactTarget::from_consensus(0),
-                nonce: 0,
-            },
-            txdata: vec![],
-        };
-        let block_bytes = serialize(&block);
-        let size = block_bytes.len() as u32;
+    /// Returns the size statistics of the scripts in the block: script sigs, script pubkeys and
+    /// witnesses, useful to know what is consuming the block space. The block is visited once
+    /// without decoding it
+    pub fn script_size_stats(&self) -> ScriptSizeStats {
+        let mut visitor = ScriptSizeVisitor::default();
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        visitor.stats
+    }
 
-        BlockExtra {
-            version: 0,
-            block_bytes,
-            block: OnceLock::new(),
-            block_hash: BlockHash::from_slice(&[
-                1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0,
-            ])
-            .unwrap(),
-            size,
-            next: vec![BlockHash::all_zeros()],
-            height: 0,
-            outpoint_values_vec: {
-                let mut m = Vec::new();
-                m.push((OutPoint::default(), TxOut::NULL));
-                m
-            },
-            outpoint_values: OnceLock::new(),
-            block_total_inputs: 0,
-            block_total_outputs: 0,
-            block_total_txs: 0,
-            txids: vec![],
+    /// Returns the number of transactions for each transaction version, coinbase included,
+    /// useful to study the adoption of version 2 enabling relative locktimes (BIP68). The block is
+    /// visited once without decoding it
+    pub fn tx_versions(&self) -> TxVersions {
+        let mut visitor = TxVersionsVisitor::default();
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        visitor.0
+    }
+
+    /// Returns the number of transactions by kind of locktime, coinbase included. The block is
+    /// visited once without decoding it
+    pub fn locktime_histogram(&self) -> LocktimeHistogram {
+        let mut visitor = LocktimeVisitor::default();
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        visitor.0
+    }
+
+    /// Returns the witness commitment of the block as defined in BIP141, the 32 bytes following
+    /// the `aa21a9ed` marker in the last coinbase output committing to it.
+    ///
+    /// Returns `None` for pre-segwit blocks and for blocks without a commitment. Only the coinbase
+    /// outputs are visited, the block is not decoded
+    pub fn witness_commitment(&self) -> Option<[u8; 32]> {
+        let mut visitor = WitnessCommitmentVisitor(None);
+        // the visit is interrupted after the coinbase, thus the result is an error
+        let _ = bsl::Block::visit(&self.block_bytes, &mut visitor);
+        visitor.0
+    }
+
+    /// Returns the transaction with `txid` together with the outputs spent by its inputs, in
+    /// inputs order, as needed to compute signature hashes or to verify scripts.
+    ///
+    /// Returns `None` if the transaction is not in the block, if it's the coinbase or if the
+    /// previous outputs are not available, like when `skip_prevout` is used
+    pub fn tx_with_inputs(&self, txid: &Txid) -> Option<TxWithPrevouts> {
+        let index = self.txids_or_compute().iter().position(|t| t == txid)?;
+        if index == 0 {
+            return None;
         }
+        let tx = self.nth_tx(index)?;
+        let prevouts = tx
+            .input
+            .iter()
+            .map(|input| self.outpoint_values().get(&input.previous_output).cloned())
+            .collect::<Option<Vec<_>>>()?;
+        Some(TxWithPrevouts { tx, prevouts })
     }
 
-    #[test]
-    fn test_block_reward() {
-        let mut be = block_extra();
-        assert_eq!(be.base_reward(), 50 * 100_000_000);
-        be.height = 209_999;
-        assert_eq!(be.base_reward(), 50 * 100_000_000);
-        be.height = 210_000;
-        assert_eq!(be.base_reward(), 25 * 100_000_000);
-        be.height = 420_000;
-        assert_eq!(be.base_reward(), 1_250_000_000);
-        be.height = 630_000;
-        assert_eq!(be.base_reward(), 625_000_000);
+    /// Returns the category of the script of the output spent by `outpoint` in this block.
+    ///
+    /// Returns `None` if `outpoint` isn't spent in this block, for the coinbase input, or if the
+    /// previous outputs are not available, like when `skip_prevout` is used
+    pub fn prevout_script_type(&self, outpoint: &OutPoint) -> Option<ScriptType> {
+        if outpoint.is_null() {
+            return None;
+        }
+        let prevout = self.outpoint_values().get(outpoint)?;
+        Some(ScriptType::of(&prevout.script_pubkey))
     }
 
-    #[test]
-    fn test_hex() {
-        let be = block_extra();
-        let hex0 = serialize_hex(&be);
-        assert_eq!(hex0, "0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001000000000000000000000000000000000000000000000000000000000000005100000001000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000ffffffffffffffffffffffff00000000000000000000000000");
+    /// Returns the total sigop cost of the block as limited by consensus to
+    /// [`bitcoin::Weight::MAX_BLOCK`] / 50: legacy sigops in scripts and p2sh redeem scripts are
+    /// weighted 4, segwit v0 sigops 1. Taproot spends don't count, they have a per input budget.
+    ///
+    /// Returns `None` if the previous outputs are not available, like when `skip_prevout` is used,
+    /// since they are needed to count p2sh and segwit sigops. Transactions are decoded one at a
+    /// time visiting the block bytes, the block is not cached
+    pub fn sigops(&self) -> Option<u64> {
+        if !self.has_prevouts() || !self.has_block_bytes() {
+            return None;
+        }
+        let mut visitor = SigopsVisitor {
+            outpoint_values: self.outpoint_values(),
+            cost: 0,
+            missing: false,
+        };
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        (!visitor.missing).then_some(visitor.cost)
+    }
+
+    /// Returns the number of inputs by kind of output spent, the coinbase input excluded. Nested
+    /// segwit spends are told apart from legacy p2sh ones by the redeem script in the script sig.
+    ///
+    /// Returns `None` if the previous outputs are not available, like when `skip_prevout` is used,
+    /// since the script pubkeys spent are needed
+    pub fn input_type_counts(&self) -> Option<InputTypeCounts> {
+        if !self.has_prevouts() || !self.has_block_bytes() {
+            return None;
+        }
+        let mut visitor = InputTypeVisitor {
+            outpoint_values: self.outpoint_values(),
+            counts: InputTypeCounts::default(),
+            missing: false,
+        };
+        bsl::Block::visit(&self.block_bytes, &mut visitor).expect("block bytes are valid");
+        (!visitor.missing).then_some(visitor.counts)
+    }
+}
+
+struct RbfVisitor {
+    current: usize,
+    signaling: bool,
+    found: Vec<usize>,
+}
 
-        let mut be1 = be;
-        be1.version = 1;
-        let hex1 = serialize_hex(&be1);
-        assert_eq!(hex1, "0151000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000001000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000ffffffffffffffffffffffff00000000000000000000000000");
-        assert_ne!(hex0, hex1);
+impl Visitor for RbfVisitor {
+    fn visit_tx_in(&mut self, _vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
+        self.signaling |= tx_in.sequence() < 0xffff_fffe;
+        ControlFlow::Continue(())
     }
 
-    #[test]
-    fn block_extra_unsupported_version() {
-        assert_eq!(
-            "parse failed: Only version 0 and 1 are supported",
-            BlockExtra::consensus_decode(&mut &[2u8][..])
-                .unwrap_err()
-                .to_string()
-        );
+    fn visit_transaction(&mut self, _tx: &bsl::Transaction) -> ControlFlow<()> {
+        if std::mem::take(&mut self.signaling) {
+            self.found.push(self.current);
+        }
+        self.current += 1;
+        ControlFlow::Continue(())
+    }
+}
+
+struct NthTxVisitor {
+    index: usize,
+    current: usize,
+    tx: Option<Transaction>,
+}
+
+impl Visitor for NthTxVisitor {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        if self.current == self.index {
+            self.tx = Some(
+                Transaction::consensus_decode(&mut tx.as_ref()).expect("block bytes are valid"),
+            );
+            return ControlFlow::Break(());
+        }
+        self.current += 1;
+        ControlFlow::Continue(())
+    }
+}
+
+struct PubkeysVisitor(Vec<PublicKey>);
+
+impl Visitor for PubkeysVisitor {
+    fn visit_tx_in(&mut self, _vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
+        // spending p2pkh the script sig is `<signature> <public key>`
+        let mut pushes = Script::from_bytes(tx_in.script_sig()).instructions();
+        if let (Some(Ok(Instruction::PushBytes(_))), Some(Ok(Instruction::PushBytes(key))), None) =
+            (pushes.next(), pushes.next(), pushes.next())
+        {
+            if let Ok(key) = PublicKey::from_slice(key.as_bytes()) {
+                self.0.push(key);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        if let Some(key) = Script::from_bytes(tx_out.script_pubkey()).p2pk_public_key() {
+            self.0.push(key);
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn visit_witness_element(&mut self, _witness_i: usize, witness_element: &[u8]) {
+        if witness_element.len() == 33 {
+            if let Ok(key) = PublicKey::from_slice(witness_element) {
+                self.0.push(key);
+            }
+        }
+    }
+}
+
+/// Sums the values of all the outputs
+struct TotalValueVisitor(u64);
+
+impl Visitor for TotalValueVisitor {
+    fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        self.0 += tx_out.value();
+        ControlFlow::Continue(())
+    }
+}
+
+/// Sums the values of the outputs not starting with `OP_RETURN`
+struct SpendableValueVisitor(u64);
+
+impl Visitor for SpendableValueVisitor {
+    fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        if !Script::from_bytes(tx_out.script_pubkey()).is_op_return() {
+            self.0 += tx_out.value();
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[derive(Default)]
+struct ScriptSizeVisitor {
+    stats: ScriptSizeStats,
+    witness_size: usize,
+}
+
+impl Visitor for ScriptSizeVisitor {
+    fn visit_tx_in(&mut self, _vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
+        self.stats.script_sig.add(tx_in.script_sig().len());
+        ControlFlow::Continue(())
+    }
+
+    fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        self.stats.script_pubkey.add(tx_out.script_pubkey().len());
+        ControlFlow::Continue(())
+    }
+
+    fn visit_witness_total_element(&mut self, witness_total: usize) {
+        self.witness_size = VarInt(witness_total as u64).size();
+    }
+
+    fn visit_witness_element(&mut self, _witness_i: usize, witness_element: &[u8]) {
+        self.witness_size += VarInt(witness_element.len() as u64).size() + witness_element.len();
+    }
+
+    fn visit_witness_end(&mut self) {
+        self.stats.witness.add(self.witness_size);
+    }
+}
+
+#[derive(Default)]
+struct TxVersionsVisitor(TxVersions);
+
+impl Visitor for TxVersionsVisitor {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        *self.0 .0.entry(tx.version()).or_default() += 1;
+        ControlFlow::Continue(())
+    }
+}
+
+#[derive(Default)]
+struct LocktimeVisitor(LocktimeHistogram);
+
+impl Visitor for LocktimeVisitor {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        let count = match tx.locktime() {
+            0 => &mut self.0.zero,
+            l if l < LOCK_TIME_THRESHOLD => &mut self.0.height,
+            _ => &mut self.0.time,
+        };
+        *count += 1;
+        ControlFlow::Continue(())
+    }
+}
+
+/// Keeps the commitment in the last coinbase output matching the BIP141 pattern
+struct WitnessCommitmentVisitor(Option<[u8; 32]>);
+
+impl Visitor for WitnessCommitmentVisitor {
+    fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        // OP_RETURN OP_PUSHBYTES_36 followed by the marker
+        const PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        let script = tx_out.script_pubkey();
+        if script.len() >= 38 && script.starts_with(&PREFIX) {
+            self.0 = <[u8; 32]>::try_from(&script[6..38]).ok();
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn visit_transaction(&mut self, _tx: &bsl::Transaction) -> ControlFlow<()> {
+        // outputs of the coinbase are visited, stop
+        ControlFlow::Break(())
+    }
+}
+
+/// Returns the bytes of segwit data (marker, flag and witnesses) in `tx`
+pub(crate) fn tx_witness_size(tx: &bsl::Transaction) -> u32 {
+    let (version, inputs_outputs, locktime) = tx.txid_preimage();
+    (tx.as_ref().len() - version.len() - inputs_outputs.len() - locktime.len()) as u32
+}
+
+/// Collects the byte range of every transaction in the visited block
+#[derive(Default)]
+pub(crate) struct TxOffsetsVisitor {
+    pub(crate) offsets: Vec<(usize, usize)>,
+    next: usize,
+}
+
+impl Visitor for TxOffsetsVisitor {
+    fn visit_block_begin(&mut self, total_transactions: usize) {
+        self.offsets.reserve_exact(total_transactions);
+        // transactions follow the header and their count
+        self.next = Header::SIZE + VarInt(total_transactions as u64).size();
+    }
+
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        let end = self.next + tx.as_ref().len();
+        self.offsets.push((self.next, end));
+        self.next = end;
+        ControlFlow::Continue(())
+    }
+}
+
+#[derive(Default)]
+struct InputsOutputsCounter {
+    inputs: usize,
+    outputs: usize,
+}
+
+impl Visitor for InputsOutputsCounter {
+    fn visit_tx_ins(&mut self, total_inputs: usize) {
+        self.inputs += total_inputs;
+    }
+
+    fn visit_tx_outs(&mut self, total_outputs: usize) {
+        self.outputs += total_outputs;
+    }
+}
+
+/// Sums the sigop cost of the transactions, flagging previous outputs missing from
+/// `outpoint_values`
+struct SigopsVisitor<'a> {
+    outpoint_values: &'a HashMap<OutPoint, TxOut>,
+    cost: u64,
+    missing: bool,
+}
+
+impl Visitor for SigopsVisitor<'_> {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        let tx = Transaction::consensus_decode(&mut tx.as_ref()).expect("block bytes are valid");
+        let cost = tx.total_sigop_cost(|outpoint| {
+            let prevout = self.outpoint_values.get(outpoint).cloned();
+            // the coinbase input doesn't spend anything, the sentinel may be missing
+            self.missing |= prevout.is_none() && !outpoint.is_null();
+            prevout
+        });
+        self.cost += cost as u64;
+        ControlFlow::Continue(())
+    }
+}
+
+/// Counts the inputs by kind of output spent, flagging previous outputs missing from
+/// `outpoint_values`
+struct InputTypeVisitor<'a> {
+    outpoint_values: &'a HashMap<OutPoint, TxOut>,
+    counts: InputTypeCounts,
+    missing: bool,
+}
+
+impl Visitor for InputTypeVisitor<'_> {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        let tx = Transaction::consensus_decode(&mut tx.as_ref()).expect("block bytes are valid");
+        if tx.is_coinbase() {
+            return ControlFlow::Continue(());
+        }
+        for input in tx.input.iter() {
+            match self.outpoint_values.get(&input.previous_output) {
+                Some(prevout) => self.counts.add(&prevout.script_pubkey, &input.script_sig),
+                None => self.missing = true,
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+struct WitnessSizeVisitor(u32);
+
+impl Visitor for WitnessSizeVisitor {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        self.0 += tx_witness_size(tx);
+        ControlFlow::Continue(())
+    }
+}
+
+struct TxWeightsVisitor(Vec<u64>);
+
+impl Visitor for TxWeightsVisitor {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        let witness = tx_witness_size(tx) as u64;
+        let stripped = tx.as_ref().len() as u64 - witness;
+        self.0.push(stripped * 4 + witness);
+        ControlFlow::Continue(())
+    }
+}
+
+impl fmt::Debug for BlockExtra {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // computing the fee requires decoding the block, avoid it if prevouts are missing anyway
+        let fee = if !self.has_prevouts() {
+            None
+        } else {
+            self.fee()
+        };
+        f.debug_struct("BlockExtra")
+            .field("height", &self.height)
+            .field("block_hash", &self.block_hash)
+            .field("size", &self.size)
+            .field("txs", &self.block_total_txs)
+            .field("fee", &fee)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A transaction touching some of the scripts in [`crate::Config::watch_scripts`], returned by
+/// [`BlockExtra::watch_hits()`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Height of the block containing the transaction
+    pub height: u32,
+
+    /// Id of the transaction
+    pub txid: Txid,
+
+    /// The watched scripts in the outputs or in the previous outputs of the transaction, in order
+    /// of appearance and without duplicates
+    pub matched: Vec<ScriptBuf>,
+}
+
+/// A transaction with the outputs spent by its inputs, returned by
+/// [`BlockExtra::tx_with_inputs()`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxWithPrevouts {
+    /// The transaction
+    pub tx: Transaction,
+
+    /// The outputs spent by the transaction, `prevouts[i]` is spent by `tx.input[i]`
+    pub prevouts: Vec<TxOut>,
+}
+
+/// The reward of a block, returned by [`BlockExtra::coinbase_breakdown()`], values are in
+/// satoshi
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoinbaseBreakdown {
+    /// The newly created coins, see [`BlockExtra::base_reward()`]
+    pub subsidy: u64,
+
+    /// The fees of the transactions in the block
+    pub fees: u64,
+
+    /// The sum of the coinbase outputs
+    pub claimed: u64,
+
+    /// The reward not claimed by the miner, `subsidy + fees - claimed`
+    pub burned: u64,
+}
+
+/// The block subsidy of a chain: it starts at `initial` satoshi and it halves every
+/// `halving_interval` blocks, see [`crate::Config::subsidy_schedule`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubsidySchedule {
+    /// The subsidy of the first blocks, in satoshi
+    pub initial: u64,
+
+    /// Number of blocks between halvings
+    pub halving_interval: u32,
+}
+
+impl SubsidySchedule {
+    /// The schedule of `network`, halving every 150 blocks on regtest and every 210_000 elsewhere
+    pub fn for_network(network: Network) -> Self {
+        let halving_interval = match network {
+            Network::Regtest => 150,
+            _ => 210_000,
+        };
+        SubsidySchedule {
+            halving_interval,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the subsidy in satoshi of the block at `height`, 0 after 64 halvings
+    pub fn subsidy(&self, height: u32) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+        self.initial.checked_shr(halvings).unwrap_or(0)
+    }
+
+    /// Returns the total subsidy in satoshi of the blocks before `height`, the genesis included
+    pub fn supply(&self, height: u32) -> u64 {
+        let interval = self.halving_interval.max(1);
+        let halvings = height / interval;
+        let full: u64 = (0..halvings.min(64))
+            .map(|halving| interval as u64 * self.subsidy(halving * interval))
+            .sum();
+        full + (height % interval) as u64 * self.subsidy(height)
+    }
+}
+
+impl Default for SubsidySchedule {
+    /// The bitcoin mainnet schedule
+    fn default() -> Self {
+        SubsidySchedule {
+            initial: 50 * 100_000_000,
+            halving_interval: 210_000,
+        }
+    }
+}
+
+/// Size statistics of a kind of script, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeStats {
+    /// Number of scripts
+    pub count: u64,
+
+    /// Sum of the sizes of the scripts
+    pub total: u64,
+
+    /// Size of the biggest script
+    pub max: u64,
+}
+
+impl SizeStats {
+    fn add(&mut self, size: usize) {
+        self.count += 1;
+        self.total += size as u64;
+        self.max = self.max.max(size as u64);
+    }
+
+    /// Returns the average size of the scripts, 0 if there aren't any
+    pub fn avg(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count as f64
+        }
+    }
+}
+
+impl fmt::Display for SizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "count:{} total:{} avg:{:.1} max:{}",
+            self.count,
+            self.total,
+            self.avg(),
+            self.max
+        )
+    }
+}
+
+/// The sizes of the scripts in a block, returned by [`BlockExtra::script_size_stats()`]. Sizes
+/// don't include the length prefixes, except for witnesses which are counted as serialized
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScriptSizeStats {
+    /// The script sigs of the inputs, coinbase included
+    pub script_sig: SizeStats,
+
+    /// The script pubkeys of the outputs
+    pub script_pubkey: SizeStats,
+
+    /// The witnesses of the inputs of segwit transactions, an empty one has size 1
+    pub witness: SizeStats,
+}
+
+impl fmt::Display for ScriptSizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "script_sig {}; script_pubkey {}; witness {}",
+            self.script_sig, self.script_pubkey, self.witness
+        )
+    }
+}
+
+/// The number of transactions for each transaction version, returned by
+/// [`BlockExtra::tx_versions()`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxVersions(pub HashMap<i32, u32>);
+
+impl fmt::Display for TxVersions {
+    /// Versions in ascending order, like `v1:10 v2:3`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut versions: Vec<_> = self.0.iter().collect();
+        versions.sort();
+        let versions: Vec<_> = versions
+            .into_iter()
+            .map(|(version, count)| format!("v{}:{}", version, count))
+            .collect();
+        write!(f, "{}", versions.join(" "))
+    }
+}
+
+/// The number of transactions by kind of locktime, returned by
+/// [`BlockExtra::locktime_histogram()`]. The locktime is counted even if it's not enforced
+/// because all the inputs have final sequences
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LocktimeHistogram {
+    /// Transactions with locktime 0
+    pub zero: u32,
+
+    /// Transactions locked to a block height, locktime below 500_000_000
+    pub height: u32,
+
+    /// Transactions locked to a unix timestamp, locktime from 500_000_000
+    pub time: u32,
+}
+
+impl fmt::Display for LocktimeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "zero:{} height:{} time:{}",
+            self.zero, self.height, self.time
+        )
+    }
+}
+
+/// The number of inputs by kind of output spent, returned by [`BlockExtra::input_type_counts()`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputTypeCounts {
+    /// Inputs spending outputs without witness programs, like p2pk, p2pkh, bare multisig and p2sh
+    /// with a redeem script which isn't a witness program
+    pub legacy: u32,
+
+    /// Inputs spending p2sh outputs with a witness program as redeem script, like p2sh-p2wpkh
+    pub nested_segwit: u32,
+
+    /// Inputs spending segwit v0 outputs, p2wpkh and p2wsh
+    pub native_segwit: u32,
+
+    /// Inputs spending taproot outputs
+    pub taproot: u32,
+
+    /// Inputs spending witness programs of versions without consensus meaning yet
+    pub unknown: u32,
+}
+
+impl InputTypeCounts {
+    fn add(&mut self, script_pubkey: &Script, script_sig: &Script) {
+        if script_pubkey.is_p2tr() {
+            self.taproot += 1;
+        } else if script_pubkey.is_witness_program() {
+            match script_pubkey.witness_version() {
+                Some(WitnessVersion::V0) => self.native_segwit += 1,
+                _ => self.unknown += 1,
+            }
+        } else if script_pubkey.is_p2sh() && is_nested_segwit(script_sig) {
+            self.nested_segwit += 1;
+        } else {
+            self.legacy += 1;
+        }
+    }
+}
+
+/// Returns true if the script sig is a single push of a witness program, the redeem script of a
+/// nested segwit spend
+fn is_nested_segwit(script_sig: &Script) -> bool {
+    let mut pushes = script_sig.instructions();
+    match (pushes.next(), pushes.next()) {
+        (Some(Ok(Instruction::PushBytes(redeem))), None) => {
+            Script::from_bytes(redeem.as_bytes()).is_witness_program()
+        }
+        _ => false,
+    }
+}
+
+impl fmt::Display for InputTypeCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "legacy:{} nested_segwit:{} native_segwit:{} taproot:{} unknown:{}",
+            self.legacy, self.nested_segwit, self.native_segwit, self.taproot, self.unknown
+        )
+    }
+}
+
+/// Returned by [`BlockExtra::full_debug()`], debug prints every field of the [`BlockExtra`]
+pub struct FullDebug<'a>(&'a BlockExtra);
+
+impl<'a> fmt::Debug for FullDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let be = self.0;
+        f.debug_struct("BlockExtra")
+            .field("version", &be.version)
+            .field("block_bytes", &be.block_bytes)
+            .field("block", &be.block)
+            .field("block_hash", &be.block_hash)
+            .field("size", &be.size)
+            .field("next", &be.next)
+            .field("height", &be.height)
+            .field("outpoint_values", &be.outpoint_values)
+            .field("outpoint_values_vec", &be.outpoint_values_vec)
+            .field("block_total_inputs", &be.block_total_inputs)
+            .field("block_total_outputs", &be.block_total_outputs)
+            .field("txids", &be.txids)
+            .field("block_total_txs", &be.block_total_txs)
+            .field("witness_size", &be.witness_size)
+            .field("tx_offsets", &be.tx_offsets)
+            .field("location", &be.location)
+            .field("bip30_duplicate", &be.bip30_duplicate)
+            .field("chain_work", &be.chain_work)
+            .field("retarget_timespan", &be.retarget_timespan)
+            .field("forks", &be.forks)
+            .field("subsidy_schedule", &be.subsidy_schedule)
+            .field("extra", &be.extra)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::bitcoin::consensus::{deserialize, serialize};
+    use crate::bitcoin::{Block, OutPoint, TxOut};
+    use crate::block_extra::{LocktimeHistogram, SizeStats};
+    use crate::BlockExtra;
+    use bitcoin::block::{Header, Version};
+    use bitcoin::hash_types::TxMerkleNode;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{
+        absolute, transaction, Amount, ScriptBuf, Sequence, Transaction, TxIn, Txid, Witness,
+    };
+    use bitcoin::{BlockHash, CompactTarget};
+    use std::sync::OnceLock;
+
+    pub fn block_extra() -> BlockExtra {
+        let block = Block {
+            header: Header {
+                version: Version::from_consensus(0),
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0,
+            },
+            txdata: vec![],
+        };
+        let block_bytes = serialize(&block);
+        let size = block_bytes.len() as u32;
+
+        BlockExtra {
+            version: 0,
+            block_bytes: block_bytes.into(),
+            block: OnceLock::new(),
+            block_hash: BlockHash::from_slice(&[
+                1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0,
+            ])
+            .unwrap(),
+            size,
+            next: vec![BlockHash::all_zeros()],
+            height: 0,
+            outpoint_values_vec: vec![(OutPoint::default(), TxOut::NULL)],
+            outpoint_values: OnceLock::new(),
+            block_total_inputs: 0,
+            block_total_outputs: 0,
+            block_total_txs: 0,
+            txids: vec![],
+            witness_size: OnceLock::new(),
+            tx_offsets: OnceLock::new(),
+            location: None,
+            bip30_duplicate: false,
+            chain_work: None,
+            retarget_timespan: None,
+            forks: vec![],
+            subsidy_schedule: Default::default(),
+            extra: Default::default(),
+            coinbase_spends: vec![],
+            utxo_delta: None,
+            utxo_muhash: None,
+            prevout_codes: None,
+            coinbase_value: None,
+        }
+    }
+
+    /// Returns a [`BlockExtra`] containing `block`, other fields as in [`block_extra()`]
+    pub fn block_extra_from_block(block: Block) -> BlockExtra {
+        let mut be = block_extra();
+        be.txids = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        be.block_total_txs = be.txids.len();
+        be.block_bytes = serialize(&block).into();
+        be.size = be.block_bytes.len() as u32;
+        be.block_hash = block.block_hash();
+        be
+    }
+
+    /// Returns a transaction spending `inputs` and creating `outputs` outputs of `value` sats
+    pub fn tx(inputs: Vec<OutPoint>, outputs: usize, value: u64) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: (0..outputs)
+                .map(|_| TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_intra_block_spends() {
+        let external = OutPoint::new(Txid::all_zeros(), 7);
+        let coinbase = tx(vec![OutPoint::null()], 1, 50);
+        let tx1 = tx(vec![external], 2, 10);
+        let tx2 = tx(vec![OutPoint::new(tx1.compute_txid(), 1)], 2, 4);
+        let tx3 = tx(vec![OutPoint::new(tx2.compute_txid(), 0), external], 1, 1);
+        let expected = vec![
+            OutPoint::new(tx1.compute_txid(), 1),
+            OutPoint::new(tx2.compute_txid(), 0),
+        ];
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, tx1, tx2, tx3];
+        let be = block_extra_from_block(block);
+        assert_eq!(be.intra_block_spends(), expected);
+
+        assert!(block_extra().intra_block_spends().is_empty());
+    }
+
+    #[test]
+    fn test_txs_by_feerate() {
+        let prevout = |vout| OutPoint::new(Txid::all_zeros(), vout);
+        let coinbase = tx(vec![OutPoint::null()], 1, 50);
+        let low = tx(vec![prevout(0)], 1, 990);
+        let high = tx(vec![prevout(1)], 1, 900);
+        let high_bigger = tx(vec![prevout(2), prevout(3)], 1, 1_850);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, low.clone(), high.clone(), high_bigger.clone()];
+        let mut be = block_extra_from_block(block);
+        assert!(be.txs_by_feerate().is_empty(), "prevouts missing");
+
+        be.outpoint_values_vec = (0..4)
+            .map(|vout| {
+                let value = Amount::from_sat(1_000);
+                let script_pubkey = ScriptBuf::new();
+                (
+                    prevout(vout),
+                    TxOut {
+                        value,
+                        script_pubkey,
+                    },
+                )
+            })
+            .collect();
+        be.outpoint_values = OnceLock::new();
+        let feerates = be.txs_by_feerate();
+        let txids: Vec<_> = feerates.iter().map(|(txid, _)| *txid).collect();
+        assert_eq!(
+            txids,
+            vec![
+                high.compute_txid(),
+                high_bigger.compute_txid(),
+                low.compute_txid()
+            ]
+        );
+        assert_eq!(feerates[0].1, 100.0 / high.vsize() as f64);
+        assert_eq!(feerates[2].1, 10.0 / low.vsize() as f64);
+        assert!(feerates[0].1 > feerates[1].1);
+        assert!(feerates.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+
+    #[test]
+    fn test_nth_tx() {
+        let coinbase = tx(vec![OutPoint::null()], 1, 50);
+        let tx1 = tx(vec![OutPoint::new(Txid::all_zeros(), 7)], 2, 10);
+        let tx2 = tx(vec![OutPoint::new(tx1.compute_txid(), 1)], 2, 4);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, tx1, tx2];
+        let be = block_extra_from_block(block.clone());
+        for (i, expected) in block.txdata.iter().enumerate() {
+            assert_eq!(be.nth_tx(i).as_ref(), Some(expected));
+        }
+        assert_eq!(be.nth_tx(3), None);
+        assert!(be.block.get().is_none());
+
+        be.block();
+        assert_eq!(be.nth_tx(2).as_ref(), block.txdata.get(2));
+        assert_eq!(be.nth_tx(3), None);
+    }
+
+    #[test]
+    fn test_raw_tx() {
+        let coinbase = tx(vec![OutPoint::null()], 1, 50);
+        let tx1 = tx(vec![OutPoint::new(Txid::all_zeros(), 7)], 2, 10);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, tx1];
+        let be = block_extra_from_block(block.clone());
+        assert!(be.tx_offsets.get().is_none());
+        for (i, expected) in block.txdata.iter().enumerate() {
+            let raw = be.raw_tx(i).unwrap();
+            let decoded: Transaction = deserialize(raw).unwrap();
+            assert_eq!(decoded.compute_txid(), be.txids()[i]);
+            assert_eq!(&decoded, expected);
+        }
+        assert!(be.tx_offsets.get().is_some());
+        assert_eq!(be.raw_tx(2), None);
+    }
+
+    #[test]
+    fn test_tx_with_inputs() {
+        let mut conf = crate::inner_test::test_conf();
+        let mut spending = 0;
+        for be in crate::iter(conf.clone()) {
+            assert!(be.tx_with_inputs(&be.txids()[0]).is_none());
+            for (txid, tx) in be.iter_tx().skip(1) {
+                let with_inputs = be.tx_with_inputs(txid).unwrap();
+                assert_eq!(&with_inputs.tx, tx);
+                assert_eq!(with_inputs.prevouts.len(), tx.input.len());
+                let inputs: u64 = with_inputs.prevouts.iter().map(|p| p.value.to_sat()).sum();
+                let outputs: u64 = tx.output.iter().map(|o| o.value.to_sat()).sum();
+                assert_eq!(Some(inputs - outputs), be.tx_fee(tx));
+                spending += 1;
+            }
+            assert!(be.tx_with_inputs(&Txid::all_zeros()).is_none());
+        }
+        assert!(spending > 0);
+
+        conf.skip_prevout = true;
+        for be in crate::iter(conf) {
+            for txid in be.txids().iter().skip(1) {
+                assert!(be.tx_with_inputs(txid).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_input_prevout_pairs() {
+        let mut conf = crate::inner_test::test_conf();
+        let mut inputs = 0;
+        for be in crate::iter(conf.clone()) {
+            let pairs: Vec<_> = be.input_prevout_pairs().collect();
+            let block_inputs: Vec<_> = be
+                .block()
+                .txdata
+                .iter()
+                .skip(1)
+                .flat_map(|tx| tx.input.iter())
+                .map(|input| &input.previous_output)
+                .collect();
+            assert_eq!(pairs.len(), block_inputs.len());
+            for ((outpoint, prevout), block_input) in pairs.into_iter().zip(block_inputs) {
+                assert_eq!(outpoint, block_input);
+                assert!(!outpoint.is_null());
+                assert_eq!(Some(prevout), be.outpoint_values().get(outpoint));
+                inputs += 1;
+            }
+        }
+        assert!(inputs > 0);
+
+        conf.skip_prevout = true;
+        for be in crate::iter(conf) {
+            assert_eq!(be.input_prevout_pairs().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_prevout_script_type() {
+        use crate::ScriptType;
+
+        let mut conf = crate::inner_test::test_conf();
+        let mut p2pk = 0;
+        for be in crate::iter(conf.clone()) {
+            assert_eq!(be.prevout_script_type(&OutPoint::null()), None);
+            for (outpoint, prevout) in be.real_prevouts() {
+                let script_type = be.prevout_script_type(outpoint).unwrap();
+                assert_eq!(script_type, ScriptType::of(&prevout.script_pubkey));
+                if script_type == ScriptType::P2pk {
+                    p2pk += 1;
+                }
+            }
+        }
+        assert!(p2pk > 0);
+
+        conf.skip_prevout = true;
+        for be in crate::iter(conf) {
+            for (_, tx) in be.iter_tx().skip(1) {
+                assert_eq!(be.prevout_script_type(&tx.input[0].previous_output), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sigops() {
+        use bitcoin::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_1, OP_PUSHNUM_3};
+        use bitcoin::script::Builder;
+        use bitcoin::{PubkeyHash, PublicKey, ScriptHash, WPubkeyHash, Witness};
+
+        let key = PublicKey::from_slice(&[2u8; 33]).unwrap();
+        let p2pkh = ScriptBuf::new_p2pkh(&PubkeyHash::hash(&[1]));
+        // 3 sigops counted accurately in the redeem script, 20 if it were a script pubkey
+        let redeem = Builder::new()
+            .push_opcode(OP_PUSHNUM_1)
+            .push_key(&key)
+            .push_key(&key)
+            .push_key(&key)
+            .push_opcode(OP_PUSHNUM_3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let prevouts = [
+            ScriptBuf::new_p2sh(&ScriptHash::hash(&[2])),
+            ScriptBuf::new_p2sh(&redeem.script_hash()),
+            ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(&[3])),
+        ];
+        let outpoints: Vec<_> = (0..3)
+            .map(|vout| OutPoint::new(Txid::all_zeros(), vout))
+            .collect();
+
+        let mut coinbase = tx(vec![OutPoint::null()], 1, 50);
+        coinbase.output[0].script_pubkey = ScriptBuf::new_p2pk(&key); // 1 legacy sigop
+        let mut spend = tx(outpoints.clone(), 1, 1);
+        spend.output[0].script_pubkey = p2pkh; // 1 legacy sigop
+
+        // a multisig (1 for each possible key, 20) in the script sig of the p2sh input is legacy
+        spend.input[0].script_sig = Builder::new()
+            .push_opcode(OP_CHECKMULTISIG)
+            .push_slice([0u8; 1])
+            .into_script();
+        let mut redeem_push = bitcoin::script::PushBytesBuf::new();
+        redeem_push.extend_from_slice(redeem.as_bytes()).unwrap();
+        spend.input[1].script_sig = Builder::new().push_slice(redeem_push).into_script();
+        spend.input[2].witness = Witness::from_slice(&[vec![0u8; 71], key.to_bytes()]);
+
+        let block = Block {
+            header: block_extra().block().header,
+            txdata: vec![coinbase, spend],
+        };
+        let mut be = block_extra_from_block(block);
+        be.outpoint_values_vec = vec![(OutPoint::default(), TxOut::NULL)];
+        assert_eq!(be.sigops(), None, "missing prevouts");
+
+        let prevouts = prevouts.map(|script_pubkey| TxOut {
+            value: Amount::from_sat(1),
+            script_pubkey,
+        });
+        be.outpoint_values_vec
+            .extend(outpoints.into_iter().zip(prevouts));
+        be.outpoint_values = OnceLock::new();
+        // coinbase 4, legacy output 4, legacy script sig 20 * 4, redeem script 3 * 4, p2wpkh 1
+        assert_eq!(be.sigops(), Some(4 + 4 + 80 + 12 + 1));
+
+        be.outpoint_values_vec.clear();
+        be.outpoint_values = OnceLock::new();
+        assert_eq!(be.sigops(), None);
+
+        let mut conf = crate::inner_test::test_conf();
+        for be in crate::iter(conf.clone()) {
+            let expected: usize = be
+                .block()
+                .txdata
+                .iter()
+                .map(|tx| tx.total_sigop_cost(|o| be.outpoint_values().get(o).cloned()))
+                .sum();
+            assert_eq!(be.sigops(), Some(expected as u64));
+        }
+        conf.skip_prevout = true;
+        assert!(crate::iter(conf).all(|be| be.sigops().is_none()));
+    }
+
+    #[test]
+    fn test_input_type_counts() {
+        use crate::InputTypeCounts;
+        use bitcoin::script::PushBytesBuf;
+        use bitcoin::{PubkeyHash, PublicKey, ScriptHash, WPubkeyHash};
+
+        let key = PublicKey::from_slice(&[2u8; 33]).unwrap();
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(&[1]));
+        let mut redeem_push = PushBytesBuf::new();
+        redeem_push.extend_from_slice(p2wpkh.as_bytes()).unwrap();
+        let nested = ScriptBuf::new_p2sh(&p2wpkh.script_hash());
+        let prevouts = [
+            p2wpkh.clone(),
+            nested,
+            ScriptBuf::new_p2sh(&ScriptHash::hash(&[2])),
+            ScriptBuf::new_p2pkh(&PubkeyHash::hash(&[3])),
+        ];
+        let outpoints: Vec<_> = (0..4)
+            .map(|vout| OutPoint::new(Txid::all_zeros(), vout))
+            .collect();
+
+        let mut spend = tx(outpoints.clone(), 1, 1);
+        let witness = Witness::from_slice(&[vec![0u8; 71], key.to_bytes()]);
+        // the same witness, the script sig tells the nested spend apart
+        spend.input[0].witness = witness.clone();
+        spend.input[1].witness = witness;
+        spend.input[1].script_sig = bitcoin::script::Builder::new()
+            .push_slice(redeem_push)
+            .into_script();
+        // a legacy p2sh with the push of a non witness program redeem script
+        let mut legacy_push = PushBytesBuf::new();
+        legacy_push.extend_from_slice(&[0x51]).unwrap();
+        spend.input[2].script_sig = bitcoin::script::Builder::new()
+            .push_slice(legacy_push)
+            .into_script();
+
+        let block = Block {
+            header: block_extra().block().header,
+            txdata: vec![tx(vec![OutPoint::null()], 1, 50), spend],
+        };
+        let mut be = block_extra_from_block(block);
+        be.outpoint_values_vec = vec![(OutPoint::default(), TxOut::NULL)];
+        assert_eq!(be.input_type_counts(), None, "missing prevouts");
+
+        let prevouts = prevouts.map(|script_pubkey| TxOut {
+            value: Amount::from_sat(1),
+            script_pubkey,
+        });
+        be.outpoint_values_vec
+            .extend(outpoints.into_iter().zip(prevouts));
+        be.outpoint_values = OnceLock::new();
+        let counts = be.input_type_counts().unwrap();
+        assert_eq!(
+            counts,
+            InputTypeCounts {
+                legacy: 2,
+                nested_segwit: 1,
+                native_segwit: 1,
+                taproot: 0,
+                unknown: 0,
+            }
+        );
+        assert_eq!(
+            counts.to_string(),
+            "legacy:2 nested_segwit:1 native_segwit:1 taproot:0 unknown:0"
+        );
+
+        let mut conf = crate::inner_test::test_conf();
+        for be in crate::iter(conf.clone()) {
+            let counts = be.input_type_counts().unwrap();
+            let inputs = be.real_prevouts().count() as u32;
+            assert_eq!(counts.legacy, inputs, "early testnet spends are legacy");
+        }
+        conf.skip_prevout = true;
+        for be in crate::iter(conf) {
+            assert_eq!(be.input_type_counts(), None);
+        }
+    }
+
+    #[test]
+    fn test_extract_pubkeys() {
+        use bitcoin::script::PushBytesBuf;
+        use bitcoin::secp256k1::{Secp256k1, SecretKey};
+        use bitcoin::PublicKey;
+        use std::convert::TryFrom;
+
+        let secp = Secp256k1::new();
+        let key = |i: u8| {
+            let secret = SecretKey::from_slice(&[i; 32]).unwrap();
+            PublicKey::new(secret.public_key(&secp))
+        };
+        let (p2pk, p2pkh, p2wpkh) = (key(1), key(2), key(3));
+        let p2pkh = PublicKey::new_uncompressed(p2pkh.inner);
+        let signature = PushBytesBuf::try_from(vec![1u8; 71]).unwrap();
+
+        let mut coinbase = tx(vec![OutPoint::null()], 1, 50);
+        coinbase.output[0].script_pubkey = ScriptBuf::new_p2pk(&p2pk);
+        let mut spending = tx(
+            vec![
+                OutPoint::new(Txid::all_zeros(), 0),
+                OutPoint::new(Txid::all_zeros(), 1),
+            ],
+            1,
+            10,
+        );
+        spending.input[0].script_sig = ScriptBuf::builder()
+            .push_slice(&signature)
+            .push_key(&p2pkh)
+            .into_script();
+        spending.input[1].witness.push(signature.as_bytes());
+        spending.input[1].witness.push(p2wpkh.to_bytes());
+        // not a key
+        spending.input[1].witness.push([0u8; 33]);
+        spending.output[0].script_pubkey = ScriptBuf::new_p2pkh(&p2pkh.pubkey_hash());
+
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, spending];
+        let be = block_extra_from_block(block);
+        assert_eq!(be.extract_pubkeys(), vec![p2pk, p2pkh, p2wpkh]);
+
+        for be in crate::iter(crate::inner_test::test_conf()) {
+            let keys = be.extract_pubkeys();
+            for output in be.block().txdata[0].output.iter() {
+                if let Some(key) = output.script_pubkey.p2pk_public_key() {
+                    assert!(keys.contains(&key));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_spendable_output_value() {
+        let coinbase = tx(vec![OutPoint::null()], 2, 50);
+        let mut spending = tx(vec![OutPoint::new(Txid::all_zeros(), 0)], 3, 10);
+        spending.output[1].script_pubkey = ScriptBuf::new_op_return([1u8; 4]);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, spending];
+        let be = block_extra_from_block(block);
+        assert_eq!(be.spendable_output_value(), 50 * 2 + 10 * 2);
+        assert_eq!(be.total_output_value(), 50 * 2 + 10 * 3);
+
+        for be in crate::iter(crate::inner_test::test_conf()) {
+            let expected: u64 = be
+                .block()
+                .txdata
+                .iter()
+                .flat_map(|tx| tx.output.iter())
+                .filter(|o| !o.script_pubkey.is_op_return())
+                .map(|o| o.value.to_sat())
+                .sum();
+            assert_eq!(be.spendable_output_value(), expected);
+        }
+    }
+
+    #[test]
+    fn test_script_size_stats() {
+        let mut coinbase = tx(vec![OutPoint::null()], 1, 50);
+        coinbase.input[0].script_sig = ScriptBuf::from(vec![1u8; 10]);
+        let mut spending = tx(vec![OutPoint::new(Txid::all_zeros(), 0); 2], 2, 10);
+        spending.input[1].witness.push([2u8; 72]);
+        spending.input[1].witness.push([3u8; 33]);
+        spending.output[0].script_pubkey = ScriptBuf::from(vec![4u8; 22]);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, spending];
+        let stats = block_extra_from_block(block).script_size_stats();
+
+        assert_eq!(stats.script_sig.count, 3);
+        assert_eq!(stats.script_sig.total, 10);
+        assert_eq!(stats.script_sig.max, 10);
+        assert_eq!(stats.script_pubkey.count, 3);
+        assert_eq!(stats.script_pubkey.total, 22);
+        assert!((stats.script_pubkey.avg() - 22.0 / 3.0).abs() < 1e-9);
+        // only the inputs of the segwit transaction, the first one has an empty witness
+        assert_eq!(stats.witness.count, 2);
+        assert_eq!(stats.witness.total, 1 + (1 + 1 + 72 + 1 + 33));
+        assert_eq!(stats.witness.max, 1 + 1 + 72 + 1 + 33);
+        assert_eq!(
+            stats.to_string(),
+            "script_sig count:3 total:10 avg:3.3 max:10; \
+             script_pubkey count:3 total:22 avg:7.3 max:22; \
+             witness count:2 total:109 avg:54.5 max:108"
+        );
+
+        for be in crate::iter(crate::inner_test::test_conf()) {
+            let block = be.block();
+            let stats = be.script_size_stats();
+            let inputs = block.txdata.iter().flat_map(|tx| tx.input.iter());
+            let script_sigs: usize = inputs.map(|i| i.script_sig.len()).sum();
+            assert_eq!(stats.script_sig.total, script_sigs as u64);
+            assert_eq!(stats.script_sig.count, be.block_total_inputs as u64);
+            assert_eq!(stats.script_pubkey.count, be.block_total_outputs as u64);
+            assert_eq!(stats.witness, SizeStats::default());
+        }
+    }
+
+    #[test]
+    fn test_tx_weights() {
+        let mut spending = tx(vec![OutPoint::new(Txid::all_zeros(), 0)], 2, 10);
+        spending.input[0].witness = Witness::from_slice(&[vec![1u8; 72], vec![2u8; 33]]);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![tx(vec![OutPoint::null()], 1, 50), spending];
+        let be = block_extra_from_block(block.clone());
+
+        let weights = be.tx_weights();
+        assert_eq!(weights.len(), 2);
+        for ((txid, weight), tx) in weights.iter().zip(block.txdata.iter()) {
+            assert_eq!(*txid, tx.compute_txid());
+            assert_eq!(*weight, tx.weight().to_wu());
+        }
+        assert!(weights[1].1 < 4 * block.txdata[1].total_size() as u64);
+
+        for be in crate::iter(crate::inner_test::test_conf()).take(120) {
+            let weights = be.tx_weights();
+            assert_eq!(weights.len(), be.block_total_txs);
+            for ((txid, weight), tx) in weights.iter().zip(be.block().txdata.iter()) {
+                assert_eq!(*txid, tx.compute_txid());
+                assert_eq!(*weight, tx.weight().to_wu());
+            }
+        }
+
+        let mut conf = crate::inner_test::test_conf();
+        conf.compute_txids = false;
+        for be in crate::iter(conf).take(120) {
+            let weights = be.tx_weights();
+            assert_eq!(weights.len(), be.block_total_txs);
+            assert_eq!(weights[0].0, be.block().txdata[0].compute_txid());
+        }
+    }
+
+    #[test]
+    fn test_tx_versions_locktime_histogram() {
+        use bitcoin::absolute::LockTime;
+
+        let mut txs = vec![tx(vec![OutPoint::null()], 1, 50)];
+        for (version, locktime) in [(1, 0), (2, 100), (2, 500_000_001), (3, 499_999_999)] {
+            let mut spending = tx(vec![OutPoint::new(Txid::all_zeros(), 0)], 1, 10);
+            spending.version = transaction::Version(version);
+            spending.lock_time = LockTime::from_consensus(locktime);
+            txs.push(spending);
+        }
+        let mut block = block_extra().block().clone();
+        block.txdata = txs;
+        let be = block_extra_from_block(block);
+
+        let versions = be.tx_versions();
+        assert_eq!(versions.0.len(), 3);
+        assert_eq!(versions.0[&1], 1);
+        assert_eq!(versions.0[&2], 3);
+        assert_eq!(versions.0[&3], 1);
+        assert_eq!(versions.to_string(), "v1:1 v2:3 v3:1");
+
+        let locktimes = be.locktime_histogram();
+        assert_eq!(
+            locktimes,
+            LocktimeHistogram {
+                zero: 2,
+                height: 2,
+                time: 1
+            }
+        );
+        assert_eq!(locktimes.to_string(), "zero:2 height:2 time:1");
+
+        for be in crate::iter(crate::inner_test::test_conf()) {
+            let versions = be.tx_versions();
+            let total: u32 = versions.0.values().sum();
+            assert_eq!(total as usize, be.block_total_txs);
+            let locktimes = be.locktime_histogram();
+            assert_eq!(
+                (locktimes.zero + locktimes.height + locktimes.time) as usize,
+                be.block_total_txs
+            );
+        }
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let mut coinbase = tx(vec![OutPoint::null()], 1, 50);
+        coinbase.input[0].witness.push([0u8; 32]);
+        let mut spending = tx(vec![OutPoint::new(Txid::all_zeros(), 0)], 1, 10);
+        spending.input[0].witness.push([1u8; 72]);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, spending];
+        let be = block_extra_from_block(block.clone());
+        assert_eq!(be.witness_commitment(), None);
+
+        let witness_root = block.witness_root().unwrap();
+        let commitment = Block::compute_witness_commitment(&witness_root, &[0u8; 32]);
+        let mut script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        script.extend(commitment.to_byte_array());
+        block.txdata[0].output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(script),
+        });
+        assert!(block.check_witness_commitment());
+        let be = block_extra_from_block(block);
+        assert_eq!(be.witness_commitment(), Some(commitment.to_byte_array()));
+
+        for be in crate::iter(crate::inner_test::test_conf()) {
+            assert_eq!(be.witness_commitment(), None);
+        }
+    }
+
+    #[test]
+    fn test_rbf_signaling_txids() {
+        let mut coinbase = tx(vec![OutPoint::null()], 1, 50);
+        coinbase.input[0].sequence = Sequence::ZERO;
+        let final_tx = tx(vec![OutPoint::new(Txid::all_zeros(), 0)], 1, 10);
+        let mut rbf = tx(
+            vec![
+                OutPoint::new(Txid::all_zeros(), 1),
+                OutPoint::new(Txid::all_zeros(), 2),
+            ],
+            1,
+            10,
+        );
+        rbf.input[1].sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+        let mut locktime_only = tx(vec![OutPoint::new(Txid::all_zeros(), 3)], 1, 10);
+        locktime_only.input[0].sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+        let expected = vec![rbf.compute_txid()];
+
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, final_tx, rbf, locktime_only];
+        let be = block_extra_from_block(block);
+        assert_eq!(be.rbf_signaling_txids(), expected);
+        assert!(block_extra().rbf_signaling_txids().is_empty());
+    }
+
+    #[test]
+    fn test_tx_fee_inconsistent_prevouts() {
+        let external = OutPoint::new(Txid::all_zeros(), 7);
+        let coinbase = tx(vec![OutPoint::null()], 1, 50);
+        let spending = tx(vec![external], 2, 10);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase.clone(), spending.clone()];
+        let mut be = block_extra_from_block(block);
+        let prevout = |value| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new(),
+        };
+
+        be.outpoint_values_vec = vec![(OutPoint::null(), prevout(50)), (external, prevout(30))];
+        assert_eq!(be.tx_fee(&spending), Some(10));
+        assert_eq!(be.fee(), Some(10));
+
+        // outputs exceed the inputs
+        let mut be = block_extra_from_block(be.block().clone());
+        be.outpoint_values_vec = vec![(OutPoint::null(), prevout(50)), (external, prevout(5))];
+        assert_eq!(be.tx_fee(&coinbase), Some(0));
+        assert_eq!(be.tx_fee(&spending), None);
+        assert_eq!(be.fee(), None);
+    }
+
+    #[test]
+    fn test_coinbase_breakdown() {
+        let external = OutPoint::new(Txid::all_zeros(), 7);
+        let subsidy = block_extra().base_reward();
+        let spending = tx(vec![external], 1, 20);
+        let prevout = |value| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new(),
+        };
+        let with_coinbase = |claimed| {
+            let coinbase = tx(vec![OutPoint::null()], 1, claimed);
+            let mut block = block_extra().block().clone();
+            block.txdata = vec![coinbase, spending.clone()];
+            let mut be = block_extra_from_block(block);
+            be.outpoint_values_vec = vec![
+                (OutPoint::null(), prevout(claimed)),
+                (external, prevout(30)),
+            ];
+            be
+        };
+
+        let breakdown = with_coinbase(subsidy + 3).coinbase_breakdown().unwrap();
+        assert_eq!(breakdown.subsidy, subsidy);
+        assert_eq!(breakdown.fees, 10);
+        assert_eq!(breakdown.claimed, subsidy + 3);
+        assert_eq!(breakdown.burned, 7);
+
+        assert_eq!(
+            with_coinbase(subsidy + 10)
+                .coinbase_breakdown()
+                .unwrap()
+                .burned,
+            0
+        );
+        assert_eq!(with_coinbase(subsidy + 11).coinbase_breakdown(), None);
+        let mut without_prevouts = with_coinbase(subsidy);
+        without_prevouts.outpoint_values_vec = vec![];
+        assert_eq!(without_prevouts.coinbase_breakdown(), None);
+
+        for be in crate::iter(crate::inner_test::test_conf()) {
+            assert_eq!(be.coinbase_breakdown().unwrap().burned, 0);
+        }
+    }
+
+    #[test]
+    fn test_block_reward() {
+        let mut be = block_extra();
+        assert_eq!(be.base_reward(), 50 * 100_000_000);
+        be.height = 209_999;
+        assert_eq!(be.base_reward(), 50 * 100_000_000);
+        be.height = 210_000;
+        assert_eq!(be.base_reward(), 25 * 100_000_000);
+        be.height = 420_000;
+        assert_eq!(be.base_reward(), 1_250_000_000);
+        be.height = 630_000;
+        assert_eq!(be.base_reward(), 625_000_000);
+        be.height = 64 * 210_000;
+        assert_eq!(be.base_reward(), 0);
+    }
+
+    #[test]
+    fn test_fee_from_coinbase() {
+        let mut conf = crate::inner_test::test_conf();
+        let mut count = 0;
+        for be in crate::iter(conf.clone()) {
+            let breakdown = be.coinbase_breakdown().unwrap();
+            let expected = breakdown.fees as i64 - breakdown.burned as i64;
+            assert_eq!(be.fee_from_coinbase(), Some(expected));
+            count += (be.fee().unwrap() > 0) as usize;
+        }
+        assert!(count > 0);
+
+        conf.skip_prevout = true;
+        assert!(
+            crate::iter(conf).all(|be| be.fee().is_none() && be.fee_from_coinbase().unwrap() >= 0)
+        );
+    }
+
+    #[test]
+    fn test_subsidy_schedule() {
+        use crate::bitcoin::blockdata::constants::genesis_block;
+        use crate::bitcoin::Network;
+        use crate::inner_test::child_block;
+        use crate::Config;
+        use crate::SubsidySchedule;
+
+        let regtest = SubsidySchedule::for_network(Network::Regtest);
+        assert_eq!(regtest.subsidy(149), 50 * 100_000_000);
+        assert_eq!(regtest.subsidy(150), 25 * 100_000_000);
+        assert_eq!(regtest.subsidy(64 * 150), 0);
+        assert_eq!(regtest.supply(0), 0);
+        assert_eq!(
+            regtest.supply(151),
+            150 * 50 * 100_000_000 + 25 * 100_000_000
+        );
+        let mainnet = SubsidySchedule::default();
+        assert_eq!(mainnet.supply(210_000), 210_000 * 50 * 100_000_000);
+        // the known total, lower than 21 million bitcoin because of the rounding down
+        assert_eq!(mainnet.supply(u32::MAX), 2_099_999_997_690_000);
+        assert_eq!(
+            SubsidySchedule::for_network(Network::Testnet),
+            SubsidySchedule::default()
+        );
+
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..310 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let mut conf = Config::from_blocks(chain, Network::Regtest);
+        conf.skip_prevout = true;
+        let rewards: Vec<_> = crate::iter(conf.clone()).map(|b| b.base_reward()).collect();
+        assert_eq!(rewards[149], 50 * 100_000_000);
+        assert_eq!(rewards[150], 25 * 100_000_000);
+        assert_eq!(rewards[300], 1_250_000_000);
+
+        conf.subsidy_schedule = Some(SubsidySchedule {
+            initial: 1_000,
+            halving_interval: 100,
+        });
+        let rewards: Vec<_> = crate::iter(conf).map(|b| b.base_reward()).collect();
+        assert_eq!((rewards[99], rewards[100], rewards[200]), (1_000, 500, 250));
+    }
+
+    #[test]
+    fn test_block_bytes_shared() {
+        let be = block_extra();
+        let shared = be.block_bytes_shared();
+        assert_eq!(&shared[..], be.block_bytes());
+        assert!(std::ptr::eq(shared.as_ptr(), be.block_bytes().as_ptr()));
+    }
+
+    #[test]
+    fn test_into_block() {
+        let be = block_extra();
+        let expected = be.block().clone();
+        assert!(be.block.get().is_some());
+        assert_eq!(be.into_block(), expected);
+
+        // a deserialized block is not decoded
+        let be: BlockExtra =
+            crate::bitcoin::consensus::deserialize(&serialize(&block_extra())).unwrap();
+        assert!(be.block.get().is_none());
+        assert_eq!(be.into_block(), expected);
+    }
+
+    #[test]
+    fn test_header_bytes() {
+        let be = block_extra();
+        assert_eq!(be.header_bytes(), &serialize(&be.block().header)[..]);
+        assert_eq!(be.header_bytes().len(), 80);
+        assert!(std::ptr::eq(
+            be.header_bytes().as_ptr(),
+            be.block_bytes().as_ptr()
+        ));
+    }
+
+    #[test]
+    fn test_time() {
+        let conf = crate::inner_test::test_conf();
+        let mut count = 0;
+        for be in crate::iter(conf) {
+            // a deserialized block is not decoded
+            let be: BlockExtra = crate::bitcoin::consensus::deserialize(&serialize(&be)).unwrap();
+            let time = be.time();
+            assert!(be.block.get().is_none());
+            assert_eq!(time, be.block().header.time);
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        use crate::{BlockFile, Error, FsBlock};
+        use std::convert::TryFrom;
+        use std::io::Cursor;
+        use std::path::PathBuf;
+        use std::sync::{Arc, Mutex};
+
+        let block_bytes = serialize(&block_extra().block());
+        let fs_block = |bytes: &[u8]| FsBlock {
+            file: Arc::new(Mutex::new(BlockFile::Memory(Cursor::new(bytes.to_vec())))),
+            path: Some(Arc::new(PathBuf::from("blk00000.dat"))),
+            xor_key: None,
+            start: 8,
+            end: 8 + block_bytes.len(),
+            hash: BlockHash::all_zeros(),
+            prev: BlockHash::all_zeros(),
+            next: vec![BlockHash::all_zeros()],
+            serialization_version: 1,
+            block_total_inputs: 0,
+            block_total_outputs: 0,
+            block_total_txs: 0,
+        };
+        let mut file = vec![0u8; 8];
+        file.extend(&block_bytes);
+        let be = BlockExtra::try_from(fs_block(&file)).unwrap();
+        assert_eq!(be.block_bytes(), &block_bytes[..]);
+
+        match BlockExtra::try_from(fs_block(&file[..50])) {
+            Err(Error::TruncatedBlock {
+                path,
+                start,
+                end,
+                available,
+                ..
+            }) => {
+                assert_eq!(path, Some(PathBuf::from("blk00000.dat")));
+                assert_eq!((start, end), (8, 8 + block_bytes.len()));
+                assert_eq!(available, 42);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(
+            BlockExtra::try_from(fs_block(&[])),
+            Err(Error::TruncatedBlock { available: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_bip158_filter() {
+        for skip_prevout in [false, true] {
+            let mut conf = crate::inner_test::test_conf();
+            conf.skip_prevout = skip_prevout;
+            let mut blocks_with_spends = 0;
+            for be in crate::iter(conf) {
+                let filter = be.build_bip158_filter();
+                let block = be.block();
+                if block.txdata.len() == 1 {
+                    // no prevouts needed
+                    let filter = filter.unwrap();
+                    let scripts = block.txdata[0]
+                        .output
+                        .iter()
+                        .map(|o| o.script_pubkey.as_bytes());
+                    assert!(filter.match_all(&be.block_hash, scripts).unwrap());
+                    continue;
+                }
+                blocks_with_spends += 1;
+                if skip_prevout {
+                    assert!(filter.is_none());
+                } else {
+                    let filter = filter.unwrap();
+                    let prevouts = be
+                        .outpoint_values()
+                        .values()
+                        .map(|o| o.script_pubkey.as_bytes())
+                        .filter(|s| !s.is_empty());
+                    assert!(filter.match_all(&be.block_hash, prevouts).unwrap());
+                }
+            }
+            assert_eq!(blocks_with_spends, 10);
+        }
+    }
+
+    #[test]
+    fn test_input_output_count() {
+        assert_eq!(block_extra().input_count(), 0);
+        assert_eq!(block_extra().output_count(), 0);
+
+        let coinbase = tx(vec![OutPoint::null()], 2, 50);
+        let spending = tx(vec![OutPoint::new(Txid::all_zeros(), 0); 3], 4, 10);
+        let mut block = block_extra().block().clone();
+        block.txdata = vec![coinbase, spending];
+        let be = block_extra_from_block(block);
+        assert_eq!(be.block_total_inputs(), 0, "not populated");
+        assert_eq!(be.input_count(), 4);
+        assert_eq!(be.output_count(), 6);
+
+        let mut conf = crate::inner_test::test_conf();
+        for skip_prevout in [false, true] {
+            conf.skip_prevout = skip_prevout;
+            for mut be in crate::iter(conf.clone()) {
+                let txs = &be.block().txdata;
+                let inputs: usize = txs.iter().map(|tx| tx.input.len()).sum();
+                let outputs: usize = txs.iter().map(|tx| tx.output.len()).sum();
+                assert_eq!(be.block_total_inputs(), inputs);
+                assert_eq!(be.block_total_outputs(), outputs);
+                assert_eq!((be.input_count(), be.output_count()), (inputs, outputs));
+                be.block_total_inputs = 0;
+                be.block_total_outputs = 0;
+                assert_eq!((be.input_count(), be.output_count()), (inputs, outputs));
+            }
+        }
+    }
+
+    #[test]
+    fn test_stripped_size() {
+        let coinbase = tx(vec![OutPoint::null()], 1, 50);
+        let mut segwit = tx(vec![OutPoint::new(Txid::all_zeros(), 0)], 2, 10);
+        segwit.input[0].witness.push([1u8; 72]);
+        segwit.input[0].witness.push([2u8; 33]);
+
+        for txdata in [vec![coinbase.clone()], vec![coinbase, segwit]] {
+            let mut block = block_extra().block().clone();
+            block.txdata = txdata;
+            let has_witness = block
+                .txdata
+                .iter()
+                .any(|tx| !tx.input[0].witness.is_empty());
+            let total_size = block.total_size();
+            let base_size = (block.weight().to_wu() as usize - total_size) / 3;
+
+            let be = block_extra_from_block(block);
+            assert_eq!(be.size() as usize, total_size);
+            assert_eq!(be.stripped_size() as usize, base_size);
+            assert_eq!(be.has_witness(), has_witness);
+        }
+    }
+
+    #[test]
+    fn test_difficulty_chain_work() {
+        let mut conf = crate::inner_test::test_conf();
+        conf.skip_prevout = true;
+        conf.track_chainwork = true;
+        let mut total = None;
+        for be in crate::iter(conf) {
+            let header = be.block().header;
+            assert_eq!(be.difficulty(), header.difficulty_float());
+            total = Some(match total {
+                Some(total) => total + header.work(),
+                None => header.work(),
+            });
+            assert_eq!(be.chain_work(), total);
+            if be.height() == 0 {
+                assert_eq!(be.difficulty(), 1.0);
+                assert_eq!(
+                    be.chain_work().unwrap().to_be_bytes()[27..],
+                    [1, 0, 1, 0, 1]
+                );
+            }
+        }
+
+        let mut conf = crate::inner_test::test_conf();
+        conf.skip_prevout = true;
+        assert!(crate::iter(conf).all(|be| be.chain_work().is_none()));
+    }
+
+    #[test]
+    fn test_retarget() {
+        use crate::inner_test::child_block;
+        use bitcoin::blockdata::constants::{genesis_block, DIFFCHANGE_TIMESPAN};
+        use bitcoin::Network;
+
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..2016 + 6 {
+            let mut block = child_block(chain.last().unwrap(), 0);
+            // ten minutes apart, after the first block following the genesis
+            if chain.len() > 1 {
+                block.header.time = chain.last().unwrap().header.time + 600;
+            }
+            chain.push(block);
+        }
+        let first_time = chain[1].header.time;
+        let mut conf = crate::Config::from_blocks(chain, Network::Regtest);
+        conf.skip_prevout = true;
+        conf.track_chainwork = true;
+        let blocks: Vec<_> = crate::iter(conf.clone()).collect();
+        assert_eq!(blocks.len(), 2017);
+        for be in blocks.iter() {
+            assert_eq!(be.is_retarget(), be.height() == 2016);
+            if be.height() != 2016 {
+                assert_eq!(be.retarget_timespan(), None);
+            }
+        }
+        let last_time = blocks[2015].block().header.time;
+        let genesis_time = blocks[0].block().header.time;
+        assert_eq!(last_time - first_time, 2014 * 600);
+        assert_eq!(
+            blocks[2016].retarget_timespan(),
+            Some(((last_time - genesis_time) as i64, DIFFCHANGE_TIMESPAN))
+        );
+
+        conf.track_chainwork = false;
+        assert!(crate::iter(conf).all(|be| be.retarget_timespan().is_none()));
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut be = block_extra();
+        be.height = 42;
+        let debug = format!("{:?}", be);
+        assert!(debug.starts_with("BlockExtra { height: 42, block_hash: "));
+        assert!(debug.ends_with(", size: 81, txs: 0, fee: Some(0), .. }"));
+
+        let full = format!("{:?}", be.full_debug());
+        assert!(full.contains("block_bytes: ["));
+        assert!(full.len() > debug.len());
     }
 }
@@ -0,0 +1,462 @@
+use crate::read_range;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+#[cfg(feature = "tar")]
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// A collection of `blk*.dat`-like files containing the blocks to iterate.
+///
+/// Implement this trait to read blocks from somewhere else than the local filesystem, for example
+/// from an object storage via HTTP range requests, and set it in
+/// [`crate::Config::block_files_source`].
+///
+/// Every file is read entirely once to detect the blocks it contains, then the bytes of every
+/// block are read again with [`BlockSource::read_range`] when the block is emitted.
+pub trait BlockSource: fmt::Debug + Send + Sync {
+    /// Returns the names of the files containing the blocks, in the order they should be read
+    fn files(&self) -> io::Result<Vec<String>>;
+
+    /// Append the whole content of the file `name` to `buffer`
+    fn read_file(&self, name: &str, buffer: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Returns the bytes from `start` (included) to `end` (excluded) of the file `name`.
+    /// Fewer bytes may be returned if the file ends before `end`
+    fn read_range(&self, name: &str, start: usize, end: usize) -> io::Result<Vec<u8>>;
+
+    /// Returns the path of the file `name` if it's available on the local filesystem, in this case
+    /// the file is opened directly instead of using the other methods
+    fn local_path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// The default [`BlockSource`], reading the `blk*.dat` files in a local blocks directory
+#[derive(Debug, Clone)]
+pub struct FsBlockSource {
+    blocks_dir: PathBuf,
+    extra_dirs: Vec<PathBuf>,
+    file_range: Option<(u32, u32)>,
+}
+
+impl FsBlockSource {
+    /// Creates a source reading the `blk*.dat` files contained in `blocks_dir`
+    pub fn new<P: AsRef<Path>>(blocks_dir: P) -> Self {
+        FsBlockSource {
+            blocks_dir: blocks_dir.as_ref().to_owned(),
+            extra_dirs: vec![],
+            file_range: None,
+        }
+    }
+
+    /// Read also the `blk*.dat` files contained in `extra_dirs`. The files of the directory at
+    /// index `i` are named `extra<i>/<file name>`, files with the same name in different
+    /// directories are read one after the other, starting from `blocks_dir`
+    pub fn with_extra_dirs(mut self, extra_dirs: Vec<PathBuf>) -> Self {
+        self.extra_dirs = extra_dirs;
+        self
+    }
+
+    /// Read only the `blkNNNNN.dat` files whose index `NNNNN` is between `first` and `last`
+    /// included, in every directory. Files without a numeric index are skipped
+    pub fn with_file_range(mut self, file_range: Option<(u32, u32)>) -> Self {
+        self.file_range = file_range;
+        self
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        let extra = name.split_once('/').and_then(|(prefix, file)| {
+            let index: usize = prefix.strip_prefix("extra")?.parse().ok()?;
+            Some(self.extra_dirs.get(index)?.join(file))
+        });
+        extra.unwrap_or_else(|| self.blocks_dir.join(name))
+    }
+}
+
+/// Returns the index `NNNNN` of a file named `blkNNNNN.dat`, `None` for other names
+fn blk_file_index(name: &str) -> Option<u32> {
+    let index = name.strip_prefix("blk")?.strip_suffix(".dat")?;
+    if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    index.parse().ok()
+}
+
+/// Returns the names of the `blk*.dat` files in `dir`, only the ones with index in `file_range`
+/// if given
+fn list_block_files(dir: &Path, file_range: Option<(u32, u32)>) -> io::Result<Vec<String>> {
+    let pattern = dir.join("blk*.dat");
+    let pattern = pattern
+        .to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid blocks_dir"))?;
+    let entries = glob::glob(pattern)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry.map_err(|e| e.into_error())?;
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            if let Some((first, last)) = file_range {
+                match blk_file_index(name) {
+                    Some(index) if first <= index && index <= last => (),
+                    _ => continue,
+                }
+            }
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+impl BlockSource for FsBlockSource {
+    fn files(&self) -> io::Result<Vec<String>> {
+        let mut names: Vec<_> = list_block_files(&self.blocks_dir, self.file_range)?
+            .into_iter()
+            .map(|name| (name, None))
+            .collect();
+        for (i, dir) in self.extra_dirs.iter().enumerate() {
+            names.extend(
+                list_block_files(dir, self.file_range)?
+                    .into_iter()
+                    .map(|name| (name, Some(i))),
+            );
+        }
+        names.sort();
+        Ok(names
+            .into_iter()
+            .map(|(name, extra)| match extra {
+                Some(i) => format!("extra{}/{}", i, name),
+                None => name,
+            })
+            .collect())
+    }
+
+    fn read_file(&self, name: &str, buffer: &mut Vec<u8>) -> io::Result<()> {
+        File::open(self.path(name))?.read_to_end(buffer)?;
+        Ok(())
+    }
+
+    fn read_range(&self, name: &str, start: usize, end: usize) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(name))?;
+        let len = file.metadata()?.len() as usize;
+        read_range(&mut file, start, end.min(len).max(start))
+    }
+
+    fn local_path(&self, name: &str) -> Option<PathBuf> {
+        Some(self.path(name))
+    }
+}
+
+/// A [`BlockSource`] reading the `blk*.dat` entries of an uncompressed tar archive, in any
+/// directory of the archive, ordered by entry name.
+///
+/// The archive isn't extracted: the position of every entry is recorded when listing the files
+/// and its bytes are read directly from the archive
+#[cfg(feature = "tar")]
+#[derive(Debug)]
+pub struct TarBlockSource {
+    path: PathBuf,
+    file_range: Option<(u32, u32)>,
+    /// Position in the archive and size of every `blk*.dat` entry, filled by `files`
+    entries: std::sync::Mutex<std::collections::HashMap<String, (u64, u64)>>,
+}
+
+#[cfg(feature = "tar")]
+impl TarBlockSource {
+    /// Creates a source reading the `blk*.dat` entries of the tar archive at `path`
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        TarBlockSource {
+            path: path.as_ref().to_owned(),
+            file_range: None,
+            entries: Default::default(),
+        }
+    }
+
+    /// Read only the `blkNNNNN.dat` entries whose index `NNNNN` is between `first` and `last`
+    /// included. Entries without a numeric index are skipped
+    pub fn with_file_range(mut self, file_range: Option<(u32, u32)>) -> Self {
+        self.file_range = file_range;
+        self
+    }
+
+    /// Returns the content of the `xor.dat` entry of the archive, if any
+    pub(crate) fn read_xor_file(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut archive = tar::Archive::new(File::open(&self.path)?);
+        for entry in archive.entries_with_seek()? {
+            let mut entry = entry?;
+            if entry.path()?.file_name().and_then(|n| n.to_str()) == Some("xor.dat") {
+                let mut content = vec![];
+                entry.read_to_end(&mut content)?;
+                return Ok(Some(content));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the position in the archive and the size of the entry `name`
+    fn entry(&self, name: &str) -> io::Result<(u64, u64)> {
+        if let Some(entry) = self.entries.lock().unwrap().get(name) {
+            return Ok(*entry);
+        }
+        self.files()?;
+        self.entries
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, name.to_string()))
+    }
+}
+
+#[cfg(feature = "tar")]
+impl BlockSource for TarBlockSource {
+    fn files(&self) -> io::Result<Vec<String>> {
+        let mut archive = tar::Archive::new(File::open(&self.path)?);
+        let mut entries = std::collections::HashMap::new();
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?;
+            let file_name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(file_name) if file_name.starts_with("blk") && file_name.ends_with(".dat") => {
+                    file_name
+                }
+                _ => continue,
+            };
+            if let Some((first, last)) = self.file_range {
+                match blk_file_index(file_name) {
+                    Some(index) if first <= index && index <= last => (),
+                    _ => continue,
+                }
+            }
+            let name = path
+                .to_str()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid entry name"))?
+                .to_string();
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+        let mut names: Vec<_> = entries.keys().cloned().collect();
+        names.sort();
+        *self.entries.lock().unwrap() = entries;
+        Ok(names)
+    }
+
+    fn read_file(&self, name: &str, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let (position, size) = self.entry(name)?;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(position))?;
+        file.take(size).read_to_end(buffer)?;
+        Ok(())
+    }
+
+    fn read_range(&self, name: &str, start: usize, end: usize) -> io::Result<Vec<u8>> {
+        let (position, size) = self.entry(name)?;
+        let end = end.min(size as usize).max(start);
+        let position = position as usize;
+        read_range(
+            &mut File::open(&self.path)?,
+            position + start,
+            position + end,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{blk_file_index, BlockSource, FsBlockSource};
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use test_log::test;
+
+    /// Hides the local paths so that the blocks are read with range requests, counting them
+    #[derive(Debug)]
+    struct RemoteSource {
+        inner: FsBlockSource,
+        ranges: AtomicUsize,
+    }
+
+    impl BlockSource for RemoteSource {
+        fn files(&self) -> io::Result<Vec<String>> {
+            self.inner.files()
+        }
+        fn read_file(&self, name: &str, buffer: &mut Vec<u8>) -> io::Result<()> {
+            self.inner.read_file(name, buffer)
+        }
+        fn read_range(&self, name: &str, start: usize, end: usize) -> io::Result<Vec<u8>> {
+            self.ranges.fetch_add(1, Ordering::Relaxed);
+            self.inner.read_range(name, start, end)
+        }
+    }
+
+    #[test]
+    fn test_fs_block_source() {
+        let conf = test_conf();
+        let source = FsBlockSource::new(&conf.blocks_dir);
+        assert_eq!(source.files().unwrap(), vec!["blk-testnet.dat".to_string()]);
+        let mut buffer = vec![];
+        source.read_file("blk-testnet.dat", &mut buffer).unwrap();
+        assert_eq!(
+            source.read_range("blk-testnet.dat", 4, 8).unwrap(),
+            buffer[4..8]
+        );
+        let len = buffer.len();
+        assert_eq!(
+            source
+                .read_range("blk-testnet.dat", len - 2, len + 2)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            source.local_path("blk-testnet.dat"),
+            Some(PathBuf::from(&conf.blocks_dir).join("blk-testnet.dat"))
+        );
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        std::fs::write(tempdir.path().join("blk00000.dat"), [1u8]).unwrap();
+        let source = FsBlockSource::new(&conf.blocks_dir)
+            .with_extra_dirs(vec![tempdir.path().to_path_buf(), conf.blocks_dir.clone()]);
+        assert_eq!(
+            source.files().unwrap(),
+            vec![
+                "blk-testnet.dat",
+                "extra1/blk-testnet.dat",
+                "extra0/blk00000.dat"
+            ]
+        );
+        assert_eq!(
+            source.read_range("extra0/blk00000.dat", 0, 4).unwrap(),
+            [1u8]
+        );
+        assert_eq!(
+            source.read_range("extra1/blk-testnet.dat", 4, 8).unwrap(),
+            buffer[4..8]
+        );
+    }
+
+    #[test]
+    fn test_file_range() {
+        assert_eq!(blk_file_index("blk00000.dat"), Some(0));
+        assert_eq!(blk_file_index("blk01234.dat"), Some(1234));
+        assert_eq!(blk_file_index("blk-testnet.dat"), None);
+        assert_eq!(blk_file_index("blk+1.dat"), None);
+        assert_eq!(blk_file_index("blk.dat"), None);
+        assert_eq!(blk_file_index("blk00001.dat.bak"), None);
+        assert_eq!(blk_file_index("rev00001.dat"), None);
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let extra = tempfile::TempDir::new().unwrap();
+        for i in 0..5 {
+            let name = format!("blk{:05}.dat", i);
+            std::fs::write(tempdir.path().join(&name), [1u8]).unwrap();
+            std::fs::write(extra.path().join(&name), [1u8]).unwrap();
+        }
+        std::fs::write(tempdir.path().join("blk-other.dat"), [1u8]).unwrap();
+
+        let source = FsBlockSource::new(tempdir.path());
+        assert_eq!(source.files().unwrap().len(), 6);
+        let source = source
+            .with_extra_dirs(vec![extra.path().to_path_buf()])
+            .with_file_range(Some((1, 2)));
+        assert_eq!(
+            source.files().unwrap(),
+            vec![
+                "blk00001.dat",
+                "extra0/blk00001.dat",
+                "blk00002.dat",
+                "extra0/blk00002.dat"
+            ]
+        );
+        let source = source.with_file_range(Some((7, 9)));
+        assert!(source.files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_custom_block_source() {
+        let conf = test_conf();
+        let source = Arc::new(RemoteSource {
+            inner: FsBlockSource::new(&conf.blocks_dir),
+            ranges: AtomicUsize::new(0),
+        });
+        let mut remote_conf = conf.clone();
+        remote_conf.blocks_dir = PathBuf::new();
+        remote_conf.block_files_source = Some(source.clone());
+
+        let expected: Vec<_> = iter(conf).map(|b| (b.block_hash, b.fee())).collect();
+        let blocks: Vec<_> = iter(remote_conf).map(|b| (b.block_hash, b.fee())).collect();
+        assert_eq!(expected, blocks);
+        assert_eq!(source.ranges.load(Ordering::Relaxed), blocks.len());
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_tar_block_source() {
+        use super::TarBlockSource;
+
+        let conf = test_conf();
+        let blk = std::fs::read(conf.blocks_dir.join("blk-testnet.dat")).unwrap();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let tar_path = tempdir.path().join("blocks.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&tar_path).unwrap());
+        let mut append = |name: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data).unwrap();
+        };
+        append("blocks/rev00000.dat", &[1u8; 10]);
+        append("blocks/blk00001.dat", &blk);
+        // the same blocks in another entry are emitted once
+        append("blocks/blk00000.dat", &blk);
+        append("blocks/xor.dat", &[0u8; 8]);
+        builder.finish().unwrap();
+        drop(builder);
+
+        let source = TarBlockSource::new(&tar_path);
+        assert_eq!(
+            source.files().unwrap(),
+            vec!["blocks/blk00000.dat", "blocks/blk00001.dat"]
+        );
+        let mut buffer = vec![];
+        source
+            .read_file("blocks/blk00001.dat", &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, blk);
+        assert_eq!(
+            source.read_range("blocks/blk00000.dat", 4, 8).unwrap(),
+            blk[4..8]
+        );
+        let len = blk.len();
+        assert_eq!(
+            source
+                .read_range("blocks/blk00000.dat", len - 2, len + 2)
+                .unwrap(),
+            blk[len - 2..]
+        );
+        assert!(source.read_range("blocks/rev00000.dat", 0, 4).is_err());
+        let source = TarBlockSource::new(&tar_path).with_file_range(Some((1, 1)));
+        assert_eq!(source.files().unwrap(), vec!["blocks/blk00001.dat"]);
+
+        let mut tar_conf = conf.clone();
+        tar_conf.blocks_dir = PathBuf::new();
+        tar_conf.blocks_tar = Some(tar_path);
+        let expected: Vec<_> = iter(conf).map(|b| (b.block_hash, b.fee())).collect();
+        let blocks: Vec<_> = iter(tar_conf.clone())
+            .map(|b| (b.block_hash, b.fee()))
+            .collect();
+        assert_eq!(expected, blocks);
+
+        tar_conf.blocks_tar = Some(tempdir.path().join("missing.tar"));
+        assert!(matches!(
+            crate::iterate(tar_conf, std::sync::mpsc::sync_channel(1).0).join(),
+            Ok(Err(crate::Error::BlocksTarNotFound(_)))
+        ));
+    }
+}
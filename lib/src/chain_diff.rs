@@ -0,0 +1,127 @@
+use crate::bitcoin::BlockHash;
+use crate::{iter, Config};
+
+/// A divergence between two chains, returned by [`diff_chains`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainDiff {
+    /// The chains contain different blocks at `height`
+    Block {
+        /// Height of the blocks
+        height: u32,
+
+        /// Hash of the block in the first chain
+        hash_a: BlockHash,
+
+        /// Hash of the block in the second chain
+        hash_b: BlockHash,
+    },
+
+    /// The chains have a different length, always the last item returned
+    Length {
+        /// Number of blocks iterated in the first chain
+        len_a: u32,
+
+        /// Number of blocks iterated in the second chain
+        len_b: u32,
+    },
+}
+
+/// Return an Iterator of the [`ChainDiff`] between the chains iterated with `config_a` and
+/// `config_b`, for example to check two datadirs of different nodes contain the same blocks.
+///
+/// The chains are iterated in lockstep and compared height by height, so the configs should have
+/// the same `start_at_height`. Only block hashes are compared, thus `skip_prevout` is forced to
+/// `true` and `watch_scripts` is ignored. When the shorter chain ends, the remaining blocks of the
+/// longer one are only counted to report their lengths in a final [`ChainDiff::Length`].
+pub fn diff_chains(mut config_a: Config, mut config_b: Config) -> impl Iterator<Item = ChainDiff> {
+    for config in [&mut config_a, &mut config_b] {
+        config.skip_prevout = true;
+        config.watch_scripts = None;
+    }
+    let mut chain_a = iter(config_a);
+    let mut chain_b = iter(config_b);
+    let mut len = 0u32;
+    let mut ended = false;
+
+    std::iter::from_fn(move || {
+        while !ended {
+            match (chain_a.next(), chain_b.next()) {
+                (Some(block_a), Some(block_b)) => {
+                    len += 1;
+                    if block_a.block_hash != block_b.block_hash {
+                        return Some(ChainDiff::Block {
+                            height: block_a.height,
+                            hash_a: block_a.block_hash,
+                            hash_b: block_b.block_hash,
+                        });
+                    }
+                }
+                (None, None) => ended = true,
+                (Some(_), None) => {
+                    ended = true;
+                    return Some(ChainDiff::Length {
+                        len_a: len + 1 + chain_a.by_ref().count() as u32,
+                        len_b: len,
+                    });
+                }
+                (None, Some(_)) => {
+                    ended = true;
+                    return Some(ChainDiff::Length {
+                        len_a: len,
+                        len_b: len + 1 + chain_b.by_ref().count() as u32,
+                    });
+                }
+            }
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_chains, ChainDiff};
+    use crate::bitcoin::blockdata::constants::genesis_block;
+    use crate::bitcoin::Network;
+    use crate::inner_test::{child_block, test_conf};
+    use crate::Config;
+    use test_log::test;
+
+    #[test]
+    fn test_diff_chains() {
+        assert_eq!(diff_chains(test_conf(), test_conf()).count(), 0);
+
+        let mut short = test_conf();
+        short.stop_at_height = Some(100);
+        let diffs: Vec<_> = diff_chains(test_conf(), short).collect();
+        let len_a = 400 - test_conf().max_reorg as u32 + 1;
+        assert_eq!(diffs, vec![ChainDiff::Length { len_a, len_b: 101 }]);
+
+        let genesis = genesis_block(Network::Regtest);
+        let mut chain_a = vec![genesis];
+        for _ in 0..20 {
+            chain_a.push(child_block(chain_a.last().unwrap(), 0));
+        }
+        let mut chain_b = chain_a[..5].to_vec();
+        for _ in 0..17 {
+            chain_b.push(child_block(chain_b.last().unwrap(), 1));
+        }
+        let conf_a = Config::from_blocks(chain_a.clone(), Network::Regtest);
+        let conf_b = Config::from_blocks(chain_b.clone(), Network::Regtest);
+        let max_reorg = conf_a.max_reorg as usize;
+        let len_a = chain_a.len() - max_reorg;
+        let len_b = chain_b.len() - max_reorg;
+
+        let mut expected: Vec<_> = (5..len_a)
+            .map(|height| ChainDiff::Block {
+                height: height as u32,
+                hash_a: chain_a[height].block_hash(),
+                hash_b: chain_b[height].block_hash(),
+            })
+            .collect();
+        expected.push(ChainDiff::Length {
+            len_a: len_a as u32,
+            len_b: len_b as u32,
+        });
+        assert_eq!(diff_chains(conf_a, conf_b).collect::<Vec<_>>(), expected);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::bitcoin::BlockHash;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The last emitted blocks, persisted in [`crate::Config::chain_tip_file`] every time a block is
+/// returned by the iterator
+pub(crate) struct ChainTip {
+    path: PathBuf,
+    blocks: VecDeque<(u32, BlockHash)>,
+    capacity: usize,
+}
+
+impl ChainTip {
+    /// Keeps `max_reorg + 1` blocks, starting from the ones persisted at `path` if any
+    pub(crate) fn new(path: PathBuf, max_reorg: u8) -> Self {
+        let blocks = match load_chain_tip(&path) {
+            Ok(blocks) => blocks.into(),
+            Err(e) => {
+                log::warn!("ignoring chain tip file {:?}: {}", path, e);
+                VecDeque::new()
+            }
+        };
+        ChainTip {
+            path,
+            blocks,
+            capacity: max_reorg as usize + 1,
+        }
+    }
+
+    /// Record the block `hash` at `height` and save. Blocks at the same height or higher, from a
+    /// previous run starting again from a lower height, are dropped
+    pub(crate) fn push(&mut self, height: u32, hash: BlockHash) {
+        while matches!(self.blocks.back(), Some((h, _)) if *h >= height) {
+            self.blocks.pop_back();
+        }
+        self.blocks.push_back((height, hash));
+        while self.blocks.len() > self.capacity {
+            self.blocks.pop_front();
+        }
+        if let Err(e) = self.save() {
+            log::error!("cannot save chain tip file {:?}: {}", self.path, e);
+        }
+    }
+
+    /// Save writing a temporary file first to not leave a truncated file
+    fn save(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut content = Vec::with_capacity(self.blocks.len() * 76);
+        for (height, hash) in self.blocks.iter() {
+            writeln!(content, "{} {}", height, hash)?;
+        }
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// Load the `(height, hash)` of the last emitted blocks, in height order, saved in `path` by an
+/// iteration with [`crate::Config::chain_tip_file`].
+///
+/// An indexer restarting can compare the tip it recorded with these blocks: if its tip isn't
+/// among them a reorg happened while it was down, or it's behind more than `max_reorg` blocks.
+/// Returns an empty vector if `path` doesn't exist
+pub fn load_chain_tip<P: AsRef<Path>>(path: P) -> io::Result<Vec<(u32, BlockHash)>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    content
+        .lines()
+        .map(|line| {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, line.to_string());
+            let (height, hash) = line.split_once(' ').ok_or_else(invalid)?;
+            Ok((
+                height.parse().map_err(|_| invalid())?,
+                BlockHash::from_str(hash).map_err(|_| invalid())?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_chain_tip;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use std::fs;
+    use test_log::test;
+
+    #[test]
+    fn test_chain_tip_file() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("chain_tip");
+        assert!(load_chain_tip(&path).unwrap().is_empty());
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        conf.chain_tip_file = Some(path.clone());
+        let emitted: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height(), b.block_hash()))
+            .collect();
+        let tip = load_chain_tip(&path).unwrap();
+        assert_eq!(tip.len(), conf.max_reorg as usize + 1);
+        assert_eq!(tip, emitted[emitted.len() - tip.len()..]);
+
+        // a new run from the genesis replaces the blocks of the previous one
+        conf.stop_at_height = Some(10);
+        iter(conf.clone()).count();
+        assert_eq!(load_chain_tip(&path).unwrap(), emitted[4..=10]);
+
+        // dropping the iterator, only the blocks returned are recorded
+        conf.stop_at_height = None;
+        iter(conf.clone()).take(3).count();
+        assert_eq!(load_chain_tip(&path).unwrap(), emitted[..3]);
+
+        fs::write(&path, "1 not_a_hash\n").unwrap();
+        assert!(load_chain_tip(&path).is_err());
+    }
+}
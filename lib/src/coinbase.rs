@@ -0,0 +1,110 @@
+use crate::bitcoin::TxOut;
+use crate::{iter, printable_ascii_runs, Config};
+
+/// Runs of printable ASCII characters shorter than this are considered noise
+const MIN_TAG_RUN: usize = 4;
+
+/// The coinbase transaction of a block, returned by [`coinbase_iter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinbaseInfo {
+    /// Height of the block
+    pub height: u32,
+
+    /// The script_sig of the coinbase input, where miners usually put their tag
+    pub script_sig: Vec<u8>,
+
+    /// The outputs of the coinbase
+    pub outputs: Vec<TxOut>,
+
+    /// The printable ASCII runs of at least 4 characters in `script_sig`, joined by a space.
+    /// `None` if there aren't any. A heuristic to attribute the block to a mining pool
+    pub extracted_tag: Option<String>,
+}
+
+/// Return an Iterator of the [`CoinbaseInfo`] of the blocks iterated with `config`, useful for
+/// mining pool attribution. Only the coinbase of every block is decoded, consider using
+/// `skip_prevout` since previous outputs aren't needed
+pub fn coinbase_iter(config: Config) -> impl Iterator<Item = CoinbaseInfo> {
+    iter(config).filter_map(|block_extra| {
+        let coinbase = block_extra.nth_tx(0)?;
+        let script_sig = coinbase.input.first()?.script_sig.to_bytes();
+        Some(CoinbaseInfo {
+            height: block_extra.height,
+            extracted_tag: extract_tag(&script_sig),
+            script_sig,
+            outputs: coinbase.output,
+        })
+    })
+}
+
+fn extract_tag(script_sig: &[u8]) -> Option<String> {
+    let runs = printable_ascii_runs(script_sig, MIN_TAG_RUN);
+    if runs.is_empty() {
+        None
+    } else {
+        Some(runs.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{coinbase_iter, extract_tag};
+    use crate::inner_test::{child_block, test_conf};
+    use crate::{iter, Config};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Network, ScriptBuf};
+    use test_log::test;
+
+    #[test]
+    fn test_extract_tag() {
+        assert_eq!(extract_tag(b""), None);
+        assert_eq!(extract_tag(b"\x03\x10\x27\x00ab\x01"), None);
+        assert_eq!(
+            extract_tag(b"\x03\x10\x27\x00/ViaBTC/\x00\x01Mined by x\xff"),
+            Some("/ViaBTC/ Mined by x".to_string())
+        );
+        assert_eq!(
+            extract_tag(b"\x04    \x05"),
+            None,
+            "whitespace only runs are ignored"
+        );
+    }
+
+    #[test]
+    fn test_coinbase_iter() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let expected: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height(), b.coinbase_output_value().unwrap()))
+            .collect();
+        let coinbases: Vec<_> = coinbase_iter(conf)
+            .map(|c| (c.height, c.outputs.iter().map(|o| o.value.to_sat()).sum()))
+            .collect();
+        assert_eq!(coinbases, expected);
+
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..10 {
+            let mut block = child_block(chain.last().unwrap(), 0);
+            let mut script_sig = block.txdata[0].input[0].script_sig.to_bytes();
+            script_sig.extend(b"\x00/test pool/");
+            block.txdata[0].input[0].script_sig = ScriptBuf::from(script_sig);
+            block.header.merkle_root = block.compute_merkle_root().unwrap();
+            chain.push(block);
+        }
+        let mut conf = Config::from_blocks(chain.clone(), Network::Regtest);
+        conf.skip_prevout = true;
+        let coinbases: Vec<_> = coinbase_iter(conf).collect();
+        assert_eq!(coinbases.len(), 11 - 6);
+        assert!(coinbases[0]
+            .extracted_tag
+            .as_ref()
+            .unwrap()
+            .contains("The Times 03/Jan/2009"));
+        for c in coinbases.iter().skip(1) {
+            let tx = &chain[c.height as usize].txdata[0];
+            assert_eq!(c.script_sig, tx.input[0].script_sig.to_bytes());
+            assert_eq!(c.outputs, tx.output);
+            assert!(c.extracted_tag.as_ref().unwrap().ends_with("/test pool/"));
+        }
+    }
+}
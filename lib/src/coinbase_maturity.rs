@@ -0,0 +1,170 @@
+use crate::bitcoin::blockdata::constants::COINBASE_MATURITY;
+use crate::bitcoin::OutPoint;
+use crate::{BlockExtra, Error};
+use fxhash::FxHashMap;
+
+/// An input of a block spending a coinbase output, returned by
+/// [`BlockExtra::coinbase_spends()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoinbaseSpend {
+    /// The coinbase output spent
+    pub outpoint: OutPoint,
+
+    /// Height of the block creating the coinbase output
+    pub created_height: u32,
+
+    /// True if the output is spent at least [`COINBASE_MATURITY`] blocks after its creation, as
+    /// required by consensus rules
+    pub mature: bool,
+}
+
+/// The unspent coinbase outputs with their creation height, built in the fee stage when
+/// [`crate::Config::track_coinbase_maturity`] is set.
+///
+/// Only coinbase outputs are kept, tens of millions for mainnet, most of them are the unspent
+/// early pay to public key outputs
+pub(crate) struct CoinbaseMaturity {
+    unspent: FxHashMap<OutPoint, u32>,
+    check: bool,
+}
+
+impl CoinbaseMaturity {
+    /// When `check` is true, a coinbase output spent before maturity makes [`Self::add`] fail
+    pub(crate) fn new(check: bool) -> Self {
+        CoinbaseMaturity {
+            unspent: FxHashMap::default(),
+            check,
+        }
+    }
+
+    /// Record the coinbase outputs created by `block_extra` and set in it the coinbase outputs it
+    /// spends. A coinbase output spent in the same block is immature
+    pub(crate) fn add(&mut self, block_extra: &mut BlockExtra) -> Result<(), Error> {
+        let height = block_extra.height;
+        let block = block_extra.block();
+        let coinbase_txid = block_extra.txids_or_compute()[0];
+        for (vout, output) in block.txdata[0].output.iter().enumerate() {
+            if !output.script_pubkey.is_op_return() {
+                // a duplicated coinbase (BIP30) overwrites the previous outputs
+                self.unspent
+                    .insert(OutPoint::new(coinbase_txid, vout as u32), height);
+            }
+        }
+
+        let mut spends = vec![];
+        for input in block.txdata.iter().skip(1).flat_map(|tx| tx.input.iter()) {
+            if let Some(created_height) = self.unspent.remove(&input.previous_output) {
+                let mature = height - created_height >= COINBASE_MATURITY;
+                if self.check && !mature {
+                    return Err(Error::ImmatureCoinbaseSpend {
+                        outpoint: input.previous_output,
+                        created_height,
+                        height,
+                    });
+                }
+                spends.push(CoinbaseSpend {
+                    outpoint: input.previous_output,
+                    created_height,
+                    mature,
+                });
+            }
+        }
+        block_extra.coinbase_spends = spends;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CoinbaseMaturity;
+    use crate::bitcoin::blockdata::constants::genesis_block;
+    use crate::bitcoin::{Network, OutPoint};
+    use crate::block_extra::test::{block_extra_from_block, tx};
+    use crate::inner_test::{child_block, test_conf};
+    use crate::{iter, try_iter, Config, Error};
+    use test_log::test;
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut conf = test_conf();
+        conf.track_coinbase_maturity = true;
+        let mut spends = 0;
+        for be in iter(conf.clone()) {
+            for spend in be.coinbase_spends() {
+                assert!(spend.mature);
+                assert!(spend.created_height + 100 <= be.height());
+                assert!(be.outpoint_values().contains_key(&spend.outpoint));
+                spends += 1;
+            }
+        }
+        assert!(spends > 0);
+        assert!(iter(test_conf()).all(|be| be.coinbase_spends().is_empty()));
+
+        conf.check_coinbase_maturity = true;
+        assert_eq!(iter(conf).count(), 395);
+    }
+
+    #[test]
+    fn test_coinbase_immature() {
+        // the coinbase of the second block is spent in the third one
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        chain.push(child_block(&chain[0], 0));
+        let mut block = child_block(&chain[1], 0);
+        let coinbase = chain[1].txdata[0].compute_txid();
+        block
+            .txdata
+            .push(tx(vec![OutPoint::new(coinbase, 0)], 1, 1));
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        chain.push(block);
+
+        let mut maturity = CoinbaseMaturity::new(false);
+        let mut spent = vec![];
+        for (height, block) in chain.iter().enumerate() {
+            let mut be = block_extra_from_block(block.clone());
+            be.height = height as u32;
+            maturity.add(&mut be).unwrap();
+            spent.push(be.coinbase_spends().to_vec());
+        }
+        assert!(spent[0].is_empty() && spent[1].is_empty());
+        assert_eq!(spent[2].len(), 1);
+        assert_eq!(spent[2][0].outpoint, OutPoint::new(coinbase, 0));
+        assert_eq!(spent[2][0].created_height, 1);
+        assert!(!spent[2][0].mature);
+
+        // followed by enough blocks to be emitted
+        for _ in 0..10 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let mut conf = Config::from_blocks(chain, Network::Regtest);
+        conf.track_coinbase_maturity = true;
+        let blocks: Vec<_> = iter(conf.clone()).collect();
+        assert!(blocks.len() > 2);
+        assert_eq!(blocks[2].coinbase_spends(), &spent[2][..]);
+
+        conf.check_coinbase_maturity = true;
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 2);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::ImmatureCoinbaseSpend { outpoint, created_height: 1, height: 2 })
+                if *outpoint == OutPoint::new(coinbase, 0)
+        ));
+    }
+
+    #[test]
+    fn test_coinbase_maturity_not_from_genesis() {
+        let mut conf = test_conf();
+        conf.check_coinbase_maturity = true;
+        conf.since_file_state = Some("since".into());
+        assert!(matches!(
+            conf.utxo_manager(),
+            Err(Error::CoinbaseMaturityNotFromGenesis)
+        ));
+        conf.since_file_state = None;
+        conf.resume_from_utxo_db = true;
+        assert!(matches!(
+            conf.utxo_manager(),
+            Err(Error::CoinbaseMaturityNotFromGenesis)
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::{self, Cursor, Read, Write};
+
+/// Compression of a stream of serialized [`crate::BlockExtra`], like the output of the cli with
+/// [`crate::Config::compress`].
+///
+/// The compressed formats are streaming ones: the records are compressed one after the other and
+/// remain self delimiting once decompressed, thus [`decoder`] can be used in front of
+/// [`crate::read_block_extras`] and [`crate::PipeIterator`] detects compressed input
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// The snappy framing format, fast but compressing less
+    Snappy,
+
+    /// The xz format, slow but compressing the most
+    Xz,
+
+    /// The zstd format, a balance between speed and compression
+    Zstd,
+}
+
+/// Bytes needed to recognize every supported format
+const MAGIC_LEN: usize = 10;
+
+const SNAPPY_MAGIC: [u8; 10] = [0xff, 0x06, 0x00, 0x00, b's', b'N', b'a', b'P', b'p', b'Y'];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+impl Compression {
+    /// Returns the compression of a stream starting with `prefix`, `None` if not compressed or not
+    /// enough bytes to tell
+    pub fn detect(prefix: &[u8]) -> Option<Self> {
+        if prefix.starts_with(&SNAPPY_MAGIC) {
+            Some(Compression::Snappy)
+        } else if prefix.starts_with(&XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else if prefix.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a writer compressing what is written to it into `writer`, [`Encoder::finish`] must
+    /// be called at the end to write the last compressed bytes
+    pub fn encoder<W: Write>(self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Compression::Snappy => {
+                Encoder::Snappy(Box::new(snap::write::FrameEncoder::new(writer)))
+            }
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(writer, 6)),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+}
+
+/// A writer compressing with a [`Compression`], returned by [`Compression::encoder`]
+pub enum Encoder<W: Write> {
+    /// Compressing with [`Compression::Snappy`]
+    Snappy(Box<snap::write::FrameEncoder<W>>),
+
+    /// Compressing with [`Compression::Xz`]
+    Xz(xz2::write::XzEncoder<W>),
+
+    /// Compressing with [`Compression::Zstd`]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Write the remaining compressed bytes and return the inner writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Snappy(e) => e.into_inner().map_err(|e| e.into_error()),
+            Encoder::Xz(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Snappy(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Snappy(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Returns a reader decompressing `reader` if it starts with a supported [`Compression`] format,
+/// otherwise reading it unchanged. The first bytes of `reader` are read to detect the format
+pub fn decoder<R: Read + Send + 'static>(mut reader: R) -> io::Result<Box<dyn Read + Send>> {
+    let mut prefix = Vec::with_capacity(MAGIC_LEN);
+    (&mut reader)
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    let compression = Compression::detect(&prefix);
+    let reader = Cursor::new(prefix).chain(reader);
+    Ok(match compression {
+        Some(Compression::Snappy) => Box::new(snap::read::FrameDecoder::new(reader)),
+        Some(Compression::Xz) => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::new(reader)?),
+        None => Box::new(reader),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decoder, Compression};
+    use crate::bitcoin::consensus::serialize;
+    use crate::inner_test::test_conf;
+    use crate::{iter, read_block_extras};
+    use std::io::{Cursor, Read, Write};
+    use test_log::test;
+
+    #[test]
+    fn test_compression() {
+        let mut conf = test_conf();
+        conf.stop_at_height = Some(20);
+        let expected: Vec<_> = iter(conf).map(|b| serialize(&b)).collect();
+        let plain = expected.concat();
+
+        for compression in [Compression::Snappy, Compression::Xz, Compression::Zstd] {
+            let mut encoder = compression.encoder(vec![]).unwrap();
+            for record in expected.iter() {
+                encoder.write_all(record).unwrap();
+            }
+            let compressed = encoder.finish().unwrap();
+            assert_eq!(Compression::detect(&compressed), Some(compression));
+            assert!(compressed.len() < plain.len(), "{:?}", compression);
+
+            let mut decompressed = vec![];
+            decoder(Cursor::new(compressed.clone()))
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, plain, "{:?}", compression);
+
+            let records: Vec<_> = read_block_extras(decoder(Cursor::new(compressed)).unwrap())
+                .map(|b| serialize(&b.unwrap()))
+                .collect();
+            assert_eq!(records, expected, "{:?}", compression);
+        }
+
+        assert_eq!(Compression::detect(&plain), None);
+        let mut unchanged = vec![];
+        decoder(Cursor::new(plain.clone()))
+            .unwrap()
+            .read_to_end(&mut unchanged)
+            .unwrap();
+        assert_eq!(unchanged, plain);
+
+        let mut short = vec![];
+        decoder(Cursor::new(vec![1u8, 2]))
+            .unwrap()
+            .read_to_end(&mut short)
+            .unwrap();
+        assert_eq!(short, vec![1u8, 2]);
+    }
+}
@@ -1,7 +1,17 @@
-use bitcoin::Network;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::p2p::Magic;
+use bitcoin::{Block, BlockHash, Network, Script, ScriptBuf, Work};
 #[cfg(feature = "clap")]
 use clap::Parser;
+use std::collections::HashSet;
+use std::convert::TryInto;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+
+use crate::{BlockSource, SubsidySchedule};
 
 /// Configuration parameters, most important the bitcoin blocks directory
 #[cfg_attr(feature = "clap", derive(Parser))]
@@ -11,6 +21,23 @@ pub struct Config {
     #[cfg_attr(feature = "clap", arg(short, long))]
     pub blocks_dir: PathBuf,
 
+    /// Other blocks directories, like the ones of several nodes of the same network, read together
+    /// with `blocks_dir` as a single chain. Blocks found in more directories are emitted once,
+    /// different blocks at the same height are forks resolved like in a single directory.
+    /// All the directories must have the same `xor.dat` key, unless `xor_key` is specified.
+    /// Ignored when blocks are read from `block_files_source` or `blocks_source`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub extra_blocks_dirs: Vec<PathBuf>,
+
+    /// Read only the `blkNNNNN.dat` files whose index `NNNNN` is in this range, with the end
+    /// included, skipping the other files of `blocks_dir`, `extra_blocks_dirs` or `blocks_tar`.
+    /// Unless the first file is included, the blocks won't connect to the genesis: set
+    /// `genesis_hash` to the first block to emit and use `skip_prevout` or `external_prevout`.
+    /// Ignored when blocks are read from `block_files_source` or `blocks_source`.
+    /// On the command line, the range is `<first>-<last>`
+    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_blk_file_range))]
+    pub blk_file_range: Option<(u32, u32)>,
+
     /// Network (bitcoin, testnet, regtest, signet)
     #[cfg_attr(feature = "clap", arg(short, long))]
     pub network: bitcoin::Network,
@@ -20,14 +47,57 @@ pub struct Config {
     #[cfg_attr(feature = "clap", arg(short, long))]
     pub skip_prevout: bool,
 
+    /// Magic bytes prefixing every block in the `blk*.dat` files, as 8 hex characters, allowing to
+    /// iterate chains with a magic not in [`Network`]. Defaults to the magic of `network`
+    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_magic))]
+    pub magic: Option<Magic>,
+
+    /// Challenge script of a custom signet, as hex, the magic of the chain is derived from it
+    /// unless `magic` is specified. Custom signets share the genesis of the default signet, which
+    /// is the default `genesis_hash` when this is set. `network` should be `signet`.
+    ///
+    /// Only the magic is derived, the signature of the blocks satisfying the challenge is not
+    /// validated
+    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_signet_challenge))]
+    pub signet_challenge: Option<ScriptBuf>,
+
+    /// Key obfuscating the block files, as 16 hex characters. Recent Bitcoin Core versions XOR
+    /// the block files with the key stored in `xor.dat` in `blocks_dir`, which is read when this
+    /// is not specified
+    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_xor_key))]
+    pub xor_key: Option<[u8; 8]>,
+
+    /// Hash of the first block of the chain. Defaults to the genesis block hash of `network`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub genesis_hash: Option<BlockHash>,
+
+    /// The block subsidy schedule, used by [`crate::BlockExtra::base_reward()`], allowing to
+    /// iterate chains with a schedule not in [`Network`]. Defaults to the schedule of `network`
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub subsidy_schedule: Option<SubsidySchedule>,
+
     /// Maximum length of a reorg allowed, during reordering send block to the next step only
     /// if it has `max_reorg` following blocks. Higher is more conservative, while lower faster.
     /// When parsing testnet blocks, it may be necessary to increase this a lot
     #[cfg_attr(feature = "clap", arg(short, long, default_value = "6"))]
     pub max_reorg: u8,
 
-    /// Size of the channels used to pass messages between threads
-    #[cfg_attr(feature = "clap", arg(short, long, default_value = "0"))]
+    /// Use a different `max_reorg` depending on the height of the block to emit: every entry
+    /// `(height, max_reorg)` applies from `height` included up to the next entry, `max_reorg`
+    /// applies before the first one. Heights must be increasing and values not zero. Useful for
+    /// testnet, whose early history has deep reorgs, without delaying the emission everywhere,
+    /// for example `[(0, 100), (20_000, 6)]`.
+    /// On the command line, every entry is `<height>:<max_reorg>`
+    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_max_reorg_entry))]
+    pub max_reorg_schedule: Vec<(u32, u8)>,
+
+    /// Size of the channels used to pass messages between threads.
+    ///
+    /// With 0 the channels are rendezvous: every stage waits for the next one to receive each
+    /// block, thus bursts, like the confirmed blocks emitted together by the reorder stage after
+    /// a reorg, and uneven processing times aren't smoothed and throughput suffers. Every slot
+    /// may hold a block, bigger values use more memory. Defaults to 16
+    #[cfg_attr(feature = "clap", arg(short, long, default_value = "16"))]
     pub channels_size: u8,
 
     #[cfg(feature = "db")]
@@ -36,6 +106,12 @@ pub struct Config {
     #[cfg_attr(feature = "clap", cfg_attr(feature = "clap", arg(short, long)))]
     pub utxo_db: Option<PathBuf>,
 
+    #[cfg(feature = "db")]
+    /// Number of blocks whose writes are accumulated before committing them to the rocks database.
+    /// Higher values reduce write amplification during the first run but use more memory
+    #[cfg_attr(feature = "clap", arg(long, default_value = "1"))]
+    pub db_flush_every: u32,
+
     #[cfg(feature = "redb")]
     /// Specify a **file** where a redb database will be created to store the Utxo (when `--skip-prevout` is not used)
     /// Reduce the memory requirements but it's slower and use disk space.
@@ -45,6 +121,33 @@ pub struct Config {
     #[cfg_attr(feature = "clap", arg(long))]
     pub utxo_redb: Option<PathBuf>,
 
+    /// Specify a **file** where a record of the outputs created and spent by every block is
+    /// appended, allowing to reconstruct the Utxo set externally (when `--skip-prevout` is not used).
+    /// Records are [`crate::UtxoDelta`] serialized one after the other, a write error stops the
+    /// iteration with [`crate::Error::UtxoWal`]
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub utxo_wal: Option<PathBuf>,
+
+    /// Assume the utxo db (`utxo_db` or `utxo_redb`) is already populated up to `stop_at_height`
+    /// by a previous run and only read the stored prevouts, without any utxo set logic.
+    /// With `utxo_redb` the prevouts are streamed in height order from a single read transaction
+    /// instead of opening one for every block, `utxo_db` reads them by height like a second
+    /// iteration over a populated db does.
+    /// Fails with [`crate::Error::ReplayNotPopulated`] if the db isn't populated enough, before
+    /// starting when `stop_at_height` is set, otherwise at the first block above the populated
+    /// height
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub prevouts_only_replay: bool,
+
+    /// Continue from a previous run: the blocks already in the utxo db (`utxo_db` or
+    /// `utxo_redb`) aren't processed again, only their header is read to follow the chain like
+    /// with `fast_skip`, and the iteration starts at the next block. With `start_at_height` set
+    /// lower, the blocks from there are emitted again with the prevouts stored in the db. The
+    /// block files are still entirely scanned. Fails with [`crate::Error::ResumeWithoutDb`]
+    /// without a utxo db
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub resume_from_utxo_db: bool,
+
     /// Start the blocks iteration at the specified height, note blocks*.dat file are read and
     /// analyzed anyway to follow the blockchain starting at the genesis and populate utxos,
     /// however they are not emitted
@@ -55,9 +158,403 @@ pub struct Config {
     #[cfg_attr(feature = "clap", arg(long))]
     pub stop_at_height: Option<u32>,
 
-    /// The serialization format to use for the generated `BlockExtra`
+    /// Emit only the blocks in these height ranges, with the end included, while still processing
+    /// every block for the utxos. Ranges must be sorted and not overlapping, only the last one may
+    /// be open ended. The iteration stops at the end of the last range.
+    /// On the command line, every range is `<start>-<end>` or `<start>-`
+    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_height_range))]
+    pub height_ranges: Vec<(u32, Option<u32>)>,
+
+    /// Stop the blocks iteration after emitting this number of blocks, like `take(n)` on the
+    /// iterator but letting the pipeline stop reading. Blocks filtered out by `watch_scripts` are
+    /// counted
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub max_blocks: Option<u64>,
+
+    /// Emit only one block every `height_stride`, starting from `start_at_height`, for quick
+    /// exploratory runs. Utxos are still computed for every block when prevouts are needed, while
+    /// with `skip_prevout` the txids of the blocks not emitted aren't computed
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub height_stride: Option<u32>,
+
+    /// With `skip_prevout`, read only the header of the blocks not emitted because of
+    /// `start_at_height`, `height_ranges` or `height_stride`, instead of the whole block, to get
+    /// the blocks near a given height sooner. The block files are still entirely scanned to find
+    /// the blocks, which must be followed from the genesis, but the blocks before the window are
+    /// not read again. Use `stop_at_height` to exit right after the window.
+    /// Skipped blocks aren't checked by `strict_tx_parse`, and fails without `skip_prevout` since
+    /// the utxo set needs every block
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub fast_skip: bool,
+
+    /// In the fee stage, assemble the prevouts of blocks having at least this number of inputs
+    /// with a rayon thread pool instead of serially, the order of the inputs is preserved
+    #[cfg(feature = "rayon")]
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub fee_parallel_threshold: Option<usize>,
+
+    /// In [`crate::par_tx_for_each`], group consecutive blocks until their inputs plus outputs
+    /// reach this number and process their transactions together, so that small blocks don't
+    /// leave the rayon thread pool idle. With 0, the default, every block is processed alone
+    #[cfg(feature = "rayon")]
+    #[cfg_attr(feature = "clap", arg(long, default_value = "0"))]
+    pub target_batch_work: usize,
+
+    /// The serialization format to use for the generated `BlockExtra`: 0, 1 or 2. Version 2 is a
+    /// compact format keeping only the header and the metadata of the block, not its
+    /// transactions, see [`crate::BlockExtra::has_block_bytes()`]
     #[cfg_attr(feature = "clap", arg(long, default_value = "1"))]
     pub serialization_version: u8,
+
+    /// Verify that every detected block header hashes below the target stated in its `bits`,
+    /// discarding the ones that don't. Protects from false positive detection of blocks in
+    /// `blk*.dat` files at the cost of hashing headers twice
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub check_pow: bool,
+
+    /// Trust the blocks below this height, skipping the optional validations like `check_pow` on
+    /// them, similarly to the `assumevalid` option of Bitcoin Core. Useful to validate recent
+    /// blocks without paying the cost for the whole historical chain.
+    ///
+    /// Since the height of a block is known only once it's connected to the chain, validations
+    /// are moved from block detection to reordering, where an invalid block at or above this
+    /// height isn't emitted, ending the iteration. It only affects validation, the UTXO set is
+    /// built from every block anyway
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub assume_valid_below: Option<u32>,
+
+    /// Stop the iteration with an error instead of skipping data that looks broken, for users who
+    /// prefer a failure to a silently incomplete output. Fatal sites are:
+    /// - bytes following the magic in a block file which don't parse as a block, including a
+    ///   truncated block at the end of the file
+    /// - a block whose length doesn't match its length prefix
+    /// - a block with invalid proof of work, when `check_pow` is used, at or above
+    ///   `assume_valid_below` if specified
+    /// - a block that can't be read again in [`crate::iter_rev`]
+    ///
+    /// Failures reading a block file are governed by `on_file_error` instead. The error is
+    /// [`crate::Error::Strict`], reported by [`crate::BlockExtraIterator::error()`] with
+    /// [`crate::try_iter`] and returned by [`crate::try_iter_rev`], while [`crate::iter`] only logs
+    /// it
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub strict: bool,
+
+    /// Decode every transaction with the strict rules of the `bitcoin` crate, stopping the
+    /// iteration with [`crate::Error::TxParseFailed`] at the first one failing, for example one
+    /// larger than the 4MB the `bitcoin` crate accepts. Blocks are detected with a more lenient
+    /// parser, such transactions would otherwise fail only when the block is decoded. The error is
+    /// reported like the ones of `strict`. It costs a full decoding of every block
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub strict_tx_parse: bool,
+
+    /// Record in this file the candidate blocks skipped in the block files, one
+    /// `file,offset,reason` line each, useful to diagnose damaged storage. The offset is the one
+    /// of the magic in the file, the reason includes the declared and the actual size of the
+    /// block when they don't match. Blocks with invalid proof of work are recorded too. Ignored
+    /// with `strict`, which stops at the first of them
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub anomaly_log: Option<PathBuf>,
+
+    /// Log at the end of the iteration a sha256 of the hashes of the emitted blocks, two runs
+    /// emitting the same blocks in the same order log the same digest, see also [`crate::digest`]
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub stream_digest: bool,
+
+    /// Include in the periodic stats log line the output value moved per second, in satoshi,
+    /// besides blocks and transactions per second. Costs a visit of every emitted block
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub log_value_throughput: bool,
+
+    /// Check that every block returned by the iterator follows the previous one: its height is
+    /// the next one and its `prev_blockhash` is the hash of the previous block. Heights may have
+    /// gaps when `height_stride`, `height_ranges`, `predicate` or `chainwork_range` are used, and
+    /// only the `prev_blockhash` of consecutive blocks is checked. The iteration stops with
+    /// [`crate::Error::NonContiguousOutput`] or [`crate::Error::DisconnectedOutput`] otherwise
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub self_check: bool,
+
+    /// Verify at every halving that the sum of [`crate::BlockExtra::base_reward()`] of the
+    /// blocks returned matches the issuance of the subsidy schedule, catching blocks skipped or
+    /// returned twice. The iteration stops with [`crate::Error::SupplyMismatch`] otherwise. The
+    /// sum starts from the issuance before the first block returned. Ignored when blocks are
+    /// filtered by `height_stride`, `height_ranges`, `predicate` or `chainwork_range`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub verify_supply: bool,
+
+    /// Index the first and last height at which every output script appears, taken with
+    /// [`crate::BlockExtraIterator::take_script_ages()`]. The index is kept in memory, see
+    /// [`crate::ScriptAges`] for its cost. Blocks skipped by `watch_scripts` are indexed too
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub track_script_ages: bool,
+
+    /// Track the unspent coinbase outputs to report the ones spent by every block and whether
+    /// they reached maturity, see [`crate::BlockExtra::coinbase_spends()`]. The coinbase outputs
+    /// are kept in memory. Ignored with `skip_prevout`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub track_coinbase_maturity: bool,
+
+    /// Like `track_coinbase_maturity`, but stop the iteration with
+    /// [`crate::Error::ImmatureCoinbaseSpend`] if a coinbase output is spent before maturity, which
+    /// doesn't happen in a valid chain but catches corrupted data. The coinbase outputs must be
+    /// tracked from the genesis, it fails with [`crate::Error::CoinbaseMaturityNotFromGenesis`]
+    /// with `since_file_state` or `resume_from_utxo_db`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub check_coinbase_maturity: bool,
+
+    /// Compute for every block the change of the number of unspent outputs, see
+    /// [`crate::BlockExtra::utxo_delta()`]. Ignored with `skip_prevout`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub track_utxo_delta: bool,
+
+    /// Keep the hash of the UTXO set, the `muhash` reported by `gettxoutsetinfo` of Bitcoin Core,
+    /// to cross-check the computed UTXO set, see [`crate::BlockExtra::utxo_set_hash()`]. The
+    /// UTXO set must be built from the genesis in this run, thus it fails with
+    /// [`crate::Error::UtxoHashNotFromGenesis`] with a populated utxo db or `since_file_state`.
+    /// The height and the coinbase flag of every unspent output are kept in memory. Ignored with
+    /// `skip_prevout`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub compute_utxo_hash: bool,
+
+    /// Keep the height and the coinbase flag of the unspent outputs to compute the undo data of
+    /// every block, see [`crate::BlockExtra::undo_data()`]. All the unspent outputs are kept in
+    /// memory. Implied by `undo_file` and [`OutputFormat::Undo`]. Ignored with `skip_prevout`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub track_undo_data: bool,
+
+    /// Insert in [`crate::BlockExtra::outpoint_values()`] the sentinel entry of the coinbase
+    /// input. When disabled the map contains only real previous outputs, the value of the
+    /// coinbase outputs is still available with [`crate::BlockExtra::coinbase_output_value()`].
+    /// Serialized blocks contain the sentinel anyway, to compute the fee when decoded.
+    /// On the command line, it's disabled with `--no-coinbase-sentinel`
+    #[cfg_attr(
+        feature = "clap",
+        arg(long = "no-coinbase-sentinel", action = clap::ArgAction::SetFalse)
+    )]
+    pub insert_coinbase_sentinel: bool,
+
+    /// Compute the cumulative work of the chain, see [`crate::BlockExtra::chain_work()`]
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub track_chainwork: bool,
+
+    /// Compute the txids of the transactions, disable it when they aren't needed to save a pass on
+    /// every block and the allocation of the txids. When disabled [`crate::BlockExtra::txids()`] is
+    /// empty and [`crate::BlockExtra::iter_tx()`] iterates nothing, the other methods needing the
+    /// txids, like [`crate::BlockExtra::watch_hits()`], compute them at every call.
+    /// Computing the previous outputs still needs the txids, the utxo stores compute them
+    /// internally only when they must add the outputs of a block, thus a replay with
+    /// `prevouts_only_replay` avoids computing them.
+    /// On the command line, it's disabled with `--skip-txids`
+    #[cfg_attr(
+        feature = "clap",
+        arg(long = "skip-txids", action = clap::ArgAction::SetFalse)
+    )]
+    pub compute_txids: bool,
+
+    /// Compute the byte offsets of the transactions together with the txids, see
+    /// [`crate::BlockExtra::tx_offsets()`]. Otherwise they are computed at first access
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub compute_tx_offsets: bool,
+
+    /// Used by the CLI to write the serialized blocks to a memory-mapped file instead of stdout,
+    /// given as `<path>:<estimated size in bytes>`. The file grows if the estimate is exceeded and
+    /// it's truncated to the written bytes at the end
+    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_output_mmap))]
+    pub output_mmap: Option<(PathBuf, usize)>,
+
+    /// Used by the CLI to write the blocks to files in this **directory** instead of stdout, every
+    /// one with `shard_blocks` blocks and named `shard-NNNNNN.dat`, numbered from 0. Every file
+    /// is written like the whole output, compressed on its own if requested, thus it can be
+    /// decoded independently of the others
+    #[cfg_attr(feature = "clap", arg(long, conflicts_with = "output_mmap"))]
+    pub output_dir: Option<PathBuf>,
+
+    /// Used by the CLI with `output_dir`, the number of blocks of every file, the last one may
+    /// have less
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "1000", value_parser = clap::value_parser!(u32).range(1..))
+    )]
+    pub shard_blocks: u32,
+
+    /// Used by the CLI to choose how the serialized blocks are written
+    #[cfg_attr(
+        feature = "clap",
+        arg(long = "format", value_enum, default_value = "binary")
+    )]
+    pub output_format: OutputFormat,
+
+    /// Used by the CLI to compress the written blocks, compressed input is detected by
+    /// [`crate::PipeIterator`]
+    #[cfg(feature = "compression")]
+    #[cfg_attr(feature = "clap", arg(long, value_enum))]
+    pub compress: Option<crate::Compression>,
+
+    /// Used by the CLI to write the bare blocks, as serialized by Bitcoin Core, instead of the
+    /// [`crate::BlockExtra`], and the previous outputs of every block to this **file** as
+    /// [`crate::PrevoutsRecord`], see [`crate::zip_prevouts`] to read them back together
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub prevouts_sidecar: Option<PathBuf>,
+
+    /// Used by the CLI to write the undo data of the blocks to this **file** next to the output,
+    /// framed like the `rev*.dat` files of Bitcoin Core, see [`crate::write_undo()`]. Together
+    /// with [`OutputFormat::Bootstrap`] it gives the blocks and the undo data needed to roll them
+    /// back, the file is not split like the ones of Bitcoin Core
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub undo_file: Option<PathBuf>,
+
+    /// Used by the CLI to validate the blocks without writing them: the pipeline runs as usual
+    /// and a report with the number of blocks, the height range, the gaps, the forks met and the
+    /// final chain tip is printed instead
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub check: bool,
+
+    /// Specify a **file** where the state of the block files is persisted between runs, allowing
+    /// periodic runs to scan only the files added or grown since the previous one and to emit
+    /// only the new blocks. Requires `--skip-prevout` or a utxo db and it's ignored when
+    /// `blocks_source` is used.
+    ///
+    /// Together with the position of the iteration, the file contains the set of blocks already
+    /// detected, 12 bytes for each one, so that re-scanned files don't emit them again
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub since_file_state: Option<PathBuf>,
+
+    /// Specify a **file** where the height and hash of the last `max_reorg + 1` blocks returned by
+    /// the iterator are persisted, updated at every block. An indexer restarting can detect a
+    /// reorg happened while it was down comparing its tip with them, see
+    /// [`crate::load_chain_tip`]
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub chain_tip_file: Option<PathBuf>,
+
+    /// Number of times opening and reading a block file is retried, waiting twice as long every
+    /// time, before applying `on_file_error`. Useful when iterating the blocks directory of a
+    /// running node
+    #[cfg_attr(feature = "clap", arg(long, default_value = "3"))]
+    pub file_retries: u8,
+
+    /// What to do when a block file can't be read after `file_retries` attempts
+    #[cfg_attr(feature = "clap", arg(long, value_enum, default_value = "abort"))]
+    pub on_file_error: FileErrorPolicy,
+
+    /// Number of block files read and scanned for blocks concurrently, the blocks found are still
+    /// sent in files order. Useful on many cores machines with fast storage, every thread keeps a
+    /// whole block file in memory
+    #[cfg_attr(feature = "clap", arg(long, default_value = "1"))]
+    pub detect_threads: usize,
+
+    /// Number of blocks, ready to be emitted, read concurrently from the block files before being
+    /// emitted in height order. Reads of blocks in the same file are serialized, thus it helps
+    /// when consecutive blocks are spread across files on storage with high latency
+    #[cfg_attr(feature = "clap", arg(long, default_value = "1"))]
+    pub read_threads: usize,
+
+    /// Keep following the block files after reaching the end, like `tail -f`, emitting the blocks
+    /// appended by a running node: the files are listed again every second, scanning the new ones
+    /// and the ones grown. The iteration ends only when the iterator is dropped. Blocks are
+    /// emitted when they have `max_reorg` following blocks, as usual. With `strict`, reading a block
+    /// while the node is writing it stops the iteration. Ignored when `blocks_source` is used
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub follow: bool,
+
+    /// Read the `blk*.dat` entries of this uncompressed tar archive instead of the files in
+    /// `blocks_dir`, without extracting them, see [`crate::TarBlockSource`]. The `xor.dat` entry,
+    /// if any, is used as the obfuscation key unless `xor_key` is specified.
+    /// Ignored when blocks are read from `block_files_source` or `blocks_source`
+    #[cfg(feature = "tar")]
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub blocks_tar: Option<PathBuf>,
+
+    /// Serialized blocks to iterate instead of the `blk*.dat` files in `blocks_dir`, in any order.
+    /// Useful to test against synthetic chains, see [`Config::from_blocks`]
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub blocks_source: Option<Vec<Vec<u8>>>,
+
+    /// Emit only the blocks containing transactions paying to, or spending from, any of these
+    /// scripts, see [`crate::BlockExtra::watch_hits()`] to get the matching transactions.
+    /// Spending transactions are matched only when prevouts are computed (no `skip_prevout`)
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub watch_scripts: Option<HashSet<ScriptBuf>>,
+
+    /// Ask the prevouts to a user callback, for example backed by an existing index, instead of
+    /// building the UTXO set. Outputs spent in the same block they are created are resolved
+    /// without calling it
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub external_prevout: Option<crate::ExternalPrevout>,
+
+    /// Read the block files from a custom source instead of the `blk*.dat` files in `blocks_dir`,
+    /// for example from an object storage, see [`BlockSource`]
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub block_files_source: Option<Arc<dyn BlockSource>>,
+
+    /// Choose the block to follow when a block has more than one child, for example to study
+    /// alternative fork choice rules. By default the first branch reaching `max_reorg` following
+    /// blocks is followed
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub fork_choice: Option<crate::ForkChoice>,
+
+    /// Send here the blocks of stale branches, the ones read from the block files but not part of
+    /// the iterated chain, in no particular order. Their `height` isn't known and it's left to 0.
+    /// The iteration blocks when the channel is full, so it must be drained concurrently or be
+    /// large enough. Stale blocks forking at the last `max_reorg` blocks aren't recognized
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub emit_orphans: Option<SyncSender<crate::BlockExtra>>,
+
+    /// Modify every emitted block before it's returned by the iteration, for example to attach
+    /// derived data computed in the pipeline with [`crate::BlockExtra::extra_mut()`], see
+    /// [`crate::Enrich`]
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub enrich: Option<crate::Enrich>,
+
+    /// Run a [`bitcoin_slices::Visitor`] over the bytes of every emitted block inside the
+    /// pipeline, without a further channel hop, see [`crate::BlockVisitor`] for the threading and
+    /// how to collect the results
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub block_visitor: Option<crate::BlockVisitor>,
+
+    /// Emit only the blocks matching this predicate, in addition to the height options. Blocks not
+    /// matching still update the UTXO set. [`Config::max_blocks`] counts the blocks before the
+    /// predicate is applied
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub predicate: Option<crate::BlockPredicate>,
+
+    /// Emit only the blocks whose cumulative chain work, see
+    /// [`crate::BlockExtra::chain_work()`], is between `start` and `end`, included. Requires
+    /// `track_chainwork`, the iteration fails with [`crate::Error::ChainworkRangeWithoutTracking`]
+    /// otherwise. Blocks not matching still update the UTXO set. Nothing is emitted when resuming
+    /// with `since_file_state`, since the work of the previous runs is not known
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub chainwork_range: Option<(Work, Work)>,
+}
+
+/// What to do when a block file can't be read, see [`Config::on_file_error`]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileErrorPolicy {
+    /// Stop the iteration with [`crate::Error::Io`]
+    Abort,
+
+    /// Log the error and continue with the next file. Blocks in the skipped file are missing, thus
+    /// the iteration ends at the first of them
+    Skip,
+}
+
+/// How the CLI writes the blocks, see [`Config::output_format`]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The consensus encoding of every [`crate::BlockExtra`], one after the other
+    Binary,
+
+    /// The consensus encoding of every [`crate::BlockExtra`] as hex, one per line, for shell
+    /// pipelines and tools not handling binary input
+    Hex,
+
+    /// The raw blocks in height order, each one preceded by the network magic and its size as a
+    /// little endian u32, like a `bootstrap.dat` that `bitcoind -loadblock` can import
+    Bootstrap,
+
+    /// The undo data of the blocks instead of the blocks, like the `rev*.dat` files of Bitcoin
+    /// Core, see [`crate::write_undo()`]
+    Undo,
 }
 
 impl Config {
@@ -65,50 +562,657 @@ impl Config {
     pub fn new<P: AsRef<Path>>(path: P, network: Network) -> Self {
         Self {
             blocks_dir: path.as_ref().to_owned(),
+            extra_blocks_dirs: vec![],
+            blk_file_range: None,
             network,
+            magic: None,
+            signet_challenge: None,
+            xor_key: None,
+            genesis_hash: None,
+            subsidy_schedule: None,
             skip_prevout: false,
             max_reorg: 6,
-            channels_size: 0,
+            max_reorg_schedule: vec![],
+            channels_size: 16,
             #[cfg(feature = "db")]
             utxo_db: None,
+            #[cfg(feature = "db")]
+            db_flush_every: 1,
             #[cfg(feature = "redb")]
             utxo_redb: None,
+            utxo_wal: None,
+            prevouts_only_replay: false,
+            resume_from_utxo_db: false,
             start_at_height: 0,
             stop_at_height: None,
+            max_blocks: None,
+            height_stride: None,
+            fast_skip: false,
+            height_ranges: vec![],
+            #[cfg(feature = "rayon")]
+            fee_parallel_threshold: None,
+            #[cfg(feature = "rayon")]
+            target_batch_work: 0,
             serialization_version: 1,
+            check_pow: false,
+            assume_valid_below: None,
+            strict: false,
+            strict_tx_parse: false,
+            anomaly_log: None,
+            stream_digest: false,
+            self_check: false,
+            verify_supply: false,
+            log_value_throughput: false,
+            track_script_ages: false,
+            track_coinbase_maturity: false,
+            check_coinbase_maturity: false,
+            track_utxo_delta: false,
+            compute_utxo_hash: false,
+            track_undo_data: false,
+            track_chainwork: false,
+            compute_txids: true,
+            insert_coinbase_sentinel: true,
+            compute_tx_offsets: false,
+            output_mmap: None,
+            output_dir: None,
+            shard_blocks: 1000,
+            output_format: OutputFormat::Binary,
+            #[cfg(feature = "compression")]
+            compress: None,
+            prevouts_sidecar: None,
+            undo_file: None,
+            check: false,
+            since_file_state: None,
+            chain_tip_file: None,
+            file_retries: 3,
+            on_file_error: FileErrorPolicy::Abort,
+            detect_threads: 1,
+            read_threads: 1,
+            follow: false,
+            #[cfg(feature = "tar")]
+            blocks_tar: None,
+            blocks_source: None,
+            watch_scripts: None,
+            external_prevout: None,
+            block_files_source: None,
+            fork_choice: None,
+            emit_orphans: None,
+            enrich: None,
+            block_visitor: None,
+            predicate: None,
+            chainwork_range: None,
         }
     }
 
+    /// Creates a config iterating the given `blocks` instead of reading the blocks directory.
+    ///
+    /// Blocks goes through the same pipeline, reorgs included, so they must connect to the
+    /// genesis block of `network`
+    pub fn from_blocks(blocks: Vec<Block>, network: Network) -> Self {
+        let mut config = Self::new("", network);
+        config.blocks_source = Some(blocks.iter().map(serialize).collect());
+        config
+    }
+
+    /// Creates a config from the environment variables, for services configured this way:
+    /// - `BITCOIN_NETWORK`: `bitcoin`, `testnet`, `testnet4`, `signet` or `regtest`, defaults to
+    ///   `bitcoin`
+    /// - `BITCOIN_DATADIR`: the bitcoind data directory, the blocks directory of the network is
+    ///   used, like `<datadir>/testnet3/blocks` for testnet
+    /// - `BLOCKS_ITERATOR_BLOCKS_DIR`: the blocks directory, taking precedence over
+    ///   `BITCOIN_DATADIR`, one of them is required
+    /// - `BLOCKS_ITERATOR_SKIP_PREVOUT`, `BLOCKS_ITERATOR_CHECK_POW`, `BLOCKS_ITERATOR_STRICT`:
+    ///   `true` or `false`
+    /// - `BLOCKS_ITERATOR_MAX_REORG`, `BLOCKS_ITERATOR_START_AT_HEIGHT`,
+    ///   `BLOCKS_ITERATOR_STOP_AT_HEIGHT`, `BLOCKS_ITERATOR_CHANNELS_SIZE`: numbers
+    ///
+    /// Other fields have the defaults of [`Config::new`]. Fields set on the returned config take
+    /// precedence over the environment, being applied after.
+    pub fn from_env() -> Result<Self, crate::Error> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&'static str) -> Option<String>) -> Result<Self, crate::Error> {
+        fn parse<T: std::str::FromStr>(
+            var: &impl Fn(&'static str) -> Option<String>,
+            name: &'static str,
+        ) -> Result<Option<T>, crate::Error>
+        where
+            T::Err: std::fmt::Display,
+        {
+            var(name)
+                .map(|value| {
+                    value.parse().map_err(|e: T::Err| crate::Error::InvalidEnv {
+                        name,
+                        message: format!("{:?} {}", value, e),
+                    })
+                })
+                .transpose()
+        }
+
+        let network = parse(&var, "BITCOIN_NETWORK")?.unwrap_or(Network::Bitcoin);
+        let blocks_dir = match (var("BLOCKS_ITERATOR_BLOCKS_DIR"), var("BITCOIN_DATADIR")) {
+            (Some(blocks_dir), _) => PathBuf::from(blocks_dir),
+            (None, Some(datadir)) => {
+                let datadir = PathBuf::from(datadir);
+                let network_dir = match network {
+                    Network::Bitcoin => datadir,
+                    Network::Testnet => datadir.join("testnet3"),
+                    network => datadir.join(network.to_string()),
+                };
+                network_dir.join("blocks")
+            }
+            (None, None) => {
+                return Err(crate::Error::InvalidEnv {
+                    name: "BITCOIN_DATADIR",
+                    message: "missing, also BLOCKS_ITERATOR_BLOCKS_DIR is missing".to_string(),
+                })
+            }
+        };
+
+        let mut config = Self::new(blocks_dir, network);
+        if let Some(skip_prevout) = parse(&var, "BLOCKS_ITERATOR_SKIP_PREVOUT")? {
+            config.skip_prevout = skip_prevout;
+        }
+        if let Some(check_pow) = parse(&var, "BLOCKS_ITERATOR_CHECK_POW")? {
+            config.check_pow = check_pow;
+        }
+        if let Some(strict) = parse(&var, "BLOCKS_ITERATOR_STRICT")? {
+            config.strict = strict;
+        }
+        if let Some(max_reorg) = parse(&var, "BLOCKS_ITERATOR_MAX_REORG")? {
+            config.max_reorg = max_reorg;
+        }
+        if let Some(start_at_height) = parse(&var, "BLOCKS_ITERATOR_START_AT_HEIGHT")? {
+            config.start_at_height = start_at_height;
+        }
+        config.stop_at_height = parse(&var, "BLOCKS_ITERATOR_STOP_AT_HEIGHT")?;
+        if let Some(channels_size) = parse(&var, "BLOCKS_ITERATOR_CHANNELS_SIZE")? {
+            config.channels_size = channels_size;
+        }
+        Ok(config)
+    }
+
+    /// Returns the `magic` in use, either the specified one, the one derived from
+    /// `signet_challenge` or the one of `network`
+    pub fn magic(&self) -> Magic {
+        match (self.magic, self.signet_challenge.as_ref()) {
+            (Some(magic), _) => magic,
+            (None, Some(challenge)) => signet_magic(challenge),
+            (None, None) => self.network.magic(),
+        }
+    }
+
+    /// Returns the key obfuscating the block files, either the specified one or the one in the
+    /// `xor.dat` file of `blocks_dir`, which must be the same of `extra_blocks_dirs`. `None` if
+    /// blocks aren't read from `blocks_dir` or the key is all zeros, meaning no obfuscation
+    pub(crate) fn resolve_xor_key(&self) -> Result<Option<[u8; 8]>, crate::Error> {
+        if let Some(key) = self.xor_key {
+            return Ok(Some(key));
+        }
+        if self.blocks_source.is_some() || self.block_files_source.is_some() {
+            return Ok(None);
+        }
+        #[cfg(feature = "tar")]
+        if let Some(path) = self.blocks_tar.as_ref() {
+            let what = format!("{:?} xor.dat", path);
+            return match crate::TarBlockSource::new(path).read_xor_file() {
+                Ok(Some(bytes)) => xor_key_from_bytes(&bytes, &what),
+                Ok(None) => Ok(None),
+                Err(e) => Err(crate::Error::InvalidXorKey(format!("{}: {}", what, e))),
+            };
+        }
+        let key = read_xor_key(&self.blocks_dir)?;
+        for dir in self.extra_blocks_dirs.iter() {
+            if read_xor_key(dir)? != key {
+                return Err(crate::Error::InvalidXorKey(format!(
+                    "{:?} differs from the one of {:?}",
+                    dir.join("xor.dat"),
+                    self.blocks_dir
+                )));
+            }
+        }
+        Ok(key)
+    }
+
+    /// Returns an error if `blocks_dir` or one of `extra_blocks_dirs` is not an existing directory,
+    /// when blocks are read from them
+    pub(crate) fn check_blocks_dirs(&self) -> Result<(), crate::Error> {
+        if self.blocks_source.is_some() || self.block_files_source.is_some() {
+            return Ok(());
+        }
+        #[cfg(feature = "tar")]
+        if let Some(path) = self.blocks_tar.as_ref() {
+            return match path.is_file() {
+                true => Ok(()),
+                false => Err(crate::Error::BlocksTarNotFound(path.clone())),
+            };
+        }
+        let dirs = std::iter::once(&self.blocks_dir).chain(self.extra_blocks_dirs.iter());
+        match dirs.into_iter().find(|dir| !dir.is_dir()) {
+            Some(dir) => Err(crate::Error::BlocksDirNotFound(dir.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the source of the block files when `block_files_source` isn't given: the
+    /// `blocks_tar` archive if given, otherwise `blocks_dir` and `extra_blocks_dirs`
+    pub(crate) fn default_block_files_source(&self) -> Arc<dyn BlockSource> {
+        #[cfg(feature = "tar")]
+        if let Some(path) = self.blocks_tar.as_ref() {
+            return Arc::new(crate::TarBlockSource::new(path).with_file_range(self.blk_file_range));
+        }
+        Arc::new(
+            crate::FsBlockSource::new(&self.blocks_dir)
+                .with_extra_dirs(self.extra_blocks_dirs.clone())
+                .with_file_range(self.blk_file_range),
+        )
+    }
+
+    /// Returns the height at which the iteration stops, the lowest of `stop_at_height` and the end
+    /// of the last of `height_ranges`
+    pub(crate) fn stop_at_height(&self) -> Option<u32> {
+        let ranges_end = self.height_ranges.last().and_then(|(_, end)| *end);
+        match (self.stop_at_height, ranges_end) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the block subsidy schedule, either `subsidy_schedule` or the one of `network`
+    pub fn subsidy_schedule(&self) -> SubsidySchedule {
+        self.subsidy_schedule
+            .unwrap_or_else(|| SubsidySchedule::for_network(self.network))
+    }
+
+    /// Returns the hash of the first block, either `genesis_hash`, the signet genesis if
+    /// `signet_challenge` is set or the genesis of `network`
+    pub fn genesis_hash(&self) -> BlockHash {
+        self.genesis_hash.unwrap_or_else(|| {
+            let network = match self.signet_challenge {
+                Some(_) => Network::Signet,
+                None => self.network,
+            };
+            genesis_block(network).block_hash()
+        })
+    }
+
     #[cfg(all(not(feature = "db"), not(feature = "redb")))]
-    pub(crate) fn utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
+    fn base_utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
         use crate::utxo::{self, AnyUtxo};
         Ok(AnyUtxo::Mem(utxo::MemUtxo::new(self.network)))
     }
 
     #[cfg(all(not(feature = "db"), feature = "redb"))]
-    pub(crate) fn utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
+    fn base_utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
         use crate::utxo::{self, AnyUtxo};
         Ok(match &self.utxo_redb {
-            Some(path) => AnyUtxo::Redb(utxo::RedbUtxo::new(path)?),
+            Some(path) => {
+                check_utxo_db_path(path, false)?;
+                AnyUtxo::Redb(utxo::RedbUtxo::new(path, self.prevouts_only_replay)?)
+            }
             None => AnyUtxo::Mem(utxo::MemUtxo::new(self.network)),
         })
     }
     #[cfg(all(feature = "db", not(feature = "redb")))]
-    pub(crate) fn utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
+    fn base_utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
         use crate::utxo::{self, AnyUtxo};
         Ok(match &self.utxo_db {
-            Some(path) => AnyUtxo::Db(utxo::DbUtxo::new(path)?),
+            Some(path) => {
+                check_utxo_db_path(path, true)?;
+                AnyUtxo::Db(utxo::DbUtxo::new(
+                    path,
+                    self.db_flush_every,
+                    self.prevouts_only_replay,
+                )?)
+            }
             None => AnyUtxo::Mem(utxo::MemUtxo::new(self.network)),
         })
     }
     #[cfg(all(feature = "db", feature = "redb"))]
-    pub(crate) fn utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
+    fn base_utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
         use crate::utxo::{self, AnyUtxo};
         Ok(match (&self.utxo_db, &self.utxo_redb) {
             (Some(_), Some(_)) => return Err(crate::Error::OneDb),
-            (Some(path), None) => AnyUtxo::Db(utxo::DbUtxo::new(path)?),
-            (None, Some(path)) => AnyUtxo::Redb(utxo::RedbUtxo::new(path)?),
+            (Some(path), None) => {
+                check_utxo_db_path(path, true)?;
+                AnyUtxo::Db(utxo::DbUtxo::new(
+                    path,
+                    self.db_flush_every,
+                    self.prevouts_only_replay,
+                )?)
+            }
+            (None, Some(path)) => {
+                check_utxo_db_path(path, false)?;
+                AnyUtxo::Redb(utxo::RedbUtxo::new(path, self.prevouts_only_replay)?)
+            }
             (None, None) => AnyUtxo::Mem(utxo::MemUtxo::new(self.network)),
         })
     }
+
+    pub(crate) fn utxo_manager(&self) -> Result<crate::utxo::AnyUtxo, crate::Error> {
+        use crate::utxo::{AnyUtxo, ExternalUtxo, MuHashUtxo, UtxoStore, WalUtxo};
+        let utxo = match &self.external_prevout {
+            Some(prevout) => AnyUtxo::External(ExternalUtxo::new(prevout.clone())),
+            None => self.base_utxo_manager()?,
+        };
+        if self.prevouts_only_replay {
+            let populated = utxo
+                .updated_up_to_height()
+                .ok_or(crate::Error::ReplayWithoutDb)?;
+            if let Some(required) = self.stop_at_height {
+                if populated < required as i32 {
+                    return Err(crate::Error::ReplayNotPopulated {
+                        populated,
+                        required,
+                    });
+                }
+            }
+        }
+        let utxo = if self.compute_utxo_hash {
+            let populated = utxo.updated_up_to_height().map_or(false, |h| h >= 0);
+            if populated || self.since_file_state.is_some() {
+                return Err(crate::Error::UtxoHashNotFromGenesis);
+            }
+            AnyUtxo::MuHash(Box::new(MuHashUtxo::new(utxo)))
+        } else {
+            utxo
+        };
+        if self.check_coinbase_maturity
+            && (self.since_file_state.is_some() || self.resume_from_utxo_db)
+        {
+            return Err(crate::Error::CoinbaseMaturityNotFromGenesis);
+        }
+        if self.since_file_state.is_some()
+            && self.external_prevout.is_none()
+            && utxo.updated_up_to_height().is_none()
+        {
+            return Err(crate::Error::SinceFileStateWithoutDb);
+        }
+        Ok(match &self.utxo_wal {
+            Some(path) => AnyUtxo::Wal(Box::new(WalUtxo::new(utxo, path)?)),
+            None => utxo,
+        })
+    }
+}
+
+/// Returns an error if a utxo db can't be opened or created at `path`: a directory for rocksdb
+/// (`is_dir`), a file for redb. An existing path must be of the right kind, otherwise its parent
+/// directory must exist, and the directory containing the db must be writable
+#[cfg(any(feature = "db", feature = "redb"))]
+fn check_utxo_db_path(path: &Path, is_dir: bool) -> Result<(), crate::Error> {
+    use std::io::{Error, ErrorKind};
+    let invalid = |source: Error| crate::Error::UtxoDbPath {
+        path: path.to_path_buf(),
+        source,
+    };
+    let kind = if is_dir { "directory" } else { "file" };
+    let writable_dir = match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() != is_dir => {
+            let message = format!("it exists but it's not a {}", kind);
+            return Err(invalid(Error::new(ErrorKind::InvalidInput, message)));
+        }
+        Ok(_) if is_dir => path.to_path_buf(),
+        Ok(_) | Err(_) => match path.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+    };
+    let meta = std::fs::metadata(&writable_dir).map_err(|e| {
+        invalid(Error::new(
+            e.kind(),
+            format!("directory {:?}: {}", writable_dir, e),
+        ))
+    })?;
+    if !meta.is_dir() {
+        let message = format!("{:?} is not a directory", writable_dir);
+        return Err(invalid(Error::new(ErrorKind::InvalidInput, message)));
+    }
+    if meta.permissions().readonly() {
+        let message = format!("directory {:?} is not writable", writable_dir);
+        return Err(invalid(Error::new(ErrorKind::PermissionDenied, message)));
+    }
+    Ok(())
+}
+
+/// Returns an error if `ranges` aren't sorted and non overlapping, or if a range ends before
+/// starting
+pub(crate) fn check_height_ranges(ranges: &[(u32, Option<u32>)]) -> Result<(), crate::Error> {
+    let mut next_start = 0u32;
+    for (i, (start, end)) in ranges.iter().enumerate() {
+        let invalid = |message: &str| {
+            Err(crate::Error::InvalidHeightRanges(format!(
+                "{:?} {}",
+                ranges[i], message
+            )))
+        };
+        if *start < next_start {
+            return invalid("overlaps or precedes the previous range");
+        }
+        match end {
+            Some(end) if end < start => return invalid("ends before starting"),
+            Some(end) => next_start = end.saturating_add(1),
+            None if i + 1 < ranges.len() => return invalid("is open ended but not the last"),
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if the heights of `schedule` aren't strictly increasing or a value is zero
+pub(crate) fn check_max_reorg_schedule(schedule: &[(u32, u8)]) -> Result<(), crate::Error> {
+    for (i, entry) in schedule.iter().enumerate() {
+        let message = match (entry, i.checked_sub(1).map(|p| schedule[p])) {
+            ((_, 0), _) => "has a zero max reorg",
+            ((height, _), Some((previous, _))) if *height <= previous => {
+                "doesn't follow the previous entry"
+            }
+            _ => continue,
+        };
+        return Err(crate::Error::InvalidMaxReorgSchedule(format!(
+            "{:?} {}",
+            entry, message
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the key in the `xor.dat` file of `blocks_dir`, `None` if missing or all zeros
+pub(crate) fn read_xor_key(blocks_dir: &Path) -> Result<Option<[u8; 8]>, crate::Error> {
+    let path = blocks_dir.join("xor.dat");
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(crate::Error::InvalidXorKey(format!("{:?}: {}", path, e))),
+    };
+    xor_key_from_bytes(&bytes, &format!("{:?}", path))
+}
+
+/// Returns the key contained in the `bytes` of `what`, `None` if all zeros
+fn xor_key_from_bytes(bytes: &[u8], what: &str) -> Result<Option<[u8; 8]>, crate::Error> {
+    let key: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| crate::Error::InvalidXorKey(format!("{} has {} bytes", what, bytes.len())))?;
+    Ok(if key == [0u8; 8] { None } else { Some(key) })
+}
+
+/// Returns an error if `magic` can't be used to find blocks: all zero bytes would match the zero
+/// padding of preallocated block files
+pub(crate) fn check_magic(magic: Magic) -> Result<Magic, crate::Error> {
+    if magic.to_bytes() == [0u8; 4] {
+        Err(crate::Error::InvalidMagic(magic.to_string()))
+    } else {
+        Ok(magic)
+    }
+}
+
+/// Returns the magic of the signet with `challenge`, the first 4 bytes of the double sha256 of the
+/// serialized challenge
+pub(crate) fn signet_magic(challenge: &Script) -> Magic {
+    let hash = sha256d::Hash::hash(&serialize(challenge));
+    Magic::from_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+#[cfg(feature = "clap")]
+fn parse_magic(s: &str) -> Result<Magic, crate::Error> {
+    let magic = s
+        .parse()
+        .map_err(|_| crate::Error::InvalidMagic(s.to_string()))?;
+    check_magic(magic)
+}
+
+#[cfg(feature = "clap")]
+fn parse_xor_key(s: &str) -> Result<[u8; 8], crate::Error> {
+    use bitcoin::hashes::hex::FromHex;
+    <[u8; 8]>::from_hex(s).map_err(|_| crate::Error::InvalidXorKey(s.to_string()))
+}
+
+#[cfg(feature = "clap")]
+fn parse_signet_challenge(s: &str) -> Result<ScriptBuf, crate::Error> {
+    ScriptBuf::from_hex(s).map_err(|_| crate::Error::InvalidSignetChallenge(s.to_string()))
+}
+
+#[cfg(feature = "clap")]
+fn parse_height_range(s: &str) -> Result<(u32, Option<u32>), crate::Error> {
+    let invalid = || crate::Error::InvalidHeightRanges(format!("{:?} is not <start>-[<end>]", s));
+    let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+    let start = start.parse().map_err(|_| invalid())?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse().map_err(|_| invalid())?),
+    };
+    Ok((start, end))
+}
+
+#[cfg(feature = "clap")]
+fn parse_max_reorg_entry(s: &str) -> Result<(u32, u8), crate::Error> {
+    let invalid =
+        || crate::Error::InvalidMaxReorgSchedule(format!("{:?} is not <height>:<max_reorg>", s));
+    let (height, max_reorg) = s.split_once(':').ok_or_else(invalid)?;
+    let height = height.parse().map_err(|_| invalid())?;
+    let max_reorg = max_reorg.parse().map_err(|_| invalid())?;
+    Ok((height, max_reorg))
+}
+
+#[cfg(feature = "clap")]
+fn parse_blk_file_range(s: &str) -> Result<(u32, u32), crate::Error> {
+    let invalid = || crate::Error::InvalidBlkFileRange(s.to_string());
+    let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+    let first = first.parse().map_err(|_| invalid())?;
+    let last = last.parse().map_err(|_| invalid())?;
+    if last < first {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+#[cfg(feature = "clap")]
+fn parse_output_mmap(s: &str) -> Result<(PathBuf, usize), crate::Error> {
+    let (path, size) = s
+        .rsplit_once(':')
+        .ok_or_else(|| crate::Error::InvalidOutputMmap(s.to_string()))?;
+    let size = size
+        .parse()
+        .map_err(|_| crate::Error::InvalidOutputMmap(s.to_string()))?;
+    Ok((PathBuf::from(path), size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{signet_magic, Config};
+    use crate::Error;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Network, ScriptBuf};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use test_log::test;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<Config, Error> {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        Config::from_vars(|name| vars.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn test_from_env() {
+        assert!(matches!(
+            from_vars(&[]),
+            Err(Error::InvalidEnv {
+                name: "BITCOIN_DATADIR",
+                ..
+            })
+        ));
+
+        let config = from_vars(&[("BITCOIN_DATADIR", "/data")]).unwrap();
+        assert_eq!(config.network, Network::Bitcoin);
+        assert_eq!(config.blocks_dir, Path::new("/data/blocks"));
+        assert!(!config.skip_prevout);
+        assert_eq!(config.max_reorg, 6);
+        assert_eq!(config.stop_at_height, None);
+
+        for (network, dir) in [
+            ("testnet", "/data/testnet3/blocks"),
+            ("testnet4", "/data/testnet4/blocks"),
+            ("signet", "/data/signet/blocks"),
+            ("regtest", "/data/regtest/blocks"),
+        ] {
+            let vars = [("BITCOIN_DATADIR", "/data"), ("BITCOIN_NETWORK", network)];
+            assert_eq!(from_vars(&vars).unwrap().blocks_dir, Path::new(dir));
+        }
+
+        let config = from_vars(&[
+            ("BITCOIN_DATADIR", "/data"),
+            ("BITCOIN_NETWORK", "signet"),
+            ("BLOCKS_ITERATOR_BLOCKS_DIR", "/blocks"),
+            ("BLOCKS_ITERATOR_SKIP_PREVOUT", "true"),
+            ("BLOCKS_ITERATOR_MAX_REORG", "10"),
+            ("BLOCKS_ITERATOR_STOP_AT_HEIGHT", "100"),
+        ])
+        .unwrap();
+        assert_eq!(config.network, Network::Signet);
+        assert_eq!(config.blocks_dir, Path::new("/blocks"));
+        assert!(config.skip_prevout);
+        assert_eq!(config.max_reorg, 10);
+        assert_eq!(config.stop_at_height, Some(100));
+
+        for (name, value) in [
+            ("BITCOIN_NETWORK", "mainnet"),
+            ("BLOCKS_ITERATOR_SKIP_PREVOUT", "yes"),
+            ("BLOCKS_ITERATOR_MAX_REORG", "300"),
+        ] {
+            let vars = [("BITCOIN_DATADIR", "/data"), (name, value)];
+            match from_vars(&vars) {
+                Err(Error::InvalidEnv { name: n, message }) => {
+                    assert_eq!(n, name);
+                    assert!(message.contains(value), "{}", message);
+                }
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_signet_challenge() {
+        let default_challenge = ScriptBuf::from_hex("512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae").unwrap();
+        assert_eq!(signet_magic(&default_challenge), Network::Signet.magic());
+
+        let mut config = Config::new("", Network::Signet);
+        config.signet_challenge = Some(ScriptBuf::from_hex("51").unwrap());
+        let magic = config.magic();
+        assert_ne!(magic, Network::Signet.magic());
+        assert_eq!(magic, signet_magic(&ScriptBuf::from_hex("51").unwrap()));
+        assert_eq!(
+            config.genesis_hash(),
+            genesis_block(Network::Signet).block_hash()
+        );
+
+        config.network = Network::Regtest;
+        assert_eq!(config.magic(), magic);
+        assert_eq!(
+            config.genesis_hash(),
+            genesis_block(Network::Signet).block_hash()
+        );
+
+        config.magic = Some(Network::Testnet.magic());
+        assert_eq!(config.magic(), Network::Testnet.magic());
+    }
 }
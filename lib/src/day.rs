@@ -0,0 +1,112 @@
+use crate::{iter, BlockExtra, Config};
+use chrono::{DateTime, NaiveDate};
+use std::collections::BTreeMap;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// How much a block timestamp could be behind the timestamp of a following block, consensus
+/// allows a block timestamp to be up to 2 hours in the future
+const TOLERANCE_SECS: u64 = 2 * 60 * 60;
+
+/// Iterate the blocks like [`iter`], calling `f` once for every UTC day with the blocks having a
+/// header timestamp in that day, in height order.
+///
+/// Since timestamps are not monotonic, a day is considered complete only when a block with a
+/// timestamp later than 2 hours after the end of the day is found. A block with a timestamp in an
+/// already completed day is included in the following day. Days without blocks are not reported
+/// and the last days are reported when the iteration ends.
+pub fn for_each_day<F: FnMut(NaiveDate, &[BlockExtra])>(config: Config, mut f: F) {
+    let mut days: BTreeMap<u64, Vec<BlockExtra>> = BTreeMap::new();
+    let mut completed: Option<u64> = None;
+
+    for block_extra in iter(config) {
+        let time = block_extra.time() as u64;
+        while let Some(entry) = days.first_entry() {
+            let day = *entry.key();
+            if time < (day + 1) * SECS_PER_DAY + TOLERANCE_SECS {
+                break;
+            }
+            completed = Some(day);
+            f(date(day), &entry.remove());
+        }
+        let day = match completed {
+            Some(completed) => (time / SECS_PER_DAY).max(completed + 1),
+            None => time / SECS_PER_DAY,
+        };
+        days.entry(day).or_default().push(block_extra);
+    }
+    for (day, blocks) in days {
+        f(date(day), &blocks);
+    }
+}
+
+fn date(day: u64) -> NaiveDate {
+    DateTime::from_timestamp((day * SECS_PER_DAY) as i64, 0)
+        .expect("block timestamp in range")
+        .date_naive()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{for_each_day, SECS_PER_DAY};
+    use crate::bitcoin::blockdata::constants::genesis_block;
+    use crate::bitcoin::Network;
+    use crate::inner_test::{child_block, test_conf};
+    use crate::Config;
+    use chrono::{DateTime, Days};
+    use test_log::test;
+
+    #[test]
+    fn test_for_each_day() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let mut heights = vec![];
+        let mut last_date = None;
+        for_each_day(conf.clone(), |date, blocks| {
+            assert!(last_date < Some(date));
+            last_date = Some(date);
+            heights.extend(blocks.iter().map(|b| b.height()));
+        });
+        let expected: Vec<_> = crate::iter(conf).map(|b| b.height()).collect();
+        assert_eq!(heights, expected);
+    }
+
+    #[test]
+    fn test_for_each_day_boundary() {
+        let genesis = genesis_block(Network::Regtest);
+        let day = SECS_PER_DAY as u32;
+        let midnight = (genesis.header.time / day + 1) * day;
+        let times = [
+            midnight - 600,
+            midnight + 600,
+            midnight - 300,  // late, but the previous day is not completed
+            midnight + 7200, // completes the previous day
+            midnight - 60,   // late, goes in the following day
+            midnight + day + 7200,
+        ];
+        let mut chain = vec![genesis];
+        for i in 0..12 {
+            let mut block = child_block(chain.last().unwrap(), 0);
+            block.header.time = match times.get(i) {
+                Some(time) => *time,
+                None => times[times.len() - 1] + i as u32,
+            };
+            chain.push(block);
+        }
+
+        let mut days = vec![];
+        let conf = Config::from_blocks(chain, Network::Regtest);
+        for_each_day(conf, |date, blocks| {
+            days.push((date, blocks.iter().map(|b| b.height()).collect::<Vec<_>>()))
+        });
+        let first = DateTime::from_timestamp(midnight as i64 - 1, 0)
+            .unwrap()
+            .date_naive();
+        let expected = vec![
+            (first, vec![0, 1, 3]),
+            (first + Days::new(1), vec![2, 4, 5]),
+            (first + Days::new(2), vec![6]),
+        ];
+        assert_eq!(days, expected);
+    }
+}
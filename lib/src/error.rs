@@ -10,4 +10,140 @@ pub enum Error {
 
     #[error("You can use only one db at a time")]
     OneDb,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Cannot write the utxo wal: {0}")]
+    UtxoWal(std::io::Error),
+
+    #[error("Prevouts only replay requires a utxo db")]
+    ReplayWithoutDb,
+
+    #[error("Resuming from the utxo db requires a utxo db")]
+    ResumeWithoutDb,
+
+    #[error(
+        "Prevouts only replay requires a db populated up to {required}, it is up to {populated}"
+    )]
+    ReplayNotPopulated { populated: i32, required: u32 },
+
+    #[error("Since file state with prevouts requires a utxo db")]
+    SinceFileStateWithoutDb,
+
+    #[error("Invalid magic {0}, it must be 4 bytes in hex, not all zero")]
+    InvalidMagic(String),
+
+    #[error("Invalid xor key {0}, it must be 8 bytes")]
+    InvalidXorKey(String),
+
+    #[error("Invalid height ranges: {0}")]
+    InvalidHeightRanges(String),
+
+    #[error("Invalid max reorg schedule: {0}")]
+    InvalidMaxReorgSchedule(String),
+
+    #[error("Invalid signet challenge {0}, it must be a script in hex")]
+    InvalidSignetChallenge(String),
+
+    #[error("Invalid environment variable {name}: {message}")]
+    InvalidEnv { name: &'static str, message: String },
+
+    #[error("A thread of the iteration panicked: {0}")]
+    ThreadPanic(String),
+
+    #[error("Invalid utxo db path {path:?}: {source}")]
+    UtxoDbPath {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid since file state {path:?}: {source}")]
+    InvalidSinceFileState {
+        path: std::path::PathBuf,
+        source: bitcoin::consensus::encode::Error,
+    },
+
+    #[error("Block {hash} in {path:?} is truncated: expected bytes {start}..{end}, {available} available")]
+    TruncatedBlock {
+        hash: bitcoin::BlockHash,
+        path: Option<std::path::PathBuf>,
+        start: usize,
+        end: usize,
+        available: usize,
+    },
+
+    #[error("Strict mode: {0}")]
+    Strict(String),
+
+    #[error("Transaction {index} of block at height {height} doesn't parse")]
+    TxParseFailed { height: u32, index: usize },
+
+    #[error("Corrupted record at offset {offset}: {message}")]
+    CorruptRecord { offset: u64, message: String },
+
+    #[error("Unsupported serialization version {0}, only version 0, 1 and 2 are supported")]
+    UnsupportedSerializationVersion(u8),
+
+    #[error("Record with serialization version {found} in a stream of version {expected}")]
+    MixedSerializationVersions { expected: u8, found: u8 },
+
+    #[error("Invalid output mmap {0}, it must be <path>:<estimated size in bytes>")]
+    InvalidOutputMmap(String),
+
+    #[error("Invalid blk file range {0}, it must be <first>-<last>")]
+    InvalidBlkFileRange(String),
+
+    #[error("Blocks directory {0:?} not found")]
+    BlocksDirNotFound(std::path::PathBuf),
+
+    #[error("Blocks tar archive {0:?} not found")]
+    BlocksTarNotFound(std::path::PathBuf),
+
+    #[error("Fast skip requires skip prevout, the utxo set needs every block")]
+    FastSkipWithPrevouts,
+
+    #[error("Chainwork range requires track chainwork")]
+    ChainworkRangeWithoutTracking,
+
+    #[error("The UTXO set hash requires building the UTXO set from the genesis")]
+    UtxoHashNotFromGenesis,
+
+    #[error("Checking the coinbase maturity requires processing the blocks from the genesis")]
+    CoinbaseMaturityNotFromGenesis,
+
+    #[error(
+        "Coinbase output {outpoint} created at height {created_height} is spent at height {height}"
+    )]
+    ImmatureCoinbaseSpend {
+        outpoint: bitcoin::OutPoint,
+        created_height: u32,
+        height: u32,
+    },
+
+    #[error("Self check: expected a block at height {expected_height}, got {got_height}")]
+    NonContiguousOutput {
+        expected_height: u32,
+        got_height: u32,
+    },
+
+    #[error("Self check: block at height {height} doesn't follow the previous block")]
+    DisconnectedOutput { height: u32 },
+
+    #[error("The block at height {height} has no transactions")]
+    EmptyBlock { height: u32 },
+
+    #[error("The UTXO store returned {got} prevouts for the block at height {height}, expected {expected}")]
+    PrevoutCountMismatch {
+        height: u32,
+        expected: usize,
+        got: usize,
+    },
+
+    #[error("Supply check: {got} satoshi issued before height {height}, expected {expected}")]
+    SupplyMismatch {
+        height: u32,
+        expected: u64,
+        got: u64,
+    },
 }
@@ -0,0 +1,119 @@
+use bitcoin::Network;
+use std::ops::BitOr;
+
+/// A set of consensus features, like the ones active at a block returned by
+/// [`crate::BlockExtra::active_forks()`]. Combine them with `|`:
+///
+/// ```
+/// use blocks_iterator::ForkFlags;
+/// use blocks_iterator::bitcoin::Network;
+///
+/// let flags = ForkFlags::at_height(Network::Bitcoin, 700_000);
+/// assert!(flags.contains(ForkFlags::CSV | ForkFlags::SEGWIT));
+/// assert!(!flags.contains(ForkFlags::TAPROOT));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ForkFlags(u8);
+
+impl ForkFlags {
+    /// Relative locktimes and `OP_CHECKSEQUENCEVERIFY`, BIP68, BIP112 and BIP113
+    pub const CSV: ForkFlags = ForkFlags(1);
+
+    /// Segregated witness, BIP141, BIP143 and BIP147
+    pub const SEGWIT: ForkFlags = ForkFlags(1 << 1);
+
+    /// Taproot, BIP340, BIP341 and BIP342
+    pub const TAPROOT: ForkFlags = ForkFlags(1 << 2);
+
+    /// No feature
+    pub fn empty() -> Self {
+        ForkFlags(0)
+    }
+
+    /// Returns true if no feature is in the set
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns true if all the features of `other` are in the set
+    pub fn contains(&self, other: ForkFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features active at `height` on `network`, from the activation heights of Bitcoin
+    /// Core. Networks other than mainnet, testnet and regtest have everything active from height
+    /// 1, like signet and testnet4
+    pub fn at_height(network: Network, height: u32) -> Self {
+        activation_heights(network)
+            .iter()
+            .filter(|(_, activation)| height >= *activation)
+            .fold(ForkFlags::empty(), |flags, (flag, _)| flags | *flag)
+    }
+}
+
+impl BitOr for ForkFlags {
+    type Output = ForkFlags;
+
+    fn bitor(self, rhs: ForkFlags) -> ForkFlags {
+        ForkFlags(self.0 | rhs.0)
+    }
+}
+
+/// The height of the first block enforcing each feature
+fn activation_heights(network: Network) -> [(ForkFlags, u32); 3] {
+    let (csv, segwit, taproot) = match network {
+        Network::Bitcoin => (419_328, 481_824, 709_632),
+        Network::Testnet => (770_112, 834_624, 2_011_968),
+        Network::Regtest => (1, 0, 0),
+        _ => (1, 1, 0),
+    };
+    [
+        (ForkFlags::CSV, csv),
+        (ForkFlags::SEGWIT, segwit),
+        (ForkFlags::TAPROOT, taproot),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::ForkFlags;
+    use crate::bitcoin::Network;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use test_log::test;
+
+    #[test]
+    fn test_fork_flags() {
+        let mainnet = |height| ForkFlags::at_height(Network::Bitcoin, height);
+        assert!(mainnet(419_327).is_empty());
+        assert_eq!(mainnet(419_328), ForkFlags::CSV);
+        assert_eq!(mainnet(481_823), ForkFlags::CSV);
+        assert_eq!(mainnet(481_824), ForkFlags::CSV | ForkFlags::SEGWIT);
+        assert!(!mainnet(709_631).contains(ForkFlags::TAPROOT));
+        let all = ForkFlags::CSV | ForkFlags::SEGWIT | ForkFlags::TAPROOT;
+        assert_eq!(mainnet(709_632), all);
+        assert!(all.contains(ForkFlags::SEGWIT | ForkFlags::TAPROOT));
+        assert!(all.contains(ForkFlags::empty()));
+        assert!(!ForkFlags::CSV.contains(all));
+
+        assert_eq!(
+            ForkFlags::at_height(Network::Regtest, 0),
+            ForkFlags::SEGWIT | ForkFlags::TAPROOT
+        );
+        assert_eq!(ForkFlags::at_height(Network::Regtest, 1), all);
+        assert_eq!(ForkFlags::at_height(Network::Signet, 0), ForkFlags::TAPROOT);
+        assert_eq!(ForkFlags::at_height(Network::Signet, 1), all);
+        assert_eq!(ForkFlags::at_height(Network::Testnet4, 1), all);
+
+        // the test blocks are early testnet ones
+        for block_extra in iter(test_conf()).take(10) {
+            assert!(block_extra.active_forks(Network::Testnet).is_empty());
+            assert_eq!(
+                block_extra.active_forks(Network::Bitcoin),
+                ForkFlags::empty()
+            );
+        }
+        let block_extra = iter(test_conf()).nth(1).unwrap();
+        assert_eq!(block_extra.active_forks(Network::Regtest), all);
+    }
+}
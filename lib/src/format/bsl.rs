@@ -18,6 +18,23 @@ impl<'a> Visit<'a> for BlockExtra<'a> {
         let version = read_u8(slice)?;
         let mut consumed = 1;
 
+        if version == 2 {
+            // block size, header and block hash
+            consumed += 4 + 80 + 32;
+            if slice.len() < consumed {
+                return Err(bitcoin_slices::Error::MoreBytesNeeded);
+            }
+            let next_len = scan_len(&slice[consumed..], &mut consumed)? as usize;
+            consumed += 32 * next_len;
+            // height, transactions, inputs and outputs
+            consumed += 4 * 4;
+            if slice.len() < consumed {
+                return Err(bitcoin_slices::Error::MoreBytesNeeded);
+            }
+            let (slice, remaining) = slice.split_at(consumed);
+            return Ok(ParseResult::new(remaining, BlockExtra { slice }));
+        }
+
         let block_size = if version == 0 {
             let block = bsl::Block::visit(&slice[consumed..], visit)?;
             consumed += block.consumed();
@@ -99,5 +116,13 @@ mod test {
         let block_extra1 = super::BlockExtra::parse(&bytes1[..]).unwrap();
         assert_eq!(block_extra1.consumed(), 216);
         assert_eq!(block_extra1.remaining(), &[]);
+
+        let mut be2 = be1;
+        be2.version = 2;
+        let bytes2 = bitcoin::consensus::serialize(&be2);
+        let block_extra2 = super::BlockExtra::parse(&bytes2[..]).unwrap();
+        assert_eq!(block_extra2.consumed(), bytes2.len());
+        assert_eq!(block_extra2.remaining(), &[]);
+        assert!(super::BlockExtra::parse(&bytes2[..bytes2.len() - 1]).is_err());
     }
 }
@@ -0,0 +1,371 @@
+//! The serialization format of [`BlockExtra`], used for example by the cli output and read back
+//! by [`crate::PipeIterator`].
+//!
+//! This module depends only on [`bitcoin`] and [`bitcoin_slices`], not on the threads of the
+//! iteration, on the block files listing or on the utxo dbs, so that decoding the format doesn't
+//! require the rest of the crate.
+//!
+//! A record is made of:
+//! - the serialization version, a byte, 0, 1 or 2
+//! - version 2 only, the compact record: the block size as u32, the block header, the block hash,
+//!   the hashes of the following blocks as a consensus encoded vec, the height, the number of
+//!   transactions, the total number of inputs and outputs in the block, all u32. Nothing else
+//!   follows. Otherwise:
+//! - version 1 only: the block size as u32
+//! - the consensus encoded block
+//! - the block hash
+//! - version 0 only: the block size as u32
+//! - the hashes of the following blocks, as a consensus encoded vec
+//! - the height as u32
+//! - the number of prevouts as u32, followed by the prevouts as outpoint and output, including
+//!   the coinbase sentinel also when [`crate::Config::insert_coinbase_sentinel`] is false
+//! - the total number of inputs and outputs in the block as u32
+//! - the number of txids as u32, followed by the txids
+
+use crate::bitcoin::block::Header;
+use crate::bitcoin::consensus::encode::{self, deserialize, serialize, Decodable, Encodable};
+use crate::bitcoin::{Amount, Block, OutPoint, ScriptBuf, TxOut, VarInt};
+use crate::BlockExtra;
+use std::convert::TryFrom;
+use std::sync::OnceLock;
+
+mod bsl;
+
+/// Decoded lengths are not trusted to preallocate more than this number of elements, a corrupted
+/// record would cause a huge allocation
+pub(crate) const MAX_PREALLOCATED: usize = 1 << 16;
+
+impl TryFrom<&[u8]> for BlockExtra {
+    type Error = encode::Error;
+
+    /// Decodes a [`BlockExtra`] from `bytes`, which must not contain trailing data
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        deserialize(bytes)
+    }
+}
+
+impl BlockExtra {
+    /// Decodes the [`BlockExtra`] at the beginning of `bytes`, as serialized with
+    /// [`Encodable::consensus_encode`], returning it together with the number of bytes consumed.
+    ///
+    /// Useful to read a stream of concatenated records, like the output of the cli
+    pub fn from_consensus_bytes(bytes: &[u8]) -> Result<(Self, usize), encode::Error> {
+        let mut remaining = bytes;
+        let block_extra = Self::consensus_decode(&mut remaining)?;
+        Ok((block_extra, bytes.len() - remaining.len()))
+    }
+}
+
+impl Encodable for BlockExtra {
+    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let mut written = 0;
+        written += self.version.consensus_encode(writer)?;
+        if self.version == 2 {
+            written += self.size.consensus_encode(writer)?;
+            writer.write_all(self.header_bytes())?;
+            written += Header::SIZE;
+            written += self.block_hash.consensus_encode(writer)?;
+            written += self.next.consensus_encode(writer)?;
+            written += self.height.consensus_encode(writer)?;
+            written += (self.block_total_txs as u32).consensus_encode(writer)?;
+            written += self.block_total_inputs.consensus_encode(writer)?;
+            written += self.block_total_outputs.consensus_encode(writer)?;
+            return Ok(written);
+        }
+        if self.version == 1 {
+            written += self.size.consensus_encode(writer)?;
+        }
+        writer.write_all(&self.block_bytes)?;
+        written += self.block_bytes.len();
+        written += self.block_hash.consensus_encode(writer)?;
+        if self.version == 0 {
+            written += self.size.consensus_encode(writer)?;
+        }
+        written += self.next.consensus_encode(writer)?;
+        written += self.height.consensus_encode(writer)?;
+        // without the sentinel the fee of the decoded record wouldn't be available
+        let sentinel = self
+            .coinbase_value
+            .filter(|_| !self.outpoint_values_vec.iter().any(|(o, _)| o.is_null()))
+            .map(|value| {
+                let tx_out = TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::new(),
+                };
+                (OutPoint::default(), tx_out)
+            });
+        let len = self.outpoint_values_vec.len() + sentinel.is_some() as usize;
+        written += (len as u32).consensus_encode(writer)?;
+        for (out_point, tx_out) in self.outpoint_values_vec.iter().chain(sentinel.iter()) {
+            written += out_point.consensus_encode(writer)?;
+            written += tx_out.consensus_encode(writer)?;
+        }
+        written += self.block_total_inputs.consensus_encode(writer)?;
+        written += self.block_total_outputs.consensus_encode(writer)?;
+        written += (self.txids.len() as u32).consensus_encode(writer)?;
+        for txid in self.txids.iter() {
+            written += txid.consensus_encode(writer)?;
+        }
+        Ok(written)
+    }
+}
+
+impl Decodable for BlockExtra {
+    fn consensus_decode<D: bitcoin::io::Read + ?Sized>(d: &mut D) -> Result<Self, encode::Error> {
+        let version = Decodable::consensus_decode(d)?;
+        if version == 2 {
+            return decode_compact(d);
+        }
+        let (size, block_bytes, block_hash) = match version {
+            0 => {
+                let block = Block::consensus_decode(d)?;
+                let block_bytes = serialize(&block);
+                let block_hash = Decodable::consensus_decode(d)?;
+                let size = Decodable::consensus_decode(d)?;
+                (size, block_bytes, block_hash)
+            }
+            1 => {
+                let size = Decodable::consensus_decode(d)?;
+                if size as usize > encode::MAX_VEC_SIZE {
+                    return Err(encode::Error::ParseFailed("Block size too big"));
+                }
+                let mut block_bytes = vec![0u8; size as usize];
+                d.read_exact(&mut block_bytes)?;
+                let block_hash = Decodable::consensus_decode(d)?;
+                (size, block_bytes, block_hash)
+            }
+            _ => {
+                return Err(encode::Error::ParseFailed(
+                    "Only version 0, 1 and 2 are supported",
+                ));
+            }
+        };
+        let mut b = BlockExtra {
+            version,
+            block_bytes: block_bytes.into(),
+            block: OnceLock::new(),
+            block_hash,
+            size,
+            next: Decodable::consensus_decode(d)?,
+            height: Decodable::consensus_decode(d)?,
+            outpoint_values: OnceLock::new(),
+            outpoint_values_vec: {
+                let len = u32::consensus_decode(d)?;
+                let mut m = Vec::with_capacity((len as usize).min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    m.push((
+                        Decodable::consensus_decode(d)?,
+                        Decodable::consensus_decode(d)?,
+                    ));
+                }
+                m
+            },
+            block_total_inputs: Decodable::consensus_decode(d)?,
+            block_total_outputs: Decodable::consensus_decode(d)?,
+            txids: {
+                let len = u32::consensus_decode(d)?;
+                let mut v = Vec::with_capacity((len as usize).min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    v.push(Decodable::consensus_decode(d)?);
+                }
+                v
+            },
+            block_total_txs: 0, // To be initialized
+            witness_size: OnceLock::new(),
+            tx_offsets: OnceLock::new(),
+            location: None,
+            bip30_duplicate: false,
+            chain_work: None,
+            retarget_timespan: None,
+            forks: vec![],
+            subsidy_schedule: Default::default(),
+            extra: Default::default(),
+            coinbase_spends: vec![],
+            utxo_delta: None,
+            utxo_muhash: None,
+            prevout_codes: None,
+            coinbase_value: None,
+        };
+        b.block_total_txs = if b.txids.is_empty() {
+            // records written without txids, the count precedes the transactions in the block
+            let mut txs = b.block_bytes.get(Header::SIZE..).unwrap_or_default();
+            VarInt::consensus_decode(&mut txs)?.0 as usize
+        } else {
+            b.txids.len()
+        };
+        Ok(b)
+    }
+}
+
+/// Decode the version 2 record following the version byte, the block bytes are the header
+/// followed by a zero transactions count, so that they are a valid block
+fn decode_compact<D: bitcoin::io::Read + ?Sized>(d: &mut D) -> Result<BlockExtra, encode::Error> {
+    let size = Decodable::consensus_decode(d)?;
+    let mut block_bytes = vec![0u8; Header::SIZE + 1];
+    d.read_exact(&mut block_bytes[..Header::SIZE])?;
+    Ok(BlockExtra {
+        version: 2,
+        block_bytes: block_bytes.into(),
+        block: OnceLock::new(),
+        block_hash: Decodable::consensus_decode(d)?,
+        size,
+        next: Decodable::consensus_decode(d)?,
+        height: Decodable::consensus_decode(d)?,
+        outpoint_values: OnceLock::new(),
+        outpoint_values_vec: vec![],
+        block_total_txs: u32::consensus_decode(d)? as usize,
+        block_total_inputs: Decodable::consensus_decode(d)?,
+        block_total_outputs: Decodable::consensus_decode(d)?,
+        txids: vec![],
+        witness_size: OnceLock::new(),
+        tx_offsets: OnceLock::new(),
+        location: None,
+        bip30_duplicate: false,
+        chain_work: None,
+        retarget_timespan: None,
+        forks: vec![],
+        subsidy_schedule: Default::default(),
+        extra: Default::default(),
+        coinbase_spends: vec![],
+        utxo_delta: None,
+        utxo_muhash: None,
+        prevout_codes: None,
+        coinbase_value: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitcoin::consensus::encode::serialize_hex;
+    use crate::bitcoin::consensus::{deserialize, serialize, Decodable};
+    use crate::block_extra::test::block_extra;
+    use crate::BlockExtra;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn block_extra_round_trip() {
+        let be = block_extra();
+        let ser = serialize(&be);
+        let deser = deserialize(&ser).unwrap();
+        assert_eq!(be, deser);
+
+        let mut be1 = be;
+        be1.version = 1;
+        let ser = serialize(&be1);
+        let deser = deserialize(&ser).unwrap();
+        assert_eq!(be1, deser);
+    }
+
+    #[test]
+    fn test_from_consensus_bytes() {
+        let mut be = block_extra();
+        be.height = 42;
+        let mut stream = serialize(&block_extra());
+        let first_len = stream.len();
+        stream.extend(serialize(&be));
+
+        let (first, consumed) = BlockExtra::from_consensus_bytes(&stream).unwrap();
+        assert_eq!(first, block_extra());
+        assert_eq!(consumed, first_len);
+        let (second, consumed) = BlockExtra::from_consensus_bytes(&stream[first_len..]).unwrap();
+        assert_eq!(second, be);
+        assert_eq!(consumed, stream.len() - first_len);
+        assert!(BlockExtra::from_consensus_bytes(&stream[..first_len - 1]).is_err());
+
+        assert_eq!(BlockExtra::try_from(&stream[first_len..]).unwrap(), be);
+        assert!(BlockExtra::try_from(&stream[..]).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let be = block_extra();
+        let hex0 = serialize_hex(&be);
+        assert_eq!(hex0, "0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001000000000000000000000000000000000000000000000000000000000000005100000001000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000ffffffffffffffffffffffff00000000000000000000000000");
+
+        let mut be1 = be;
+        be1.version = 1;
+        let hex1 = serialize_hex(&be1);
+        assert_eq!(hex1, "0151000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000001000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000ffffffffffffffffffffffff00000000000000000000000000");
+        assert_ne!(hex0, hex1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut conf = crate::inner_test::test_conf();
+        conf.serialization_version = 2;
+        conf.stop_at_height = Some(20);
+        for be in crate::iter(conf) {
+            assert!(be.has_block_bytes());
+            let ser = serialize(&be);
+            assert_eq!(ser.len(), 1 + 4 + 80 + 32 + 1 + 32 + 4 * 4);
+            let compact: BlockExtra = deserialize(&ser).unwrap();
+            assert!(!compact.has_block_bytes());
+            assert_eq!(compact.version(), 2);
+            assert_eq!(compact.header_bytes(), be.header_bytes());
+            assert_eq!(compact.block().header, be.block().header);
+            assert!(compact.block().txdata.is_empty());
+            assert_eq!(compact.block_hash(), be.block_hash());
+            assert_eq!(compact.height(), be.height());
+            assert_eq!(compact.size(), be.size());
+            assert_eq!(compact.next(), be.next());
+            assert_eq!(compact.block_total_txs, be.block_total_txs);
+            assert_eq!(compact.block_total_inputs(), be.block_total_inputs());
+            assert_eq!(compact.block_total_outputs(), be.block_total_outputs());
+            assert!(be.fee().is_some());
+            assert_eq!(compact.fee(), None);
+            assert_eq!(compact.coinbase_output_value(), None);
+            assert_eq!(compact.fee_from_coinbase(), None);
+            assert_eq!(compact.build_bip158_filter(), None);
+            assert!(compact.txids().is_empty());
+            assert_eq!(serialize(&compact), ser);
+        }
+    }
+
+    #[test]
+    fn test_without_coinbase_sentinel() {
+        let mut conf = crate::inner_test::test_conf();
+        conf.insert_coinbase_sentinel = false;
+        for mut be in crate::iter(conf) {
+            assert!(be.fee().is_some());
+            for version in [0, 1] {
+                be.version = version;
+                let decoded: BlockExtra = deserialize(&serialize(&be)).unwrap();
+                assert_eq!(decoded.fee(), be.fee());
+                assert_eq!(decoded.coinbase_output_value(), be.coinbase_output_value());
+                assert!(decoded.real_prevouts().eq(be.real_prevouts()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_without_txids() {
+        let mut conf = crate::inner_test::test_conf();
+        conf.compute_txids = false;
+        let mut be = crate::iter(conf).find(|b| b.block_total_txs > 1).unwrap();
+        assert!(be.txids().is_empty());
+        for version in [0, 1] {
+            be.version = version;
+            let decoded: BlockExtra = deserialize(&serialize(&be)).unwrap();
+            assert!(decoded.txids().is_empty());
+            assert_eq!(decoded.block_total_txs, be.block().txdata.len());
+            assert_eq!(decoded.average_fee(), be.average_fee());
+            assert!(decoded.average_fee().unwrap().is_finite());
+        }
+
+        be.block_total_txs = 0;
+        assert_eq!(be.average_fee(), None);
+    }
+
+    #[test]
+    fn block_extra_unsupported_version() {
+        assert_eq!(
+            "parse failed: Only version 0, 1 and 2 are supported",
+            BlockExtra::consensus_decode(&mut &[3u8][..])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
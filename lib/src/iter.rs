@@ -1,48 +1,457 @@
 use std::{
+    collections::HashSet,
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicBool, Ordering},
     sync::mpsc::{sync_channel, Receiver},
+    sync::{Arc, Condvar, Mutex},
     thread::JoinHandle,
 };
 
-use log::error;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::{BlockHash, ScriptBuf};
+use log::{error, info};
 
-use crate::{iterate, BlockExtra, Config};
+use crate::chain_tip::ChainTip;
+use crate::stages::panic_message;
+use crate::{run_pipeline, BlockExtra, Config, Error, ScriptAges, SubsidySchedule};
 
-struct BlockExtraIterator {
-    handle: Option<JoinHandle<()>>,
+/// The Iterator of [`BlockExtra`] returned by [`try_iter`], allowing to know at the end if all the
+/// blocks have been iterated or the iteration stopped because of an error.
+///
+/// Dropping it before the end stops the pipeline in the background: the stages notice the
+/// consumer is gone when they fail sending and end on their own, but the drop doesn't wait for
+/// them, thus the UTXO store may still be committing when it returns. Use
+/// [`BlockExtraIterator::shutdown()`] to wait for the teardown
+pub struct BlockExtraIterator {
+    handle: Option<JoinHandle<Result<(), Error>>>,
     recv: Receiver<Option<BlockExtra>>,
+    watch_scripts: Option<HashSet<ScriptBuf>>,
+    digest: Option<sha256::HashEngine>,
+    script_ages: Option<ScriptAges>,
+    chain_tip: Option<ChainTip>,
+    self_check: Option<SelfCheck>,
+    supply_check: Option<SupplyCheck>,
+    resume_panics: bool,
+    outcome: Option<Result<(), Error>>,
+    consumer_gone: Arc<AtomicBool>,
+    pauser: Pauser,
 }
+
+/// A handle to pause and resume the production of blocks of a [`BlockExtraIterator`], returned by
+/// [`BlockExtraIterator::pauser()`].
+///
+/// While paused the reorder stage doesn't emit blocks and the threads are kept alive, thus the
+/// state of the iteration is preserved. The blocks already emitted are still returned by the
+/// iterator, then the channels fill up and stop the reading of the block files.
+#[derive(Debug, Clone, Default)]
+pub struct Pauser(Arc<(Mutex<bool>, Condvar)>);
+
+impl Pauser {
+    /// Pause the production of blocks
+    pub fn pause(&self) {
+        *self.0 .0.lock().unwrap() = true;
+    }
+
+    /// Resume the production of blocks
+    pub fn resume(&self) {
+        *self.0 .0.lock().unwrap() = false;
+        self.0 .1.notify_all();
+    }
+
+    /// Returns true if the production of blocks is paused
+    pub fn is_paused(&self) -> bool {
+        *self.0 .0.lock().unwrap()
+    }
+
+    /// Blocks the calling thread while paused
+    pub(crate) fn wait(&self) {
+        let (paused, condvar) = &*self.0;
+        let _guard = condvar
+            .wait_while(paused.lock().unwrap(), |paused| *paused)
+            .unwrap();
+    }
+}
+
+impl BlockExtraIterator {
+    fn new(config: Config, resume_panics: bool) -> Self {
+        let (send, recv) = sync_channel(config.channels_size.into());
+        let watch_scripts = config.watch_scripts.clone();
+        let digest = config.stream_digest.then(sha256::Hash::engine);
+        let script_ages = config.track_script_ages.then(ScriptAges::default);
+        let chain_tip = config
+            .chain_tip_file
+            .clone()
+            .map(|path| ChainTip::new(path, config.max_reorg));
+
+        let contiguous = config.height_stride.is_none()
+            && config.height_ranges.is_empty()
+            && config.predicate.is_none()
+            && config.chainwork_range.is_none();
+        let self_check = config.self_check.then(|| SelfCheck {
+            contiguous,
+            last: None,
+        });
+        let supply_check = (config.verify_supply && contiguous).then(|| SupplyCheck {
+            schedule: config.subsidy_schedule(),
+            issued: None,
+        });
+
+        let consumer_gone = Arc::new(AtomicBool::new(false));
+        let pauser = Pauser::default();
+        let handle = Some(run_pipeline(
+            config,
+            send,
+            consumer_gone.clone(),
+            pauser.clone(),
+        ));
+
+        BlockExtraIterator {
+            handle,
+            recv,
+            watch_scripts,
+            digest,
+            script_ages,
+            chain_tip,
+            self_check,
+            supply_check,
+            resume_panics,
+            outcome: None,
+            consumer_gone,
+            pauser,
+        }
+    }
+
+    /// Returns a handle to pause and resume the production of blocks, for example while the
+    /// consumer is busy with something else
+    pub fn pauser(&self) -> Pauser {
+        self.pauser.clone()
+    }
+
+    /// Returns `true` if the iteration ended and it went through all the blocks requested by the
+    /// config, `false` if it's not ended yet or it stopped because of an error
+    pub fn finished_cleanly(&self) -> bool {
+        matches!(self.outcome, Some(Ok(())))
+    }
+
+    /// Returns the error that stopped the iteration, if any
+    pub fn error(&self) -> Option<&Error> {
+        self.outcome
+            .as_ref()
+            .and_then(|outcome| outcome.as_ref().err())
+    }
+
+    /// Takes the index of the first and last height of the output scripts, when
+    /// [`Config::track_script_ages`] is set. Usually taken at the end of the iteration, when it's
+    /// complete, taking it before restarts the tracking from the next block
+    pub fn take_script_ages(&mut self) -> Option<ScriptAges> {
+        let script_ages = self.script_ages.take();
+        if script_ages.is_some() && self.outcome.is_none() {
+            self.script_ages = Some(ScriptAges::default());
+        }
+        script_ages
+    }
+
+    /// Stops the iteration, if not ended yet, and waits the teardown of the pipeline: the blocks
+    /// already produced are discarded, every stage thread is joined and the UTXO store is
+    /// finalized, committing the pending writes of the persistent ones. Returns the error that
+    /// stopped the iteration, if any, while stopping early is not an error
+    pub fn shutdown(mut self) -> Result<(), Error> {
+        if self.outcome.is_none() {
+            self.disconnect();
+            self.end();
+        }
+        self.outcome.take().unwrap_or(Ok(()))
+    }
+
+    fn end(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let outcome = match handle.join() {
+                Ok(result) => result,
+                Err(panic) if self.resume_panics => std::panic::resume_unwind(panic),
+                Err(panic) => Err(Error::ThreadPanic(panic_message(&panic))),
+            };
+            self.outcome = Some(outcome);
+        }
+        if let Some(engine) = self.digest.take() {
+            info!("stream digest: {}", sha256::Hash::from_engine(engine));
+        }
+    }
+
+    /// Dropping the receiver makes the stages fail sending and stop
+    fn disconnect(&mut self) {
+        self.consumer_gone.store(true, Ordering::Relaxed);
+        // a paused stage would wait forever
+        self.pauser.resume();
+        drop(std::mem::replace(&mut self.recv, sync_channel(0).1));
+    }
+
+    /// Stop the pipeline before the end, then join the stages
+    fn stop(&mut self) {
+        self.disconnect();
+        if let Some(handle) = self.handle.take() {
+            // the outcome isn't interesting, the iteration is interrupted
+            let _ = handle.join();
+        }
+    }
+}
+impl Drop for BlockExtraIterator {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            // dropping the receiver makes the stages fail sending and stop, without joining them
+            self.consumer_gone.store(true, Ordering::Relaxed);
+            // a paused stage would wait forever
+            self.pauser.resume();
+        }
+    }
+}
+
 impl Iterator for BlockExtraIterator {
     type Item = BlockExtra;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.recv.recv() {
-            Ok(Some(val)) => Some(val),
-            Ok(None) => {
-                if let Some(handle) = self.handle.take() {
-                    handle.join().unwrap();
+        if self.outcome.is_some() {
+            return None;
+        }
+        loop {
+            match self.recv.recv() {
+                Ok(Some(val)) => {
+                    if let Some(Err(e)) = self.self_check.as_mut().map(|c| c.check(&val)) {
+                        error!("{}", e);
+                        self.stop();
+                        self.outcome = Some(Err(e));
+                        return None;
+                    }
+                    if let Some(Err(e)) = self.supply_check.as_mut().map(|c| c.check(&val)) {
+                        error!("{}", e);
+                        self.stop();
+                        self.outcome = Some(Err(e));
+                        return None;
+                    }
+                    if let Some(script_ages) = self.script_ages.as_mut() {
+                        script_ages.add(&val);
+                    }
+                    match self.watch_scripts.as_ref() {
+                        Some(scripts) if val.watch_hits(scripts).is_empty() => continue,
+                        _ => {
+                            if let Some(engine) = self.digest.as_mut() {
+                                engine.input(val.block_hash.as_byte_array());
+                            }
+                            if let Some(chain_tip) = self.chain_tip.as_mut() {
+                                chain_tip.push(val.height, val.block_hash);
+                            }
+                            return Some(val);
+                        }
+                    }
                 }
-                None
-            }
-            Err(e) => {
-                error!("error iterating {:?}", e);
-                if let Some(handle) = self.handle.take() {
-                    handle.join().unwrap();
+                Ok(None) => {
+                    self.end();
+                    return None;
+                }
+                Err(e) => {
+                    error!("error iterating {:?}", e);
+                    self.end();
+                    return None;
                 }
-                None
             }
         }
     }
 }
 
+/// Checks the blocks returned follow each other, see [`Config::self_check`]
+struct SelfCheck {
+    /// Heights must be consecutive, no filter skips blocks
+    contiguous: bool,
+    last: Option<(u32, BlockHash)>,
+}
+
+impl SelfCheck {
+    fn check(&mut self, block_extra: &BlockExtra) -> Result<(), Error> {
+        let height = block_extra.height;
+        if let Some((last_height, last_hash)) = self.last {
+            let expected_height = last_height + 1;
+            if height < expected_height || (self.contiguous && height != expected_height) {
+                return Err(Error::NonContiguousOutput {
+                    expected_height,
+                    got_height: height,
+                });
+            }
+            if height == expected_height && block_extra.header().prev_blockhash != last_hash {
+                return Err(Error::DisconnectedOutput { height });
+            }
+        }
+        self.last = Some((height, block_extra.block_hash));
+        Ok(())
+    }
+}
+
+/// Checks the subsidy issued at every halving, see [`Config::verify_supply`]
+struct SupplyCheck {
+    schedule: SubsidySchedule,
+    /// The sum of the base reward of the blocks returned, and of the blocks before the first one
+    issued: Option<u64>,
+}
+
+impl SupplyCheck {
+    fn check(&mut self, block_extra: &BlockExtra) -> Result<(), Error> {
+        let height = block_extra.height;
+        let issued = self.issued.unwrap_or_else(|| self.schedule.supply(height));
+        if height % self.schedule.halving_interval.max(1) == 0 {
+            let expected = self.schedule.supply(height);
+            if issued != expected {
+                return Err(Error::SupplyMismatch {
+                    height,
+                    expected,
+                    got: issued,
+                });
+            }
+        }
+        self.issued = Some(issued + block_extra.base_reward());
+        Ok(())
+    }
+}
+
 /// Return an Iterator of [`BlockExtra`] read from `blocks*.dat` contained in the `config.blocks_dir`
 /// Blocks returned are iterated in order, starting from the genesis to the highest block
 /// (minus `config.max_reorg`) in the directory, unless `config.stop_at_height` is specified.
+/// When `config.watch_scripts` is specified, only the blocks touching the scripts are returned.
 pub fn iter(config: Config) -> impl Iterator<Item = BlockExtra> {
-    let (send, recv) = sync_channel(config.channels_size.into());
+    BlockExtraIterator::new(config, true)
+}
 
-    let handle = Some(iterate(config, send));
+/// Return an Iterator of [`BlockExtra`] like [`iter`], which can be asked at the end if the
+/// iteration completed, see [`BlockExtraIterator::finished_cleanly()`].
+///
+/// With [`iter`] an invalid config is only logged and a panic in the pipeline is propagated to the
+/// caller, while here both are reported by [`BlockExtraIterator::error()`], useful for example to
+/// not mark a partial run as complete.
+pub fn try_iter(config: Config) -> BlockExtraIterator {
+    BlockExtraIterator::new(config, false)
+}
+
+/// Iterate the blocks like [`iter`] and return the sha256 of the hashes of the emitted blocks, in
+/// order. The same blocks emitted with the same config give the same digest, useful to assert
+/// a deterministic output, for example in CI.
+pub fn digest(config: Config) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    for block_extra in iter(config) {
+        engine.input(block_extra.block_hash.as_byte_array());
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+/// Iterate the blocks like [`iter`] folding them in height order with `f`, starting from `init`,
+/// and return the final state, for example the total fees:
+///
+/// ```no_run
+/// # let config = blocks_iterator::Config::new("blocks", bitcoin::Network::Bitcoin);
+/// let total_fees = blocks_iterator::fold_blocks(config, 0u64, |total, block_extra| {
+///     total + block_extra.fee().unwrap_or(0)
+/// });
+/// ```
+///
+/// If `f` panics the pipeline is stopped and its threads are joined before propagating the panic
+pub fn fold_blocks<S, F>(config: Config, init: S, mut f: F) -> S
+where
+    F: FnMut(S, &BlockExtra) -> S,
+{
+    let mut blocks = BlockExtraIterator::new(config, true);
+    let mut state = init;
+    while let Some(block_extra) = blocks.next() {
+        state = match panic::catch_unwind(AssertUnwindSafe(|| f(state, &block_extra))) {
+            Ok(state) => state,
+            Err(panic) => {
+                blocks.stop();
+                panic::resume_unwind(panic)
+            }
+        };
+    }
+    state
+}
+
+/// Return an Iterator of [`BlockExtra`] like [`iter`], but starting from the highest block down to
+/// `config.start_at_height`.
+///
+/// Blocks are read in two passes: the first one goes through the whole chain keeping only the
+/// position of every block, the second one reads again every block from its position while the
+/// iterator is consumed. Since the UTXO set is built going forward, prevouts are not available
+/// and `config.skip_prevout` is forced to `true`. A block that can't be read again ends the
+/// iteration, use [`try_iter_rev`] to get the error
+pub fn iter_rev(mut config: Config) -> impl Iterator<Item = BlockExtra> {
+    config.skip_prevout = true;
+    let strict = config.strict;
+    let skeletons = iter(config).map(unload).collect();
+    reload_rev(skeletons, None, strict).map_while(|block_extra| match block_extra {
+        Ok(block_extra) => Some(block_extra),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    })
+}
 
-    BlockExtraIterator { handle, recv }
+/// Return an Iterator of [`BlockExtra`] like [`iter_rev`], reporting the errors like
+/// [`try_iter`]: if the first pass stops because of an error, the error is the only item
+/// returned, while a block that can't be read again is returned as [`Error::Strict`] with
+/// `config.strict`, otherwise it ends the iteration like in [`iter_rev`]
+pub fn try_iter_rev(mut config: Config) -> impl Iterator<Item = Result<BlockExtra, Error>> {
+    config.skip_prevout = true;
+    let strict = config.strict;
+    let mut blocks = try_iter(config);
+    let skeletons = blocks.by_ref().map(unload).collect();
+    reload_rev(skeletons, blocks.shutdown().err(), strict)
+}
+
+fn unload(mut block_extra: BlockExtra) -> BlockExtra {
+    block_extra.unload();
+    block_extra
+}
+
+/// Read again the `skeletons` from the last one, returning only `error` if any
+fn reload_rev(
+    mut skeletons: Vec<BlockExtra>,
+    mut error: Option<Error>,
+    strict: bool,
+) -> impl Iterator<Item = Result<BlockExtra, Error>> {
+    if error.is_some() {
+        skeletons.clear();
+    }
+    std::iter::from_fn(move || {
+        if let Some(e) = error.take() {
+            return Some(Err(e));
+        }
+        let mut block_extra = skeletons.pop()?;
+        match block_extra.reload() {
+            Ok(()) => Some(Ok(block_extra)),
+            Err(e) => {
+                skeletons.clear();
+                let e = format!("cannot reload block {}: {}", block_extra.block_hash, e);
+                if strict {
+                    Some(Err(Error::Strict(e)))
+                } else {
+                    error!("{}", e);
+                    None
+                }
+            }
+        }
+    })
+}
+
+/// Return an Iterator of the height and the raw bytes of the blocks, in order like [`iter`], for
+/// consumers parsing the bytes themselves, for example with [`bitcoin_slices`] visitors.
+///
+/// Only the reading and the reordering of the blocks do real work: prevouts and txids are not
+/// computed and the blocks are never decoded, thus `config.skip_prevout` and
+/// `config.compute_txids` are overridden, while `config.enrich`, `config.compute_tx_offsets` and
+/// `config.watch_scripts`, which need the transactions, are ignored. Heights are still filtered by
+/// `config.start_at_height`, `config.stop_at_height` and the other height options
+pub fn raw_iter(mut config: Config) -> impl Iterator<Item = (u32, Arc<[u8]>)> {
+    config.skip_prevout = true;
+    config.compute_txids = false;
+    config.compute_tx_offsets = false;
+    config.enrich = None;
+    config.watch_scripts = None;
+    config.track_script_ages = false;
+    iter(config).map(|block_extra| (block_extra.height, block_extra.block_bytes_shared()))
 }
 
 #[cfg(test)]
@@ -51,7 +460,7 @@ mod inner_test {
 
     use super::*;
     use crate::bitcoin::Network;
-    use crate::inner_test::test_conf;
+    use crate::inner_test::{child_block, test_conf};
     use test_log::test;
 
     #[test]
@@ -66,6 +475,491 @@ mod inner_test {
         assert_ne!(genesis, current);
     }
 
+    #[test]
+    fn test_iter_rev() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let forward: Vec<_> = iter(conf.clone()).collect();
+        let backward: Vec<_> = iter_rev(conf).collect();
+        assert_eq!(forward.len(), backward.len());
+
+        for (f, b) in forward.iter().zip(backward.iter().rev()) {
+            assert_eq!(f.height, b.height);
+            assert_eq!(f.block_hash, b.block_hash);
+            assert_eq!(f.block_bytes(), b.block_bytes());
+            assert_eq!(f.txids, b.txids);
+        }
+        for pair in backward.windows(2) {
+            assert_eq!(pair[0].height, pair[1].height + 1);
+            assert_eq!(pair[0].block().header.prev_blockhash, pair[1].block_hash);
+        }
+    }
+
+    #[test]
+    fn test_try_iter_rev() {
+        let mut conf = test_conf();
+        conf.strict = true;
+        // the fixture ends with a truncated block, the first pass stops
+        let errors: Vec<_> = try_iter_rev(conf.clone()).collect();
+        assert!(matches!(errors[..], [Err(Error::Strict(_))]));
+
+        let buffer = std::fs::read("../blocks/blk-testnet.dat").unwrap();
+        let detected = crate::stages::detect(&buffer, Network::Testnet.magic(), false);
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("blk00000.dat");
+        std::fs::write(&path, &buffer[..detected.last().unwrap().end()]).unwrap();
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        let mut blocks = try_iter_rev(conf.clone());
+        // the first pass is complete, the blocks can't be read again
+        std::fs::write(&path, []).unwrap();
+        assert!(matches!(blocks.next(), Some(Err(Error::Strict(_)))));
+        assert!(blocks.next().is_none());
+
+        conf.strict = false;
+        let mut blocks = try_iter_rev(conf);
+        std::fs::write(&path, []).unwrap();
+        assert!(blocks.next().is_none());
+    }
+
+    #[test]
+    fn test_raw_iter() {
+        let mut conf = test_conf();
+        let expected: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height, b.block_bytes().to_vec()))
+            .collect();
+        let raw: Vec<_> = raw_iter(conf.clone())
+            .map(|(height, bytes)| (height, bytes.to_vec()))
+            .collect();
+        assert_eq!(raw, expected);
+
+        conf.start_at_height = 2;
+        conf.stop_at_height = Some(10);
+        let heights: Vec<_> = raw_iter(conf).map(|(height, _)| height).collect();
+        assert_eq!(heights, (2..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fold_blocks() {
+        let conf = test_conf();
+        let (fees, max_size) = fold_blocks(conf.clone(), (0, 0), |(fees, max_size), b| {
+            (fees + b.fee().unwrap(), max_size.max(b.size()))
+        });
+        let blocks: Vec<_> = iter(conf.clone()).collect();
+        assert_eq!(fees, blocks.iter().map(|b| b.fee().unwrap()).sum::<u64>());
+        assert_eq!(max_size, blocks.iter().map(|b| b.size()).max().unwrap());
+
+        let heights = fold_blocks(conf.clone(), vec![], |mut heights, b| {
+            heights.push(b.height());
+            heights
+        });
+        assert_eq!(heights, (0..blocks.len() as u32).collect::<Vec<_>>());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            fold_blocks(conf, 0, |count, b| {
+                assert!(b.height() < 10, "folding panicked");
+                count + 1
+            })
+        }));
+        let panic = result.unwrap_err();
+        assert_eq!(panic_message(&panic), "folding panicked");
+    }
+
+    #[test]
+    fn test_self_check() {
+        use crate::block_extra::test::block_extra_from_block;
+
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..3 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let blocks: Vec<_> = chain
+            .iter()
+            .enumerate()
+            .map(|(height, block)| {
+                let mut block_extra = block_extra_from_block(block.clone());
+                block_extra.height = height as u32;
+                block_extra
+            })
+            .collect();
+        let check = |contiguous: bool, heights: &[usize]| {
+            let mut self_check = SelfCheck {
+                contiguous,
+                last: None,
+            };
+            heights
+                .iter()
+                .try_for_each(|h| self_check.check(&blocks[*h]))
+        };
+
+        assert!(check(true, &[0, 1, 2, 3]).is_ok());
+        assert!(check(true, &[2, 3]).is_ok());
+        assert!(matches!(
+            check(true, &[0, 1, 3]),
+            Err(Error::NonContiguousOutput {
+                expected_height: 2,
+                got_height: 3
+            })
+        ));
+        assert!(check(false, &[0, 1, 3]).is_ok());
+        assert!(matches!(
+            check(false, &[0, 2, 2]),
+            Err(Error::NonContiguousOutput {
+                expected_height: 3,
+                got_height: 2
+            })
+        ));
+
+        // a block at the next height on another branch
+        let mut fork = block_extra_from_block(child_block(&chain[1], 1));
+        fork.height = 3;
+        let mut self_check = SelfCheck {
+            contiguous: true,
+            last: None,
+        };
+        for block_extra in blocks[..3].iter() {
+            self_check.check(block_extra).unwrap();
+        }
+        assert!(matches!(
+            self_check.check(&fork),
+            Err(Error::DisconnectedOutput { height: 3 })
+        ));
+
+        // the crate tests iterate with the check on
+        let mut conf = test_conf();
+        assert!(conf.self_check);
+        conf.height_stride = Some(7);
+        let mut blocks = try_iter(conf);
+        assert!(blocks.by_ref().count() > 0);
+        assert!(blocks.finished_cleanly());
+    }
+
+    #[test]
+    fn test_supply_check() {
+        use crate::block_extra::test::block_extra;
+
+        let schedule = SubsidySchedule {
+            initial: 1_000,
+            halving_interval: 10,
+        };
+        let check = |heights: &[u32]| {
+            let mut supply_check = SupplyCheck {
+                schedule,
+                issued: None,
+            };
+            heights.iter().try_for_each(|height| {
+                let mut block_extra = block_extra();
+                block_extra.height = *height;
+                block_extra.subsidy_schedule = schedule;
+                supply_check.check(&block_extra)
+            })
+        };
+
+        assert!(check(&(0..35).collect::<Vec<_>>()).is_ok());
+        assert!(check(&(13..35).collect::<Vec<_>>()).is_ok());
+        let mut duplicated: Vec<_> = (0..25).collect();
+        duplicated.insert(5, 5);
+        assert!(matches!(
+            check(&duplicated),
+            Err(Error::SupplyMismatch {
+                height: 10,
+                expected: 10_000,
+                got: 11_000
+            })
+        ));
+        let skipped: Vec<_> = (0..25).filter(|h| *h != 15).collect();
+        assert!(matches!(
+            check(&skipped),
+            Err(Error::SupplyMismatch {
+                height: 20,
+                expected: 15_000,
+                got: 14_500
+            })
+        ));
+
+        let mut conf = test_conf();
+        conf.verify_supply = true;
+        conf.subsidy_schedule = Some(SubsidySchedule {
+            initial: 5_000_000_000,
+            halving_interval: 50,
+        });
+        let mut blocks = try_iter(conf.clone());
+        assert!(blocks.by_ref().count() > 100);
+        assert!(blocks.finished_cleanly());
+        conf.start_at_height = 75;
+        let mut blocks = try_iter(conf);
+        assert!(blocks.by_ref().count() > 100);
+        assert!(blocks.finished_cleanly());
+    }
+
+    #[test]
+    fn test_digest() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let first = digest(conf.clone());
+        assert_eq!(first, digest(conf.clone()));
+
+        let mut engine = sha256::Hash::engine();
+        for block_extra in iter(conf.clone()) {
+            engine.input(block_extra.block_hash.as_byte_array());
+        }
+        assert_eq!(first, sha256::Hash::from_engine(engine));
+
+        conf.stop_at_height = Some(100);
+        assert_ne!(first, digest(conf));
+    }
+
+    #[test]
+    fn test_height_stride() {
+        let all: Vec<_> = iter(test_conf()).map(|b| (b.height(), b.fee())).collect();
+        for skip_prevout in [false, true] {
+            let mut conf = test_conf();
+            conf.skip_prevout = skip_prevout;
+            conf.start_at_height = 5;
+            conf.height_stride = Some(100);
+            let sampled: Vec<_> = iter(conf).map(|b| (b.height(), b.fee())).collect();
+            let expected: Vec<_> = [5, 105, 205, 305]
+                .iter()
+                .map(|h| (*h, all[*h as usize].1.filter(|_| !skip_prevout)))
+                .collect();
+            assert_eq!(sampled, expected);
+        }
+    }
+
+    #[test]
+    fn test_height_ranges() {
+        let all: Vec<_> = iter(test_conf()).map(|b| (b.height(), b.fee())).collect();
+        for skip_prevout in [false, true] {
+            let mut conf = test_conf();
+            conf.skip_prevout = skip_prevout;
+            conf.height_ranges = vec![(10, Some(12)), (100, Some(100)), (390, None)];
+            let emitted: Vec<_> = iter(conf.clone()).map(|b| (b.height(), b.fee())).collect();
+            let expected: Vec<_> = [10, 11, 12, 100, 390, 391, 392, 393, 394]
+                .iter()
+                .map(|h| (*h, all[*h as usize].1.filter(|_| !skip_prevout)))
+                .collect();
+            assert_eq!(emitted, expected);
+
+            conf.height_ranges = vec![(10, Some(12)), (20, Some(21))];
+            conf.height_stride = Some(2);
+            let heights: Vec<_> = iter(conf).map(|b| b.height()).collect();
+            assert_eq!(heights, vec![10, 12, 20]);
+        }
+
+        for ranges in [
+            vec![(10, Some(9))],
+            vec![(10, Some(20)), (20, Some(30))],
+            vec![(20, Some(30)), (10, Some(15))],
+            vec![(10, None), (20, Some(30))],
+        ] {
+            let mut conf = test_conf();
+            conf.height_ranges = ranges;
+            let mut blocks = try_iter(conf);
+            assert_eq!(blocks.by_ref().count(), 0);
+            assert!(matches!(
+                blocks.error(),
+                Some(Error::InvalidHeightRanges(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_chainwork_range() {
+        let mut conf = test_conf();
+        conf.track_chainwork = true;
+        let works: Vec<_> = iter(conf.clone())
+            .map(|b| b.chain_work().unwrap())
+            .collect();
+        for skip_prevout in [false, true] {
+            conf.skip_prevout = skip_prevout;
+            conf.chainwork_range = Some((works[10], works[20]));
+            let heights: Vec<_> = iter(conf.clone()).map(|b| b.height()).collect();
+            assert_eq!(heights, (10..=20).collect::<Vec<_>>());
+
+            conf.chainwork_range = Some((works[20], works[10]));
+            assert_eq!(iter(conf.clone()).count(), 0);
+        }
+
+        conf.track_chainwork = false;
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::ChainworkRangeWithoutTracking)
+        ));
+    }
+
+    #[test]
+    fn test_max_blocks() {
+        let heights = |conf: Config| -> Vec<u32> { iter(conf).map(|b| b.height()).collect() };
+        for skip_prevout in [false, true] {
+            let mut conf = test_conf();
+            conf.skip_prevout = skip_prevout;
+            conf.max_blocks = Some(3);
+            assert_eq!(heights(conf.clone()), vec![0, 1, 2]);
+
+            conf.start_at_height = 5;
+            conf.height_stride = Some(100);
+            assert_eq!(heights(conf.clone()), vec![5, 105, 205]);
+
+            conf.max_blocks = Some(0);
+            assert!(try_iter(conf).all(|_| false));
+        }
+
+        // blocks not emitted are pending for the next run
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        conf.since_file_state = Some(tempdir.path().join("state"));
+        conf.max_blocks = Some(10);
+        assert_eq!(heights(conf.clone()), (0..10).collect::<Vec<_>>());
+        assert_eq!(heights(conf.clone()), (10..20).collect::<Vec<_>>());
+
+        // the consumer stopping early doesn't make the pipeline panic
+        assert_eq!(iter(test_conf()).take(3).count(), 3);
+    }
+
+    #[test]
+    fn test_try_iter() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let expected = iter(conf.clone()).count();
+        let mut blocks = try_iter(conf.clone());
+        assert!(blocks.next().is_some());
+        assert!(!blocks.finished_cleanly());
+        assert_eq!(blocks.by_ref().count(), expected - 1);
+        assert!(blocks.finished_cleanly());
+        assert!(blocks.error().is_none());
+        assert!(blocks.next().is_none());
+
+        let mut invalid = conf.clone();
+        invalid.magic = Some(bitcoin::p2p::Magic::from_bytes([0u8; 4]));
+        let mut blocks = try_iter(invalid);
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(!blocks.finished_cleanly());
+        assert!(matches!(
+            blocks.error(),
+            Some(crate::Error::InvalidMagic(_))
+        ));
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let missing = tempdir.path().join("missing");
+        for extra in [false, true] {
+            let mut invalid = conf.clone();
+            if extra {
+                invalid.extra_blocks_dirs = vec![missing.clone()];
+            } else {
+                invalid.blocks_dir = missing.clone();
+            }
+            let mut blocks = try_iter(invalid);
+            assert_eq!(blocks.by_ref().count(), 0);
+            match blocks.error() {
+                Some(crate::Error::BlocksDirNotFound(path)) => assert_eq!(path, &missing),
+                e => panic!("unexpected {:?}", e),
+            }
+        }
+
+        // reading a directory fails, aborting the iteration
+        let tempdir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(tempdir.path().join("blk00000.dat")).unwrap();
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        conf.file_retries = 0;
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(!blocks.finished_cleanly());
+        assert!(matches!(blocks.error(), Some(crate::Error::Io(_))));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        use crate::bitcoin::consensus::Decodable;
+
+        let mut conf = test_conf();
+        let expected = iter(conf.clone()).count();
+
+        let mut blocks = try_iter(conf.clone());
+        assert_eq!(blocks.by_ref().count(), expected);
+        assert!(blocks.shutdown().is_ok());
+
+        // stopping early isn't an error, the utxo store is finalized before returning
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        conf.utxo_wal = Some(path.to_path_buf());
+        let mut blocks = try_iter(conf.clone());
+        assert_eq!(blocks.by_ref().take(20).count(), 20);
+        blocks.pauser().pause();
+        assert!(blocks.shutdown().is_ok());
+        let wal = std::fs::read(&path).unwrap();
+        let mut reader = &wal[..];
+        let mut records = 0;
+        while !reader.is_empty() {
+            let delta = crate::utxo::UtxoDelta::consensus_decode(&mut reader).unwrap();
+            assert_eq!(delta.height, records);
+            records += 1;
+        }
+        assert!(records >= 20);
+
+        let mut invalid = conf;
+        invalid.max_reorg_schedule = vec![(0, 0)];
+        assert!(matches!(
+            try_iter(invalid).shutdown(),
+            Err(crate::Error::InvalidMaxReorgSchedule(_))
+        ));
+    }
+
+    #[test]
+    fn test_pauser() {
+        use std::sync::atomic::AtomicUsize;
+        use std::time::Duration;
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let expected = iter(conf.clone()).count();
+
+        let blocks = try_iter(conf);
+        let pauser = blocks.pauser();
+        pauser.pause();
+        assert!(pauser.is_paused());
+        let count = Arc::new(AtomicUsize::new(0));
+        let consumer = {
+            let count = count.clone();
+            std::thread::spawn(move || {
+                let mut blocks = blocks;
+                for _ in blocks.by_ref() {
+                    count.fetch_add(1, Ordering::SeqCst);
+                }
+                blocks.finished_cleanly()
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        let paused_count = count.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(count.load(Ordering::SeqCst), paused_count);
+        assert!(paused_count < expected);
+
+        pauser.resume();
+        assert!(!pauser.is_paused());
+        assert!(consumer.join().unwrap());
+        assert_eq!(count.load(Ordering::SeqCst), expected);
+    }
+
+    #[test]
+    fn test_stage_panic() {
+        let mut conf = test_conf();
+        conf.enrich = Some(crate::Enrich::new(|block_extra| {
+            if block_extra.height() == 3 {
+                panic!("cannot enrich block 3");
+            }
+        }));
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 3);
+        match blocks.error() {
+            Some(crate::Error::ThreadPanic(message)) => {
+                assert!(message.starts_with("fee stage panicked: "), "{}", message);
+                assert!(message.contains("cannot enrich block 3"), "{}", message);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
     #[test]
     fn test_start_stop() {
         let mut conf = test_conf();
@@ -88,4 +982,136 @@ mod inner_test {
             conf.skip_prevout = true;
         }
     }
+
+    #[test]
+    fn test_check_pow() {
+        let mut conf = test_conf();
+        conf.check_pow = true;
+        conf.skip_prevout = true;
+        let last = iter(conf.clone()).last().unwrap();
+        assert_eq!(last.height(), 400 - conf.max_reorg as u32);
+    }
+
+    #[test]
+    fn test_assume_valid_below() {
+        use bitcoin::pow::Target;
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for i in 1..20 {
+            let mut block = child_block(chain.last().unwrap(), 0);
+            let target = Target::from_compact(block.header.bits);
+            // the block at height 5 has an invalid proof of work, the others a valid one
+            while target.is_met_by(block.block_hash()) == (i == 5) {
+                block.header.nonce += 1;
+            }
+            chain.push(block);
+        }
+        let mut conf = Config::from_blocks(chain, Network::Regtest);
+        conf.check_pow = true;
+        let count = |conf: Config| iter(conf).count();
+        // the block is discarded when detected, the previous ones miss `max_reorg` followers
+        assert_eq!(count(conf.clone()), 0);
+
+        conf.assume_valid_below = Some(6);
+        assert_eq!(count(conf.clone()), 20 - conf.max_reorg as usize);
+
+        conf.assume_valid_below = Some(5);
+        assert_eq!(count(conf.clone()), 5);
+
+        conf.strict = true;
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 5);
+        assert!(matches!(blocks.error(), Some(crate::Error::Strict(_))));
+    }
+
+    #[test]
+    fn test_watch_scripts() {
+        let blocks: Vec<_> = iter(test_conf()).collect();
+        let script = blocks
+            .iter()
+            .find_map(|b| b.real_prevouts().next())
+            .map(|(_, tx_out)| tx_out.script_pubkey.clone())
+            .unwrap();
+        let scripts: HashSet<_> = std::iter::once(script.clone()).collect();
+        let expected: Vec<_> = blocks
+            .iter()
+            .filter(|b| !b.watch_hits(&scripts).is_empty())
+            .map(|b| b.height())
+            .collect();
+        // created and then spent
+        assert!(expected.len() >= 2);
+
+        let mut conf = test_conf();
+        conf.watch_scripts = Some(scripts.clone());
+        let mut heights = vec![];
+        for b in iter(conf) {
+            for hit in b.watch_hits(&scripts) {
+                assert_eq!(hit.height, b.height());
+                assert_eq!(hit.matched, vec![script.clone()]);
+            }
+            heights.push(b.height());
+        }
+        assert_eq!(heights, expected);
+    }
+
+    #[test]
+    fn test_bip30_duplicate() {
+        let genesis = genesis_block(Network::Regtest);
+        let mut chain = vec![genesis];
+        for i in 0..10 {
+            let mut block = child_block(chain.last().unwrap(), 0);
+            if i == 3 {
+                // same coinbase, thus same txid, of the block at height 1
+                block.txdata = chain[1].txdata.clone();
+                block.header.merkle_root = block.compute_merkle_root().unwrap();
+            }
+            chain.push(block);
+        }
+
+        let duplicates = |conf: Config| -> Vec<u32> {
+            iter(conf)
+                .filter(|b| b.bip30_duplicate())
+                .map(|b| b.height())
+                .collect()
+        };
+        let conf = Config::from_blocks(chain, Network::Regtest);
+        assert_eq!(duplicates(conf.clone()), vec![4]);
+
+        #[cfg(feature = "redb")]
+        {
+            let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+            let mut conf = conf;
+            conf.utxo_redb = Some(path.to_path_buf());
+            assert_eq!(duplicates(conf), vec![4]);
+        }
+    }
+
+    #[test]
+    fn test_from_blocks() {
+        let genesis = genesis_block(Network::Regtest);
+        let mut main_chain = vec![genesis];
+        for _ in 0..10 {
+            let block = child_block(main_chain.last().unwrap(), 0);
+            main_chain.push(block);
+        }
+        let fork_1 = child_block(&main_chain[3], 1);
+        let fork_2 = child_block(&fork_1, 1);
+
+        let mut blocks = main_chain.clone();
+        blocks.push(fork_2.clone());
+        blocks.push(fork_1.clone());
+        blocks.reverse();
+
+        for skip_prevout in [false, true] {
+            let mut conf = Config::from_blocks(blocks.clone(), Network::Regtest);
+            conf.skip_prevout = skip_prevout;
+            let expected: Vec<_> = main_chain
+                .iter()
+                .take(main_chain.len() - conf.max_reorg as usize)
+                .enumerate()
+                .map(|(i, b)| (i as u32, b.block_hash()))
+                .collect();
+            let emitted: Vec<_> = iter(conf).map(|b| (b.height(), b.block_hash())).collect();
+            assert_eq!(emitted, expected);
+        }
+    }
 }
@@ -11,8 +11,13 @@
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
 use bitcoin::BlockHash;
-use log::{info, Level};
+use coinbase_maturity::CoinbaseMaturity;
+use log::{debug, info, Level};
+use std::fmt;
 use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use undo::CoinHeights;
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{sync_channel, SyncSender};
@@ -23,27 +28,289 @@ use std::time::Instant;
 
 pub use period::{PeriodCounter, Periodic};
 
+#[cfg(feature = "async")]
+mod async_iter;
 mod block_extra;
-mod bsl;
+mod block_source;
+mod chain_diff;
+mod chain_tip;
+mod coinbase;
+mod coinbase_maturity;
+#[cfg(feature = "compression")]
+mod compression;
 mod config;
+#[cfg(feature = "chrono")]
+mod day;
 mod error;
+mod forks;
+mod format;
 mod iter;
+mod merkle_proof;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod muhash;
+#[cfg(feature = "rayon")]
+mod par;
 mod period;
 mod pipe;
+mod predicate;
+mod prevouts;
+mod script_ages;
+mod script_history;
+mod script_type;
+mod since_file;
 mod stages;
+mod undo;
+mod util;
 mod utxo;
 
 // re-exporting deps
 pub use bitcoin;
+pub use bitcoin_slices;
+#[cfg(feature = "chrono")]
+pub use chrono;
 pub use fxhash;
 pub use glob;
 pub use log;
 
-pub use block_extra::BlockExtra;
-pub use config::Config;
+#[cfg(feature = "async")]
+pub use async_iter::async_iter;
+pub use block_extra::{
+    BlockExtra, CoinbaseBreakdown, FullDebug, InputTypeCounts, LocktimeHistogram, ScriptSizeStats,
+    SizeStats, SubsidySchedule, TxVersions, TxWithPrevouts, WatchHit,
+};
+#[cfg(feature = "tar")]
+pub use block_source::TarBlockSource;
+pub use block_source::{BlockSource, FsBlockSource};
+pub use chain_diff::{diff_chains, ChainDiff};
+pub use chain_tip::load_chain_tip;
+pub use coinbase::{coinbase_iter, CoinbaseInfo};
+pub use coinbase_maturity::CoinbaseSpend;
+#[cfg(feature = "compression")]
+pub use compression::{decoder, Compression, Encoder};
+pub use config::{Config, FileErrorPolicy, OutputFormat};
+#[cfg(feature = "chrono")]
+pub use day::for_each_day;
 pub use error::Error;
-pub use iter::iter;
-pub use pipe::PipeIterator;
+pub use forks::ForkFlags;
+pub use iter::{
+    digest, fold_blocks, iter, iter_rev, raw_iter, try_iter, try_iter_rev, BlockExtraIterator,
+    Pauser,
+};
+pub use merkle_proof::MerkleProof;
+#[cfg(feature = "prometheus")]
+pub use metrics::spawn_metrics_server;
+pub use muhash::MuHash;
+#[cfg(feature = "rayon")]
+pub use par::{par_tx_for_each, TxInBlock};
+pub use pipe::{iter_from_export, read_block_extras, PipeIterator};
+pub use predicate::BlockPredicate;
+pub use prevouts::{zip_prevouts, PrevoutsReader, PrevoutsRecord};
+pub use script_ages::ScriptAges;
+pub use script_history::{index_script_history, ScriptHistoryEntry};
+pub use script_type::ScriptType;
+pub use stages::{
+    detect, detect_magics, scan_networks, BlockVisitor, DetectedBlock, Enrich, ForkChoice,
+};
+pub use undo::write_undo;
+pub use util::printable_ascii_runs;
+#[cfg(feature = "db")]
+pub use utxo::DbUtxo;
+#[cfg(feature = "redb")]
+pub use utxo::RedbUtxo;
+pub use utxo::{ExternalPrevout, UtxoDelta, UtxoStore};
+
+/// The source of the bytes of a [`FsBlock`]
+pub enum BlockFile {
+    /// A `blk*.dat` file in the blocks directory
+    File(File),
+
+    /// Serialized blocks framed as in a `blk*.dat` file, see [`Config::blocks_source`]
+    Memory(Cursor<Vec<u8>>),
+
+    /// The file `name` of a custom [`BlockSource`], read with [`BlockSource::read_range`]
+    Source {
+        source: Arc<dyn BlockSource>,
+        name: String,
+        position: u64,
+    },
+}
+
+impl Read for BlockFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BlockFile::File(file) => file.read(buf),
+            BlockFile::Memory(cursor) => cursor.read(buf),
+            BlockFile::Source {
+                source,
+                name,
+                position,
+            } => {
+                let start = *position as usize;
+                let bytes = source.read_range(name, start, start + buf.len())?;
+                let len = bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+                *position += len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl Seek for BlockFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            BlockFile::File(file) => file.seek(pos),
+            BlockFile::Memory(cursor) => cursor.seek(pos),
+            BlockFile::Source { position, .. } => {
+                let new_position = match pos {
+                    SeekFrom::Start(start) => Some(start),
+                    SeekFrom::Current(delta) => position.checked_add_signed(delta),
+                    SeekFrom::End(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "seeking from the end of a BlockSource file",
+                        ))
+                    }
+                };
+                *position = new_position.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position")
+                })?;
+                Ok(*position)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BlockFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockFile::File(file) => file.fmt(f),
+            BlockFile::Memory(cursor) => write!(f, "Memory({} bytes)", cursor.get_ref().len()),
+            BlockFile::Source { source, name, .. } => write!(f, "Source({:?}, {})", source, name),
+        }
+    }
+}
+
+/// Where the bytes of a block are stored, allowing to read them again after the block has been
+/// emitted
+#[derive(Debug, Clone)]
+pub(crate) enum BlockLocation {
+    /// In the `blk*.dat` file at `path`, which is re-opened when needed to avoid keeping many
+    /// files open
+    File {
+        path: Arc<PathBuf>,
+        start: usize,
+        end: usize,
+        xor_key: Option<[u8; 8]>,
+    },
+
+    /// In a file kept alive by the reference, used for in-memory sources and custom
+    /// [`BlockSource`]s
+    Shared {
+        file: Arc<Mutex<BlockFile>>,
+        start: usize,
+        end: usize,
+        xor_key: Option<[u8; 8]>,
+    },
+}
+
+impl PartialEq for BlockLocation {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                BlockLocation::File {
+                    path, start, end, ..
+                },
+                BlockLocation::File {
+                    path: other_path,
+                    start: other_start,
+                    end: other_end,
+                    ..
+                },
+            ) => path == other_path && start == other_start && end == other_end,
+            (
+                BlockLocation::Shared {
+                    file, start, end, ..
+                },
+                BlockLocation::Shared {
+                    file: other_file,
+                    start: other_start,
+                    end: other_end,
+                    ..
+                },
+            ) => Arc::ptr_eq(file, other_file) && start == other_start && end == other_end,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for BlockLocation {}
+
+impl BlockLocation {
+    /// Read the block bytes from the location
+    pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+        let (mut bytes, start, xor_key) = match self {
+            BlockLocation::File {
+                path,
+                start,
+                end,
+                xor_key,
+            } => (
+                read_range(&mut File::open(path.as_ref())?, *start, *end)?,
+                start,
+                xor_key,
+            ),
+            BlockLocation::Shared {
+                file,
+                start,
+                end,
+                xor_key,
+            } => {
+                let mut guard = file
+                    .lock()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                (read_range(&mut *guard, *start, *end)?, start, xor_key)
+            }
+        };
+        if let Some(key) = xor_key.as_ref() {
+            xor(&mut bytes, key, *start);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Decode in place `bytes` starting at `offset` of a block file obfuscated with `key`, see
+/// [`Config::xor_key`]. Encoding is the same operation
+pub(crate) fn xor(bytes: &mut [u8], key: &[u8; 8], offset: usize) {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte ^= key[(offset + i) % 8];
+    }
+}
+
+/// Read the bytes between `start` and `end` of `file`
+pub(crate) fn read_range<R: Read + Seek>(
+    file: &mut R,
+    start: usize,
+    end: usize,
+) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(start as u64))?;
+    let mut bytes = vec![0u8; end - start];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read the bytes between `start` and `end` of `file`, fewer if `file` ends before `end`
+pub(crate) fn read_available<R: Read + Seek>(
+    file: &mut R,
+    start: usize,
+    end: usize,
+) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(start as u64))?;
+    let mut bytes = Vec::with_capacity(end - start);
+    file.take((end - start) as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
 
 /// Before reorder we keep only the position of the block in the file system and data relative
 /// to the block hash, the previous hash and the following hash (populated during reorder phase)
@@ -57,7 +324,13 @@ pub struct FsBlock {
     /// It's a Mutex to allow to be sent between threads but only one thread (reorder) mutably
     /// access to it so there is no contention. (Arc alone isn't enough cause it can't be mutated,
     /// RefCell can be mutated but not sent between threads)
-    pub file: Arc<Mutex<File>>,
+    pub file: Arc<Mutex<BlockFile>>,
+
+    /// The path of `file`, `None` for in-memory sources
+    pub(crate) path: Option<Arc<PathBuf>>,
+
+    /// The key obfuscating `file`, if any
+    pub(crate) xor_key: Option<[u8; 8]>,
 
     /// The start position in bytes in the `file` at which the block identified by `hash`
     pub start: usize,
@@ -88,30 +361,245 @@ pub struct FsBlock {
     pub(crate) block_total_txs: u32,
 }
 
-fn iterate(config: Config, channel: SyncSender<Option<BlockExtra>>) -> JoinHandle<()> {
+impl FsBlock {
+    /// Read the block bytes from `file`, [`Error::TruncatedBlock`] if the file is shorter than
+    /// when the block was detected
+    pub(crate) fn read_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.read_bytes_until(self.end)
+    }
+
+    /// Read only the 80 bytes of the block header from `file`, see [`Config::fast_skip`]
+    pub(crate) fn read_header_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.read_bytes_until(self.end.min(self.start + bitcoin::block::Header::SIZE))
+    }
+
+    fn read_bytes_until(&self, end: usize) -> Result<Vec<u8>, Error> {
+        let mut guard = self
+            .file
+            .lock()
+            .map_err(|e| Error::ThreadPanic(e.to_string()))?;
+        let file = &mut *guard;
+        debug!("going to read: {:?}", file);
+        let mut block_bytes = read_available(file, self.start, end)?;
+        drop(guard);
+        if block_bytes.len() != end - self.start {
+            return Err(Error::TruncatedBlock {
+                hash: self.hash,
+                path: self.path.as_deref().cloned(),
+                start: self.start,
+                end,
+                available: block_bytes.len(),
+            });
+        }
+        if let Some(key) = self.xor_key.as_ref() {
+            xor(&mut block_bytes, key, self.start);
+        }
+        Ok(block_bytes)
+    }
+}
+
+/// Runs the iteration in a new thread sending the blocks to `sender`, to integrate the pipeline
+/// in a custom channel topology, for example selecting over multiple sources. Otherwise
+/// [`iter()`] is simpler.
+///
+/// Every block is sent as `Some(block_extra)`, in height order, then a single `None` marks the
+/// end, also when the iteration stops because of an error. The error is returned by joining the
+/// handle. Dropping the receiver stops the iteration and, like dropping the iterator returned
+/// by [`iter()`], the [`Config::since_file_state`] is not saved since the blocks buffered in the
+/// channels were never consumed.
+pub fn iterate(
+    config: Config,
+    sender: SyncSender<Option<BlockExtra>>,
+) -> JoinHandle<Result<(), Error>> {
+    run_pipeline(config, sender, Arc::default(), Pauser::default())
+}
+
+/// Runs the pipeline sending the blocks to `channel`, `consumer_gone` is set when the receiving
+/// side is dropped before the end
+fn run_pipeline(
+    config: Config,
+    channel: SyncSender<Option<BlockExtra>>,
+    consumer_gone: Arc<AtomicBool>,
+    pauser: Pauser,
+) -> JoinHandle<Result<(), Error>> {
     thread::spawn(move || {
         let now = Instant::now();
         let early_stop = Arc::new(AtomicBool::new(false));
+        let mut result = Ok(());
+
+        if config.channels_size == 0 {
+            log::warn!("channels_size is 0, stages can't buffer blocks and throughput may suffer");
+        }
+
+        let magic = match config::check_magic(config.magic()) {
+            Ok(magic) => magic,
+            Err(e) => {
+                log::error!("{e}");
+                let _ = channel.send(None);
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = config::check_height_ranges(&config.height_ranges) {
+            log::error!("{e}");
+            let _ = channel.send(None);
+            return Err(e);
+        }
+
+        if let Err(e) = config::check_max_reorg_schedule(&config.max_reorg_schedule) {
+            log::error!("{e}");
+            let _ = channel.send(None);
+            return Err(e);
+        }
+
+        if config.fast_skip && !config.skip_prevout {
+            let e = Error::FastSkipWithPrevouts;
+            log::error!("{e}");
+            let _ = channel.send(None);
+            return Err(e);
+        }
+
+        if config.chainwork_range.is_some() && !config.track_chainwork {
+            let e = Error::ChainworkRangeWithoutTracking;
+            log::error!("{e}");
+            let _ = channel.send(None);
+            return Err(e);
+        }
+
+        if let Err(e) = config.check_blocks_dirs() {
+            log::error!("{e}");
+            let _ = channel.send(None);
+            return Err(e);
+        }
+
+        let xor_key = match config.resolve_xor_key() {
+            Ok(xor_key) => xor_key,
+            Err(e) => {
+                log::error!("{e}");
+                let _ = channel.send(None);
+                return Err(e);
+            }
+        };
+
+        let anomaly_log = match config.anomaly_log.as_ref().map(File::create).transpose() {
+            Ok(anomaly_log) => anomaly_log,
+            Err(e) => {
+                log::error!("cannot create the anomaly log: {e}");
+                let _ = channel.send(None);
+                return Err(e.into());
+            }
+        };
+
+        let utxo_manager = if config.skip_prevout {
+            None
+        } else {
+            match config.utxo_manager() {
+                Ok(utxo_manager) => Some(utxo_manager),
+                Err(e) => {
+                    log::error!("{e}");
+                    let _ = channel.send(None);
+                    return Err(e);
+                }
+            }
+        };
+
+        // the blocks before this height are already in the utxo db, only their header is read
+        let resume_height = if config.resume_from_utxo_db {
+            match utxo_manager.as_ref().and_then(|u| u.updated_up_to_height()) {
+                Some(populated) => Some((populated + 1) as u32),
+                None => {
+                    let e = Error::ResumeWithoutDb;
+                    log::error!("{e}");
+                    let _ = channel.send(None);
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+        let fast_skip_below = match resume_height {
+            Some(resume_height) => {
+                info!("resuming from the utxo db at height {}", resume_height);
+                Some(resume_height)
+            }
+            None => config.fast_skip.then_some(u32::MAX),
+        };
+
+        let since_file_state = match (&config.since_file_state, &config.blocks_source) {
+            (Some(path), None) => {
+                match since_file::SinceFileState::load(path, config.genesis_hash()) {
+                    Ok(state) => Some(Arc::new(Mutex::new(state))),
+                    Err(source) => {
+                        let e = Error::InvalidSinceFileState {
+                            path: path.clone(),
+                            source,
+                        };
+                        log::error!("{e}");
+                        let _ = channel.send(None);
+                        return Err(e);
+                    }
+                }
+            }
+            _ => None,
+        };
 
         // FsBlock is a small struct (~120b), so 10_000 is not a problem but allows the read_detect to read ahead the next block file
         let (send_block_fs, receive_block_fs) = sync_channel(0);
-        let _read = stages::ReadDetect::new(
-            config.blocks_dir.clone(),
-            config.network,
+        let read = stages::ReadDetect::new(
+            config
+                .block_files_source
+                .clone()
+                .unwrap_or_else(|| config.default_block_files_source()),
+            config.blocks_source.clone(),
+            magic,
+            xor_key,
             early_stop.clone(),
             send_block_fs,
             config.serialization_version,
+            config.check_pow && config.assume_valid_below.is_none(),
+            config.file_retries,
+            config.on_file_error,
+            config.strict,
+            config.detect_threads,
+            config.follow,
+            consumer_gone.clone(),
+            since_file_state.clone(),
+            anomaly_log,
         );
 
         let (send_ordered_blocks, receive_ordered_blocks) =
             sync_channel(config.channels_size.into());
-        let _reorder = stages::Reorder::new(
-            config.network,
+        let emit_filter = stages::EmitFilter {
+            start_at_height: match resume_height {
+                Some(resume_height) if config.start_at_height == 0 => resume_height,
+                _ => config.start_at_height,
+            },
+            height_stride: config.height_stride,
+            height_ranges: config.height_ranges.clone(),
+            predicate: config.predicate.clone(),
+            chainwork_range: config.chainwork_range,
+        };
+        let reorder = stages::Reorder::new(
+            config.genesis_hash(),
             config.max_reorg,
-            config.stop_at_height,
+            config.max_reorg_schedule.clone(),
+            config.stop_at_height(),
+            config.max_blocks,
+            emit_filter.clone(),
+            config.track_chainwork,
+            config.assume_valid_below.filter(|_| config.check_pow),
+            config.strict,
+            config.fork_choice.clone(),
+            config.emit_orphans.clone(),
+            config.subsidy_schedule(),
+            config.read_threads,
+            fast_skip_below,
+            config.log_value_throughput,
+            pauser,
             early_stop.clone(),
             receive_block_fs,
             send_ordered_blocks,
+            since_file_state.clone(),
         );
 
         let (send_blocks_with_txids, receive_blocks_with_txids) =
@@ -123,32 +611,82 @@ fn iterate(config: Config, channel: SyncSender<Option<BlockExtra>>) -> JoinHandl
             send_blocks_with_txids
         };
 
-        let _compute_txids = stages::ComputeTxids::new(
+        let compute_txids = stages::ComputeTxids::new(
             config.skip_prevout,
-            config.start_at_height,
+            emit_filter.clone(),
+            config.compute_txids,
+            config.compute_tx_offsets,
+            config.strict_tx_parse,
+            // with prevouts the blocks are enriched by the fee stage
+            config.enrich.clone().filter(|_| config.skip_prevout),
+            config.block_visitor.clone(),
             receive_ordered_blocks,
             send_blocks_with_txids,
+            consumer_gone.clone(),
         );
 
-        if !config.skip_prevout {
-            match config.utxo_manager() {
-                Ok(utxo_manager) => {
-                    let _fee = stages::Fee::new(
-                        config.start_at_height,
-                        receive_blocks_with_txids,
-                        channel,
-                        utxo_manager,
-                    );
-                }
-                Err(e) => {
-                    log::error!("{e}");
-                    early_stop.store(true, Ordering::Relaxed);
-                    channel.send(None).unwrap();
-                }
+        #[cfg(feature = "rayon")]
+        let fee_parallel_threshold = config.fee_parallel_threshold;
+        #[cfg(not(feature = "rayon"))]
+        let fee_parallel_threshold = None;
+
+        let fee_result = match utxo_manager {
+            Some(utxo_manager) => {
+                let coinbase_maturity = (config.track_coinbase_maturity
+                    || config.check_coinbase_maturity)
+                    .then(|| CoinbaseMaturity::new(config.check_coinbase_maturity));
+                let coin_heights = (config.track_undo_data
+                    || config.undo_file.is_some()
+                    || config.output_format == OutputFormat::Undo)
+                    .then(CoinHeights::new);
+                let fee = stages::Fee::new(
+                    emit_filter,
+                    fee_parallel_threshold,
+                    config.enrich.clone(),
+                    coinbase_maturity,
+                    coin_heights,
+                    config.track_utxo_delta,
+                    config.insert_coinbase_sentinel,
+                    receive_blocks_with_txids,
+                    channel,
+                    consumer_gone.clone(),
+                    utxo_manager,
+                );
+                fee.join()
+            }
+            None => Ok(()),
+        };
+        // a failing stage ends the following ones, the error of the first is the cause
+        let reorder_result = reorder.join();
+        let compute_txids_result = compute_txids.join();
+        let stages_result = read
+            .join()
+            .and(reorder_result)
+            .and(compute_txids_result)
+            .and(fee_result);
+        if let Err(e) = stages_result {
+            early_stop.store(true, Ordering::Relaxed);
+            result = Err(e);
+        }
+
+        if let (Some(state), Some(path)) = (since_file_state, &config.since_file_state) {
+            // both stages update the state before ending, they are already joined
+            let state = state.lock().expect("since file state poisoned");
+            if consumer_gone.load(Ordering::Relaxed) {
+                // blocks in the channels are marked as emitted but nobody received them
+                log::warn!("iterator dropped before the end, since file state not saved");
+            } else if result.is_err() {
+                // the state of a failed stage may be incomplete, the next run starts again from
+                // the last saved one
+                log::warn!("iteration failed, since file state not saved");
+            } else if let Err(e) = state.save(path) {
+                log::error!("cannot save since file state: {e}");
+                result = Err(e.into());
             }
         }
 
         info!("Total time elapsed: {}s", now.elapsed().as_secs());
+        result
     })
 }
 
@@ -165,14 +703,55 @@ pub fn periodic_log_level(i: u32, every: u32) -> Level {
 #[cfg(test)]
 mod inner_test {
     use crate::bitcoin::Network;
-    use crate::{iterate, Config};
-    use bitcoin::Txid;
+    use crate::{iterate, run_pipeline, Config};
+    use bitcoin::block::{Header, Version};
+    use bitcoin::hash_types::TxMerkleNode;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{absolute, transaction, Amount, Block, OutPoint, ScriptBuf, Sequence};
+    use bitcoin::{Transaction, TxIn, TxOut, Txid, Witness};
     use std::str::FromStr;
     use std::sync::mpsc::sync_channel;
     use test_log::test;
 
     pub fn test_conf() -> Config {
-        Config::new("../blocks", Network::Testnet)
+        let mut conf = Config::new("../blocks", Network::Testnet);
+        conf.self_check = true;
+        conf
+    }
+
+    /// Returns a block with a single coinbase transaction following `prev`, `tag` is used to
+    /// create different blocks with the same parent
+    pub fn child_block(prev: &Block, tag: u8) -> Block {
+        // the coinbase must be unique, thus it commits to the parent and the tag
+        let mut script_sig = prev.block_hash()[..8].to_vec();
+        script_sig.push(tag);
+        let coinbase = Transaction {
+            version: transaction::Version::ONE,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from(script_sig),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(50 * 100_000_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mut block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: prev.header.time + 1,
+                bits: prev.header.bits,
+                nonce: tag as u32,
+            },
+            txdata: vec![coinbase],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        block
     }
 
     #[test]
@@ -182,7 +761,7 @@ mod inner_test {
 
         let mut inputs = 0;
         let mut outputs = 0;
-        let handle = iterate(conf, send);
+        let handle = run_pipeline(conf, send, std::sync::Arc::default(), Default::default());
         let t1 = Txid::from_str("63375db7e443e491c99bcf46ce49422d05708f83b65335c935dee0a06855ebff")
             .unwrap();
         let t2 = Txid::from_str("0280d22f8aaa210b9ec8509067ecc523bf79609d8378cc56196857848cf42ce4")
@@ -197,15 +776,80 @@ mod inner_test {
                 assert_eq!(b.block_total_txs, 3);
             }
 
+            let real_prevouts: Vec<_> = b.real_prevouts().collect();
+            assert_eq!(real_prevouts.len() + 1, b.outpoint_values().len());
+            assert!(real_prevouts.iter().all(|(o, _)| !o.is_null()));
+            assert_eq!(
+                b.coinbase_output_value(),
+                Some(b.base_reward() + b.fee().unwrap())
+            );
+
+            // no segwit in the first testnet blocks
+            assert_eq!(b.stripped_size(), b.size);
+            assert!(!b.has_witness());
+
             inputs += b.block_total_inputs;
             outputs += b.block_total_outputs;
         }
-        handle.join().unwrap();
+        handle.join().unwrap().unwrap();
 
         assert_eq!(inputs, 448);
         assert_eq!(outputs, 426);
     }
 
+    #[test]
+    fn test_iterate() {
+        let (send, recv) = sync_channel(10);
+        let handle = iterate(test_conf(), send);
+        let mut heights = vec![];
+        while let Some(block_extra) = recv.recv().unwrap() {
+            heights.push(block_extra.height());
+        }
+        handle.join().unwrap().unwrap();
+        let expected: Vec<_> = crate::iter(test_conf()).map(|b| b.height()).collect();
+        assert_eq!(heights, expected);
+
+        // the end is signaled also on error
+        let mut conf = test_conf();
+        conf.magic = Some(bitcoin::p2p::Magic::from_bytes([0u8; 4]));
+        let (send, recv) = sync_channel(10);
+        let handle = iterate(conf, send);
+        assert!(recv.recv().unwrap().is_none());
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(crate::Error::InvalidMagic(_))
+        ));
+
+        // dropping the receiver stops the iteration
+        let (send, recv) = sync_channel(0);
+        let handle = iterate(test_conf(), send);
+        assert!(recv.recv().unwrap().is_some());
+        drop(recv);
+        handle.join().unwrap().unwrap();
+
+        // the blocks buffered in the channels aren't marked as consumed, both when the last
+        // stage is the compute txids one and when it's the fee one, the latter needing a db
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut confs = vec![test_conf()];
+        confs[0].skip_prevout = true;
+        #[cfg(feature = "redb")]
+        {
+            let mut conf = test_conf();
+            conf.utxo_redb = Some(tempdir.path().join("db"));
+            confs.push(conf);
+        }
+        for (i, mut conf) in confs.into_iter().enumerate() {
+            let state = tempdir.path().join(format!("state{i}"));
+            conf.since_file_state = Some(state.clone());
+            let (send, recv) = sync_channel(0);
+            let handle = iterate(conf, send);
+            assert!(recv.recv().unwrap().is_some());
+            drop(recv);
+            handle.join().unwrap().unwrap();
+            assert!(!state.exists());
+        }
+    }
+
     #[cfg(feature = "db")]
     #[test]
     fn test_blk_testnet_db() {
@@ -231,10 +875,54 @@ mod inner_test {
         assert_eq!(max_height, 400 - conf.max_reorg as u32);
 
         // iterating twice, this time prevouts come directly from db
-        for b in super::iter(conf) {
+        for b in super::iter(conf.clone()) {
             if b.height == 394 {
                 assert_eq!(b.fee(), Some(50_000));
             }
         }
+
+        let mut conf = conf;
+        conf.prevouts_only_replay = true;
+        conf.stop_at_height = Some(max_height);
+        let mut total_fee = 0;
+        for b in super::iter(conf) {
+            total_fee += b.fee().unwrap();
+        }
+        assert_eq!(total_fee, 450_000);
+    }
+
+    #[test]
+    fn test_prevouts_only_replay_without_db() {
+        let mut conf = test_conf();
+        conf.prevouts_only_replay = true;
+        assert!(matches!(
+            conf.utxo_manager(),
+            Err(crate::Error::ReplayWithoutDb)
+        ));
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn test_blk_testnet_db_flush_every() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let conf = {
+            let mut conf = test_conf();
+            conf.utxo_db = Some(tempdir.path().to_path_buf());
+            conf.db_flush_every = 7;
+            conf
+        };
+
+        let mut total_fee = 0;
+        for b in super::iter(conf.clone()) {
+            total_fee += b.fee().unwrap();
+        }
+        assert_eq!(total_fee, 450_000);
+
+        // the last partial batch is committed when the store is dropped
+        let mut total_fee = 0;
+        for b in super::iter(conf) {
+            total_fee += b.fee().unwrap();
+        }
+        assert_eq!(total_fee, 450_000);
     }
 }
@@ -0,0 +1,111 @@
+use crate::bitcoin::hashes::{sha256d, Hash, HashEngine};
+use crate::bitcoin::{TxMerkleNode, Txid};
+use crate::BlockExtra;
+
+/// The merkle branch proving the inclusion of a transaction in a block, returned by
+/// [`BlockExtra::merkle_proof()`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Id of the proved transaction
+    pub txid: Txid,
+
+    /// Position of the transaction in the block
+    pub index: u32,
+
+    /// The sibling hashes from the transaction level up to the level below the root
+    pub branch: Vec<TxMerkleNode>,
+}
+
+impl MerkleProof {
+    /// Returns the merkle root obtained hashing the txid with the branch
+    pub fn compute_root(&self) -> TxMerkleNode {
+        let mut node = TxMerkleNode::from_raw_hash(self.txid.to_raw_hash());
+        for (level, sibling) in self.branch.iter().enumerate() {
+            node = if (self.index >> level) & 1 == 1 {
+                combine(sibling, &node)
+            } else {
+                combine(&node, sibling)
+            };
+        }
+        node
+    }
+
+    /// Returns true if the proof commits to `merkle_root`, usually taken from a block header
+    pub fn verify(&self, merkle_root: &TxMerkleNode) -> bool {
+        &self.compute_root() == merkle_root
+    }
+}
+
+fn combine(left: &TxMerkleNode, right: &TxMerkleNode) -> TxMerkleNode {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(left.as_ref());
+    engine.input(right.as_ref());
+    TxMerkleNode::from_raw_hash(sha256d::Hash::from_engine(engine))
+}
+
+impl BlockExtra {
+    /// Returns the merkle branch proving `txid` is included in this block, built from the
+    /// precomputed txids. Returns `None` if the transaction is not in the block
+    pub fn merkle_proof(&self, txid: &Txid) -> Option<MerkleProof> {
+        let position = self.txids.iter().position(|t| t == txid)?;
+        let mut index = position;
+        let mut level: Vec<TxMerkleNode> = self
+            .txids
+            .iter()
+            .map(|t| TxMerkleNode::from_raw_hash(t.to_raw_hash()))
+            .collect();
+        let mut branch = vec![];
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(*level.last().expect("not empty"));
+            }
+            branch.push(level[index ^ 1]);
+            level = level
+                .chunks(2)
+                .map(|pair| combine(&pair[0], &pair[1]))
+                .collect();
+            index /= 2;
+        }
+        Some(MerkleProof {
+            txid: *txid,
+            index: position as u32,
+            branch,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitcoin::hashes::Hash;
+    use crate::bitcoin::{TxMerkleNode, Txid};
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use test_log::test;
+
+    #[test]
+    fn test_merkle_proof() {
+        let mut proofs = 0;
+        for block_extra in iter(test_conf()) {
+            let merkle_root = block_extra.block().header.merkle_root;
+            for txid in block_extra.txids() {
+                let proof = block_extra.merkle_proof(txid).unwrap();
+                assert_eq!(&proof.txid, txid);
+                assert!(proof.verify(&merkle_root));
+                assert!(!proof.verify(&TxMerkleNode::all_zeros()));
+                let leaf = TxMerkleNode::from_raw_hash(txid.to_raw_hash());
+                if proof
+                    .branch
+                    .first()
+                    .map_or(false, |sibling| sibling != &leaf)
+                {
+                    let mut wrong = proof.clone();
+                    wrong.index ^= 1;
+                    assert!(!wrong.verify(&merkle_root));
+                }
+                proofs += 1;
+            }
+            assert!(block_extra.merkle_proof(&Txid::all_zeros()).is_none());
+        }
+        assert!(proofs > 0);
+    }
+}
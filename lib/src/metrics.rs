@@ -0,0 +1,186 @@
+use log::{debug, warn};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+/// The stages of the iteration, whose busy time is exported
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stage {
+    ReadDetect = 0,
+    Reorder = 1,
+    ComputeTxids = 2,
+    Fee = 3,
+}
+
+const STAGE_NAMES: [&str; 4] = ["read_detect", "reorder", "compute_txids", "fee"];
+
+struct Metrics {
+    blocks: AtomicU64,
+    txs: AtomicU64,
+    height: AtomicU64,
+    utxo_size: AtomicU64,
+    busy_nanos: [AtomicU64; 4],
+}
+
+/// Metrics are global to the process, if more iterations are running they are summed, while the
+/// gauges hold the last value set by any of them
+static METRICS: Metrics = Metrics {
+    blocks: AtomicU64::new(0),
+    txs: AtomicU64::new(0),
+    height: AtomicU64::new(0),
+    utxo_size: AtomicU64::new(0),
+    busy_nanos: [
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+    ],
+};
+
+/// Count a block emitted by the reorder stage
+pub(crate) fn block_emitted(height: u32, txs: usize) {
+    METRICS.blocks.fetch_add(1, Ordering::Relaxed);
+    METRICS.txs.fetch_add(txs as u64, Ordering::Relaxed);
+    METRICS.height.store(height as u64, Ordering::Relaxed);
+}
+
+/// Set the time `stage` spent working, in nanoseconds
+pub(crate) fn set_busy(stage: Stage, nanos: u128) {
+    METRICS.busy_nanos[stage as usize].store(nanos as u64, Ordering::Relaxed);
+}
+
+/// Set the number of unspent outputs, if known by the utxo store
+pub(crate) fn set_utxo_size(size: Option<u64>) {
+    if let Some(size) = size {
+        METRICS.utxo_size.store(size, Ordering::Relaxed);
+    }
+}
+
+fn render() -> String {
+    let mut s = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(s, "# HELP blocks_iterator_{name} {help}");
+        let _ = writeln!(s, "# TYPE blocks_iterator_{name} {kind}");
+        let _ = writeln!(s, "blocks_iterator_{name} {value}");
+    };
+    let load = |a: &AtomicU64| a.load(Ordering::Relaxed);
+    metric(
+        "blocks_total",
+        "counter",
+        "Blocks emitted by the reorder stage",
+        load(&METRICS.blocks),
+    );
+    metric(
+        "transactions_total",
+        "counter",
+        "Transactions in the blocks emitted by the reorder stage",
+        load(&METRICS.txs),
+    );
+    metric(
+        "height",
+        "gauge",
+        "Height of the last block emitted by the reorder stage",
+        load(&METRICS.height),
+    );
+    metric(
+        "utxo_set_size",
+        "gauge",
+        "Unspent outputs in the utxo set, only for the in memory store",
+        load(&METRICS.utxo_size),
+    );
+
+    let _ = writeln!(
+        s,
+        "# HELP blocks_iterator_stage_busy_seconds Time spent working by a stage of the iteration"
+    );
+    let _ = writeln!(s, "# TYPE blocks_iterator_stage_busy_seconds gauge");
+    for (name, nanos) in STAGE_NAMES.iter().zip(METRICS.busy_nanos.iter()) {
+        let secs = load(nanos) as f64 / 1_000_000_000.0;
+        let _ = writeln!(
+            s,
+            "blocks_iterator_stage_busy_seconds{{stage=\"{name}\"}} {secs}"
+        );
+    }
+    s
+}
+
+/// Serve the metrics of the iterations running in this process at `http://<addr>/metrics`, in the
+/// Prometheus text format, from a background thread.
+///
+/// Returns the address the server is listening to, useful when `addr` has port 0
+pub fn spawn_metrics_server<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = serve(stream) {
+                        debug!("metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("metrics server cannot accept: {}", e),
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+fn serve(mut stream: TcpStream) -> io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let (status, body) = if request_line.starts_with("GET /metrics ") {
+        ("200 OK", render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::spawn_metrics_server;
+    use crate::{inner_test::test_conf, iter};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use test_log::test;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics_server() {
+        let addr = spawn_metrics_server("127.0.0.1:0").unwrap();
+        let blocks = iter(test_conf()).count();
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let value = |name: &str| -> u64 {
+            let line = response
+                .lines()
+                .find(|l| l.starts_with(&format!("blocks_iterator_{} ", name)))
+                .unwrap();
+            line.split(' ').nth(1).unwrap().parse().unwrap()
+        };
+        // other tests may be iterating concurrently
+        assert!(value("blocks_total") >= blocks as u64);
+        assert!(value("transactions_total") >= blocks as u64);
+        assert!(value("utxo_set_size") > 0);
+        assert!(response.contains("blocks_iterator_stage_busy_seconds{stage=\"fee\"}"));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,286 @@
+use bitcoin::hashes::{sha256, Hash};
+use std::convert::TryInto;
+
+/// Number of 64 bits limbs of a 3072 bits number
+const LIMBS: usize = 48;
+
+/// The modulus is `2^3072 - MODULUS_DIFF`, the largest 3072 bits safe prime
+const MODULUS_DIFF: u64 = 1_103_717;
+
+/// The MuHash3072 rolling hash of a set, the one used by Bitcoin Core for the `muhash` of
+/// `gettxoutsetinfo`, see [`crate::Config::compute_utxo_hash`].
+///
+/// Every element is mapped to a number modulo a 3072 bits prime, the set is the product of its
+/// elements thus the order of insertion doesn't matter and an element can be removed by dividing
+/// for it. The division is deferred, the removed elements are accumulated in a denominator and
+/// the only modular inversion happens in [`MuHash::finalize()`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MuHash {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl Default for MuHash {
+    fn default() -> Self {
+        MuHash {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+}
+
+impl MuHash {
+    /// The hash of the empty set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the element serialized in `data` to the set
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = self.numerator.mul(&Num3072::from_data(data));
+    }
+
+    /// Remove the element serialized in `data` from the set, it must have been inserted
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = self.denominator.mul(&Num3072::from_data(data));
+    }
+
+    /// Returns the hash of the set, the sha256 of the little endian bytes of the product of its
+    /// elements. Bitcoin Core shows it in reverse byte order, like txids.
+    ///
+    /// It computes a modular inversion, some milliseconds
+    pub fn finalize(&self) -> [u8; 32] {
+        let value = self
+            .numerator
+            .mul(&self.denominator.inverse())
+            .full_reduce();
+        sha256::Hash::hash(&value.to_bytes()).to_byte_array()
+    }
+}
+
+/// A number lower than `2^3072` in little endian limbs, not necessarily reduced modulo the prime
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Num3072([u64; LIMBS]);
+
+impl Num3072 {
+    fn one() -> Self {
+        let mut limbs = [0u64; LIMBS];
+        limbs[0] = 1;
+        Num3072(limbs)
+    }
+
+    /// Map `data` to a number: the little endian bytes of the chacha20 keystream keyed with the
+    /// sha256 of `data`
+    fn from_data(data: &[u8]) -> Self {
+        let key = sha256::Hash::hash(data).to_byte_array();
+        let mut limbs = [0u64; LIMBS];
+        // every chacha20 block fills 8 limbs
+        for (counter, chunk) in limbs.chunks_mut(8).enumerate() {
+            let block = chacha20_block(&key, counter as u32);
+            for (limb, bytes) in chunk.iter_mut().zip(block.chunks(8)) {
+                *limb = u64::from_le_bytes(bytes.try_into().expect("8 bytes"));
+            }
+        }
+        Num3072(limbs)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|limb| limb.to_le_bytes()).collect()
+    }
+
+    /// Returns `self * other` modulo the prime, lower than `2^3072` but not fully reduced
+    fn mul(&self, other: &Num3072) -> Num3072 {
+        let mut product = [0u64; 2 * LIMBS];
+        for (i, a) in self.0.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, b) in other.0.iter().enumerate() {
+                let t = (*a as u128) * (*b as u128) + product[i + j] as u128 + carry;
+                product[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            product[i + LIMBS] = carry as u64;
+        }
+
+        // 2^3072 is MODULUS_DIFF modulo the prime, thus high * 2^3072 + low is
+        // high * MODULUS_DIFF + low
+        let mut limbs = [0u64; LIMBS];
+        let mut carry = 0u128;
+        for i in 0..LIMBS {
+            let t = product[i + LIMBS] as u128 * MODULUS_DIFF as u128 + product[i] as u128 + carry;
+            limbs[i] = t as u64;
+            carry = t >> 64;
+        }
+        // fold the carry, twice since adding it can overflow again, but then the value is tiny
+        let mut result = Num3072(limbs);
+        let overflow = result.add_small(carry * MODULUS_DIFF as u128);
+        if overflow {
+            result.add_small(MODULUS_DIFF as u128);
+        }
+        result
+    }
+
+    /// Add `value` lower than `2^128`, returns true if the result overflowed `2^3072`
+    fn add_small(&mut self, value: u128) -> bool {
+        let mut carry = value;
+        for limb in self.0.iter_mut() {
+            if carry == 0 {
+                return false;
+            }
+            let t = *limb as u128 + (carry as u64) as u128;
+            *limb = t as u64;
+            carry = (carry >> 64) + (t >> 64);
+        }
+        carry != 0
+    }
+
+    /// Returns the number fully reduced modulo the prime
+    fn full_reduce(&self) -> Num3072 {
+        // self >= prime only if self + MODULUS_DIFF overflows, the result being self - prime
+        let mut reduced = self.clone();
+        if reduced.add_small(MODULUS_DIFF as u128) {
+            reduced
+        } else {
+            self.clone()
+        }
+    }
+
+    /// Returns the modular inverse, `self^(prime - 2)` since the modulus is prime
+    fn inverse(&self) -> Num3072 {
+        // all the bits of prime - 2 = 2^3072 - MODULUS_DIFF - 2 are set but in the lowest limb
+        let lowest = u64::MAX - (MODULUS_DIFF + 1);
+        let mut result = Num3072::one();
+        for i in (0..LIMBS).rev() {
+            let limb = if i == 0 { lowest } else { u64::MAX };
+            for bit in (0..64).rev() {
+                result = result.mul(&result);
+                if limb >> bit & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// The chacha20 block `counter` of the keystream of `key` with a zero nonce (RFC 8439)
+fn chacha20_block(key: &[u8; 32], counter: u32) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (word, bytes) in state[4..12].iter_mut().zip(key.chunks(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().expect("4 bytes"));
+    }
+    state[12] = counter;
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; 64];
+    for (i, bytes) in block.chunks_mut(4).enumerate() {
+        bytes.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{chacha20_block, MuHash, Num3072, LIMBS, MODULUS_DIFF};
+    use bitcoin::hashes::hex::FromHex;
+    use test_log::test;
+
+    fn from_int(i: u8) -> [u8; 32] {
+        let mut data = [0u8; 32];
+        data[0] = i;
+        data
+    }
+
+    #[test]
+    fn test_chacha20_block() {
+        // RFC 8439 appendix A.1, test vectors 1 and 2
+        let block = chacha20_block(&[0u8; 32], 0);
+        assert_eq!(
+            block.to_vec(),
+            hex(
+                "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+                 da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+            )
+        );
+        let block = chacha20_block(&[0u8; 32], 1);
+        assert_eq!(block[..8], hex("9f07e7be5551387a")[..]);
+    }
+
+    #[test]
+    fn test_muhash() {
+        // the vector of the MuHash3072 unit test of Bitcoin Core, shown reversed
+        let mut muhash = MuHash::new();
+        muhash.insert(&from_int(0));
+        muhash.insert(&from_int(1));
+        muhash.remove(&from_int(2));
+        let mut hash = muhash.finalize();
+        hash.reverse();
+        assert_eq!(
+            hash.to_vec(),
+            hex("10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863")
+        );
+
+        // the order doesn't matter and removing an element restores the previous hash
+        let mut other = MuHash::new();
+        other.remove(&from_int(2));
+        other.insert(&from_int(1));
+        other.insert(&from_int(0));
+        assert_eq!(other.finalize(), muhash.finalize());
+        let empty = MuHash::new().finalize();
+        other.insert(&from_int(3));
+        assert_ne!(other.finalize(), muhash.finalize());
+        other.remove(&from_int(3));
+        other.insert(&from_int(2));
+        other.remove(&from_int(0));
+        other.remove(&from_int(1));
+        assert_eq!(other.finalize(), empty);
+    }
+
+    #[test]
+    fn test_num3072() {
+        // numbers between the prime and 2^3072 are reduced
+        let mut prime_plus_one = Num3072([u64::MAX; LIMBS]);
+        prime_plus_one.0[0] = u64::MAX - MODULUS_DIFF + 2;
+        assert_eq!(prime_plus_one.full_reduce(), Num3072::one());
+        let max = Num3072([u64::MAX; LIMBS]);
+        assert_eq!(
+            max.mul(&Num3072::one()).full_reduce().0[0],
+            MODULUS_DIFF - 1
+        );
+        assert_eq!(max.mul(&max).full_reduce(), {
+            // (-MODULUS_DIFF + 1)^2
+            let mut expected = Num3072([0; LIMBS]);
+            expected.0[0] = (MODULUS_DIFF - 1) * (MODULUS_DIFF - 1);
+            expected
+        });
+
+        let a = Num3072::from_data(b"a");
+        assert_eq!(a.mul(&a.inverse()).full_reduce(), Num3072::one());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        Vec::from_hex(s).unwrap()
+    }
+}
@@ -0,0 +1,212 @@
+use crate::bitcoin::{Transaction, TxOut, Txid};
+use crate::{iter, BlockExtra, Config};
+use rayon::prelude::*;
+use std::ops::ControlFlow;
+
+/// A transaction of a block, passed to the callback of [`par_tx_for_each`]
+#[derive(Debug, Clone, Copy)]
+pub struct TxInBlock<'a> {
+    /// The block containing the transaction
+    pub block: &'a BlockExtra,
+
+    /// The transaction
+    pub tx: &'a Transaction,
+
+    /// The id of the transaction
+    pub txid: Txid,
+
+    /// The position of the transaction in the block, 0 is the coinbase
+    pub index: usize,
+
+    /// The fee of the transaction, see [`BlockExtra::tx_fee()`]. `None` for the coinbase or if the
+    /// previous outputs are not available, like when `skip_prevout` is used
+    pub fee: Option<u64>,
+
+    /// The fee rate of the transaction in sat/vB, `None` when [`TxInBlock::fee`] is
+    pub feerate: Option<f64>,
+}
+
+impl<'a> TxInBlock<'a> {
+    /// Returns the outputs spent by the transaction, `prevouts[i]` is spent by `tx.input[i]`.
+    ///
+    /// Returns `None` for the coinbase or if the previous outputs are not available, like when
+    /// `skip_prevout` is used
+    pub fn prevouts(&self) -> Option<Vec<&'a TxOut>> {
+        if self.index == 0 {
+            return None;
+        }
+        let outpoint_values = self.block.outpoint_values();
+        self.tx
+            .input
+            .iter()
+            .map(|input| outpoint_values.get(&input.previous_output))
+            .collect()
+    }
+}
+
+/// Iterate the blocks like [`iter`], calling `f` for every transaction of every block on the
+/// rayon thread pool. Transactions of a block are processed in parallel and in any order, while
+/// blocks are processed one after the other in height order, or in groups of consecutive blocks
+/// when [`Config::target_batch_work`] is set.
+///
+/// When `f` returns [`ControlFlow::Break`] no more transactions are started and the iteration
+/// stops after the current block, or group of blocks
+pub fn par_tx_for_each<F>(config: Config, f: F)
+where
+    F: Fn(&TxInBlock) -> ControlFlow<()> + Sync,
+{
+    let target_batch_work = config.target_batch_work;
+    let mut batch = vec![];
+    let mut batch_work = 0;
+    for block_extra in iter(config) {
+        // the work is estimated from the counts known since the block was detected
+        batch_work += block_extra.block_total_inputs() + block_extra.block_total_outputs();
+        batch.push(block_extra);
+        if batch_work >= target_batch_work {
+            if par_batch(&batch, &f).is_break() {
+                return;
+            }
+            batch.clear();
+            batch_work = 0;
+        }
+    }
+    let _ = par_batch(&batch, &f);
+}
+
+/// Calls `f` for every transaction of the blocks in `batch` on the rayon thread pool
+fn par_batch<F>(batch: &[BlockExtra], f: &F) -> ControlFlow<()>
+where
+    F: Fn(&TxInBlock) -> ControlFlow<()> + Sync,
+{
+    let txs: Vec<_> = batch
+        .iter()
+        .flat_map(|block_extra| {
+            let txids = block_extra.txids();
+            block_extra
+                .block()
+                .txdata
+                .iter()
+                .enumerate()
+                .map(move |(index, tx)| (block_extra, txids.get(index).copied(), index, tx))
+        })
+        .collect();
+    txs.par_iter()
+        .try_for_each(|(block_extra, txid, index, tx)| {
+            let txid = txid.unwrap_or_else(|| tx.compute_txid());
+            let fee = (*index != 0).then(|| block_extra.tx_fee(tx)).flatten();
+            f(&TxInBlock {
+                block: block_extra,
+                tx,
+                txid,
+                index: *index,
+                fee,
+                feerate: fee.map(|fee| fee as f64 / tx.vsize() as f64),
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::par_tx_for_each;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use std::ops::ControlFlow;
+    use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use test_log::test;
+
+    #[test]
+    fn test_par_tx_for_each() {
+        let conf = test_conf();
+        let (txs, inputs) = iter(conf.clone()).fold((0, 0), |(txs, inputs), b| {
+            (
+                txs + b.block().txdata.len(),
+                inputs + b.block_total_inputs(),
+            )
+        });
+
+        let count = AtomicUsize::new(0);
+        let prevouts = AtomicUsize::new(0);
+        let fees = AtomicU64::new(0);
+        par_tx_for_each(conf.clone(), |tx| {
+            assert_eq!(tx.txid, tx.tx.compute_txid());
+            assert_eq!(tx.block.block().txdata[tx.index], *tx.tx);
+            count.fetch_add(1, Ordering::Relaxed);
+            match tx.prevouts() {
+                Some(p) => {
+                    assert_eq!(p.len(), tx.tx.input.len());
+                    prevouts.fetch_add(p.len(), Ordering::Relaxed);
+                    let input: u64 = p.iter().map(|p| p.value.to_sat()).sum();
+                    let output: u64 = tx.tx.output.iter().map(|o| o.value.to_sat()).sum();
+                    assert_eq!(tx.fee, Some(input - output));
+                    let feerate = (input - output) as f64 / tx.tx.vsize() as f64;
+                    assert_eq!(tx.feerate, Some(feerate));
+                    fees.fetch_add(input - output, Ordering::Relaxed);
+                }
+                None => {
+                    assert_eq!(tx.index, 0);
+                    assert!(tx.fee.is_none() && tx.feerate.is_none());
+                }
+            }
+            ControlFlow::Continue(())
+        });
+        assert_eq!(count.into_inner(), txs);
+        let blocks = iter(conf.clone()).count();
+        assert_eq!(
+            prevouts.into_inner(),
+            inputs - blocks,
+            "coinbase inputs excluded"
+        );
+        let total_fee: u64 = iter(conf.clone()).map(|b| b.fee().unwrap()).sum();
+        assert!(total_fee > 0);
+        assert_eq!(fees.into_inner(), total_fee);
+
+        let mut conf = conf;
+        conf.skip_prevout = true;
+        let max_height = AtomicU32::new(0);
+        par_tx_for_each(conf, |tx| {
+            assert_eq!(tx.txid, tx.tx.compute_txid());
+            assert!(tx.index == 0 || tx.prevouts().is_none());
+            assert!(tx.fee.is_none() && tx.feerate.is_none());
+            max_height.fetch_max(tx.block.height(), Ordering::Relaxed);
+            if tx.block.height() == 10 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(max_height.into_inner(), 10);
+    }
+
+    #[test]
+    fn test_target_batch_work() {
+        let collect = |conf| {
+            let txs = Mutex::new(vec![]);
+            par_tx_for_each(conf, |tx| {
+                let entry = (tx.block.height(), tx.index, tx.txid, tx.fee);
+                txs.lock().unwrap().push(entry);
+                ControlFlow::Continue(())
+            });
+            let mut txs = txs.into_inner().unwrap();
+            txs.sort();
+            txs
+        };
+        let mut conf = test_conf();
+        let expected = collect(conf.clone());
+        conf.target_batch_work = 50;
+        assert_eq!(collect(conf.clone()), expected);
+
+        // stops within the group of blocks containing height 10
+        let max_height = AtomicU32::new(0);
+        par_tx_for_each(conf, |tx| {
+            max_height.fetch_max(tx.block.height(), Ordering::Relaxed);
+            if tx.block.height() == 10 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        let max_height = max_height.into_inner();
+        assert!(max_height >= 10 && max_height < expected.last().unwrap().0);
+    }
+}
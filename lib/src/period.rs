@@ -18,12 +18,16 @@ pub struct PeriodCounter {
 pub struct Stats {
     current: BlocksTxs,
     total: BlocksTxs,
+    /// Whether the output value is counted, see [`PeriodCounter::with_value()`]
+    with_value: bool,
 }
 
 #[derive(Debug, Default, Clone)]
 struct BlocksTxs {
     blocks: u64,
     txs: u64,
+    /// Sum of the output values in satoshi
+    value: u64,
     period: Duration,
 }
 
@@ -37,6 +41,9 @@ impl BlocksTxs {
     fn txs_per_sec(&self) -> u64 {
         ((self.txs as u128 * 1000u128) / self.period.as_millis()) as u64
     }
+    fn sats_per_sec(&self) -> u64 {
+        ((self.value as u128 * 1000u128) / self.period.as_millis()) as u64
+    }
 }
 
 impl PeriodCounter {
@@ -50,8 +57,27 @@ impl PeriodCounter {
         }
     }
 
+    /// Count also the output value moved by the blocks, shown as satoshi per second. It requires
+    /// a visit of every counted block, see [`BlockExtra::total_output_value()`]
+    pub fn with_value(mut self) -> Self {
+        self.stats.with_value = true;
+        self
+    }
+
     /// Count statistics of the given block
     pub fn count_block(&mut self, block_extra: &BlockExtra) {
+        self.count_block_without_value(block_extra);
+
+        if self.stats.with_value {
+            let value = block_extra.total_output_value();
+            self.stats.current.value += value;
+            self.stats.total.value += value;
+        }
+    }
+
+    /// Count statistics of the given block like [`PeriodCounter::count_block()`] but not its
+    /// output value, for blocks whose transactions aren't available or needed
+    pub fn count_block_without_value(&mut self, block_extra: &BlockExtra) {
         self.stats.current.blocks += 1;
         self.stats.current.txs += block_extra.block_total_txs as u64;
 
@@ -76,15 +102,29 @@ impl PeriodCounter {
 
 impl fmt::Display for Stats {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Current {}: {:>5} blk/s; {:>6} txs/s; Total: {:>5} blk/s; {:>6} tx/s;",
-            self.total.blocks(),
-            self.current.blocks_per_sec(),
-            self.current.txs_per_sec(),
-            self.total.blocks_per_sec(),
-            self.total.txs_per_sec()
-        )
+        if self.with_value {
+            write!(
+                f,
+                "Current {}: {:>5} blk/s; {:>6} txs/s; {:>14} sat/s; Total: {:>5} blk/s; {:>6} tx/s; {:>14} sat/s;",
+                self.total.blocks(),
+                self.current.blocks_per_sec(),
+                self.current.txs_per_sec(),
+                self.current.sats_per_sec(),
+                self.total.blocks_per_sec(),
+                self.total.txs_per_sec(),
+                self.total.sats_per_sec()
+            )
+        } else {
+            write!(
+                f,
+                "Current {}: {:>5} blk/s; {:>6} txs/s; Total: {:>5} blk/s; {:>6} tx/s;",
+                self.total.blocks(),
+                self.current.blocks_per_sec(),
+                self.current.txs_per_sec(),
+                self.total.blocks_per_sec(),
+                self.total.txs_per_sec()
+            )
+        }
     }
 }
 
@@ -111,3 +151,34 @@ impl Periodic {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::PeriodCounter;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use std::time::Duration;
+    use test_log::test;
+
+    #[test]
+    fn test_period_counter_value() {
+        let blocks: Vec<_> = iter(test_conf()).take(10).collect();
+        let expected: u64 = blocks.iter().map(|b| b.total_output_value()).sum();
+        assert!(expected > 0);
+
+        let mut with_value = PeriodCounter::new(Duration::ZERO).with_value();
+        let mut without = PeriodCounter::new(Duration::ZERO);
+        for block in blocks.iter() {
+            with_value.count_block(block);
+            without.count_block(block);
+        }
+        std::thread::sleep(Duration::from_millis(2));
+
+        let stats = with_value.period_elapsed().unwrap();
+        assert_eq!(stats.total.value, expected);
+        assert!(stats.to_string().contains("sat/s"));
+        let stats = without.period_elapsed().unwrap();
+        assert_eq!(stats.total.value, 0);
+        assert!(!stats.to_string().contains("sat/s"));
+    }
+}
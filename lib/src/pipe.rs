@@ -1,21 +1,45 @@
+use crate::bitcoin::consensus::encode;
 use crate::bitcoin::consensus::{Decodable, Encodable};
-use crate::BlockExtra;
+use crate::bitcoin::io::FromStd;
+use crate::{BlockExtra, Error};
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 
 const MAX_BLOCK_EXTRA_SIZE: usize = 10 * 1024 * 1024;
 
 /// Iterator to use un Unix-style pipe composition when receiving BlockExtra from stdin and
 /// optionally propogating those to stdout
+///
+/// The serialization version of the first record is checked and exposed with
+/// [`PipeIterator::version()`], all the following records must have the same version. The
+/// iteration ends at the end of the stream or at the first error, available with
+/// [`PipeIterator::error()`]
 pub struct PipeIterator {
-    stdin: io::Stdin, // from docs, stdin is buffered, non need to wrap in BufReader
+    stdin: Box<dyn Read + Send>, // from docs, stdin is buffered, non need to wrap in BufReader
     stdout: Option<io::Stdout>,
     buffer: Vec<u8>,
+    version: Option<u8>,
+    error: Option<Error>,
 }
 
 impl PipeIterator {
-    /// Creates new PipeIterator from stdin and stdout
+    /// Creates new PipeIterator from stdin and stdout.
+    ///
+    /// With the `compression` feature, stdin compressed with any [`crate::Compression`] is
+    /// decompressed, the first bytes of stdin are read here to detect it. Blocks propagated to
+    /// stdout are never compressed
     pub fn new(stdin: io::Stdin, stdout: Option<io::Stdout>) -> Self {
+        #[cfg(feature = "compression")]
+        let stdin = crate::decoder(stdin).unwrap_or_else(|e| {
+            log::error!("cannot read stdin: {}", e);
+            Box::new(io::empty())
+        });
+        #[cfg(not(feature = "compression"))]
+        let stdin = Box::new(stdin);
+        Self::from_reader(stdin, stdout)
+    }
+
+    pub(crate) fn from_reader(stdin: Box<dyn Read + Send>, stdout: Option<io::Stdout>) -> Self {
         let buffer = if stdout.is_some() {
             vec![0u8; MAX_BLOCK_EXTRA_SIZE]
         } else {
@@ -25,7 +49,47 @@ impl PipeIterator {
             stdin,
             stdout,
             buffer,
+            version: None,
+            error: None,
+        }
+    }
+
+    /// The serialization version of the records, detected from the first one. `None` before
+    /// the first record is read or if the stream is empty
+    pub fn version(&self) -> Option<u8> {
+        self.version
+    }
+
+    /// The error which ended the iteration, if any
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    fn read_next(&mut self) -> Result<Option<BlockExtra>, Error> {
+        let mut version = [0u8];
+        loop {
+            match self.stdin.read(&mut version) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
         }
+        let found = version[0];
+        match self.version {
+            None if found > 2 => return Err(Error::UnsupportedSerializationVersion(found)),
+            None => self.version = Some(found),
+            Some(expected) if expected != found => {
+                return Err(Error::MixedSerializationVersions { expected, found })
+            }
+            Some(_) => (),
+        }
+
+        // the version byte is already consumed, give it back to the decoder
+        let mut reader = FromStd::new((&version[..]).chain(&mut self.stdin));
+        let block_extra = BlockExtra::consensus_decode(&mut reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Some(block_extra))
     }
 }
 
@@ -33,7 +97,17 @@ impl Iterator for PipeIterator {
     type Item = BlockExtra;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let block_extra = BlockExtra::consensus_decode(&mut self.stdin).ok()?;
+        if self.error.is_some() {
+            return None;
+        }
+        let block_extra = match self.read_next() {
+            Ok(block_extra) => block_extra?,
+            Err(e) => {
+                log::error!("{}", e);
+                self.error = Some(e);
+                return None;
+            }
+        };
 
         if let Some(stdout) = self.stdout.as_mut() {
             // using StreamReader we can't send received bytes directly to stdout, thus we need to
@@ -47,3 +121,317 @@ impl Iterator for PipeIterator {
         Some(block_extra)
     }
 }
+
+/// Records claiming to be bigger than this are considered corrupted
+const MAX_RECORD_SIZE: usize = 32 * 1024 * 1024;
+
+/// Bytes read from the underlying reader at once
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// Return an Iterator of the [`BlockExtra`] serialized one after the other in `reader`, like the
+/// output of the cli.
+///
+/// Unlike [`PipeIterator`], a corrupted record is reported with an [`Error::CorruptRecord`] item
+/// and the reading continues with the next record found: every following position starting with
+/// a serialization version byte is tried, until a record is decoded and its block hash matches its
+/// header. A truncated last record is reported the same way. The iteration ends after an error
+/// reading from `reader`, reported as [`Error::Io`]
+pub fn read_block_extras<R: Read>(reader: R) -> impl Iterator<Item = Result<BlockExtra, Error>> {
+    BlockExtraReader {
+        reader,
+        buffer: Vec::new(),
+        pos: 0,
+        offset: 0,
+        eof: false,
+        resync: false,
+    }
+}
+
+/// Return an Iterator of the [`BlockExtra`] exported in `reader`, like a file written by the cli,
+/// to run the analysis written for [`crate::iter`] without reading the block files again:
+///
+/// ```no_run
+/// let export = std::fs::File::open("blocks.dat").unwrap();
+/// let txs: usize = blocks_iterator::iter_from_export(export)
+///     .map(|b| b.txids().len())
+///     .sum();
+/// ```
+///
+/// With the `compression` feature a compressed export is decompressed. Corrupted records are
+/// logged and skipped and the iteration ends at the first error reading `reader`, use
+/// [`read_block_extras`] to handle them
+pub fn iter_from_export<R: Read + Send + 'static>(reader: R) -> impl Iterator<Item = BlockExtra> {
+    #[cfg(feature = "compression")]
+    let reader = crate::decoder(reader).unwrap_or_else(|e| {
+        log::error!("cannot read export: {}", e);
+        Box::new(io::empty())
+    });
+    read_block_extras(reader).filter_map(|record| match record {
+        Ok(block_extra) => Some(block_extra),
+        Err(e) => {
+            log::error!("{}", e);
+            None
+        }
+    })
+}
+
+struct BlockExtraReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+
+    /// Position in `buffer` of the next record
+    pos: usize,
+
+    /// Position in the stream of the start of `buffer`
+    offset: u64,
+
+    eof: bool,
+
+    /// Searching the next record after a corrupted one
+    resync: bool,
+}
+
+impl<R: Read> BlockExtraReader<R> {
+    /// Read another chunk, dropping the consumed bytes from `buffer`
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.drain(..self.pos);
+        self.offset += self.pos as u64;
+        self.pos = 0;
+        let len = self.buffer.len();
+        self.buffer.resize(len + READ_CHUNK, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => break r,
+            }
+        };
+        self.buffer.truncate(len + *read.as_ref().unwrap_or(&0));
+        self.eof = matches!(read, Ok(0));
+        read.map(|_| ())
+    }
+}
+
+impl<R: Read> Iterator for BlockExtraReader<R> {
+    type Item = Result<BlockExtra, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let available = &self.buffer[self.pos..];
+            if available.is_empty() {
+                if self.eof {
+                    return None;
+                }
+                if let Err(e) = self.fill() {
+                    self.eof = true;
+                    self.buffer.clear();
+                    self.pos = 0;
+                    return Some(Err(e.into()));
+                }
+                continue;
+            }
+            // records start with the serialization version, 0, 1 or 2
+            if self.resync && available[0] > 2 {
+                self.pos += 1;
+                continue;
+            }
+            let message = match decode(available) {
+                Ok((block_extra, len)) => {
+                    self.pos += len;
+                    self.resync = false;
+                    return Some(Ok(block_extra));
+                }
+                Err(None) if !self.eof && available.len() < MAX_RECORD_SIZE => {
+                    if let Err(e) = self.fill() {
+                        self.eof = true;
+                        self.buffer.clear();
+                        self.pos = 0;
+                        return Some(Err(e.into()));
+                    }
+                    continue;
+                }
+                Err(None) => "truncated record".to_string(),
+                Err(Some(message)) => message,
+            };
+            let offset = self.offset + self.pos as u64;
+            self.pos += 1;
+            if !self.resync {
+                self.resync = true;
+                return Some(Err(Error::CorruptRecord { offset, message }));
+            }
+        }
+    }
+}
+
+/// Decode the record at the start of `bytes`, the error is `None` if `bytes` ends before the end
+/// of the record
+fn decode(bytes: &[u8]) -> Result<(BlockExtra, usize), Option<String>> {
+    let (block_extra, len) = BlockExtra::from_consensus_bytes(bytes).map_err(|e| match e {
+        encode::Error::Io(e) if e.kind() == bitcoin::io::ErrorKind::UnexpectedEof => None,
+        e => Some(e.to_string()),
+    })?;
+    let header = bitcoin::block::Header::consensus_decode(&mut block_extra.block_bytes())
+        .map_err(|e| Some(e.to_string()))?;
+    if header.block_hash() != block_extra.block_hash() {
+        return Err(Some(format!(
+            "block hash {} doesn't match the header",
+            block_extra.block_hash()
+        )));
+    }
+    Ok((block_extra, len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{iter_from_export, read_block_extras, PipeIterator};
+    use crate::bitcoin::consensus::{deserialize, serialize};
+    use crate::inner_test::test_conf;
+    use crate::{iter, BlockExtra, Error};
+    use std::io::{Cursor, Write};
+    use test_log::test;
+
+    #[test]
+    fn test_read_block_extras() {
+        let mut conf = test_conf();
+        conf.stop_at_height = Some(20);
+        let blocks: Vec<_> = iter(conf).collect();
+        let records: Vec<_> = blocks.iter().map(serialize).collect();
+        let stream = records.concat();
+
+        // comparing the serialization since not serialized fields aren't read back
+        let read: Vec<_> = read_block_extras(Cursor::new(&stream))
+            .map(|r| serialize(&r.unwrap()))
+            .collect();
+        assert_eq!(read, records);
+
+        // corrupt the header of the record at height 10, thus its hash doesn't match
+        let start: usize = records[..10].iter().map(Vec::len).sum();
+        let mut corrupted = stream.clone();
+        corrupted[start + 16] ^= 1;
+        let read: Vec<_> = read_block_extras(Cursor::new(&corrupted)).collect();
+        assert_eq!(read.len(), blocks.len());
+        match &read[10] {
+            Err(Error::CorruptRecord { offset, .. }) => assert_eq!(*offset, start as u64),
+            r => panic!("unexpected {:?}", r),
+        }
+        for (i, r) in read.iter().enumerate().filter(|(i, _)| *i != 10) {
+            assert_eq!(serialize(r.as_ref().unwrap()), records[i]);
+        }
+
+        // a truncated stream
+        let truncated = &stream[..stream.len() - 10];
+        let read: Vec<_> = read_block_extras(Cursor::new(truncated)).collect();
+        assert_eq!(read.len(), blocks.len());
+        assert!(matches!(
+            read.last().unwrap(),
+            Err(Error::CorruptRecord { .. })
+        ));
+        let second_last = serialize(read[read.len() - 2].as_ref().unwrap());
+        assert_eq!(second_last, records[records.len() - 2]);
+    }
+
+    #[test]
+    fn test_resync_compact() {
+        let mut conf = test_conf();
+        conf.stop_at_height = Some(20);
+        conf.serialization_version = 2;
+        let records: Vec<_> = iter(conf).map(|b| serialize(&b)).collect();
+
+        let start: usize = records[..10].iter().map(Vec::len).sum();
+        let mut corrupted = records.concat();
+        corrupted[start + 16] ^= 1;
+        let read: Vec<_> = read_block_extras(Cursor::new(&corrupted)).collect();
+        assert_eq!(read.len(), records.len());
+        assert!(matches!(read[10], Err(Error::CorruptRecord { .. })));
+        for (i, r) in read.iter().enumerate().filter(|(i, _)| *i != 10) {
+            assert_eq!(serialize(r.as_ref().unwrap()), records[i]);
+        }
+
+        let heights: Vec<_> = iter_from_export(Cursor::new(corrupted))
+            .map(|b| b.height())
+            .collect();
+        let expected: Vec<_> = (0..=20).filter(|h| *h != 10).collect();
+        assert_eq!(heights, expected);
+    }
+
+    #[test]
+    fn test_iter_from_export() {
+        let mut conf = test_conf();
+        conf.stop_at_height = Some(20);
+        let blocks: Vec<_> = iter(conf).collect();
+        let records: Vec<_> = blocks.iter().map(serialize).collect();
+
+        let mut export = tempfile::NamedTempFile::new().unwrap();
+        export.write_all(&records.concat()).unwrap();
+        let read: Vec<_> = iter_from_export(std::fs::File::open(export.path()).unwrap())
+            .map(|b| serialize(&b))
+            .collect();
+        assert_eq!(read, records);
+
+        // the corrupted record is skipped
+        let start: usize = records[..10].iter().map(Vec::len).sum();
+        let mut corrupted = records.concat();
+        corrupted[start + 16] ^= 1;
+        let heights: Vec<_> = iter_from_export(Cursor::new(corrupted))
+            .map(|b| b.height())
+            .collect();
+        let expected: Vec<_> = (0..=20).filter(|h| *h != 10).collect();
+        assert_eq!(heights, expected);
+
+        #[cfg(feature = "compression")]
+        {
+            let mut encoder = crate::Compression::Zstd.encoder(vec![]).unwrap();
+            encoder.write_all(&records.concat()).unwrap();
+            let compressed = encoder.finish().unwrap();
+            assert_eq!(
+                iter_from_export(Cursor::new(compressed)).count(),
+                blocks.len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_pipe_iterator_version() {
+        let mut conf = test_conf();
+        conf.stop_at_height = Some(5);
+        let blocks: Vec<_> = iter(conf).collect();
+        let records: Vec<_> = blocks.iter().map(serialize).collect();
+        let pipe = |stream: Vec<u8>| PipeIterator::from_reader(Box::new(Cursor::new(stream)), None);
+
+        let mut iter = pipe(records.concat());
+        assert_eq!(iter.version(), None);
+        assert_eq!(iter.by_ref().count(), blocks.len());
+        assert_eq!(iter.version(), Some(blocks[0].version()));
+        assert!(iter.error().is_none());
+
+        let mut iter = pipe(Vec::new());
+        assert_eq!(iter.next().map(|b| b.height()), None);
+        assert_eq!(iter.version(), None);
+        assert!(iter.error().is_none());
+
+        let mut iter = pipe(vec![3u8; 100]);
+        assert!(iter.next().is_none());
+        assert!(matches!(
+            iter.error(),
+            Some(Error::UnsupportedSerializationVersion(3))
+        ));
+
+        let mut other: BlockExtra = deserialize(&records[2]).unwrap();
+        other.version = 0;
+        let mut stream = records[..2].concat();
+        stream.extend(serialize(&other));
+        let mut iter = pipe(stream);
+        assert_eq!(iter.by_ref().count(), 2);
+        match iter.error() {
+            Some(Error::MixedSerializationVersions { expected, found }) => {
+                assert_eq!(*expected, blocks[0].version());
+                assert_eq!(*found, 0);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+        assert!(iter.next().is_none());
+
+        let mut iter = pipe(records.concat()[..records[0].len() - 1].to_vec());
+        assert!(iter.next().is_none());
+        assert!(matches!(iter.error(), Some(Error::Io(_))));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::BlockExtra;
+
+/// A declarative condition on the blocks to emit, see [`crate::Config::predicate`].
+///
+/// Conditions are combined with [`BlockPredicate::and()`] and [`BlockPredicate::or()`], for
+/// example blocks after height 100_000 with at least 1000 transactions:
+///
+/// ```
+/// use blocks_iterator::BlockPredicate;
+///
+/// let predicate = BlockPredicate::height(100_000, None).and(BlockPredicate::MinTxs(1000));
+/// ```
+///
+/// Blocks not matching are still processed, thus the UTXO set is complete, they are only not
+/// returned by the iteration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockPredicate {
+    /// The height is between `start` and `end`, included. No `end` means no upper bound
+    Height { start: u32, end: Option<u32> },
+
+    /// The header timestamp, in seconds since the Unix epoch, is between `start` and `end`,
+    /// included. No `end` means no upper bound
+    Time { start: u32, end: Option<u32> },
+
+    /// The block has at least this number of transactions, coinbase included
+    MinTxs(usize),
+
+    /// Any transaction in the block contains segwit data
+    HasWitness,
+
+    /// The block fee is at least this number of satoshi. Never matches without the previous
+    /// outputs, like with [`crate::Config::skip_prevout`]
+    MinFee(u64),
+
+    /// All the predicates match, true if empty
+    And(Vec<BlockPredicate>),
+
+    /// At least one of the predicates match, false if empty
+    Or(Vec<BlockPredicate>),
+}
+
+impl BlockPredicate {
+    /// Blocks with height between `start` and `end`, included
+    pub fn height(start: u32, end: Option<u32>) -> Self {
+        BlockPredicate::Height { start, end }
+    }
+
+    /// Blocks with timestamp between `start` and `end`, included
+    pub fn time(start: u32, end: Option<u32>) -> Self {
+        BlockPredicate::Time { start, end }
+    }
+
+    /// Blocks matching both `self` and `other`
+    pub fn and(self, other: BlockPredicate) -> Self {
+        match self {
+            BlockPredicate::And(mut predicates) => {
+                predicates.push(other);
+                BlockPredicate::And(predicates)
+            }
+            _ => BlockPredicate::And(vec![self, other]),
+        }
+    }
+
+    /// Blocks matching `self` or `other`
+    pub fn or(self, other: BlockPredicate) -> Self {
+        match self {
+            BlockPredicate::Or(mut predicates) => {
+                predicates.push(other);
+                BlockPredicate::Or(predicates)
+            }
+            _ => BlockPredicate::Or(vec![self, other]),
+        }
+    }
+
+    /// Returns true if `block_extra` satisfies the predicate. Conditions are evaluated in order
+    /// and the evaluation stops as soon as the result is known, thus cheap conditions like the
+    /// height should come first
+    pub fn matches(&self, block_extra: &BlockExtra) -> bool {
+        match self {
+            BlockPredicate::Height { start, end } => in_range(block_extra.height, *start, *end),
+            BlockPredicate::Time { start, end } => in_range(block_extra.time(), *start, *end),
+            BlockPredicate::MinTxs(min) => block_extra.block_total_txs >= *min,
+            BlockPredicate::HasWitness => block_extra.has_witness(),
+            BlockPredicate::MinFee(min) => block_extra.fee().map_or(false, |fee| fee >= *min),
+            BlockPredicate::And(predicates) => predicates.iter().all(|p| p.matches(block_extra)),
+            BlockPredicate::Or(predicates) => predicates.iter().any(|p| p.matches(block_extra)),
+        }
+    }
+}
+
+fn in_range(value: u32, start: u32, end: Option<u32>) -> bool {
+    value >= start && end.map_or(true, |end| value <= end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::BlockPredicate;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use test_log::test;
+
+    #[test]
+    fn test_predicate() {
+        let all: Vec<_> = iter(test_conf()).collect();
+        let check = |predicate: BlockPredicate| {
+            let mut conf = test_conf();
+            conf.predicate = Some(predicate.clone());
+            let expected: Vec<_> = all
+                .iter()
+                .filter(|b| predicate.matches(b))
+                .map(|b| b.height())
+                .collect();
+            let heights: Vec<_> = iter(conf).map(|b| b.height()).collect();
+            assert_eq!(heights, expected);
+            heights
+        };
+
+        assert_eq!(
+            check(BlockPredicate::height(10, Some(12))),
+            vec![10, 11, 12]
+        );
+        let spending = check(BlockPredicate::MinTxs(2));
+        assert!(!spending.is_empty() && spending.len() < all.len());
+        let with_fee = check(BlockPredicate::MinFee(1));
+        assert!(with_fee.iter().all(|h| spending.contains(h)));
+        assert_eq!(
+            check(BlockPredicate::MinTxs(2).or(BlockPredicate::height(0, Some(0))))[0],
+            0
+        );
+        let time = all[100].time();
+        let heights = check(
+            BlockPredicate::time(time, None)
+                .and(BlockPredicate::height(0, Some(200)))
+                .and(BlockPredicate::HasWitness.or(BlockPredicate::MinTxs(1))),
+        );
+        assert!(heights.contains(&100) && heights.iter().all(|h| *h <= 200));
+        assert!(check(BlockPredicate::HasWitness).is_empty());
+        assert!(check(BlockPredicate::Or(vec![])).is_empty());
+        assert_eq!(check(BlockPredicate::And(vec![])).len(), all.len());
+
+        // without prevouts the fee is unknown
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        conf.predicate = Some(BlockPredicate::MinTxs(2));
+        assert_eq!(iter(conf.clone()).count(), spending.len());
+        conf.predicate = Some(BlockPredicate::MinFee(0));
+        assert_eq!(iter(conf).count(), 0);
+    }
+}
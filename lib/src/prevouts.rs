@@ -0,0 +1,202 @@
+use crate::bitcoin::consensus::encode;
+use crate::bitcoin::consensus::{Decodable, Encodable};
+use crate::bitcoin::io::FromStd;
+use crate::bitcoin::{Block, TxOut};
+use crate::format::MAX_PREALLOCATED;
+use crate::{BlockExtra, Error};
+use std::io::{self, BufRead, BufReader, Read};
+
+/// The previous outputs spent by a block, in inputs order and excluding the coinbase, like the
+/// undo data of Bitcoin Core. Written by the cli with [`crate::Config::prevouts_sidecar`] next to
+/// the bare blocks, see [`zip_prevouts`] to read them back together.
+///
+/// Serialized as the height and the number of prevouts as u32, followed by the prevouts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrevoutsRecord {
+    /// Height of the block
+    pub height: u32,
+
+    /// The outputs spent by the inputs of the block, empty if the previous outputs weren't
+    /// computed, like with `skip_prevout`
+    pub prevouts: Vec<TxOut>,
+}
+
+impl From<&BlockExtra> for PrevoutsRecord {
+    fn from(block_extra: &BlockExtra) -> Self {
+        PrevoutsRecord {
+            height: block_extra.height(),
+            prevouts: block_extra
+                .real_prevouts()
+                .map(|(_, prevout)| prevout.clone())
+                .collect(),
+        }
+    }
+}
+
+impl Encodable for PrevoutsRecord {
+    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let mut written = self.height.consensus_encode(writer)?;
+        written += (self.prevouts.len() as u32).consensus_encode(writer)?;
+        for prevout in self.prevouts.iter() {
+            written += prevout.consensus_encode(writer)?;
+        }
+        Ok(written)
+    }
+}
+
+impl Decodable for PrevoutsRecord {
+    fn consensus_decode<D: bitcoin::io::Read + ?Sized>(d: &mut D) -> Result<Self, encode::Error> {
+        let height = Decodable::consensus_decode(d)?;
+        let len = u32::consensus_decode(d)?;
+        let mut prevouts = Vec::with_capacity((len as usize).min(MAX_PREALLOCATED));
+        for _ in 0..len {
+            prevouts.push(Decodable::consensus_decode(d)?);
+        }
+        Ok(PrevoutsRecord { height, prevouts })
+    }
+}
+
+/// Iterator of the [`PrevoutsRecord`] serialized one after the other in a reader, like the sidecar
+/// written by the cli with [`crate::Config::prevouts_sidecar`].
+///
+/// The iteration ends after the first error
+pub struct PrevoutsReader<R: Read> {
+    reader: FromStd<BufReader<R>>,
+    failed: bool,
+}
+
+impl<R: Read> PrevoutsReader<R> {
+    /// Creates a reader of the records in `reader`
+    pub fn new(reader: R) -> Self {
+        PrevoutsReader {
+            reader: FromStd::new(BufReader::new(reader)),
+            failed: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for PrevoutsReader<R> {
+    type Item = Result<PrevoutsRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = decode_next(&mut self.reader);
+        self.failed = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+/// Returns an Iterator of the bare blocks serialized one after the other in `blocks`, each one
+/// together with its [`PrevoutsRecord`] read from `prevouts`, as written by the cli with
+/// [`crate::Config::prevouts_sidecar`].
+///
+/// A record must have as many prevouts as the inputs of its block, excluding the coinbase, or
+/// none. An error is returned otherwise, or if one of the streams ends before the other, and the
+/// iteration ends
+pub fn zip_prevouts<B: Read, P: Read>(
+    blocks: B,
+    prevouts: P,
+) -> impl Iterator<Item = Result<(Block, PrevoutsRecord), Error>> {
+    let mut blocks = FromStd::new(BufReader::new(blocks));
+    let mut prevouts = PrevoutsReader::new(prevouts);
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let result = match (decode_next::<Block, _>(&mut blocks), prevouts.next()) {
+            (None, None) => return None,
+            (Some(Ok(block)), Some(Ok(record))) => {
+                let inputs: usize = block.txdata.iter().skip(1).map(|tx| tx.input.len()).sum();
+                if record.prevouts.is_empty() || record.prevouts.len() == inputs {
+                    Ok((block, record))
+                } else {
+                    Err(invalid_data(format!(
+                        "block {} has {} inputs but {} prevouts",
+                        record.height,
+                        inputs,
+                        record.prevouts.len()
+                    )))
+                }
+            }
+            (Some(Err(e)), _) | (_, Some(Err(e))) => Err(e),
+            (Some(_), None) => Err(invalid_data("prevouts ended before blocks".to_string())),
+            (None, Some(_)) => Err(invalid_data("blocks ended before prevouts".to_string())),
+        };
+        failed = result.is_err();
+        Some(result)
+    })
+}
+
+/// Decodes the next `T` in `reader`, `None` if `reader` is at its end
+fn decode_next<T: Decodable, R: Read>(
+    reader: &mut FromStd<BufReader<R>>,
+) -> Option<Result<T, Error>> {
+    match reader.inner_mut().fill_buf() {
+        Ok([]) => return None,
+        Ok(_) => (),
+        Err(e) => return Some(Err(e.into())),
+    }
+    Some(T::consensus_decode(reader).map_err(|e| invalid_data(e.to_string())))
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{zip_prevouts, PrevoutsReader, PrevoutsRecord};
+    use crate::bitcoin::consensus::Encodable;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use std::io::Cursor;
+    use test_log::test;
+
+    #[test]
+    fn test_zip_prevouts() {
+        let mut blocks = vec![];
+        let mut prevouts = vec![];
+        let mut expected = vec![];
+        for block_extra in iter(test_conf()) {
+            blocks.extend_from_slice(block_extra.block_bytes());
+            let record = PrevoutsRecord::from(&block_extra);
+            assert_eq!(record.prevouts.len(), block_extra.block_total_inputs() - 1);
+            record.consensus_encode(&mut prevouts).unwrap();
+            expected.push((block_extra.block().clone(), record));
+        }
+
+        let records: Vec<_> = PrevoutsReader::new(Cursor::new(&prevouts))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records.len(), expected.len());
+        let zipped: Vec<_> = zip_prevouts(Cursor::new(&blocks), Cursor::new(&prevouts))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(zipped, expected);
+
+        // the records of a block with inputs are missing, the following are misaligned
+        let spending = expected
+            .iter()
+            .position(|(b, _)| b.txdata.len() > 1)
+            .unwrap();
+        let mut misaligned = vec![];
+        for (_, record) in expected.iter().skip(1) {
+            record.consensus_encode(&mut misaligned).unwrap();
+        }
+        let results: Vec<_> =
+            zip_prevouts(Cursor::new(&blocks), Cursor::new(&misaligned)).collect();
+        assert!(results.len() <= spending + 1);
+        assert!(results.last().unwrap().is_err());
+
+        let truncated = &prevouts[..prevouts.len() - 1];
+        let results: Vec<_> = zip_prevouts(Cursor::new(&blocks), Cursor::new(truncated)).collect();
+        assert_eq!(results.len(), expected.len());
+        assert!(results.last().unwrap().is_err());
+    }
+}
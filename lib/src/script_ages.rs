@@ -0,0 +1,145 @@
+use crate::bitcoin::hashes::{sha256, Hash};
+use crate::bitcoin::Script;
+use crate::BlockExtra;
+use fxhash::FxHashMap;
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+/// The first and last height at which every output script appeared, built when
+/// [`crate::Config::track_script_ages`] is set and returned by
+/// [`crate::BlockExtraIterator::take_script_ages()`].
+///
+/// Scripts are keyed by the first 16 bytes of their sha256, see [`ScriptAges::key`], and provably
+/// unspendable `OP_RETURN` outputs are excluded. The whole index is kept in memory, roughly 40
+/// bytes per distinct script, tens of gigabytes for mainnet. To bound it, take the index
+/// periodically during the iteration, each one covering the blocks since the previous take, and
+/// stream it out with [`ScriptAges::write_to`], merging the files later
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptAges {
+    ages: FxHashMap<u128, (u32, u32)>,
+}
+
+impl ScriptAges {
+    /// Record the output scripts of `block_extra`
+    pub(crate) fn add(&mut self, block_extra: &BlockExtra) {
+        let height = block_extra.height();
+        for tx in block_extra.block().txdata.iter() {
+            for output in tx.output.iter() {
+                if output.script_pubkey.is_op_return() {
+                    continue;
+                }
+                self.ages
+                    .entry(Self::key(&output.script_pubkey))
+                    .and_modify(|(_, last)| *last = height)
+                    .or_insert((height, height));
+            }
+        }
+    }
+
+    /// The key of `script` in the index, the first 16 bytes of its sha256
+    pub fn key(script: &Script) -> u128 {
+        let hash = sha256::Hash::hash(script.as_bytes());
+        u128::from_le_bytes(hash[..16].try_into().expect("16 bytes"))
+    }
+
+    /// Returns the first and last height at which `script` appeared in an output
+    pub fn get(&self, script: &Script) -> Option<(u32, u32)> {
+        self.ages.get(&Self::key(script)).copied()
+    }
+
+    /// Number of distinct scripts
+    pub fn len(&self) -> usize {
+        self.ages.len()
+    }
+
+    /// Returns true if no script has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.ages.is_empty()
+    }
+
+    /// Iterate the `(key, first height, last height)` of the scripts, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (u128, u32, u32)> + '_ {
+        self.ages
+            .iter()
+            .map(|(key, (first, last))| (*key, *first, *last))
+    }
+
+    /// Write a line `<key in hex> <first height> <last height>` for every script, in no particular
+    /// order
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (key, first, last) in self.iter() {
+            writeln!(writer, "{:032x} {} {}", key, first, last)?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for ScriptAges {
+    type Item = (u128, u32, u32);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item>>;
+
+    /// Consume the index, returning the `(key, first height, last height)` of the scripts
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(
+            self.ages
+                .into_iter()
+                .map(|(key, (first, last))| (key, first, last)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ScriptAges;
+    use crate::inner_test::test_conf;
+    use crate::{iter, try_iter};
+    use std::collections::HashMap;
+    use test_log::test;
+
+    #[test]
+    fn test_script_ages() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let mut expected = HashMap::new();
+        for block_extra in iter(conf.clone()) {
+            for (_, tx) in block_extra.iter_tx() {
+                for output in tx.output.iter().filter(|o| !o.script_pubkey.is_op_return()) {
+                    expected
+                        .entry(output.script_pubkey.clone())
+                        .and_modify(|(_, last)| *last = block_extra.height())
+                        .or_insert((block_extra.height(), block_extra.height()));
+                }
+            }
+        }
+
+        let mut blocks = try_iter(conf.clone());
+        assert!(blocks.take_script_ages().is_none());
+        blocks.by_ref().count();
+        assert!(blocks.take_script_ages().is_none(), "not tracked");
+
+        conf.track_script_ages = true;
+        let mut blocks = try_iter(conf);
+        blocks.by_ref().count();
+        let ages = blocks.take_script_ages().unwrap();
+        assert!(blocks.take_script_ages().is_none());
+        assert_eq!(ages.len(), expected.len());
+        for (script, first_last) in expected.iter() {
+            assert_eq!(ages.get(script), Some(*first_last));
+        }
+        assert!(expected.values().any(|(first, last)| first != last));
+
+        let mut written = vec![];
+        ages.write_to(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert_eq!(written.lines().count(), ages.len());
+        let (script, (first, last)) = expected.iter().next().unwrap();
+        let line = format!("{:032x} {} {}", ScriptAges::key(script), first, last);
+        assert!(written.lines().any(|l| l == line));
+
+        let mut iterated: Vec<_> = ages.iter().collect();
+        let mut consumed: Vec<_> = ages.into_iter().collect();
+        iterated.sort();
+        consumed.sort();
+        assert_eq!(iterated, consumed);
+    }
+}
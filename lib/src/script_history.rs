@@ -0,0 +1,106 @@
+use crate::bitcoin::{ScriptBuf, Txid};
+use crate::{iter, BlockExtra, Config};
+
+/// A transaction creating or spending an output with `script`, returned by
+/// [`index_script_history`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptHistoryEntry {
+    /// The script of the output created or spent
+    pub script: ScriptBuf,
+
+    /// Id of the transaction creating or spending the output
+    pub txid: Txid,
+
+    /// Height of the block containing the transaction
+    pub height: u32,
+
+    /// True if the transaction spends an output with `script`, false if it creates one
+    pub is_spend: bool,
+}
+
+/// Return an Iterator of the [`ScriptHistoryEntry`] of the blocks iterated with `config`, the
+/// primitive to build an address index like the ones of electrum servers or block explorers.
+///
+/// Every transaction yields one entry per spent output, then one per created output, provably
+/// unspendable `OP_RETURN` outputs excluded. Spends are known only when prevouts are computed,
+/// thus with `skip_prevout` only creations are returned. Entries are streamed block by block,
+/// nothing is indexed in memory.
+pub fn index_script_history(config: Config) -> impl Iterator<Item = ScriptHistoryEntry> {
+    iter(config).flat_map(|block_extra| block_script_history(&block_extra))
+}
+
+fn block_script_history(block_extra: &BlockExtra) -> Vec<ScriptHistoryEntry> {
+    let mut entries =
+        Vec::with_capacity(block_extra.block_total_inputs() + block_extra.block_total_outputs());
+    let outpoint_values = block_extra.outpoint_values();
+    let txids = block_extra.txids_or_compute();
+    for (txid, tx) in txids.iter().zip(block_extra.block().txdata.iter()) {
+        let entry = |script: &ScriptBuf, is_spend| ScriptHistoryEntry {
+            script: script.clone(),
+            txid: *txid,
+            height: block_extra.height,
+            is_spend,
+        };
+        if !tx.is_coinbase() {
+            for input in tx.input.iter() {
+                if let Some(prevout) = outpoint_values.get(&input.previous_output) {
+                    entries.push(entry(&prevout.script_pubkey, true));
+                }
+            }
+        }
+        for output in tx.output.iter() {
+            if !output.script_pubkey.is_op_return() {
+                entries.push(entry(&output.script_pubkey, false));
+            }
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::index_script_history;
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use std::collections::HashMap;
+    use test_log::test;
+
+    #[test]
+    fn test_index_script_history() {
+        let mut created = 0;
+        let mut spent = 0;
+        for block_extra in iter(test_conf()) {
+            for (_, tx) in block_extra.iter_tx() {
+                created += tx
+                    .output
+                    .iter()
+                    .filter(|o| !o.script_pubkey.is_op_return())
+                    .count();
+                if !tx.is_coinbase() {
+                    spent += tx.input.len();
+                }
+            }
+        }
+        assert!(spent > 0);
+
+        // every spend matches the creation of the same script at a lower height
+        let mut scripts = HashMap::new();
+        let mut entries = 0;
+        for entry in index_script_history(test_conf()) {
+            entries += 1;
+            if entry.is_spend {
+                let created_at = scripts.get(&entry.script).unwrap();
+                assert!(*created_at <= entry.height);
+            } else {
+                scripts.entry(entry.script).or_insert(entry.height);
+            }
+        }
+        assert_eq!(entries, created + spent);
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let history: Vec<_> = index_script_history(conf).collect();
+        assert_eq!(history.len(), created);
+        assert!(history.iter().all(|e| !e.is_spend));
+    }
+}
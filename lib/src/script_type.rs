@@ -0,0 +1,102 @@
+use crate::bitcoin::Script;
+
+/// The category of a script pubkey, stored in one byte. It extends the categories of the scripts
+/// kept on the stack by the in-memory utxo set with the ones having a standard template
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ScriptType {
+    /// Pay to public key, compressed or uncompressed
+    P2pk = 0,
+
+    /// Pay to public key hash
+    P2pkh = 1,
+
+    /// Pay to script hash
+    P2sh = 2,
+
+    /// Pay to witness public key hash, segwit v0
+    P2wpkh = 3,
+
+    /// Pay to witness script hash, segwit v0
+    P2wsh = 4,
+
+    /// Pay to taproot, segwit v1
+    P2tr = 5,
+
+    /// Provably unspendable, starting with `OP_RETURN`
+    OpReturn = 6,
+
+    /// Any other script, bare multisig included
+    Other = 7,
+}
+
+impl ScriptType {
+    /// Returns the category of `script`
+    pub fn of(script: &Script) -> Self {
+        if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2tr() {
+            ScriptType::P2tr
+        } else if script.is_p2pk() {
+            ScriptType::P2pk
+        } else if script.is_op_return() {
+            ScriptType::OpReturn
+        } else {
+            ScriptType::Other
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ScriptType;
+    use crate::bitcoin::hashes::Hash;
+    use crate::bitcoin::key::{Secp256k1, UntweakedPublicKey};
+    use crate::bitcoin::{PubkeyHash, PublicKey, ScriptBuf, ScriptHash, WPubkeyHash, WScriptHash};
+    use std::str::FromStr;
+    use test_log::test;
+
+    #[test]
+    fn test_script_type() {
+        let key = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let secp = Secp256k1::verification_only();
+        let scripts = [
+            (ScriptBuf::new_p2pk(&key), ScriptType::P2pk),
+            (
+                ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros()),
+                ScriptType::P2pkh,
+            ),
+            (
+                ScriptBuf::new_p2sh(&ScriptHash::all_zeros()),
+                ScriptType::P2sh,
+            ),
+            (
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+                ScriptType::P2wpkh,
+            ),
+            (
+                ScriptBuf::new_p2wsh(&WScriptHash::all_zeros()),
+                ScriptType::P2wsh,
+            ),
+            (
+                ScriptBuf::new_p2tr(&secp, UntweakedPublicKey::from(key.inner), None),
+                ScriptType::P2tr,
+            ),
+            (ScriptBuf::new_op_return([1u8; 4]), ScriptType::OpReturn),
+            (ScriptBuf::new(), ScriptType::Other),
+        ];
+        for (script, expected) in scripts.iter() {
+            assert_eq!(ScriptType::of(script), *expected, "{}", script);
+        }
+        assert_eq!(std::mem::size_of::<ScriptType>(), 1);
+    }
+}
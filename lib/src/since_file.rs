@@ -0,0 +1,299 @@
+use crate::bitcoin::consensus::{encode, Decodable, Encodable};
+use crate::bitcoin::BlockHash;
+use crate::{BlockFile, FsBlock};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Size and modification time of a block file, used to know if it changed since the last run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileMark {
+    len: u64,
+    modified: u64,
+}
+
+impl FileMark {
+    /// Returns the mark of the local file at `path`
+    pub(crate) fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(FileMark {
+            len: metadata.len(),
+            modified: modified.as_secs(),
+        })
+    }
+}
+
+/// A block detected in a previous run but not emitted yet, because it didn't have enough
+/// following blocks or it's in a fork
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PendingBlock {
+    pub(crate) name: String,
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    pub(crate) hash: BlockHash,
+    pub(crate) prev: BlockHash,
+    pub(crate) inputs: u32,
+    pub(crate) outputs: u32,
+    pub(crate) txs: u32,
+}
+
+impl PendingBlock {
+    /// Returns `None` for blocks not read from a block file
+    pub(crate) fn from_fs_block(fs_block: &FsBlock) -> Option<Self> {
+        let name = match &fs_block.path {
+            Some(path) => path.file_name()?.to_str()?.to_string(),
+            None => match &*fs_block.file.lock().ok()? {
+                BlockFile::Source { name, .. } => name.clone(),
+                _ => return None,
+            },
+        };
+        Some(PendingBlock {
+            name,
+            start: fs_block.start as u64,
+            end: fs_block.end as u64,
+            hash: fs_block.hash,
+            prev: fs_block.prev,
+            inputs: fs_block.block_total_inputs,
+            outputs: fs_block.block_total_outputs,
+            txs: fs_block.block_total_txs,
+        })
+    }
+}
+
+/// The state persisted in [`crate::Config::since_file_state`] between runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SinceFileState {
+    /// Files completely scanned, with their mark at the time of the scan
+    pub(crate) files: HashMap<String, FileMark>,
+
+    /// Keys of the blocks already sent to the reorder stage, see `Seen` in the read stage
+    pub(crate) seen: HashSet<[u8; 12]>,
+
+    /// Hash of the next block to emit
+    pub(crate) next: BlockHash,
+
+    /// Height of the next block to emit
+    pub(crate) height: u32,
+
+    /// Blocks sent to the reorder stage but not emitted
+    pub(crate) pending: Vec<PendingBlock>,
+}
+
+impl SinceFileState {
+    fn new(genesis: BlockHash) -> Self {
+        SinceFileState {
+            files: HashMap::new(),
+            seen: HashSet::new(),
+            next: genesis,
+            height: 0,
+            pending: vec![],
+        }
+    }
+
+    /// Load the state from `path`, or starts from `genesis` if it doesn't exist
+    pub(crate) fn load(path: &Path, genesis: BlockHash) -> Result<Self, encode::Error> {
+        match File::open(path) {
+            Ok(file) => Self::consensus_decode(&mut BufReader::new(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new(genesis)),
+            Err(e) => Err(encode::Error::Io(e.into())),
+        }
+    }
+
+    /// Save the state at `path`, writing a temporary file first to not leave a truncated state
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        self.consensus_encode(&mut writer)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(tmp, path)
+    }
+}
+
+impl Encodable for SinceFileState {
+    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let mut written = (self.files.len() as u32).consensus_encode(writer)?;
+        for (name, mark) in self.files.iter() {
+            written += name.consensus_encode(writer)?;
+            written += mark.len.consensus_encode(writer)?;
+            written += mark.modified.consensus_encode(writer)?;
+        }
+        written += (self.seen.len() as u32).consensus_encode(writer)?;
+        for key in self.seen.iter() {
+            writer.write_all(&key[..])?;
+            written += key.len();
+        }
+        written += self.next.consensus_encode(writer)?;
+        written += self.height.consensus_encode(writer)?;
+        written += (self.pending.len() as u32).consensus_encode(writer)?;
+        for pending in self.pending.iter() {
+            written += pending.name.consensus_encode(writer)?;
+            written += pending.start.consensus_encode(writer)?;
+            written += pending.end.consensus_encode(writer)?;
+            written += pending.hash.consensus_encode(writer)?;
+            written += pending.prev.consensus_encode(writer)?;
+            written += pending.inputs.consensus_encode(writer)?;
+            written += pending.outputs.consensus_encode(writer)?;
+            written += pending.txs.consensus_encode(writer)?;
+        }
+        Ok(written)
+    }
+}
+
+impl Decodable for SinceFileState {
+    fn consensus_decode<D: bitcoin::io::Read + ?Sized>(d: &mut D) -> Result<Self, encode::Error> {
+        // lengths are not trusted to preallocate, a corrupt file would cause huge allocations
+        let files_len = u32::consensus_decode(d)?;
+        let mut files = HashMap::new();
+        for _ in 0..files_len {
+            let name = String::consensus_decode(d)?;
+            let mark = FileMark {
+                len: Decodable::consensus_decode(d)?,
+                modified: Decodable::consensus_decode(d)?,
+            };
+            files.insert(name, mark);
+        }
+        let seen_len = u32::consensus_decode(d)?;
+        let mut seen = HashSet::new();
+        for _ in 0..seen_len {
+            let mut key = [0u8; 12];
+            d.read_exact(&mut key)?;
+            seen.insert(key);
+        }
+        let next = Decodable::consensus_decode(d)?;
+        let height = Decodable::consensus_decode(d)?;
+        let pending_len = u32::consensus_decode(d)?;
+        let mut pending = vec![];
+        for _ in 0..pending_len {
+            pending.push(PendingBlock {
+                name: Decodable::consensus_decode(d)?,
+                start: Decodable::consensus_decode(d)?,
+                end: Decodable::consensus_decode(d)?,
+                hash: Decodable::consensus_decode(d)?,
+                prev: Decodable::consensus_decode(d)?,
+                inputs: Decodable::consensus_decode(d)?,
+                outputs: Decodable::consensus_decode(d)?,
+                txs: Decodable::consensus_decode(d)?,
+            });
+        }
+        Ok(SinceFileState {
+            files,
+            seen,
+            next,
+            height,
+            pending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SinceFileState;
+    use crate::inner_test::test_conf;
+    use crate::{iter, try_iter};
+    use std::convert::TryInto;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use test_log::test;
+
+    #[test]
+    fn test_since_file_state() {
+        let blocks = fs::read("../blocks/blk-testnet.dat").unwrap();
+
+        // find a block boundary around the middle of the file
+        let mut half = 0;
+        while half < blocks.len() / 2 {
+            let len = u32::from_le_bytes(blocks[half + 4..half + 8].try_into().unwrap());
+            half += 8 + len as usize;
+        }
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let blk = tempdir.path().join("blk00000.dat");
+        let state = tempdir.path().join("state");
+        fs::write(&blk, &blocks[..half]).unwrap();
+
+        let mut conf = test_conf();
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        conf.since_file_state = Some(state.clone());
+        conf.skip_prevout = true;
+
+        let first: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height, b.block_hash))
+            .collect();
+        assert!(!first.is_empty());
+        let loaded = SinceFileState::load(&state, conf.genesis_hash()).unwrap();
+        assert_eq!(loaded.height as usize, first.len());
+        assert_eq!(loaded.pending.len(), conf.max_reorg as usize);
+
+        // nothing changed, nothing emitted
+        assert_eq!(iter(conf.clone()).count(), 0);
+
+        OpenOptions::new()
+            .append(true)
+            .open(&blk)
+            .unwrap()
+            .write_all(&blocks[half..])
+            .unwrap();
+        let second: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height, b.block_hash))
+            .collect();
+
+        let mut full_conf = test_conf();
+        full_conf.skip_prevout = true;
+        let full: Vec<_> = iter(full_conf).map(|b| (b.height, b.block_hash)).collect();
+        assert_eq!([first, second].concat(), full);
+    }
+
+    #[test]
+    fn test_since_file_state_errors() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let blk = tempdir.path().join("blk00000.dat");
+        let state = tempdir.path().join("state");
+        fs::copy("../blocks/blk-testnet.dat", &blk).unwrap();
+
+        let mut conf = test_conf();
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        conf.since_file_state = Some(state.clone());
+        conf.skip_prevout = true;
+
+        fs::write(&state, b"corrupt").unwrap();
+        let mut blocks = try_iter(conf.clone());
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(matches!(
+            blocks.error(),
+            Some(crate::Error::InvalidSinceFileState { .. })
+        ));
+
+        fs::remove_file(&state).unwrap();
+        assert!(iter(conf.clone()).count() > 0);
+
+        // the file holding the pending blocks has been removed
+        fs::remove_file(&blk).unwrap();
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(matches!(blocks.error(), Some(crate::Error::Io(_))));
+    }
+
+    #[test]
+    fn test_since_file_state_prevouts_without_db() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut conf = test_conf();
+        conf.since_file_state = Some(tempdir.path().join("state"));
+        assert!(matches!(
+            conf.utxo_manager(),
+            Err(crate::Error::SinceFileStateWithoutDb)
+        ));
+    }
+}
@@ -1,51 +1,177 @@
-use crate::BlockExtra;
-use bitcoin::Txid;
+use crate::block_extra::{tx_witness_size, TxOffsetsVisitor};
+use crate::stages::{EmitFilter, Enrich};
+use crate::{BlockExtra, Error};
+use bitcoin::block::Header;
+use bitcoin::consensus::Decodable;
+use bitcoin::{Transaction, Txid, VarInt};
 use bitcoin_slices::bsl;
 use bitcoin_slices::Visit;
 use bitcoin_slices::Visitor;
-use log::info;
+use log::{debug, error, info};
+use std::borrow::Cow;
+use std::fmt;
 use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::time::Duration;
 use std::time::Instant;
 
+type VisitorFactory = dyn Fn() -> Box<dyn Visitor> + Send + Sync;
+
+/// A factory of [`Visitor`]s run over the bytes of the blocks inside the pipeline, see
+/// [`crate::Config::block_visitor`]
+#[derive(Clone)]
+pub struct BlockVisitor(Arc<VisitorFactory>);
+
+impl BlockVisitor {
+    /// Wrap the factory `f`, called once per block to create the visitor of that block.
+    ///
+    /// Visitors are created, run over the block bytes and dropped in the compute txids stage, one
+    /// block at a time in height order, concurrently with the following stages and the consumer
+    /// of the iteration. Only the blocks emitted according to the height options are visited,
+    /// before [`crate::Config::predicate`] and [`crate::Config::watch_scripts`] are applied.
+    ///
+    /// The pipeline doesn't read anything back from the visitor: results must be collected in
+    /// state shared with the factory, like an `Arc<Mutex<_>>` or a channel sender, usually when
+    /// the visitor is dropped at the end of the block
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn() -> Box<dyn Visitor> + Send + Sync + 'static,
+    {
+        BlockVisitor(Arc::new(f))
+    }
+
+    pub(crate) fn visit(&self, block_bytes: &[u8]) {
+        let mut visitor = (self.0)();
+        // an error means the visitor stopped the visit, the bytes are valid
+        let _ = bsl::Block::visit(block_bytes, &mut DynVisitor(visitor.as_mut()));
+    }
+}
+
+impl fmt::Debug for BlockVisitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BlockVisitor")
+    }
+}
+
+/// Forwards to a boxed visitor, the visit requires a sized one
+struct DynVisitor<'a>(&'a mut dyn Visitor);
+
+impl Visitor for DynVisitor<'_> {
+    fn visit_block_header(&mut self, header: &bsl::BlockHeader) -> ControlFlow<()> {
+        self.0.visit_block_header(header)
+    }
+    fn visit_block_begin(&mut self, total_transactions: usize) {
+        self.0.visit_block_begin(total_transactions)
+    }
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        self.0.visit_transaction(tx)
+    }
+    fn visit_tx_ins(&mut self, total_inputs: usize) {
+        self.0.visit_tx_ins(total_inputs)
+    }
+    fn visit_tx_in(&mut self, vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
+        self.0.visit_tx_in(vin, tx_in)
+    }
+    fn visit_tx_outs(&mut self, total_outputs: usize) {
+        self.0.visit_tx_outs(total_outputs)
+    }
+    fn visit_tx_out(&mut self, vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        self.0.visit_tx_out(vout, tx_out)
+    }
+    fn visit_witness(&mut self, vin: usize) -> ControlFlow<()> {
+        self.0.visit_witness(vin)
+    }
+    fn visit_witness_total_element(&mut self, witness_total: usize) {
+        self.0.visit_witness_total_element(witness_total)
+    }
+    fn visit_witness_element(&mut self, witness_i: usize, witness_element: &[u8]) {
+        self.0.visit_witness_element(witness_i, witness_element)
+    }
+    fn visit_witness_end(&mut self) {
+        self.0.visit_witness_end()
+    }
+}
+
 pub struct ComputeTxids {
-    join: Option<JoinHandle<()>>,
+    join: Option<JoinHandle<Result<(), Error>>>,
 }
 
 impl Drop for ComputeTxids {
     fn drop(&mut self) {
         if let Some(jh) = self.join.take() {
-            jh.join().expect("thread failed");
+            super::join("compute_txids", jh);
         }
     }
 }
 
 impl ComputeTxids {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         skip_prevout: bool,
-        start_at_height: u32,
+        emit_filter: EmitFilter,
+        compute_txids: bool,
+        compute_tx_offsets: bool,
+        strict_tx_parse: bool,
+        enrich: Option<Enrich>,
+        block_visitor: Option<BlockVisitor>,
         receiver: Receiver<Option<BlockExtra>>,
         sender: SyncSender<Option<BlockExtra>>,
+        consumer_gone: Arc<AtomicBool>,
     ) -> Self {
         Self {
             join: Some(std::thread::spawn(move || {
                 info!("starting compute tx ids");
                 let mut now = Instant::now();
                 let mut busy_time = Duration::default();
+                let mut result = Ok(());
                 loop {
                     busy_time += now.elapsed();
                     let received = receiver.recv().unwrap();
                     now = Instant::now();
                     match received {
                         Some(mut block_extra) => {
-                            if !skip_prevout || block_extra.height >= start_at_height {
-                                // always send if we are not skipping prevouts, otherwise only if height is enough
-                                block_extra.compute_txids();
+                            if strict_tx_parse {
+                                if let Err(e) = block_extra.check_tx_parse() {
+                                    error!("{}", e);
+                                    result = Err(e);
+                                    break;
+                                }
+                            }
+                            if !skip_prevout || emit_filter.is_emitted(block_extra.height) {
+                                // always send if we are not skipping prevouts, otherwise only if the block is emitted
+                                if compute_txids {
+                                    block_extra.compute_txids(compute_tx_offsets);
+                                }
+                                if let Some(block_visitor) = block_visitor.as_ref() {
+                                    if emit_filter.is_emitted(block_extra.height) {
+                                        block_visitor.visit(block_extra.block_bytes());
+                                    }
+                                }
+                                if skip_prevout && !emit_filter.matches(&block_extra) {
+                                    // this is the last stage, the block is complete
+                                    continue;
+                                }
+                                if let Some(enrich) = enrich.as_ref() {
+                                    enrich.apply(&mut block_extra);
+                                }
                                 busy_time += now.elapsed();
-                                sender.send(Some(block_extra)).unwrap();
+                                #[cfg(feature = "prometheus")]
+                                crate::metrics::set_busy(
+                                    crate::metrics::Stage::ComputeTxids,
+                                    busy_time.as_nanos(),
+                                );
+                                if sender.send(Some(block_extra)).is_err() {
+                                    debug!("compute tx ids receiver disconnected");
+                                    if skip_prevout {
+                                        // this is the last stage, the consumer is gone
+                                        consumer_gone.store(true, Ordering::Relaxed);
+                                    }
+                                    break;
+                                }
                                 now = Instant::now();
                             }
                         }
@@ -53,38 +179,291 @@ impl ComputeTxids {
                     }
                 }
                 info!("ending compute tx ids busy time: {:?}", busy_time,);
-                sender.send(None).expect("augment: cannot send none");
+                // fails if the receiver disconnected, there is nobody to notify
+                let _ = sender.send(None);
+                result
             })),
         }
     }
+
+    /// Waits the end of the stage, returning the error that stopped it, if any
+    pub fn join(mut self) -> Result<(), Error> {
+        match self.join.take() {
+            Some(jh) => super::join("compute_txids", jh).unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
 }
 
 impl BlockExtra {
-    fn compute_txids(&mut self) {
+    /// Computes the txids, and in the same pass the transactions offsets if `with_offsets`
+    pub(crate) fn compute_txids(&mut self, with_offsets: bool) {
         if !self.txids.is_empty() {
             return;
         }
 
-        let mut visitor = TxidsVisitor::new(); // TODO add tx_count to block_extra and use it as capacity
+        // the number of transactions is known since the block was detected
+        let mut visitor = TxidsVisitor::new(self.block_total_txs, with_offsets);
         bsl::Block::visit(self.block_bytes(), &mut visitor).expect("compute txids");
         self.txids = visitor.txids;
         self.block_total_txs = self.txids.len();
+        let _ = self.witness_size.set(visitor.witness_size);
+        if let Some(offsets) = visitor.offsets {
+            let _ = self.tx_offsets.set(offsets.offsets);
+        }
+    }
+
+    /// Decodes the transactions one by one, returning the position of the first one not parsing
+    pub(crate) fn check_tx_parse(&self) -> Result<(), Error> {
+        let failed = |index| Error::TxParseFailed {
+            height: self.height,
+            index,
+        };
+        let mut bytes = &self.block_bytes[Header::SIZE..];
+        let count = VarInt::consensus_decode(&mut bytes).map_err(|_| failed(0))?;
+        for index in 0..count.0 as usize {
+            Transaction::consensus_decode(&mut bytes).map_err(|_| failed(index))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the txids, computing them without storing if they aren't available because
+    /// [`crate::Config::compute_txids`] is disabled
+    pub(crate) fn txids_or_compute(&self) -> Cow<'_, [Txid]> {
+        if !self.txids.is_empty() {
+            return Cow::Borrowed(&self.txids);
+        }
+        let mut visitor = TxidsVisitor::new(self.block_total_txs, false);
+        bsl::Block::visit(self.block_bytes(), &mut visitor).expect("compute txids");
+        Cow::Owned(visitor.txids)
     }
 }
 
 struct TxidsVisitor {
     txids: Vec<Txid>,
+    witness_size: u32,
+    offsets: Option<TxOffsetsVisitor>,
 }
 
 impl TxidsVisitor {
-    fn new() -> Self {
-        Self { txids: vec![] }
+    fn new(tx_count: usize, with_offsets: bool) -> Self {
+        Self {
+            txids: Vec::with_capacity(tx_count),
+            witness_size: 0,
+            offsets: with_offsets.then(TxOffsetsVisitor::default),
+        }
     }
 }
 
 impl Visitor for TxidsVisitor {
+    fn visit_block_begin(&mut self, total_transactions: usize) {
+        if let Some(offsets) = self.offsets.as_mut() {
+            offsets.visit_block_begin(total_transactions);
+        }
+    }
+
     fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
         self.txids.push(tx.txid().into());
+        self.witness_size += tx_witness_size(tx);
+        if let Some(offsets) = self.offsets.as_mut() {
+            let _ = offsets.visit_transaction(tx);
+        }
         ControlFlow::Continue(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::bitcoin::blockdata::constants::genesis_block;
+    use crate::bitcoin::consensus::{deserialize, serialize, Decodable};
+    use crate::bitcoin::{Network, ScriptBuf, Transaction};
+    use crate::block_extra::test::tx;
+    use crate::inner_test::{child_block, test_conf};
+    use crate::{iter, try_iter, Config, Error};
+    use std::collections::HashSet;
+    use std::sync::OnceLock;
+    use test_log::test;
+
+    #[test]
+    fn test_txids_capacity() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let largest = iter(conf).max_by_key(|b| b.txids().len()).unwrap();
+        assert!(largest.txids().len() > 1);
+        assert_eq!(largest.txids.capacity(), largest.txids().len());
+    }
+
+    #[test]
+    fn test_skip_txids() {
+        let mut conf = test_conf();
+        let expected: Vec<_> = iter(conf.clone())
+            .map(|b| (b.block_hash, b.fee(), b.txids().len()))
+            .collect();
+        conf.compute_txids = false;
+        let mut count = 0;
+        for (b, (hash, fee, txs)) in iter(conf.clone()).zip(expected.iter()) {
+            assert_eq!(&b.block_hash, hash);
+            assert_eq!(&b.fee(), fee);
+            assert!(b.txids().is_empty());
+            assert_eq!(b.txids_or_compute().len(), *txs);
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+
+        conf.skip_prevout = true;
+        assert!(iter(conf).all(|b| b.txids().is_empty()));
+    }
+
+    #[test]
+    fn test_skip_txids_consumers() {
+        let with_txids = test_conf();
+        let mut without_txids = test_conf();
+        without_txids.compute_txids = false;
+
+        for (a, b) in iter(with_txids.clone()).zip(iter(without_txids.clone())) {
+            assert_eq!(a.txs_by_feerate(), b.txs_by_feerate());
+            assert_eq!(a.intra_block_spends(), b.intra_block_spends());
+            assert_eq!(a.rbf_signaling_txids(), b.rbf_signaling_txids());
+            if let Some(txid) = a.txids().get(1) {
+                assert_eq!(a.tx_with_inputs(txid), b.tx_with_inputs(txid));
+            }
+        }
+        assert_eq!(
+            crate::index_script_history(with_txids.clone()).collect::<Vec<_>>(),
+            crate::index_script_history(without_txids.clone()).collect::<Vec<_>>()
+        );
+
+        let script = iter(test_conf())
+            .find(|b| b.block_total_txs > 1)
+            .map(|b| b.block().txdata[1].output[0].script_pubkey.clone())
+            .unwrap();
+        let scripts: HashSet<ScriptBuf> = std::iter::once(script).collect();
+        let mut script_ages = vec![];
+        let mut hits = vec![];
+        for mut conf in [with_txids, without_txids] {
+            conf.track_script_ages = true;
+            conf.watch_scripts = Some(scripts.clone());
+            let mut blocks = try_iter(conf);
+            let watched: Vec<_> = blocks
+                .by_ref()
+                .flat_map(|b| b.watch_hits(&scripts))
+                .collect();
+            assert!(!watched.is_empty(), "no block dropped");
+            hits.push(watched);
+            script_ages.push(blocks.take_script_ages().unwrap());
+        }
+        assert_eq!(hits[0], hits[1]);
+        assert_eq!(script_ages[0], script_ages[1]);
+        assert!(!script_ages[0].is_empty());
+    }
+
+    #[test]
+    fn test_tx_offsets() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        conf.compute_tx_offsets = true;
+        let largest = iter(conf).max_by_key(|b| b.txids().len()).unwrap();
+        assert!(largest.tx_offsets.get().is_some());
+        let offsets = largest.tx_offsets();
+        assert_eq!(offsets.len(), largest.txids().len());
+        for (i, (txid, start, end)) in offsets.iter().enumerate() {
+            let tx: Transaction = deserialize(&largest.block_bytes()[*start..*end]).unwrap();
+            assert_eq!(tx.compute_txid(), *txid);
+            assert_eq!(tx, largest.block().txdata[i]);
+        }
+        assert_eq!(offsets.last().unwrap().2, largest.block_bytes().len());
+
+        // computed at first access otherwise
+        let mut be = largest;
+        be.tx_offsets = OnceLock::new();
+        assert_eq!(be.tx_offsets(), offsets);
+    }
+
+    #[test]
+    fn test_strict_tx_parse() {
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..10 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        // the second transaction of block 3 is larger than the maximum the `bitcoin` crate
+        // decodes, it's accepted when detecting the blocks but fails the strict decoding
+        let mut large = tx(vec![], 1, 1);
+        large.output[0].script_pubkey = ScriptBuf::from(vec![0x6a; 4_000_000]);
+        let invalid = serialize(&large);
+        assert!(Transaction::consensus_decode(&mut &invalid[..]).is_err());
+        let mut block_bytes = serialize(&chain[3].header);
+        block_bytes.push(2);
+        block_bytes.extend(serialize(&chain[3].txdata[0]));
+        block_bytes.extend(invalid);
+
+        let mut conf = Config::from_blocks(chain, Network::Regtest);
+        conf.blocks_source.as_mut().unwrap()[3] = block_bytes;
+        conf.skip_prevout = true;
+        assert_eq!(iter(conf.clone()).count(), 5);
+
+        conf.strict_tx_parse = true;
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 3);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::TxParseFailed {
+                height: 3,
+                index: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_block_visitor() {
+        use crate::bitcoin_slices::{bsl, Visitor};
+        use crate::BlockVisitor;
+        use std::ops::ControlFlow;
+        use std::sync::{Arc, Mutex};
+
+        /// Counts the transactions and the outputs of a block, added to the totals when dropped
+        struct Counter {
+            txs: usize,
+            outputs: usize,
+            totals: Arc<Mutex<Vec<(usize, usize)>>>,
+        }
+        impl Visitor for Counter {
+            fn visit_transaction(&mut self, _tx: &bsl::Transaction) -> ControlFlow<()> {
+                self.txs += 1;
+                ControlFlow::Continue(())
+            }
+            fn visit_tx_outs(&mut self, total_outputs: usize) {
+                self.outputs += total_outputs;
+            }
+        }
+        impl Drop for Counter {
+            fn drop(&mut self) {
+                self.totals.lock().unwrap().push((self.txs, self.outputs));
+            }
+        }
+
+        for skip_prevout in [false, true] {
+            let totals = Arc::new(Mutex::new(vec![]));
+            let mut conf = test_conf();
+            conf.skip_prevout = skip_prevout;
+            conf.start_at_height = 100;
+            let shared = totals.clone();
+            conf.block_visitor = Some(BlockVisitor::new(move || {
+                Box::new(Counter {
+                    txs: 0,
+                    outputs: 0,
+                    totals: shared.clone(),
+                })
+            }));
+            let expected: Vec<_> = iter(conf)
+                .map(|b| {
+                    (
+                        b.block_total_txs,
+                        b.block().txdata.iter().map(|tx| tx.output.len()).sum(),
+                    )
+                })
+                .collect();
+            assert!(expected.len() > 200);
+            assert_eq!(*totals.lock().unwrap(), expected);
+        }
+    }
+}
@@ -1,42 +1,88 @@
+use crate::coinbase_maturity::CoinbaseMaturity;
+use crate::stages::EmitFilter;
+use crate::undo::CoinHeights;
 use crate::utxo::UtxoStore;
-use crate::{BlockExtra, Periodic};
-use bitcoin::{OutPoint, ScriptBuf, TxOut};
-use log::{debug, info, trace};
+use crate::{BlockExtra, Error, Periodic};
+use bitcoin::{Amount, Block, OutPoint, ScriptBuf, TxOut};
+use log::{debug, error, info, trace};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+type EnrichFn = dyn Fn(&mut BlockExtra) + Send + Sync;
+
+/// A user callback modifying every block before it's emitted, see [`crate::Config::enrich`]
+#[derive(Clone)]
+pub struct Enrich(Arc<EnrichFn>);
+
+impl Enrich {
+    /// Wrap the callback `f`, which receives the blocks in height order, with the previous outputs
+    /// already populated unless `skip_prevout` is used. Data can be attached to the block with
+    /// [`BlockExtra::extra_mut()`].
+    ///
+    /// It runs in the last stage of the pipeline, concurrently with the consumer of the
+    /// iteration, thus slow callbacks slow down the whole iteration
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&mut BlockExtra) + Send + Sync + 'static,
+    {
+        Enrich(Arc::new(f))
+    }
+
+    pub(crate) fn apply(&self, block_extra: &mut BlockExtra) {
+        (self.0)(block_extra)
+    }
+}
+
+impl fmt::Debug for Enrich {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Enrich")
+    }
+}
+
 pub struct Fee {
-    join: Option<JoinHandle<()>>,
+    join: Option<JoinHandle<Result<(), Error>>>,
 }
 
 impl Drop for Fee {
     fn drop(&mut self) {
         if let Some(jh) = self.join.take() {
-            jh.join().expect("thread failed");
+            super::join("fee", jh);
         }
     }
 }
 
 impl Fee {
-    pub fn new<T: 'static + UtxoStore + Send>(
-        start_at_height: u32,
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<T: 'static + UtxoStore>(
+        emit_filter: EmitFilter,
+        parallel_threshold: Option<usize>,
+        enrich: Option<Enrich>,
+        mut coinbase_maturity: Option<CoinbaseMaturity>,
+        mut coin_heights: Option<CoinHeights>,
+        track_utxo_delta: bool,
+        insert_coinbase_sentinel: bool,
         receiver: Receiver<Option<BlockExtra>>,
         sender: SyncSender<Option<BlockExtra>>,
+        consumer_gone: Arc<AtomicBool>,
         mut utxo: T,
     ) -> Self {
         Self {
             join: Some(std::thread::spawn(move || {
                 info!(
                     "starting fee processer. start_at_height:{}",
-                    start_at_height
+                    emit_filter.start_at_height
                 );
                 let mut now = Instant::now();
                 let mut busy_time = 0u128;
                 let mut total_txs = 0u64;
                 let mut last_height = 0;
                 let mut periodic = Periodic::new(Duration::from_secs(60));
+                let mut result = Ok(());
                 loop {
                     busy_time += now.elapsed().as_nanos();
                     let received = receiver.recv().unwrap();
@@ -45,35 +91,101 @@ impl Fee {
                         Some(mut block_extra) => {
                             last_height = block_extra.height;
                             trace!("fee received: {}", block_extra.block_hash);
-                            total_txs += block_extra.txids().len() as u64;
+                            total_txs += block_extra.block_total_txs as u64;
 
+                            if let Some(populated) = utxo
+                                .replay_up_to_height()
+                                .filter(|populated| block_extra.height as i32 > *populated)
+                            {
+                                let e = Error::ReplayNotPopulated {
+                                    populated,
+                                    required: block_extra.height,
+                                };
+                                error!("{}", e);
+                                result = Err(e);
+                                break;
+                            }
+                            if block_extra.block().txdata.is_empty() {
+                                // invalid without a coinbase, the store would add nothing
+                                let e = Error::EmptyBlock {
+                                    height: block_extra.height,
+                                };
+                                error!("{}", e);
+                                utxo.finalize();
+                                result = Err(e);
+                                break;
+                            }
+                            #[allow(unused_mut)]
                             let mut prevouts =
                                 utxo.add_outputs_get_inputs(&block_extra, block_extra.height);
-                            if block_extra.height >= start_at_height {
-                                let mut prevouts = prevouts.drain(..);
-
-                                let mut outpoint_values_vec =
-                                    Vec::with_capacity(block_extra.block_total_inputs());
+                            // pairing inputs and prevouts relies on the store returning one
+                            // prevout for every input but the coinbase one
+                            let expected = block_extra.input_count() - 1;
+                            if prevouts.len() != expected {
+                                let e = Error::PrevoutCountMismatch {
+                                    height: block_extra.height,
+                                    expected,
+                                    got: prevouts.len(),
+                                };
+                                error!("{}", e);
+                                // the store is inconsistent, its pending writes aren't committed
+                                result = Err(e);
+                                break;
+                            }
+                            if let Some(e) = utxo.take_error() {
+                                error!("{}", e);
+                                result = Err(e);
+                                break;
+                            }
+                            block_extra.bip30_duplicate = utxo.bip30_duplicate();
+                            if let Some(coinbase_maturity) = coinbase_maturity.as_mut() {
+                                if let Err(e) = coinbase_maturity.add(&mut block_extra) {
+                                    error!("{}", e);
+                                    // the store is consistent, the block is just invalid
+                                    utxo.finalize();
+                                    result = Err(e);
+                                    break;
+                                }
+                            }
+                            if let Some(coin_heights) = coin_heights.as_mut() {
+                                coin_heights.add(&mut block_extra);
+                            }
+                            #[cfg(feature = "prometheus")]
+                            crate::metrics::set_utxo_size(utxo.size());
+                            if emit_filter.is_emitted(block_extra.height) {
                                 let block = block_extra.block();
-
-                                for tx in block.txdata.iter().skip(1) {
-                                    for input in tx.input.iter() {
-                                        let previous_txout = prevouts.next().unwrap();
-
-                                        outpoint_values_vec
-                                            .push((input.previous_output, previous_txout));
+                                let mut outpoint_values_vec = match parallel_threshold {
+                                    #[cfg(feature = "rayon")]
+                                    Some(threshold)
+                                        if block_extra.block_total_inputs() >= threshold =>
+                                    {
+                                        par_outpoint_values(block, &mut prevouts)
                                     }
-                                }
-                                let coin_base_output_value =
+                                    _ => outpoint_values(block, prevouts),
+                                };
+                                let coin_base_output_value: Amount =
                                     block.txdata[0].output.iter().map(|el| el.value).sum();
-                                outpoint_values_vec.push((
-                                    OutPoint::default(),
-                                    TxOut {
-                                        script_pubkey: ScriptBuf::new(),
-                                        value: coin_base_output_value,
-                                    },
-                                ));
+                                if insert_coinbase_sentinel {
+                                    outpoint_values_vec.push((
+                                        OutPoint::default(),
+                                        TxOut {
+                                            script_pubkey: ScriptBuf::new(),
+                                            value: coin_base_output_value,
+                                        },
+                                    ));
+                                }
+                                block_extra.coinbase_value = Some(coin_base_output_value.to_sat());
                                 block_extra.outpoint_values_vec = outpoint_values_vec;
+                                if track_utxo_delta {
+                                    block_extra.utxo_delta = Some(utxo_delta(&block_extra));
+                                }
+                                block_extra.utxo_muhash = utxo.muhash().cloned().map(Box::new);
+                                if !emit_filter.matches(&block_extra) {
+                                    continue;
+                                }
+                                if let Some(enrich) = enrich.as_ref() {
+                                    enrich.apply(&mut block_extra);
+                                }
 
                                 if periodic.elapsed() {
                                     info!("{}", utxo.stat());
@@ -96,29 +208,115 @@ impl Fee {
                                 );
 
                                 busy_time += now.elapsed().as_nanos();
+                                #[cfg(feature = "prometheus")]
+                                crate::metrics::set_busy(crate::metrics::Stage::Fee, busy_time);
 
-                                sender.send(Some(block_extra)).unwrap();
+                                if sender.send(Some(block_extra)).is_err() {
+                                    debug!("fee receiver disconnected");
+                                    consumer_gone.store(true, Ordering::Relaxed);
+                                    utxo.finalize();
+                                    break;
+                                }
                                 now = Instant::now();
                             }
                         }
-                        None => break,
+                        None => {
+                            utxo.finalize();
+                            break;
+                        }
                     }
                 }
+                // errors of the writes done when finalizing
+                if let (Ok(()), Some(e)) = (&result, utxo.take_error()) {
+                    error!("{}", e);
+                    result = Err(e);
+                }
                 info!(
                     "ending fee processer total tx {}, busy time: {}s, last height: {}",
                     total_txs,
                     busy_time / 1_000_000_000,
                     last_height
                 );
-                sender.send(None).expect("fee: cannot send none");
+                // fails if the receiver disconnected, there is nobody to notify
+                let _ = sender.send(None);
+                result
             })),
         }
     }
+
+    /// Waits the end of the stage, returning the error that stopped it, if any
+    pub fn join(mut self) -> Result<(), Error> {
+        match self.join.take() {
+            Some(jh) => super::join("fee", jh).unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Outputs created by the block minus inputs spent, provably unspendable outputs and the coinbase
+/// input excluded
+fn utxo_delta(block_extra: &BlockExtra) -> i64 {
+    let created = block_extra
+        .block()
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .filter(|output| !output.script_pubkey.is_op_return())
+        .count();
+    created as i64 - (block_extra.block_total_inputs() as i64 - 1)
+}
+
+/// Pairs every input of `block`, excluding the coinbase, with its previous output in `prevouts`
+fn outpoint_values(block: &Block, prevouts: Vec<TxOut>) -> Vec<(OutPoint, TxOut)> {
+    let mut prevouts = prevouts.into_iter();
+    let mut outpoint_values_vec = Vec::with_capacity(prevouts.len() + 1);
+    for tx in block.txdata.iter().skip(1) {
+        for input in tx.input.iter() {
+            let previous_txout = prevouts.next().unwrap();
+            outpoint_values_vec.push((input.previous_output, previous_txout));
+        }
+    }
+    outpoint_values_vec
+}
+
+/// Like [`outpoint_values`] but processing transactions in parallel, preserving inputs order
+#[cfg(feature = "rayon")]
+fn par_outpoint_values(block: &Block, prevouts: &mut [TxOut]) -> Vec<(OutPoint, TxOut)> {
+    use rayon::prelude::*;
+
+    let txs = &block.txdata[1..];
+    let mut chunks = Vec::with_capacity(txs.len());
+    let mut remaining = prevouts;
+    for tx in txs.iter() {
+        let (chunk, rest) = remaining.split_at_mut(tx.input.len());
+        chunks.push(chunk);
+        remaining = rest;
+    }
+    txs.par_iter()
+        .zip(chunks.into_par_iter())
+        .flat_map_iter(|(tx, chunk)| {
+            tx.input
+                .iter()
+                .zip(chunk.iter_mut())
+                .map(|(input, tx_out)| {
+                    (
+                        input.previous_output,
+                        std::mem::replace(tx_out, TxOut::NULL),
+                    )
+                })
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
-    use bitcoin::TxOut;
+    use super::Enrich;
+    use crate::utxo::{MemUtxo, UtxoStore};
+    use crate::{inner_test::test_conf, iter};
+    use bitcoin::{Network, OutPoint, TxOut};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use test_log::test;
 
     #[test]
     fn test_size() {
@@ -127,4 +325,211 @@ mod test {
             std::mem::size_of::<TxOut>()
         );
     }
+
+    #[test]
+    fn test_enrich() {
+        let mut conf = test_conf();
+        let last_height = Arc::new(AtomicU32::new(0));
+        let last = last_height.clone();
+        conf.enrich = Some(Enrich::new(move |block_extra| {
+            let prev = last.swap(block_extra.height(), Ordering::Relaxed);
+            assert!(block_extra.height() == 0 || block_extra.height() == prev + 1);
+            let fee = block_extra.fee().map(|fee| fee.to_le_bytes().to_vec());
+            let txs = block_extra.txids().len() as u32;
+            let extra = block_extra.extra_mut();
+            extra.insert("txs".to_string(), txs.to_le_bytes().to_vec());
+            if let Some(fee) = fee {
+                extra.insert("fee".to_string(), fee);
+            }
+        }));
+        let mut count = 0;
+        for block_extra in iter(conf.clone()) {
+            let fee = block_extra.fee().unwrap().to_le_bytes();
+            assert_eq!(block_extra.extra()["fee"], fee);
+            count += 1;
+        }
+        assert_eq!(last_height.load(Ordering::Relaxed), count - 1);
+
+        conf.skip_prevout = true;
+        for block_extra in iter(conf) {
+            let txs = (block_extra.txids().len() as u32).to_le_bytes();
+            assert_eq!(block_extra.extra()["txs"], txs);
+            assert!(!block_extra.extra().contains_key("fee"));
+        }
+        assert!(iter(test_conf()).all(|b| b.extra().is_empty()));
+    }
+
+    #[test]
+    fn test_insert_coinbase_sentinel() {
+        let mut conf = test_conf();
+        conf.insert_coinbase_sentinel = false;
+        let mut spending = 0;
+        for (with, without) in iter(test_conf()).zip(iter(conf)) {
+            assert_eq!(with.height(), without.height());
+            assert!(with.outpoint_values().contains_key(&OutPoint::null()));
+            assert!(!without.outpoint_values().contains_key(&OutPoint::null()));
+            assert_eq!(
+                with.outpoint_values().len(),
+                without.outpoint_values().len() + 1
+            );
+            assert!(with.real_prevouts().eq(without.real_prevouts()));
+            assert!(with.input_prevout_pairs().eq(without.input_prevout_pairs()));
+            assert_eq!(
+                with.coinbase_output_value(),
+                without.coinbase_output_value()
+            );
+            assert_eq!(with.fee(), without.fee());
+            assert!(without.fee().is_some());
+            assert_eq!(with.coinbase_breakdown(), without.coinbase_breakdown());
+            assert_eq!(with.sigops(), without.sigops());
+            spending += without.real_prevouts().count();
+        }
+        assert!(spending > 0);
+    }
+
+    #[test]
+    fn test_utxo_delta() {
+        let mut conf = test_conf();
+        conf.track_utxo_delta = true;
+        let mut utxo = MemUtxo::new(Network::Testnet);
+        let mut total = 0i64;
+        for block_extra in iter(conf.clone()) {
+            utxo.add_outputs_get_inputs(&block_extra, block_extra.height());
+            total += block_extra.utxo_delta().unwrap();
+            assert_eq!(total as u64, utxo.size().unwrap());
+        }
+        assert!(total > 0);
+        assert!(iter(test_conf()).all(|b| b.utxo_delta().is_none()));
+
+        conf.skip_prevout = true;
+        assert!(iter(conf).all(|b| b.utxo_delta().is_none()));
+    }
+
+    #[test]
+    fn test_prevout_count_mismatch() {
+        use super::Fee;
+        use crate::stages::EmitFilter;
+        use crate::{BlockExtra, Error};
+        use std::sync::mpsc::sync_channel;
+
+        /// A store losing the last prevout of the blocks spending outputs
+        struct ShortUtxo(MemUtxo);
+        impl UtxoStore for ShortUtxo {
+            fn add_outputs_get_inputs(
+                &mut self,
+                block_extra: &BlockExtra,
+                height: u32,
+            ) -> Vec<TxOut> {
+                let mut prevouts = self.0.add_outputs_get_inputs(block_extra, height);
+                prevouts.pop();
+                prevouts
+            }
+            fn stat(&self) -> String {
+                self.0.stat()
+            }
+            fn bip30_duplicate(&self) -> bool {
+                self.0.bip30_duplicate()
+            }
+        }
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let blocks: Vec<_> = iter(conf).collect();
+        let spending = blocks.iter().find(|b| b.txids().len() > 1).unwrap();
+        let (height, expected) = (spending.height(), spending.input_count() - 1);
+
+        let (send_blocks, receive_blocks) = sync_channel(0);
+        let (send_fee, receive_fee) = sync_channel(0);
+        let fee = Fee::new(
+            EmitFilter::default(),
+            None,
+            None,
+            None,
+            None,
+            false,
+            true,
+            receive_blocks,
+            send_fee,
+            Arc::default(),
+            ShortUtxo(MemUtxo::new(Network::Testnet)),
+        );
+        std::thread::spawn(move || {
+            for block_extra in blocks {
+                if send_blocks.send(Some(block_extra)).is_err() {
+                    break;
+                }
+            }
+            let _ = send_blocks.send(None);
+        });
+        let mut received = vec![];
+        while let Some(block_extra) = receive_fee.recv().unwrap() {
+            received.push(block_extra.height());
+        }
+        assert_eq!(received, (0..height).collect::<Vec<_>>());
+        let got = expected - 1;
+        assert!(matches!(
+            fee.join(),
+            Err(Error::PrevoutCountMismatch { height: h, expected: e, got: g })
+                if (h, e, g) == (height, expected, got)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_blocks() {
+        use crate::block_extra::test::tx;
+        use crate::inner_test::child_block;
+        use crate::{try_iter, Config, Error};
+        use bitcoin::blockdata::constants::genesis_block;
+        use bitcoin::hashes::Hash;
+
+        let chain = |invalid: fn(&mut bitcoin::Block)| {
+            let mut chain = vec![genesis_block(Network::Regtest)];
+            for i in 0..10 {
+                let mut block = child_block(chain.last().unwrap(), 0);
+                if i == 2 {
+                    invalid(&mut block);
+                    if let Some(merkle_root) = block.compute_merkle_root() {
+                        block.header.merkle_root = merkle_root;
+                    }
+                }
+                chain.push(block);
+            }
+            Config::from_blocks(chain, Network::Regtest)
+        };
+
+        let mut blocks = try_iter(chain(|block| {
+            let missing = OutPoint::new(bitcoin::Txid::all_zeros(), 0);
+            block.txdata.push(tx(vec![missing], 1, 1));
+        }));
+        assert_eq!(blocks.by_ref().count(), 3);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::PrevoutCountMismatch {
+                height: 3,
+                expected: 1,
+                got: 0
+            })
+        ));
+
+        let mut blocks = try_iter(chain(|block| block.txdata.clear()));
+        assert_eq!(blocks.by_ref().count(), 3);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::EmptyBlock { height: 3 })
+        ));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_fee_parallel_threshold() {
+        let mut conf = test_conf();
+        conf.fee_parallel_threshold = Some(0);
+        let parallel: Vec<_> = iter(conf)
+            .map(|b| (b.outpoint_values_vec.clone(), b.fee()))
+            .collect();
+        let serial: Vec<_> = iter(test_conf())
+            .map(|b| (b.outpoint_values_vec.clone(), b.fee()))
+            .collect();
+        assert_eq!(parallel, serial);
+    }
 }
@@ -3,7 +3,87 @@ mod fee;
 mod read_detect;
 mod reorder;
 
-pub use compute_txids::ComputeTxids;
-pub use fee::Fee;
-pub use read_detect::ReadDetect;
-pub use reorder::Reorder;
+pub use compute_txids::{BlockVisitor, ComputeTxids};
+pub use fee::{Enrich, Fee};
+pub use read_detect::{detect, detect_magics, scan_networks, DetectedBlock, ReadDetect};
+pub use reorder::{ForkChoice, Reorder};
+
+use crate::{BlockExtra, BlockPredicate};
+use bitcoin::Work;
+use std::any::Any;
+use std::thread::JoinHandle;
+
+/// Which blocks are emitted, see [`crate::Config::start_at_height`],
+/// [`crate::Config::height_stride`] and [`crate::Config::height_ranges`]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EmitFilter {
+    pub(crate) start_at_height: u32,
+    pub(crate) height_stride: Option<u32>,
+    /// Sorted and non overlapping, empty means all the heights
+    pub(crate) height_ranges: Vec<(u32, Option<u32>)>,
+    pub(crate) predicate: Option<BlockPredicate>,
+    pub(crate) chainwork_range: Option<(Work, Work)>,
+}
+
+impl EmitFilter {
+    /// Returns true if the block at `height` must be emitted: starting from `start_at_height`, one
+    /// every `height_stride` blocks, if in one of the `height_ranges`
+    fn is_emitted(&self, height: u32) -> bool {
+        height >= self.start_at_height
+            && self.height_stride.map_or(true, |stride| {
+                (height - self.start_at_height) % stride.max(1) == 0
+            })
+            && (self.height_ranges.is_empty() || {
+                let next = self
+                    .height_ranges
+                    .partition_point(|(start, _)| *start <= height);
+                next > 0
+                    && self.height_ranges[next - 1]
+                        .1
+                        .map_or(true, |end| height <= end)
+            })
+    }
+
+    /// Returns true if `block_extra`, already emitted by height, matches the
+    /// [`crate::Config::predicate`] and the [`crate::Config::chainwork_range`], checked in the
+    /// last stage when the block data is complete
+    fn matches(&self, block_extra: &BlockExtra) -> bool {
+        self.chainwork_range.map_or(true, |(start, end)| {
+            block_extra
+                .chain_work
+                .map_or(false, |work| start <= work && work <= end)
+        }) && self
+            .predicate
+            .as_ref()
+            .map_or(true, |predicate| predicate.matches(block_extra))
+    }
+}
+
+/// Joins the thread of the stage `name`, returning its result. If it panicked panics again with
+/// the stage name and the original message, which would be lost otherwise
+fn join<T>(name: &str, handle: JoinHandle<T>) -> Option<T> {
+    match handle.join() {
+        Ok(result) => Some(result),
+        Err(panic) => {
+            let message = panic_message(&panic);
+            if std::thread::panicking() {
+                // panicking again would abort
+                log::error!("{} stage panicked: {}", name, message);
+                None
+            } else {
+                panic!("{} stage panicked: {}", name, message);
+            }
+        }
+    }
+}
+
+/// Returns the message of a `panic` payload, if it's a string
+pub(crate) fn panic_message(panic: &Box<dyn Any + Send>) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown".to_string(),
+        },
+    }
+}
@@ -1,46 +1,62 @@
-use crate::bitcoin::{BlockHash, Network};
-use crate::{FsBlock, Periodic};
+use crate::bitcoin::block::Header;
+use crate::bitcoin::consensus::deserialize;
+use crate::bitcoin::BlockHash;
+use crate::since_file::{FileMark, PendingBlock, SinceFileState};
+use crate::{BlockFile, BlockSource, Error, FileErrorPolicy, FsBlock, FsBlockSource, Periodic};
 use bitcoin::hashes::Hash;
 use bitcoin::p2p::Magic;
+use bitcoin::Network;
 use bitcoin_slices::number::{U32, U8};
 use bitcoin_slices::{bsl, Parse, Visit};
-use log::info;
-use std::collections::HashSet;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
 pub struct ReadDetect {
-    join: Option<JoinHandle<()>>,
+    join: Option<JoinHandle<Result<(), Error>>>,
 }
 
 impl Drop for ReadDetect {
     fn drop(&mut self) {
         if let Some(jh) = self.join.take() {
-            jh.join().expect("thread failed");
+            super::join("read_detect", jh);
         }
     }
 }
 
 /// Save half memory in comparison to using directly HashSet<BlockHash> while providing enough
-/// bytes to reasonably prevent collisions. Use the non-zero part of the hash
+/// bytes to reasonably prevent collisions. Use the non-zero part of the hash.
+///
+/// The keys are 96 random bits, the probability of a collision among `n` blocks is about
+/// `n^2 / 2^97`, less than 10^-17 for a million blocks. A collision would make the second block
+/// ignored. The set is persisted with [`crate::Config::since_file_state`]
 struct Seen(HashSet<[u8; 12]>);
 impl Seen {
     fn new() -> Seen {
         Seen(HashSet::new())
     }
+    fn key(hash: &BlockHash) -> [u8; 12] {
+        (&hash[..12]).try_into().unwrap()
+    }
     fn insert(&mut self, hash: &BlockHash) -> bool {
-        let key: [u8; 12] = (&hash[..12]).try_into().unwrap();
-        self.0.insert(key)
+        self.0.insert(Self::key(hash))
+    }
+    fn remove(&mut self, hash: &BlockHash) {
+        self.0.remove(&Self::key(hash));
     }
 }
 
+/// A block found in a buffer by [`detect`]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DetectedBlock {
     start: usize,
     end: usize,
@@ -49,16 +65,70 @@ pub struct DetectedBlock {
     inputs: u32,
     outputs: u32,
     txs: u32,
+    magic: Magic,
 }
 
 impl DetectedBlock {
-    fn into_fs_block(self, file: &Arc<Mutex<File>>, serialization_version: u8) -> FsBlock {
+    /// Position of the first byte of the block in the buffer, after the magic and the length
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Position after the last byte of the block in the buffer
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Hash of the block
+    pub fn hash(&self) -> BlockHash {
+        self.hash
+    }
+
+    /// Hash of the previous block, as stated in the header
+    pub fn prev(&self) -> BlockHash {
+        self.prev
+    }
+
+    /// Number of transactions in the block
+    pub fn txs(&self) -> u32 {
+        self.txs
+    }
+
+    /// Number of transaction inputs in the block, coinbase included
+    pub fn inputs(&self) -> u32 {
+        self.inputs
+    }
+
+    /// Number of transaction outputs in the block
+    pub fn outputs(&self) -> u32 {
+        self.outputs
+    }
+
+    /// The magic preceding the block, one of the magics given to [`detect_magics`]
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+
+    /// The network of [`DetectedBlock::magic()`], `None` if it's not the magic of a [`Network`]
+    pub fn network(&self) -> Option<Network> {
+        Network::from_magic(self.magic)
+    }
+
+    fn into_fs_block(
+        self,
+        file: &Arc<Mutex<BlockFile>>,
+        path: Option<&Arc<PathBuf>>,
+        serialization_version: u8,
+        xor_key: Option<[u8; 8]>,
+    ) -> FsBlock {
         FsBlock {
             start: self.start,
             end: self.end,
             hash: self.hash,
             prev: self.prev,
             file: Arc::clone(file),
+            path: path.cloned(),
+            xor_key,
             next: vec![],
             serialization_version,
             block_total_inputs: self.inputs,
@@ -69,79 +139,531 @@ impl DetectedBlock {
 }
 
 impl ReadDetect {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        blocks_dir: PathBuf,
-        network: Network,
+        source: Arc<dyn BlockSource>,
+        blocks_source: Option<Vec<Vec<u8>>>,
+        magic: Magic,
+        xor_key: Option<[u8; 8]>,
         early_stop: Arc<AtomicBool>,
         sender: SyncSender<Option<Vec<FsBlock>>>,
         serialization_version: u8,
+        check_pow: bool,
+        file_retries: u8,
+        on_file_error: FileErrorPolicy,
+        strict: bool,
+        detect_threads: usize,
+        follow: bool,
+        consumer_gone: Arc<AtomicBool>,
+        since_file_state: Option<Arc<Mutex<SinceFileState>>>,
+        anomaly_log: Option<File>,
     ) -> Self {
         let mut periodic = Periodic::new(Duration::from_secs(60));
         let mut vec = Vec::with_capacity(135_000_000);
         Self {
             join: Some(std::thread::spawn(move || {
                 info!("starting read_detect");
-
-                let mut now = Instant::now();
                 let mut seen = Seen::new();
-                let mut path = blocks_dir.clone();
-                path.push("blk*.dat");
-                info!("listing block files at {:?}", path);
-                let mut paths: Vec<PathBuf> = glob::glob(path.to_str().unwrap())
-                    .unwrap()
-                    .map(|r| r.unwrap())
-                    .collect();
-                paths.sort();
-                info!("There are {} block files", paths.len());
-                let mut busy_time = 0u128;
-
-                for path in paths.into_iter() {
-                    let mut file = File::open(&path).unwrap();
-                    file.read_to_end(&mut vec).unwrap();
-                    let detected_blocks = detect(&vec, network.magic()).unwrap();
-                    vec.clear();
-                    drop(file);
-
-                    let file = File::open(&path).unwrap();
-                    let file = Arc::new(Mutex::new(file));
+                let anomalies = Anomalies::new(anomaly_log);
 
+                if let Some(blocks) = blocks_source {
+                    info!("reading {} blocks from memory", blocks.len());
+                    let buffer = frame_blocks(blocks, magic);
+                    let detected_blocks = match detect_blocks(
+                        &buffer,
+                        &[magic],
+                        check_pow,
+                        strict,
+                        &anomalies,
+                        "memory",
+                    ) {
+                        Ok(detected_blocks) => detected_blocks,
+                        Err(e) => {
+                            error!("strict mode: {}", e);
+                            return Err(Error::Strict(e));
+                        }
+                    };
+                    let file = Arc::new(Mutex::new(BlockFile::Memory(Cursor::new(buffer))));
                     let fs_blocks: Vec<_> = detected_blocks
                         .into_iter()
                         .filter(|e| seen.insert(&e.hash))
-                        .map(|e| e.into_fs_block(&file, serialization_version))
+                        .map(|e| e.into_fs_block(&file, None, serialization_version, None))
                         .collect();
+                    // sending fails only if the reorder stage stopped, it doesn't need the blocks
+                    if !early_stop.load(Ordering::Relaxed) && sender.send(Some(fs_blocks)).is_ok() {
+                        let _ = sender.send(None);
+                    }
+                    return Ok(());
+                }
 
-                    // TODO if 0 blocks found, maybe wrong directory
-                    if periodic.elapsed() {
-                        info!("read {:?}, contains {} blocks", &path, fs_blocks.len());
+                let mut now = Instant::now();
+                let mut files = HashMap::new();
+                if let Some(state) = since_file_state.as_ref() {
+                    // the lock must be released before sending, the reorder stage locks the state
+                    // too when it's created
+                    let pending = {
+                        let mut state = state.lock().unwrap();
+                        seen.0 = std::mem::take(&mut state.seen);
+                        files = std::mem::take(&mut state.files);
+                        std::mem::take(&mut state.pending)
+                    };
+                    info!("resending {} pending blocks", pending.len());
+                    let fs_blocks: Result<Vec<_>, _> = pending
+                        .into_iter()
+                        .map(|p| p.into_fs_block(&source, serialization_version, xor_key))
+                        .collect();
+                    let fs_blocks = match fs_blocks {
+                        Ok(fs_blocks) => fs_blocks,
+                        Err(e) => {
+                            error!("cannot open the block file of a pending block: {}", e);
+                            early_stop.store(true, Ordering::Relaxed);
+                            let _ = sender.send(None);
+                            return Err(e);
+                        }
+                    };
+                    if !fs_blocks.is_empty() && sender.send(Some(fs_blocks)).is_err() {
+                        early_stop.store(true, Ordering::Relaxed);
                     }
+                }
 
-                    busy_time += now.elapsed().as_nanos();
-                    if early_stop.load(Ordering::Relaxed) {
-                        break;
+                let mut busy_time = 0u128;
+                let mut result = Ok(());
+                let mut scanned_names = HashSet::new();
+                let mut following = false;
+                let track_marks = since_file_state.is_some() || follow;
+                'follow: loop {
+                    if following {
+                        debug!("following, listing block files");
                     } else {
-                        sender.send(Some(fs_blocks)).expect("cannot send");
+                        info!("listing block files of {:?}", source);
+                    }
+                    let names = match source.files() {
+                        Ok(names) => names,
+                        Err(e) if following => {
+                            warn!("cannot list block files: {}", e);
+                            vec![]
+                        }
+                        Err(e) => {
+                            error!("cannot list block files: {}", e);
+                            early_stop.store(true, Ordering::Relaxed);
+                            let _ = sender.send(None);
+                            result = Err(e.into());
+                            break 'follow;
+                        }
+                    };
+                    if !following {
+                        info!("There are {} block files", names.len());
+                    }
+                    let last = names.last().cloned();
+
+                    let mut to_scan = vec![];
+                    for name in names.into_iter() {
+                        let mark = match (track_marks, source.local_path(&name)) {
+                            (true, Some(path)) => FileMark::of(&path).ok(),
+                            _ => None,
+                        };
+                        if mark.is_some() && files.get(&name) == mark.as_ref() {
+                            if !following {
+                                info!("skipping unchanged {}", name);
+                            }
+                            continue;
+                        }
+                        // without marks, only new files and the last one can have new blocks
+                        if following
+                            && mark.is_none()
+                            && scanned_names.contains(&name)
+                            && Some(&name) != last.as_ref()
+                        {
+                            continue;
+                        }
+                        to_scan.push((name, mark));
                     }
+                    let scan = |name: &str, buffer: &mut Vec<u8>| {
+                        let detect = |buffer: &mut [u8]| {
+                            if let Some(key) = xor_key.as_ref() {
+                                crate::xor(buffer, key, 0);
+                            }
+                            detect_blocks(buffer, &[magic], check_pow, strict, &anomalies, name)
+                        };
+                        scan_file(&source, name, buffer, file_retries, detect)
+                    };
+
+                    // files are scanned in batches of `detect_threads`, then processed in order
+                    for batch in to_scan.chunks(detect_threads.max(1)) {
+                        let scanned: Vec<_> = if batch.len() == 1 {
+                            vec![scan(&batch[0].0, &mut vec)]
+                        } else {
+                            std::thread::scope(|scope| {
+                                let handles: Vec<_> = batch
+                                    .iter()
+                                    .map(|(name, _)| {
+                                        let scan = &scan;
+                                        scope.spawn(move || scan(name, &mut vec![]))
+                                    })
+                                    .collect();
+                                handles
+                                    .into_iter()
+                                    .map(|h| h.join().expect("detect thread panicked"))
+                                    .collect()
+                            })
+                        };
+                        for ((name, mark), scanned) in batch.iter().zip(scanned) {
+                            let ScannedFile {
+                                file,
+                                path,
+                                detected,
+                            } = match (scanned, on_file_error) {
+                                (Ok(scanned), _) => scanned,
+                                (Err(e), FileErrorPolicy::Abort) => {
+                                    error!("cannot read block file {}: {}", name, e);
+                                    early_stop.store(true, Ordering::Relaxed);
+                                    let _ = sender.send(None);
+                                    result = Err(e.into());
+                                    break 'follow;
+                                }
+                                (Err(e), FileErrorPolicy::Skip) => {
+                                    error!("skipping block file {}: {}", name, e);
+                                    continue;
+                                }
+                            };
+                            let detected_blocks = match detected {
+                                Ok(detected_blocks) => detected_blocks,
+                                Err(e) => {
+                                    let e = format!("{} in {}", e, name);
+                                    error!("strict mode: {}", e);
+                                    result = Err(Error::Strict(e));
+                                    break 'follow;
+                                }
+                            };
+
+                            // the same handle is used to read the blocks later, reads always seek first
+                            let file = Arc::new(Mutex::new(file));
 
+                            let fs_blocks: Vec<_> = detected_blocks
+                                .into_iter()
+                                .filter(|e| seen.insert(&e.hash))
+                                .map(|e| {
+                                    e.into_fs_block(
+                                        &file,
+                                        path.as_ref(),
+                                        serialization_version,
+                                        xor_key,
+                                    )
+                                })
+                                .collect();
+
+                            // TODO if 0 blocks found, maybe wrong directory
+                            if periodic.elapsed() {
+                                info!("read {}, contains {} blocks", name, fs_blocks.len());
+                            }
+
+                            busy_time += now.elapsed().as_nanos();
+                            #[cfg(feature = "prometheus")]
+                            crate::metrics::set_busy(crate::metrics::Stage::ReadDetect, busy_time);
+                            if early_stop.load(Ordering::Relaxed) {
+                                // not sent, must be read again in the next run
+                                for fs_block in fs_blocks.iter() {
+                                    seen.remove(&fs_block.hash);
+                                }
+                                break 'follow;
+                            } else if sender.send(Some(fs_blocks)).is_err() {
+                                debug!("read_detect receiver disconnected");
+                                early_stop.store(true, Ordering::Relaxed);
+                                break 'follow;
+                            }
+                            if let Some(mark) = mark {
+                                files.insert(name.clone(), *mark);
+                            }
+                            scanned_names.insert(name.clone());
+
+                            now = Instant::now();
+                        }
+                    }
+                    if !follow || stop_following(&early_stop, &consumer_gone) {
+                        break;
+                    }
+                    following = true;
+                    let wait = Instant::now();
+                    while wait.elapsed() < FOLLOW_INTERVAL {
+                        if stop_following(&early_stop, &consumer_gone) {
+                            break 'follow;
+                        }
+                        std::thread::sleep(Duration::from_millis(100));
+                    }
                     now = Instant::now();
                 }
+                if let Some(state) = since_file_state.as_ref() {
+                    let mut state = state.lock().unwrap();
+                    state.seen = std::mem::take(&mut seen.0);
+                    state.files = files;
+                }
                 info!(
                     "ending read_detect , busy time: {}s",
                     (busy_time / 1_000_000_000)
                 );
-                if !early_stop.load(Ordering::Relaxed) {
+                anomalies.flush();
+                let anomalies = anomalies.count();
+                if anomalies > 0 {
+                    warn!(
+                        "skipped {} unparsable blocks in the block files, use strict to stop at the first",
+                        anomalies
+                    );
+                }
+                if !early_stop.load(Ordering::Relaxed) && result.is_ok() {
                     info!("sending None");
-                    sender.send(None).expect("cannot send");
+                    // fails if the reorder stage stopped, there is nobody to notify
+                    let _ = sender.send(None);
                 }
+                result
             })),
         }
     }
+
+    /// Waits the end of the stage, returning the error that stopped it, if any
+    pub fn join(mut self) -> Result<(), Error> {
+        match self.join.take() {
+            Some(jh) => super::join("read_detect", jh).unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
 }
 
-pub fn detect(buffer: &[u8], magic: Magic) -> Result<Vec<DetectedBlock>, bitcoin_slices::Error> {
+/// How often the block files are listed again looking for new blocks, see [`crate::Config::follow`]
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts the candidate blocks skipped by [`detect_blocks`], optionally recording each one as a
+/// `file,offset,reason` line, see [`crate::Config::anomaly_log`]
+pub(crate) struct Anomalies {
+    count: AtomicUsize,
+    log: Option<Mutex<BufWriter<File>>>,
+}
+
+impl Anomalies {
+    pub(crate) fn new(log: Option<File>) -> Self {
+        Anomalies {
+            count: AtomicUsize::new(0),
+            log: log.map(|file| Mutex::new(BufWriter::new(file))),
+        }
+    }
+
+    /// Count and log a block skipped because it's malformed
+    fn record(&self, file: &str, offset: usize, reason: fmt::Arguments) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.log(file, offset, reason);
+    }
+
+    /// Log a skipped block without counting it as malformed
+    fn log(&self, file: &str, offset: usize, reason: fmt::Arguments) {
+        if let Some(log) = self.log.as_ref() {
+            let mut log = log.lock().expect("anomaly log poisoned");
+            if let Err(e) = writeln!(log, "{},{},{}", file, offset, reason) {
+                warn!("cannot write the anomaly log: {}", e);
+            }
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn flush(&self) {
+        if let Some(log) = self.log.as_ref() {
+            if let Err(e) = log.lock().expect("anomaly log poisoned").flush() {
+                warn!("cannot write the anomaly log: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns true if following the block files must end, because the pipeline is stopping or the
+/// consumer of the blocks is gone
+fn stop_following(early_stop: &AtomicBool, consumer_gone: &AtomicBool) -> bool {
+    early_stop.load(Ordering::Relaxed) || consumer_gone.load(Ordering::Relaxed)
+}
+
+/// A block file read and scanned for blocks
+struct ScannedFile {
+    file: BlockFile,
+    path: Option<Arc<PathBuf>>,
+    /// An error only in strict mode, see [`detect_blocks`]
+    detected: Result<Vec<DetectedBlock>, String>,
+}
+
+/// Read the file `name` of `source` into `buffer`, retrying on errors, and `detect` its blocks
+fn scan_file(
+    source: &Arc<dyn BlockSource>,
+    name: &str,
+    buffer: &mut Vec<u8>,
+    retries: u8,
+    detect: impl FnOnce(&mut [u8]) -> Result<Vec<DetectedBlock>, String>,
+) -> io::Result<ScannedFile> {
+    let (file, path) = retry(retries, || {
+        buffer.clear();
+        let (mut file, path) = open_block_file(source, name)?;
+        match &mut file {
+            BlockFile::File(file) => file.read_to_end(buffer).map(|_| ())?,
+            _ => source.read_file(name, buffer)?,
+        }
+        Ok((file, path))
+    })?;
+    let detected = detect(buffer);
+    buffer.clear();
+    Ok(ScannedFile {
+        file,
+        path,
+        detected,
+    })
+}
+
+/// Open the file `name` of `source`, returning also its path if it's a local file
+fn open_block_file(
+    source: &Arc<dyn BlockSource>,
+    name: &str,
+) -> io::Result<(BlockFile, Option<Arc<PathBuf>>)> {
+    Ok(match source.local_path(name) {
+        Some(path) => (BlockFile::File(File::open(&path)?), Some(Arc::new(path))),
+        None => (
+            BlockFile::Source {
+                source: Arc::clone(source),
+                name: name.to_string(),
+                position: 0,
+            },
+            None,
+        ),
+    })
+}
+
+/// Call `f` until it succeeds, at most `retries` more times, waiting twice as long every time
+fn retry<T>(retries: u8, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut wait = Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if attempt < retries => {
+                warn!("{}, retrying in {:?}", e, wait);
+                std::thread::sleep(wait);
+                wait *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+impl PendingBlock {
+    fn into_fs_block(
+        self,
+        source: &Arc<dyn BlockSource>,
+        serialization_version: u8,
+        xor_key: Option<[u8; 8]>,
+    ) -> Result<FsBlock, Error> {
+        let (file, path) = open_block_file(source, &self.name)?;
+        Ok(FsBlock {
+            start: self.start as usize,
+            end: self.end as usize,
+            hash: self.hash,
+            prev: self.prev,
+            file: Arc::new(Mutex::new(file)),
+            path,
+            xor_key,
+            next: vec![],
+            serialization_version,
+            block_total_inputs: self.inputs,
+            block_total_outputs: self.outputs,
+            block_total_txs: self.txs,
+        })
+    }
+}
+
+/// Concatenate serialized `blocks` prefixing each one with `magic` and its length, like in
+/// `blk*.dat` files
+fn frame_blocks(blocks: Vec<Vec<u8>>, magic: Magic) -> Vec<u8> {
+    let total = blocks.iter().map(|b| b.len() + 8).sum();
+    let mut buffer = Vec::with_capacity(total);
+    for block in blocks {
+        buffer.extend(magic.to_bytes());
+        buffer.extend((block.len() as u32).to_le_bytes());
+        buffer.extend(block);
+    }
+    buffer
+}
+
+/// Returns the blocks found in `buffer`, formatted like a `blk*.dat` file: every block is
+/// prefixed by `magic` and its length.
+///
+/// This is a low level utility, useful for example to recover the blocks of a damaged file.
+/// Bytes not belonging to a block are skipped, as well as blocks whose length doesn't match the
+/// prefix and, when `check_pow` is true, blocks with invalid proof of work. A truncated block at
+/// the end of the buffer is ignored.
+pub fn detect(buffer: &[u8], magic: Magic, check_pow: bool) -> Vec<DetectedBlock> {
+    detect_magics(buffer, &[magic], check_pow)
+}
+
+/// Like [`detect`], but blocks prefixed by any of `magics` are returned, see
+/// [`DetectedBlock::magic()`] to know which one
+pub fn detect_magics(buffer: &[u8], magics: &[Magic], check_pow: bool) -> Vec<DetectedBlock> {
+    detect_blocks(buffer, magics, check_pow, false, &Anomalies::new(None), "")
+        .expect("lenient detect doesn't fail")
+}
+
+/// Returns the number of blocks of every [`Network`] found in the `blk*.dat` files of
+/// `blocks_dir`, a diagnostic for directories mixing the files of different networks, while the
+/// iteration reads the blocks of a single one. Blocks found more than once are counted once.
+///
+/// Files are de-obfuscated with the key in `xor.dat` if present. Only the default signet is
+/// recognized, not the custom ones
+pub fn scan_networks<P: AsRef<Path>>(blocks_dir: P) -> Result<HashMap<Network, usize>, Error> {
+    let blocks_dir = blocks_dir.as_ref();
+    if !blocks_dir.is_dir() {
+        return Err(Error::BlocksDirNotFound(blocks_dir.to_path_buf()));
+    }
+    let xor_key = crate::config::read_xor_key(blocks_dir)?;
+    let magics: Vec<_> = [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Testnet4,
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .iter()
+    .map(|n| n.magic())
+    .collect();
+    let source = FsBlockSource::new(blocks_dir);
+    let mut seen = Seen::new();
+    let mut counts = HashMap::new();
+    let mut buffer = vec![];
+    for name in source.files()? {
+        buffer.clear();
+        source.read_file(&name, &mut buffer)?;
+        if let Some(key) = xor_key.as_ref() {
+            crate::xor(&mut buffer, key, 0);
+        }
+        for block in detect_magics(&buffer, &magics, false) {
+            if let (true, Some(network)) = (seen.insert(&block.hash), block.network()) {
+                *counts.entry(network).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Like [`detect_magics`], but when `strict` is true returns an error instead of skipping bytes
+/// following one of `magics` which aren't a valid block. Otherwise the skipped blocks are recorded
+/// in `anomalies`, at their offset in `file`
+fn detect_blocks(
+    buffer: &[u8],
+    magics: &[Magic],
+    check_pow: bool,
+    strict: bool,
+    anomalies: &Anomalies,
+    file: &str,
+) -> Result<Vec<DetectedBlock>, String> {
     let mut pointer = 0usize;
     let mut rolling = RollingU32::default();
-    let magic_u32 = u32::from_le_bytes(magic.to_bytes());
+    let magics_u32: Vec<_> = magics
+        .iter()
+        .map(|m| u32::from_le_bytes(m.to_bytes()))
+        .collect();
 
     // Instead of sending DetecetdBlock on the channel directly, we quickly insert in the vector
     // allowing to read ahead exactly one file (reading no block ahead cause non-parallelizing
@@ -152,11 +674,20 @@ pub fn detect(buffer: &[u8], magic: Magic) -> Result<Vec<DetectedBlock>, bitcoin
         pointer += 1;
         current = byte.remaining();
         rolling.push(byte.parsed().into());
-        if magic_u32 != rolling.as_u32() {
-            continue;
-        }
+        let magic = match magics_u32.iter().position(|m| *m == rolling.as_u32()) {
+            Some(i) => magics[i],
+            None => continue,
+        };
 
-        let size = U32::parse(current)?;
+        let offset = pointer - 4; // of the magic
+        let size = match U32::parse(current) {
+            Ok(size) => size,
+            Err(_) if strict => return Err(format!("truncated block at {}", pointer)),
+            Err(_) => {
+                anomalies.record(file, offset, format_args!("truncated length prefix"));
+                break;
+            }
+        };
         let remaining = size.remaining();
         let size: u32 = size.parsed().into();
         pointer += 4;
@@ -170,6 +701,37 @@ pub fn detect(buffer: &[u8], magic: Magic) -> Result<Vec<DetectedBlock>, bitcoin
                 let hash = BlockHash::from_slice(&block.parsed().block_hash_sha2()[..]).unwrap();
                 let prev = BlockHash::from_slice(block.parsed().header().prev_blockhash()).unwrap();
                 if size as usize != end - start {
+                    if strict {
+                        return Err(format!(
+                            "block {} at {} has length {} instead of {}",
+                            hash,
+                            start,
+                            end - start,
+                            size
+                        ));
+                    }
+                    anomalies.record(
+                        file,
+                        offset,
+                        format_args!(
+                            "block {} declared size {} actual size {}",
+                            hash,
+                            size,
+                            end - start
+                        ),
+                    );
+                    continue;
+                }
+                if check_pow && !valid_pow(block.parsed().header().as_ref(), &hash) {
+                    if strict {
+                        return Err(format!("block {} has invalid proof of work", hash));
+                    }
+                    warn!("skipping block {} with invalid proof of work", hash);
+                    anomalies.log(
+                        file,
+                        offset,
+                        format_args!("block {} invalid proof of work", hash),
+                    );
                     continue;
                 }
 
@@ -181,15 +743,41 @@ pub fn detect(buffer: &[u8], magic: Magic) -> Result<Vec<DetectedBlock>, bitcoin
                     inputs: visitor.inputs,
                     outputs: visitor.outputs,
                     txs: visitor.txs,
+                    magic,
                 };
                 detected_blocks.push(detected_block);
             }
-            Err(_) => continue,
+            Err(e) if strict => {
+                return Err(format!("invalid block at {}: {:?}", start, e));
+            }
+            Err(e) => {
+                anomalies.record(
+                    file,
+                    offset,
+                    format_args!(
+                        "unparsable block declared size {} available bytes {}: {:?}",
+                        size,
+                        remaining.len(),
+                        e
+                    ),
+                );
+                continue;
+            }
         }
     }
     Ok(detected_blocks)
 }
 
+/// Returns true if `header_bytes` hash to `hash` and the hash satisfies the target in the header
+pub(crate) fn valid_pow(header_bytes: &[u8], hash: &BlockHash) -> bool {
+    match deserialize::<Header>(header_bytes) {
+        Ok(header) => header
+            .validate_pow(header.target())
+            .map_or(false, |h| &h == hash),
+        Err(_) => false,
+    }
+}
+
 struct InputsOutputsTxsCounter {
     inputs: u32,
     outputs: u32,
@@ -237,7 +825,399 @@ impl RollingU32 {
 
 #[cfg(test)]
 mod test {
-    use crate::stages::read_detect::RollingU32;
+    use crate::inner_test::{child_block, test_conf};
+    use crate::stages::read_detect::{
+        detect, detect_blocks, detect_magics, frame_blocks, retry, scan_networks, valid_pow,
+        Anomalies, RollingU32,
+    };
+    use crate::{iter, try_iter, Config, Error, FileErrorPolicy};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::p2p::Magic;
+    use bitcoin::Network;
+    use std::convert::TryInto;
+    use std::{fs, io};
+
+    #[test]
+    fn test_detect() {
+        let buffer = fs::read("../blocks/blk-testnet.dat").unwrap();
+        let magic = Network::Testnet.magic();
+        let blocks = detect(&buffer, magic, false);
+        assert_eq!(blocks.len(), 401);
+        let genesis = genesis_block(Network::Testnet);
+        assert_eq!(blocks[0].hash(), genesis.block_hash());
+        assert_eq!(blocks[0].txs(), 1);
+        for block in blocks.iter() {
+            assert_eq!(
+                buffer[block.start() - 8..block.start() - 4],
+                magic.to_bytes()
+            );
+            let len =
+                u32::from_le_bytes(buffer[block.start() - 4..block.start()].try_into().unwrap());
+            assert_eq!(block.end() - block.start(), len as usize);
+        }
+
+        // a truncated last block is ignored
+        let last = blocks.last().unwrap();
+        for end in [last.start() - 6, last.start() + 10] {
+            assert_eq!(detect(&buffer[..end], magic, false), blocks[..400]);
+        }
+        assert!(detect(&buffer, Network::Bitcoin.magic(), false).is_empty());
+    }
+
+    #[test]
+    fn test_scan_networks() {
+        let buffer = fs::read("../blocks/blk-testnet.dat").unwrap();
+        let regtest = frame_blocks(
+            vec![serialize(&genesis_block(Network::Regtest))],
+            Network::Regtest.magic(),
+        );
+        let mut mixed = regtest.clone();
+        mixed.extend(&buffer);
+        let magics = [Network::Testnet.magic(), Network::Regtest.magic()];
+        let blocks = detect_magics(&mixed, &magics, false);
+        assert_eq!(blocks.len(), 402);
+        assert_eq!(blocks[0].magic(), Network::Regtest.magic());
+        assert_eq!(blocks[0].network(), Some(Network::Regtest));
+        assert!(blocks[1..]
+            .iter()
+            .all(|b| b.network() == Some(Network::Testnet)));
+        assert_eq!(detect(&mixed, Network::Testnet.magic(), false).len(), 401);
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        fs::write(tempdir.path().join("blk00000.dat"), &buffer).unwrap();
+        fs::write(tempdir.path().join("blk00001.dat"), &regtest).unwrap();
+        // blocks already found are counted once
+        fs::write(tempdir.path().join("blk00002.dat"), &regtest).unwrap();
+        let counts = scan_networks(tempdir.path()).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&Network::Testnet], 401);
+        assert_eq!(counts[&Network::Regtest], 1);
+
+        assert!(matches!(
+            scan_networks(tempdir.path().join("missing")),
+            Err(Error::BlocksDirNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_strict() {
+        // the fixture is cut in the middle of the block following the last detected one
+        let buffer = fs::read("../blocks/blk-testnet.dat").unwrap();
+        let magic = Network::Testnet.magic();
+        let blocks = detect(&buffer, magic, false);
+        let anomalies = Anomalies::new(None);
+        assert!(detect_blocks(&buffer, &[magic], false, true, &anomalies, "").is_err());
+        let complete = &buffer[..blocks.last().unwrap().end()];
+        assert_eq!(
+            detect_blocks(complete, &[magic], true, true, &anomalies, "").unwrap(),
+            blocks
+        );
+        assert_eq!(anomalies.count(), 0);
+        detect_blocks(&buffer, &[magic], false, false, &anomalies, "").unwrap();
+        assert_eq!(anomalies.count(), 1, "the truncated block");
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let mut lenient = try_iter(conf.clone());
+        assert_eq!(lenient.by_ref().count(), 401 - conf.max_reorg as usize);
+        assert!(lenient.finished_cleanly());
+
+        conf.strict = true;
+        let mut strict = try_iter(conf);
+        assert_eq!(strict.by_ref().count(), 0);
+        assert!(matches!(strict.error(), Some(Error::Strict(_))));
+    }
+
+    #[test]
+    fn test_anomaly_log() {
+        // the fixture is cut in the middle of the block following the last detected one
+        let mut buffer = fs::read("../blocks/blk-testnet.dat").unwrap();
+        let magic = Network::Testnet.magic();
+        let blocks = detect(&buffer, magic, false);
+        let truncated = blocks.last().unwrap().end();
+        // declare the block at height 10 one byte longer
+        let start = blocks[10].start();
+        let size = u32::from_le_bytes(buffer[start - 4..start].try_into().unwrap());
+        buffer[start - 4..start].copy_from_slice(&(size + 1).to_le_bytes());
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        fs::write(tempdir.path().join("blk00000.dat"), &buffer).unwrap();
+        let log = tempdir.path().join("anomalies.csv");
+        let mut conf = test_conf();
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        conf.skip_prevout = true;
+        conf.anomaly_log = Some(log.clone());
+        assert_eq!(iter(conf.clone()).count(), 10 - conf.max_reorg as usize);
+
+        let log = fs::read_to_string(log).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            format!(
+                "blk00000.dat,{},block {} declared size {} actual size {}",
+                start - 8,
+                blocks[10].hash(),
+                size + 1,
+                size
+            )
+        );
+        assert!(lines[1].starts_with(&format!(
+            "blk00000.dat,{},unparsable block declared size ",
+            truncated
+        )));
+
+        conf.anomaly_log = Some(tempdir.path().join("missing").join("anomalies.csv"));
+        let mut iter = try_iter(conf);
+        assert_eq!(iter.by_ref().count(), 0);
+        assert!(matches!(iter.error(), Some(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_detect_threads() {
+        let buffer = fs::read("../blocks/blk-testnet.dat").unwrap();
+        let blocks = detect(&buffer, Network::Testnet.magic(), false);
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut start = 0;
+        for (i, chunk) in blocks.chunks(90).enumerate() {
+            let end = chunk.last().unwrap().end();
+            let name = format!("blk{:05}.dat", i);
+            fs::write(tempdir.path().join(name), &buffer[start..end]).unwrap();
+            start = end;
+        }
+        // blocks already seen in previous files are ignored
+        fs::copy(
+            tempdir.path().join("blk00001.dat"),
+            tempdir.path().join("blk00009.dat"),
+        )
+        .unwrap();
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let hashes = |conf: Config| -> Vec<_> { iter(conf).map(|b| b.block_hash()).collect() };
+        let expected = hashes(conf.clone());
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        for detect_threads in [1, 2, 4, 16] {
+            conf.detect_threads = detect_threads;
+            assert_eq!(hashes(conf.clone()), expected);
+        }
+    }
+
+    #[test]
+    fn test_follow() {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::mpsc::sync_channel;
+        use std::sync::Arc;
+
+        let buffer = fs::read("../blocks/blk-testnet.dat").unwrap();
+        let blocks = detect(&buffer, Network::Testnet.magic(), false);
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let expected: Vec<_> = iter(conf.clone()).map(|b| b.block_hash()).collect();
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let end = |i: usize| blocks[i - 1].end();
+        fs::write(tempdir.path().join("blk00000.dat"), &buffer[..end(100)]).unwrap();
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        conf.follow = true;
+
+        let (send, recv) = sync_channel(0);
+        let consumer_gone = Arc::new(AtomicBool::new(false));
+        let handle = crate::run_pipeline(conf, send, consumer_gone.clone(), Default::default());
+        let next = |n: usize| -> Vec<_> {
+            (0..n)
+                .map(|_| recv.recv().unwrap().unwrap().block_hash())
+                .collect()
+        };
+        let mut emitted = next(100 - 6);
+
+        // the node appends blocks to the last file and then starts a new one
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(tempdir.path().join("blk00000.dat"))
+            .unwrap();
+        file.write_all(&buffer[end(100)..end(200)]).unwrap();
+        emitted.extend(next(100));
+        fs::write(tempdir.path().join("blk00001.dat"), &buffer[end(200)..]).unwrap();
+        emitted.extend(next(expected.len() - emitted.len()));
+        assert_eq!(emitted, expected);
+
+        // like dropping the iterator
+        consumer_gone.store(true, Ordering::Relaxed);
+        drop(recv);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_xor_key() {
+        let key = [0x5A, 0x01, 0xF0, 0x33, 0x00, 0xC4, 0x7E, 0x99];
+        let mut buffer = fs::read("../blocks/blk-testnet.dat").unwrap();
+        crate::xor(&mut buffer, &key, 0);
+        let tempdir = tempfile::TempDir::new().unwrap();
+        fs::write(tempdir.path().join("blk00000.dat"), buffer).unwrap();
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let blocks = |conf: Config| -> Vec<_> {
+            iter(conf)
+                .map(|b| (b.block_hash(), b.block_bytes().to_vec()))
+                .collect()
+        };
+        let expected = blocks(conf.clone());
+        conf.blocks_dir = tempdir.path().to_path_buf();
+
+        // without the key no magic is found
+        assert!(blocks(conf.clone()).is_empty());
+
+        let mut with_key = conf.clone();
+        with_key.xor_key = Some(key);
+        assert_eq!(blocks(with_key), expected);
+
+        fs::write(tempdir.path().join("xor.dat"), key).unwrap();
+        assert_eq!(blocks(conf.clone()), expected);
+        let reloaded: Vec<_> = crate::iter_rev(conf.clone())
+            .map(|b| (b.block_hash(), b.block_bytes().to_vec()))
+            .collect();
+        assert_eq!(reloaded.into_iter().rev().collect::<Vec<_>>(), expected);
+
+        fs::write(tempdir.path().join("xor.dat"), [0u8; 4]).unwrap();
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(matches!(blocks.error(), Some(Error::InvalidXorKey(_))));
+    }
+
+    #[test]
+    fn test_custom_magic() {
+        let magic = Magic::from_bytes([0xAB, 0xCD, 0x12, 0x34]);
+        // the chain doesn't start from a known genesis
+        let genesis = child_block(&genesis_block(Network::Regtest), 7);
+        let mut chain = vec![genesis];
+        for _ in 0..10 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let buffer = frame_blocks(chain.iter().map(serialize).collect(), magic);
+        fs::write(tempdir.path().join("blk00000.dat"), buffer).unwrap();
+
+        let mut conf = Config::new(tempdir.path(), Network::Regtest);
+        conf.genesis_hash = Some(chain[0].block_hash());
+        assert_eq!(iter(conf.clone()).count(), 0);
+
+        conf.magic = Some(magic);
+        let expected: Vec<_> = chain
+            .iter()
+            .take(chain.len() - conf.max_reorg as usize)
+            .enumerate()
+            .map(|(i, b)| (i as u32, b.block_hash()))
+            .collect();
+        let emitted: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height(), b.block_hash()))
+            .collect();
+        assert_eq!(emitted, expected);
+
+        conf.magic = Some(Magic::from_bytes([0; 4]));
+        assert_eq!(iter(conf).count(), 0);
+    }
+
+    #[test]
+    fn test_extra_blocks_dirs() {
+        let magic = Network::Regtest.magic();
+        let mut main = vec![genesis_block(Network::Regtest)];
+        for _ in 0..11 {
+            main.push(child_block(main.last().unwrap(), 0));
+        }
+        let mut fork = vec![];
+        let mut prev = main[8].clone();
+        for _ in 0..10 {
+            prev = child_block(&prev, 1);
+            fork.push(prev.clone());
+        }
+        let write = |dir: &tempfile::TempDir, blocks: Vec<&bitcoin::Block>| {
+            let buffer = frame_blocks(blocks.into_iter().map(serialize).collect(), magic);
+            fs::write(dir.path().join("blk00000.dat"), buffer).unwrap();
+        };
+        let first = tempfile::TempDir::new().unwrap();
+        write(&first, main.iter().collect());
+        // the same first blocks of the main chain, then a longer fork
+        let second = tempfile::TempDir::new().unwrap();
+        write(&second, main[..9].iter().chain(fork.iter()).collect());
+
+        let mut conf = Config::new(first.path(), Network::Regtest);
+        let emitted = |conf: Config| -> Vec<_> { iter(conf).map(|b| b.block_hash()).collect() };
+        assert_eq!(emitted(conf.clone()).len(), main.len() - 6);
+
+        conf.extra_blocks_dirs = vec![second.path().to_path_buf()];
+        let chain: Vec<_> = main[..9].iter().chain(fork.iter()).collect();
+        let expected: Vec<_> = chain[..chain.len() - 6]
+            .iter()
+            .map(|b| b.block_hash())
+            .collect();
+        assert_eq!(emitted(conf.clone()), expected);
+        let forks: Vec<_> = iter(conf.clone())
+            .filter(|b| !b.forks().is_empty())
+            .map(|b| (b.height(), b.forks().to_vec()))
+            .collect();
+        assert_eq!(forks, vec![(8, vec![main[9].block_hash()])]);
+
+        fs::write(second.path().join("xor.dat"), [1u8; 8]).unwrap();
+        let mut blocks = try_iter(conf.clone());
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(matches!(blocks.error(), Some(Error::InvalidXorKey(_))));
+        conf.xor_key = Some([0u8; 8]);
+        assert_eq!(emitted(conf), expected);
+    }
+
+    #[test]
+    fn test_retry() {
+        let failing_twice = || {
+            let mut calls = 0;
+            move || {
+                calls += 1;
+                if calls <= 2 {
+                    Err(io::Error::new(io::ErrorKind::Other, "locked"))
+                } else {
+                    Ok(calls)
+                }
+            }
+        };
+        assert!(retry(1, failing_twice()).is_err());
+        assert_eq!(retry(2, failing_twice()).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_skip_file_error() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        fs::copy(
+            "../blocks/blk-testnet.dat",
+            tempdir.path().join("blk00000.dat"),
+        )
+        .unwrap();
+        // opening a directory succeeds, but reading it fails
+        fs::create_dir(tempdir.path().join("blk00001.dat")).unwrap();
+
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let expected = iter(conf.clone()).count();
+
+        conf.blocks_dir = tempdir.path().to_path_buf();
+        conf.file_retries = 0;
+        conf.on_file_error = FileErrorPolicy::Skip;
+        assert_eq!(iter(conf).count(), expected);
+    }
+
+    #[test]
+    fn test_valid_pow() {
+        let mut header = genesis_block(Network::Testnet).header;
+        let hash = header.block_hash();
+        assert!(valid_pow(&serialize(&header), &hash));
+        assert!(!valid_pow(&serialize(&header)[..79], &hash));
+
+        header.nonce += 1;
+        assert!(!valid_pow(&serialize(&header), &hash));
+        assert!(!valid_pow(&serialize(&header), &header.block_hash()));
+    }
 
     #[test]
     fn test_rolling() {
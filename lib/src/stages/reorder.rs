@@ -1,43 +1,138 @@
-use crate::{BlockExtra, FsBlock, PeriodCounter, Periodic};
-use bitcoin::blockdata::constants::genesis_block;
-use bitcoin::{BlockHash, Network};
-use log::{info, warn};
-use std::collections::HashMap;
+use crate::since_file::{PendingBlock, SinceFileState};
+use crate::stages::read_detect::valid_pow;
+use crate::stages::EmitFilter;
+use crate::{BlockExtra, Error, FsBlock, Pauser, PeriodCounter, Periodic, SubsidySchedule};
+use bitcoin::blockdata::constants::DIFFCHANGE_INTERVAL;
+use bitcoin::{BlockHash, Work};
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
+use std::fmt;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::SyncSender;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
 pub struct Reorder {
-    join: Option<JoinHandle<()>>,
+    join: Option<JoinHandle<Result<(), Error>>>,
 }
 
 impl Drop for Reorder {
     fn drop(&mut self) {
         if let Some(jh) = self.join.take() {
-            jh.join().expect("thread failed");
+            super::join("reorder", jh);
         }
     }
 }
 
+type ForkChoiceFn = dyn Fn(&[BlockHash]) -> BlockHash + Send + Sync;
+
+/// A user callback choosing the block to follow at a fork, see [`crate::Config::fork_choice`]
+#[derive(Clone)]
+pub struct ForkChoice(Arc<ForkChoiceFn>);
+
+impl ForkChoice {
+    /// Wrap the callback `f`, which receives the hashes of the blocks following the fork point, in
+    /// order of arrival, and returns the one to follow.
+    ///
+    /// It's called when one of the branches has `max_reorg` following blocks, thus only the
+    /// children of the fork point already read at that moment are candidates
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&[BlockHash]) -> BlockHash + Send + Sync + 'static,
+    {
+        ForkChoice(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for ForkChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ForkChoice")
+    }
+}
+
+/// Stale branches are pruned every this many received blocks
+const PRUNE_EVERY: usize = 1_000;
+
+/// Number of emitted or pruned hashes remembered to recognize stale blocks
+const MAX_DEAD: usize = 10_000;
+
 struct OutOfOrderBlocks {
     blocks: HashMap<BlockHash, FsBlock>,
     follows: HashMap<BlockHash, Vec<BlockHash>>,
     max_reorg: u8,
+    /// Sorted `(height, max_reorg)`, see [`crate::Config::max_reorg_schedule`]
+    max_reorg_schedule: Vec<(u32, u8)>,
+    fork_choice: Option<ForkChoice>,
+
+    /// Hashes of the blocks emitted or pruned, the recent ones, since their children other than
+    /// the next block to emit can't be emitted anymore
+    dead: HashSet<BlockHash>,
+    dead_order: VecDeque<BlockHash>,
 }
 
 impl OutOfOrderBlocks {
-    fn new(max_reorg: u8) -> Self {
+    fn new(max_reorg: u8, fork_choice: Option<ForkChoice>) -> Self {
         OutOfOrderBlocks {
             blocks: HashMap::default(),
             follows: HashMap::default(),
             max_reorg,
+            max_reorg_schedule: vec![],
+            fork_choice,
+            dead: HashSet::default(),
+            dead_order: VecDeque::default(),
+        }
+    }
+
+    /// Returns the number of following blocks required to emit the block at `height`
+    fn max_reorg_at(&self, height: u32) -> u8 {
+        let next = self
+            .max_reorg_schedule
+            .partition_point(|(start, _)| *start <= height);
+        match next {
+            0 => self.max_reorg,
+            _ => self.max_reorg_schedule[next - 1].1,
+        }
+    }
+
+    /// Remember `hash` can't be emitted anymore, forgetting the oldest one if there are too many
+    fn mark_dead(&mut self, hash: BlockHash) {
+        if self.dead.insert(hash) {
+            self.dead_order.push_back(hash);
+        }
+        if self.dead_order.len() > MAX_DEAD {
+            if let Some(oldest) = self.dead_order.pop_front() {
+                self.dead.remove(&oldest);
+            }
         }
     }
 
+    /// Remove the branches which can't be emitted anymore: the ones forking from an emitted block
+    /// other than `next`, since the iteration already followed another branch. Keeps the map
+    /// bounded when the block files contain many stale blocks. Returns the removed blocks
+    fn prune(&mut self, next: &BlockHash) -> Vec<FsBlock> {
+        let mut stale: Vec<BlockHash> = self
+            .blocks
+            .values()
+            .filter(|b| &b.hash != next && self.dead.contains(&b.prev))
+            .map(|b| b.hash)
+            .collect();
+        let mut pruned = vec![];
+        while let Some(hash) = stale.pop() {
+            if let Some(block) = self.blocks.remove(&hash) {
+                stale.extend(block.next.iter().cloned());
+                pruned.push(block);
+            }
+            if let Some(follows) = self.follows.remove(&hash) {
+                stale.extend(follows);
+            }
+            self.mark_dead(hash);
+        }
+        pruned
+    }
+
     fn add(&mut self, mut raw_block: FsBlock) {
         let prev_hash = raw_block.prev;
         self.follows
@@ -58,17 +153,22 @@ impl OutOfOrderBlocks {
         self.blocks.insert(raw_block.hash, raw_block);
     }
 
-    /// check the block identified by `hash` has at least `self.max_reorgs` blocks after, to be sure it's not a reorged block
+    /// check the block identified by `hash` has at least `max_reorg` blocks after, to be sure it's not a reorged block
     /// keep track of the followed `path` that should be initialized with empty vec in the first call
-    fn exist_and_has_followers(&self, hash: &BlockHash, path: Vec<BlockHash>) -> Option<BlockHash> {
-        if path.len() == self.max_reorg as usize {
+    fn exist_and_has_followers(
+        &self,
+        hash: &BlockHash,
+        max_reorg: u8,
+        path: Vec<BlockHash>,
+    ) -> Option<BlockHash> {
+        if path.len() == max_reorg as usize {
             return Some(path[0]);
         }
         if let Some(block) = self.blocks.get(hash) {
             for next in block.next.iter() {
                 let mut path = path.clone();
                 path.push(*next);
-                if let Some(hash) = self.exist_and_has_followers(next, path) {
+                if let Some(hash) = self.exist_and_has_followers(next, max_reorg, path) {
                     return Some(hash);
                 }
             }
@@ -76,14 +176,64 @@ impl OutOfOrderBlocks {
         None
     }
 
-    fn remove(&mut self, hash: &BlockHash) -> Option<FsBlock> {
-        if let Some(next) = self.exist_and_has_followers(hash, vec![]) {
+    /// Read concurrently the bytes of the blocks ready to be emitted starting from `hash` at
+    /// `height`, at most `max`. The blocks are followed as when emitted but without calling the
+    /// fork choice, thus the bytes of some blocks may be read and not used
+    fn prefetch(
+        &self,
+        hash: &BlockHash,
+        height: u32,
+        max: usize,
+    ) -> HashMap<BlockHash, Result<Vec<u8>, Error>> {
+        let mut ready = vec![];
+        let mut hash = *hash;
+        while ready.len() < max {
+            let max_reorg = self.max_reorg_at(height + ready.len() as u32);
+            match self.exist_and_has_followers(&hash, max_reorg, vec![]) {
+                Some(next) => {
+                    ready.extend(self.blocks.get(&hash));
+                    hash = next;
+                }
+                None => break,
+            }
+        }
+        if ready.len() < 2 {
+            // nothing to overlap
+            return HashMap::new();
+        }
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = ready
+                .iter()
+                .map(|fs_block| (fs_block.hash, scope.spawn(move || fs_block.read_bytes())))
+                .collect();
+            handles
+                .into_iter()
+                .map(|(hash, handle)| (hash, handle.join().expect("reading block bytes")))
+                .collect()
+        })
+    }
+
+    /// Remove the block `hash` at `height` if it has enough followers to be emitted, returning it
+    /// together with its children not followed
+    fn remove(&mut self, hash: &BlockHash, height: u32) -> Option<(FsBlock, Vec<BlockHash>)> {
+        let max_reorg = self.max_reorg_at(height);
+        if let Some(next) = self.exist_and_has_followers(hash, max_reorg, vec![]) {
             let mut value = self.blocks.remove(hash).unwrap();
+            let mut next = next;
             if value.next.len() > 1 {
+                if let Some(fork_choice) = self.fork_choice.as_ref() {
+                    let chosen = (fork_choice.0)(&value.next);
+                    if value.next.contains(&chosen) {
+                        next = chosen;
+                    } else {
+                        warn!("at {} fork choice returned unknown {}", value.hash, chosen);
+                    }
+                }
                 warn!("at {} fork to {:?} took {}", value.hash, value.next, next);
             }
+            let forks = value.next.iter().filter(|h| **h != next).cloned().collect();
             value.next = vec![next];
-            Some(value)
+            Some((value, forks))
         } else {
             None
         }
@@ -91,27 +241,63 @@ impl OutOfOrderBlocks {
 }
 
 impl Reorder {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        network: Network,
+        genesis: BlockHash,
         max_reorg: u8,
+        max_reorg_schedule: Vec<(u32, u8)>,
         stop_at_height: Option<u32>,
+        max_blocks: Option<u64>,
+        emit_filter: EmitFilter,
+        track_chainwork: bool,
+        check_pow_from: Option<u32>,
+        strict: bool,
+        fork_choice: Option<ForkChoice>,
+        mut orphans: Option<SyncSender<BlockExtra>>,
+        subsidy_schedule: SubsidySchedule,
+        read_threads: usize,
+        fast_skip_below: Option<u32>,
+        log_value_throughput: bool,
+        pauser: Pauser,
         early_stop: Arc<AtomicBool>,
         receiver: Receiver<Option<Vec<FsBlock>>>,
         sender: SyncSender<Option<BlockExtra>>,
+        since_file_state: Option<Arc<Mutex<SinceFileState>>>,
     ) -> Self {
-        let mut next = genesis_block(network).block_hash();
-        let mut blocks = OutOfOrderBlocks::new(max_reorg);
+        let mut next = genesis;
+        let mut blocks = OutOfOrderBlocks::new(max_reorg, fork_choice);
+        blocks.max_reorg_schedule = max_reorg_schedule;
         let mut height = 0;
+        if let Some(state) = since_file_state.as_ref() {
+            let state = state.lock().unwrap();
+            next = state.next;
+            height = state.height;
+        }
+        // the work of the blocks emitted in previous runs is not known
+        let track_chainwork = track_chainwork && height == 0;
+        let mut chain_work: Option<Work> = None;
+        // times of the first block of the current epoch and of the last block
+        let mut epoch_start_time: Option<u32> = None;
+        let mut last_time: Option<u32> = None;
         let mut periodic = Periodic::new(Duration::from_secs(60));
         Self {
             join: Some(std::thread::spawn(move || {
                 info!("starting reorder");
 
                 let mut bench = PeriodCounter::new(Duration::from_secs(10));
+                if log_value_throughput {
+                    bench = bench.with_value();
+                }
 
                 let mut busy_time = 0u128;
                 let mut now = Instant::now();
                 let mut last_height = 0;
+                let mut emitted = 0u64;
+                let mut added = 0usize;
+                let mut result = Ok(());
+                if max_blocks == Some(0) {
+                    early_stop.store(true, Ordering::Relaxed);
+                }
                 loop {
                     busy_time += now.elapsed().as_nanos();
                     let received = receiver.recv().unwrap_or_default();
@@ -120,9 +306,17 @@ impl Reorder {
                     match received {
                         Some(raw_blocks) => {
                             if early_stop.load(Ordering::SeqCst) {
+                                // kept to be persisted as pending
+                                raw_blocks.into_iter().for_each(|b| blocks.add(b));
                                 break;
                             }
-                            'outer: for raw_block in raw_blocks {
+                            let mut stopped = false;
+                            for raw_block in raw_blocks {
+                                if stopped {
+                                    // kept to be persisted as pending
+                                    blocks.add(raw_block);
+                                    continue;
+                                }
                                 if periodic.elapsed() {
                                     info!(
                                         "reorder receive:{} size:{} follows:{} next:{}",
@@ -144,18 +338,114 @@ impl Reorder {
                                     panic!("Reorder map grow more than {}", max_block_to_reorder);
                                 }
                                 blocks.add(raw_block);
-                                while let Some(block_to_send) = blocks.remove(&next) {
-                                    let mut block_extra: BlockExtra =
-                                        block_to_send.try_into().unwrap();
+                                added += 1;
+                                if added % PRUNE_EVERY == 0 {
+                                    let pruned = blocks.prune(&next);
+                                    if !pruned.is_empty() {
+                                        info!("pruned {} blocks of stale branches", pruned.len());
+                                        send_orphans(&mut orphans, pruned);
+                                    }
+                                }
+                                let mut prefetched = HashMap::new();
+                                loop {
+                                    // blocks not emitted are dropped by the next stage, only
+                                    // their header is needed to follow the chain
+                                    let header_only = fast_skip_below
+                                        .map_or(false, |below| height < below)
+                                        && !emit_filter.is_emitted(height);
+                                    if prefetched.is_empty() && read_threads > 1 && !header_only {
+                                        prefetched = blocks.prefetch(&next, height, read_threads);
+                                    }
+                                    let (block_to_send, forks) = match blocks.remove(&next, height)
+                                    {
+                                        Some(removed) => removed,
+                                        None => break,
+                                    };
+                                    let block_bytes = match prefetched.remove(&block_to_send.hash) {
+                                        Some(block_bytes) => block_bytes,
+                                        None if header_only => block_to_send.read_header_bytes(),
+                                        None => block_to_send.read_bytes(),
+                                    };
+                                    let mut block_extra = match block_bytes {
+                                        Ok(block_bytes) => {
+                                            BlockExtra::from_fs_block(block_to_send, block_bytes)
+                                        }
+                                        Err(e) => {
+                                            error!("{}, stopping at height {}", e, height);
+                                            early_stop.store(true, Ordering::Relaxed);
+                                            result = Err(e);
+                                            stopped = true;
+                                            break;
+                                        }
+                                    };
+                                    block_extra.forks = forks;
+                                    block_extra.subsidy_schedule = subsidy_schedule;
+                                    if check_pow_from.map_or(false, |h| height >= h)
+                                        && !valid_pow(
+                                            block_extra.header_bytes(),
+                                            &block_extra.block_hash,
+                                        )
+                                    {
+                                        warn!(
+                                            "block {} at height {} has invalid proof of work",
+                                            block_extra.block_hash, height
+                                        );
+                                        if strict {
+                                            result = Err(Error::Strict(format!(
+                                                "block {} has invalid proof of work",
+                                                block_extra.block_hash
+                                            )));
+                                        }
+                                        early_stop.store(true, Ordering::Relaxed);
+                                        stopped = true;
+                                        break;
+                                    }
                                     busy_time += now.elapsed().as_nanos();
                                     next = block_extra.next[0];
                                     block_extra.height = height;
                                     blocks.follows.remove(&block_extra.block_hash);
-                                    let block = block_extra.block();
+                                    blocks.mark_dead(block_extra.block_hash);
+                                    // only the header is decoded, the block is decoded later if needed
+                                    let header = block_extra.header();
+                                    let mut retarget_timespan = None;
+                                    if track_chainwork {
+                                        let work = header.work();
+                                        chain_work = Some(chain_work.map_or(work, |w| w + work));
+                                        let time = header.time;
+                                        if height % DIFFCHANGE_INTERVAL == 0 {
+                                            if let (Some(start), Some(last)) =
+                                                (epoch_start_time, last_time)
+                                            {
+                                                retarget_timespan =
+                                                    Some(last as i64 - start as i64);
+                                            }
+                                            epoch_start_time = Some(time);
+                                        }
+                                        last_time = Some(time);
+                                    }
 
-                                    blocks.blocks.remove(&block.header.prev_blockhash);
+                                    blocks.blocks.remove(&header.prev_blockhash);
+                                    block_extra.chain_work = chain_work;
+                                    block_extra.retarget_timespan = retarget_timespan;
 
-                                    bench.count_block(&block_extra);
+                                    let is_emitted = emit_filter.is_emitted(height);
+                                    if header_only || !is_emitted {
+                                        // header only blocks have no transactions, the value of the others is needed if emitted
+                                        bench.count_block_without_value(&block_extra);
+                                    } else {
+                                        bench.count_block(&block_extra);
+                                    }
+                                    #[cfg(feature = "prometheus")]
+                                    {
+                                        crate::metrics::block_emitted(
+                                            block_extra.height,
+                                            block_extra.block_total_txs,
+                                        );
+                                        crate::metrics::set_busy(
+                                            crate::metrics::Stage::Reorder,
+                                            busy_time,
+                                        );
+                                    }
                                     if let Some(stats) = bench.period_elapsed() {
                                         info!(
                                             "# {:7} {}",
@@ -163,24 +453,51 @@ impl Reorder {
                                         );
                                         info!("{}", stats);
                                     }
-                                    sender.send(Some(block_extra)).unwrap();
+                                    pauser.wait();
+                                    if !header_only && sender.send(Some(block_extra)).is_err() {
+                                        debug!("reorder receiver disconnected");
+                                        early_stop.store(true, Ordering::Relaxed);
+                                        stopped = true;
+                                        break;
+                                    }
 
                                     height += 1;
                                     now = Instant::now();
                                     last_height = height;
+                                    if is_emitted {
+                                        emitted += 1;
+                                    }
                                     if let Some(stop_at_height) = stop_at_height {
                                         if height > stop_at_height {
                                             info!("reached height: {}", stop_at_height);
                                             early_stop.store(true, Ordering::Relaxed);
-                                            break 'outer;
+                                            stopped = true;
+                                            break;
                                         }
                                     }
+                                    if max_blocks == Some(emitted) {
+                                        info!("emitted {} blocks", emitted);
+                                        early_stop.store(true, Ordering::Relaxed);
+                                        stopped = true;
+                                        break;
+                                    }
                                 }
                             }
                         }
                         None => break,
                     }
                 }
+                send_orphans(&mut orphans, blocks.prune(&next));
+                if let Some(state) = since_file_state.as_ref() {
+                    let mut state = state.lock().unwrap();
+                    state.next = next;
+                    state.height = height;
+                    state.pending = blocks
+                        .blocks
+                        .values()
+                        .filter_map(PendingBlock::from_fs_block)
+                        .collect();
+                }
                 info!(
                     "ending reorder next:{} #elements:{} #follows:{}",
                     next,
@@ -192,10 +509,520 @@ impl Reorder {
                     busy_time / 1_000_000_000,
                     last_height
                 );
-                // if !early_stop.load(Ordering::Relaxed) {
-                sender.send(None).expect("reorder cannot send none");
-                // }
+                // fails if the receiver disconnected, there is nobody to notify
+                let _ = sender.send(None);
+                result
             })),
         }
     }
+
+    /// Waits the end of the stage, returning the error that stopped it, if any
+    pub fn join(mut self) -> Result<(), Error> {
+        match self.join.take() {
+            Some(jh) => super::join("reorder", jh).unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Send the blocks of stale branches to `orphans`, if any, see [`crate::Config::emit_orphans`]
+fn send_orphans(orphans: &mut Option<SyncSender<BlockExtra>>, pruned: Vec<FsBlock>) {
+    let sender = match orphans.as_ref() {
+        Some(sender) => sender,
+        None => return,
+    };
+    for fs_block in pruned {
+        let block_extra: BlockExtra = match fs_block.try_into() {
+            Ok(block_extra) => block_extra,
+            Err(e) => {
+                warn!("skipping orphan block: {}", e);
+                continue;
+            }
+        };
+        if sender.send(block_extra).is_err() {
+            debug!("orphans receiver disconnected");
+            *orphans = None;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ForkChoice, OutOfOrderBlocks};
+    use crate::bitcoin::blockdata::constants::genesis_block;
+    use crate::bitcoin::hashes::Hash;
+    use crate::bitcoin::{Block, BlockHash, Network};
+    use crate::inner_test::child_block;
+    use crate::{iter, BlockFile, Config, FsBlock};
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use test_log::test;
+
+    fn fs_block(block: &Block) -> FsBlock {
+        synthetic_block(block.block_hash(), block.header.prev_blockhash)
+    }
+
+    /// A block with assigned `hash` and `prev`, without content, to build arbitrary topologies
+    fn synthetic_block(hash: BlockHash, prev: BlockHash) -> FsBlock {
+        FsBlock {
+            file: Arc::new(Mutex::new(BlockFile::Memory(Cursor::new(vec![])))),
+            path: None,
+            xor_key: None,
+            start: 0,
+            end: 0,
+            hash,
+            prev,
+            next: vec![],
+            serialization_version: 1,
+            block_total_inputs: 0,
+            block_total_outputs: 0,
+            block_total_txs: 0,
+        }
+    }
+
+    /// Emit the blocks like the reorder stage does, returns the next block to emit
+    fn emit(blocks: &mut OutOfOrderBlocks, mut next: BlockHash) -> BlockHash {
+        // heights matter only with a max reorg schedule
+        while let Some((block, _)) = blocks.remove(&next, 0) {
+            blocks.follows.remove(&block.hash);
+            blocks.mark_dead(block.hash);
+            next = block.next[0];
+        }
+        next
+    }
+
+    /// The hash assigned to the synthetic block `id`
+    fn id_hash(id: u32) -> BlockHash {
+        let mut bytes = [0u8; 32];
+        bytes[..4].copy_from_slice(&(id + 1).to_le_bytes());
+        BlockHash::from_byte_array(bytes)
+    }
+
+    fn hash_id(hash: &BlockHash) -> u32 {
+        let bytes = hash.to_byte_array();
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) - 1
+    }
+
+    /// Add the `(id, prev_id)` blocks in arrival order, emitting like the reorder stage after
+    /// each one starting from block 0, returns the ids of the emitted blocks and the next hash
+    fn arrive(blocks: &mut OutOfOrderBlocks, arrivals: &[(u32, u32)]) -> (Vec<u32>, BlockHash) {
+        let mut emitted = vec![];
+        let mut next = id_hash(0);
+        for (id, prev) in arrivals {
+            blocks.add(synthetic_block(id_hash(*id), id_hash(*prev)));
+            while let Some((block, _)) = blocks.remove(&next, emitted.len() as u32) {
+                blocks.follows.remove(&block.hash);
+                blocks.mark_dead(block.hash);
+                emitted.push(hash_id(&block.hash));
+                next = block.next[0];
+            }
+        }
+        (emitted, next)
+    }
+
+    /// `len` blocks with ids starting at `first`, the first one following `prev`
+    fn branch(first: u32, len: u32, prev: u32) -> Vec<(u32, u32)> {
+        (first..first + len)
+            .map(|id| (id, if id == first { prev } else { id - 1 }))
+            .collect()
+    }
+
+    #[test]
+    fn test_synthetic_out_of_order() {
+        let chain = branch(0, 50, u32::MAX - 1);
+        let expected: Vec<u32> = (0..44).collect();
+
+        let mut reversed = chain.clone();
+        reversed.reverse();
+        let mut seed = 0x2545_f491_u32;
+        let mut shuffles = vec![chain.clone(), reversed];
+        for _ in 0..20 {
+            let mut shuffled = chain.clone();
+            for i in (1..shuffled.len()).rev() {
+                // xorshift32
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                shuffled.swap(i, seed as usize % (i + 1));
+            }
+            shuffles.push(shuffled);
+        }
+        for arrivals in shuffles {
+            let mut blocks = OutOfOrderBlocks::new(6, None);
+            let (emitted, next) = arrive(&mut blocks, &arrivals);
+            assert_eq!(emitted, expected);
+            assert_eq!(next, id_hash(44));
+            assert_eq!(blocks.blocks.len(), 6);
+            assert!(blocks.prune(&next).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_synthetic_deep_fork() {
+        let main = branch(0, 31, u32::MAX - 1);
+        let side = branch(100, 20, 10);
+        let pruned = |blocks: &mut OutOfOrderBlocks, next: &BlockHash| {
+            let mut ids: Vec<_> = blocks
+                .prune(next)
+                .iter()
+                .map(|b| hash_id(&b.hash))
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        // the side branch arrives before the main chain goes past the fork, it's followed
+        let arrivals = [&main[..11], &side[..], &main[11..]].concat();
+        let mut blocks = OutOfOrderBlocks::new(6, None);
+        let (emitted, next) = arrive(&mut blocks, &arrivals);
+        assert_eq!(
+            emitted,
+            [(0..=10).collect::<Vec<_>>(), (100..114).collect()].concat()
+        );
+        assert_eq!(pruned(&mut blocks, &next), (11..=30).collect::<Vec<_>>());
+
+        // the side branch arrives after the fork point is emitted, it's stale
+        let arrivals = [&main[..], &side[..]].concat();
+        let mut blocks = OutOfOrderBlocks::new(6, None);
+        let (emitted, next) = arrive(&mut blocks, &arrivals);
+        assert_eq!(emitted, (0..25).collect::<Vec<_>>());
+        assert_eq!(pruned(&mut blocks, &next), (100..120).collect::<Vec<_>>());
+
+        // the branches arrive interleaved, the first reaching `max_reorg` blocks is followed,
+        // even if the other one ends up longer
+        let mut arrivals = main[..11].to_vec();
+        for (a, b) in main[11..18].iter().zip(side.iter()) {
+            arrivals.push(*a);
+            arrivals.push(*b);
+        }
+        arrivals.extend(&side[7..]);
+        let mut blocks = OutOfOrderBlocks::new(6, None);
+        let (emitted, next) = arrive(&mut blocks, &arrivals);
+        assert_eq!(emitted, (0..=11).collect::<Vec<_>>());
+        assert_eq!(pruned(&mut blocks, &next), (100..120).collect::<Vec<_>>());
+
+        // a fork deeper than `max_reorg` blocks is followed only up to the tip minus `max_reorg`
+        let mut blocks = OutOfOrderBlocks::new(30, None);
+        let (emitted, _) = arrive(&mut blocks, &[&main[..], &side[..]].concat());
+        assert_eq!(emitted, vec![0]);
+    }
+
+    #[test]
+    fn test_synthetic_orphans() {
+        let chain = branch(0, 20, u32::MAX - 1);
+        // a branch whose parent 500 is missing, arriving before the chain
+        let orphans = branch(200, 6, 500);
+        let mut blocks = OutOfOrderBlocks::new(6, None);
+        let (emitted, next) = arrive(&mut blocks, &[&orphans[..], &chain[..]].concat());
+        assert_eq!(emitted, (0..14).collect::<Vec<_>>());
+        assert_eq!(blocks.blocks.len(), 6 + 6);
+        // the parent isn't known to be stale, the orphans are kept
+        assert!(blocks.prune(&next).is_empty());
+
+        // the missing parent arrives late, following an emitted block, the whole branch is stale
+        blocks.add(synthetic_block(id_hash(500), id_hash(3)));
+        assert!(blocks.remove(&next, 14).is_none());
+        assert_eq!(blocks.prune(&next).len(), 1 + 6);
+        assert_eq!(blocks.blocks.len(), 6);
+        assert!(blocks
+            .follows
+            .keys()
+            .all(|h| !(200..=500).contains(&hash_id(h))));
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..30 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let mut side = vec![child_block(&chain[3], 1)];
+        for _ in 0..19 {
+            side.push(child_block(side.last().unwrap(), 0));
+        }
+
+        // the side branch arrives after its fork point is emitted, in reverse order to populate
+        // `follows` too
+        let mut blocks = OutOfOrderBlocks::new(6, None);
+        let mut next = chain[0].block_hash();
+        for block in chain.iter() {
+            blocks.add(fs_block(block));
+            next = emit(&mut blocks, next);
+        }
+        assert_eq!(next, chain[25].block_hash());
+        for block in side.iter().rev() {
+            blocks.add(fs_block(block));
+        }
+        assert_eq!(blocks.blocks.len(), 6 + 20);
+        assert_eq!(blocks.prune(&next).len(), 20);
+        assert_eq!(blocks.blocks.len(), 6);
+        assert!(blocks
+            .follows
+            .keys()
+            .all(|h| !side.iter().any(|b| &b.block_hash() == h)));
+        // a late block of the pruned branch is pruned too
+        blocks.add(fs_block(&child_block(side.last().unwrap(), 0)));
+        assert_eq!(blocks.prune(&next).len(), 1);
+        assert!(blocks.prune(&next).is_empty());
+
+        // the side branch, shorter than `max_reorg`, arrives before the main chain
+        let mut blocks = OutOfOrderBlocks::new(6, None);
+        let mut next = chain[0].block_hash();
+        for block in chain[..4]
+            .iter()
+            .chain(side[..5].iter())
+            .chain(chain[4..].iter())
+        {
+            blocks.add(fs_block(block));
+            next = emit(&mut blocks, next);
+        }
+        assert_eq!(next, chain[25].block_hash());
+        assert_eq!(blocks.prune(&next).len(), 5);
+        assert_eq!(blocks.blocks.len(), 6);
+    }
+
+    #[test]
+    fn test_fork_choice() {
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..3 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let mut branch_a = vec![child_block(&chain[3], 1)];
+        let mut branch_b = vec![child_block(&chain[3], 2)];
+        for _ in 0..10 {
+            branch_a.push(child_block(branch_a.last().unwrap(), 0));
+            branch_b.push(child_block(branch_b.last().unwrap(), 0));
+        }
+        // blocks of the two branches arrive interleaved
+        let mut blocks = chain.clone();
+        for (a, b) in branch_a.iter().zip(branch_b.iter()) {
+            blocks.push(a.clone());
+            blocks.push(b.clone());
+        }
+        let emitted =
+            |conf: Config| -> Vec<BlockHash> { iter(conf).map(|b| b.block_hash).collect() };
+
+        // by default the first branch reaching `max_reorg` blocks is followed
+        let conf = Config::from_blocks(blocks.clone(), Network::Regtest);
+        let hashes = emitted(conf.clone());
+        assert_eq!(hashes[4], branch_a[0].block_hash());
+        let forks: Vec<_> = iter(conf.clone())
+            .filter(|b| !b.forks().is_empty())
+            .map(|b| (b.height(), b.forks().to_vec()))
+            .collect();
+        assert_eq!(forks, vec![(3, vec![branch_b[0].block_hash()])]);
+
+        let mut conf = conf;
+        let b = branch_b[0].block_hash();
+        conf.fork_choice = Some(ForkChoice::new(move |candidates| {
+            assert_eq!(candidates.len(), 2);
+            assert!(candidates.contains(&b));
+            b
+        }));
+        let hashes = emitted(conf.clone());
+        let expected: Vec<_> = [chain, branch_b]
+            .concat()
+            .iter()
+            .map(|b| b.block_hash())
+            .collect();
+        assert_eq!(hashes, expected[..expected.len() - conf.max_reorg as usize]);
+
+        // an unknown choice falls back to the default
+        conf.fork_choice = Some(ForkChoice::new(|_| BlockHash::from_byte_array([7; 32])));
+        assert_eq!(emitted(conf)[4], branch_a[0].block_hash());
+    }
+
+    #[test]
+    fn test_emit_orphans() {
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..3 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let mut stale = vec![child_block(&chain[3], 1)];
+        for _ in 0..10 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        stale.push(child_block(&stale[0], 0));
+        let blocks = [chain.clone(), stale.clone()].concat();
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+        let mut conf = Config::from_blocks(blocks, Network::Regtest);
+        conf.emit_orphans = Some(sender);
+        let hashes: Vec<_> = iter(conf.clone()).map(|b| b.block_hash()).collect();
+        assert_eq!(hashes.len(), chain.len() - conf.max_reorg as usize);
+        drop(conf);
+        let mut orphans: Vec<_> = receiver.iter().map(|b| b.block().clone()).collect();
+        orphans.sort_by_key(|b| b.header.time);
+        assert_eq!(orphans, stale);
+    }
+
+    #[test]
+    fn test_read_threads() {
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..30 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        // all the blocks are ready to be emitted when the genesis arrives
+        let reversed: Vec<_> = chain.iter().rev().cloned().collect();
+        let mut conf = Config::from_blocks(reversed, Network::Regtest);
+        conf.read_threads = 4;
+        let hashes: Vec<_> = iter(conf).map(|b| b.block_hash()).collect();
+        let expected: Vec<_> = chain.iter().map(|b| b.block_hash()).collect();
+        assert_eq!(hashes, expected[..expected.len() - 6]);
+
+        let mut conf = crate::inner_test::test_conf();
+        let serial = crate::digest(conf.clone());
+        conf.read_threads = 8;
+        assert_eq!(crate::digest(conf), serial);
+    }
+
+    #[test]
+    fn test_fast_skip() {
+        use crate::{BlockSource, FsBlockSource};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Counts the bytes read again after the detection
+        #[derive(Debug)]
+        struct CountingSource(FsBlockSource, AtomicUsize);
+        impl BlockSource for CountingSource {
+            fn files(&self) -> std::io::Result<Vec<String>> {
+                self.0.files()
+            }
+            fn read_file(&self, name: &str, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+                self.0.read_file(name, buffer)
+            }
+            fn read_range(&self, name: &str, start: usize, end: usize) -> std::io::Result<Vec<u8>> {
+                self.1.fetch_add(end - start, Ordering::Relaxed);
+                self.0.read_range(name, start, end)
+            }
+        }
+
+        let mut conf = crate::inner_test::test_conf();
+        conf.skip_prevout = true;
+        conf.start_at_height = 300;
+        conf.stop_at_height = Some(310);
+        let run = |conf: &Config, fast_skip: bool, read_threads: usize| {
+            let source = Arc::new(CountingSource(
+                FsBlockSource::new(&conf.blocks_dir),
+                AtomicUsize::new(0),
+            ));
+            let mut conf = conf.clone();
+            conf.block_files_source = Some(source.clone());
+            conf.fast_skip = fast_skip;
+            conf.read_threads = read_threads;
+            let blocks: Vec<_> = iter(conf)
+                .map(|b| (b.height(), b.block_hash(), b.txids().clone()))
+                .collect();
+            (blocks, source.1.load(Ordering::Relaxed))
+        };
+        let (expected, full_bytes) = run(&conf, false, 1);
+        assert_eq!(expected.len(), 11);
+        assert_eq!(expected[0].0, 300);
+        for read_threads in [1, 4] {
+            let (blocks, bytes) = run(&conf, true, read_threads);
+            assert_eq!(blocks, expected);
+            assert!(bytes < full_bytes);
+            assert!(bytes >= 300 * 80);
+        }
+
+        conf.height_stride = Some(5);
+        let (expected, _) = run(&conf, false, 1);
+        assert_eq!(run(&conf, true, 1).0, expected);
+
+        // only the value of the emitted blocks is counted
+        conf.log_value_throughput = true;
+        assert_eq!(run(&conf, true, 1).0, expected);
+
+        conf.skip_prevout = false;
+        conf.fast_skip = true;
+        assert!(matches!(
+            crate::iterate(conf, std::sync::mpsc::sync_channel(1).0).join(),
+            Ok(Err(crate::Error::FastSkipWithPrevouts))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        use crate::{try_iter, BlockSource, Error, FsBlockSource};
+
+        /// The block files are shorter when read again after the detection, like when they are
+        /// truncated in the meantime
+        #[derive(Debug)]
+        struct TruncatingSource(FsBlockSource, usize);
+        impl BlockSource for TruncatingSource {
+            fn files(&self) -> std::io::Result<Vec<String>> {
+                self.0.files()
+            }
+            fn read_file(&self, name: &str, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+                self.0.read_file(name, buffer)
+            }
+            fn read_range(&self, name: &str, start: usize, end: usize) -> std::io::Result<Vec<u8>> {
+                self.0.read_range(name, start, end.min(self.1))
+            }
+        }
+
+        let mut conf = crate::inner_test::test_conf();
+        conf.skip_prevout = true;
+        let len = std::fs::metadata(conf.blocks_dir.join("blk-testnet.dat"))
+            .unwrap()
+            .len() as usize;
+        conf.block_files_source = Some(Arc::new(TruncatingSource(
+            FsBlockSource::new(&conf.blocks_dir),
+            len / 2,
+        )));
+        let mut blocks = try_iter(conf);
+        let count = blocks.by_ref().count();
+        assert!(count > 0 && count < 395, "{}", count);
+        assert!(!blocks.finished_cleanly());
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::TruncatedBlock { end, .. }) if *end > len / 2
+        ));
+    }
+
+    #[test]
+    fn test_max_reorg_schedule() {
+        let mut blocks = OutOfOrderBlocks::new(6, None);
+        blocks.max_reorg_schedule = vec![(10, 100), (20, 2)];
+        let max_reorgs: Vec<_> = [0, 9, 10, 19, 20, u32::MAX]
+            .iter()
+            .map(|h| blocks.max_reorg_at(*h))
+            .collect();
+        assert_eq!(max_reorgs, vec![6, 6, 100, 100, 2, 2]);
+
+        let mut chain = vec![genesis_block(Network::Regtest)];
+        for _ in 0..30 {
+            chain.push(child_block(chain.last().unwrap(), 0));
+        }
+        let emitted = |schedule: Vec<(u32, u8)>, read_threads: usize| {
+            let mut conf = Config::from_blocks(chain.clone(), Network::Regtest);
+            conf.max_reorg_schedule = schedule;
+            conf.read_threads = read_threads;
+            let hashes: Vec<_> = iter(conf).map(|b| b.block_hash()).collect();
+            let expected: Vec<_> = chain[..hashes.len()]
+                .iter()
+                .map(|b| b.block_hash())
+                .collect();
+            assert_eq!(hashes, expected);
+            hashes.len()
+        };
+        assert_eq!(emitted(vec![], 1), 31 - 6);
+        // the tip requires only 2 following blocks
+        assert_eq!(emitted(vec![(0, 10), (15, 2)], 1), 31 - 2);
+        assert_eq!(emitted(vec![(0, 10), (15, 2)], 4), 31 - 2);
+        // the block at height 21 needs 10 following blocks, there are 9
+        assert_eq!(emitted(vec![(20, 10), (22, 3)], 1), 21);
+
+        let mut conf = crate::inner_test::test_conf();
+        for invalid in [vec![(0, 10), (0, 6)], vec![(10, 6), (5, 6)], vec![(0, 0)]] {
+            conf.max_reorg_schedule = invalid;
+            let mut blocks = crate::try_iter(conf.clone());
+            assert_eq!(blocks.by_ref().count(), 0);
+            assert!(matches!(
+                blocks.error(),
+                Some(crate::Error::InvalidMaxReorgSchedule(_))
+            ));
+        }
+    }
 }
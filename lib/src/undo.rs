@@ -0,0 +1,359 @@
+use crate::bitcoin::hashes::{sha256d, Hash, HashEngine};
+use crate::bitcoin::p2p::Magic;
+use crate::bitcoin::secp256k1::PublicKey;
+use crate::bitcoin::{OutPoint, Script};
+use crate::BlockExtra;
+use fxhash::FxHashMap;
+use std::io::{self, Write};
+
+/// The first compressed scripts sizes are reserved to the special scripts
+const SPECIAL_SCRIPTS: u64 = 6;
+
+/// The height and the coinbase flag of the unspent outputs, built in the fee stage when
+/// [`crate::Config::track_undo_data`] is set, to serialize the spent outputs like the undo data
+/// of Bitcoin Core.
+///
+/// All the unspent outputs are kept, hundreds of millions for mainnet
+pub(crate) struct CoinHeights {
+    /// `(height << 1) | is_coinbase` of the unspent outputs
+    unspent: FxHashMap<OutPoint, u32>,
+}
+
+impl CoinHeights {
+    pub(crate) fn new() -> Self {
+        CoinHeights {
+            unspent: FxHashMap::default(),
+        }
+    }
+
+    /// Record the outputs created by `block_extra` and set in it the codes of the outputs it
+    /// spends. If one of them is unknown, like when it's created in a previous run, none is set
+    pub(crate) fn add(&mut self, block_extra: &mut BlockExtra) {
+        let height = block_extra.height;
+        let block = block_extra.block();
+        let txids = block_extra.txids_or_compute();
+        for (txid, tx) in txids.iter().zip(block.txdata.iter()) {
+            let code = (height << 1) | tx.is_coinbase() as u32;
+            for (vout, output) in tx.output.iter().enumerate() {
+                if !output.script_pubkey.is_op_return() {
+                    // a duplicated coinbase (BIP30) overwrites the previous outputs
+                    self.unspent.insert(OutPoint::new(*txid, vout as u32), code);
+                }
+            }
+        }
+
+        // every spent output is removed, even after an unknown one
+        let codes: Vec<Option<u32>> = block
+            .txdata
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.input.iter())
+            .map(|input| self.unspent.remove(&input.previous_output))
+            .collect();
+        block_extra.prevout_codes = codes.into_iter().collect();
+    }
+}
+
+/// Serialize the undo data of a block like Bitcoin Core does in the `rev*.dat` files, the
+/// `CBlockUndo` format unchanged since version 0.15: for every transaction but the coinbase the
+/// number of inputs and the spent outputs, each one as the varint of `(height << 1) | coinbase`,
+/// a zero byte if the height is not zero, the compressed amount and the compressed script.
+///
+/// `codes` are the ones of the previous outputs of `block_extra`, in inputs order
+pub(crate) fn serialize_block_undo(block_extra: &BlockExtra, codes: &[u32]) -> Vec<u8> {
+    let block = block_extra.block();
+    let mut data = Vec::with_capacity(block_extra.block_total_inputs() * 32);
+    let mut prevouts = block_extra.real_prevouts().zip(codes.iter());
+    write_compact_size(&mut data, block.txdata.len() as u64 - 1);
+    for tx in block.txdata.iter().skip(1) {
+        write_compact_size(&mut data, tx.input.len() as u64);
+        for ((_, prevout), code) in prevouts.by_ref().take(tx.input.len()) {
+            write_varint(&mut data, *code as u64);
+            if *code >> 1 > 0 {
+                data.push(0);
+            }
+            write_varint(&mut data, compress_amount(prevout.value.to_sat()));
+            write_compressed_script(&mut data, &prevout.script_pubkey);
+        }
+    }
+    data
+}
+
+/// Write the undo data of `block_extra` framed like in the `rev*.dat` files of Bitcoin Core: the
+/// network `magic`, the size of the undo data as a little endian u32, the undo data, see
+/// [`BlockExtra::undo_data()`], and its checksum, the double sha256 of the hash of the previous
+/// block followed by the undo data.
+///
+/// Bitcoin Core has no undo data for the genesis block, nothing is written for it. Fails with
+/// [`io::ErrorKind::InvalidData`] if the undo data is not available
+pub fn write_undo<W: Write>(
+    mut writer: W,
+    magic: Magic,
+    block_extra: &BlockExtra,
+) -> io::Result<()> {
+    if block_extra.height() == 0 {
+        return Ok(());
+    }
+    let undo = block_extra.undo_data().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("missing undo data at height {}", block_extra.height()),
+        )
+    })?;
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&block_extra.block().header.prev_blockhash.to_byte_array());
+    engine.input(&undo);
+    writer.write_all(&magic.to_bytes())?;
+    writer.write_all(&(undo.len() as u32).to_le_bytes())?;
+    writer.write_all(&undo)?;
+    writer.write_all(&sha256d::Hash::from_engine(engine).to_byte_array())
+}
+
+/// The `CompactSize` of the bitcoin serialization
+fn write_compact_size(data: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xFC => data.push(n as u8),
+        0xFD..=0xFFFF => {
+            data.push(0xFD);
+            data.extend((n as u16).to_le_bytes());
+        }
+        0x10000..=0xFFFF_FFFF => {
+            data.push(0xFE);
+            data.extend((n as u32).to_le_bytes());
+        }
+        _ => {
+            data.push(0xFF);
+            data.extend(n.to_le_bytes());
+        }
+    }
+}
+
+/// The `VARINT` of Bitcoin Core: big endian base 128, the continuation bit set on all the bytes
+/// but the last, every continuation byte is decremented by one so that the encoding is unique
+fn write_varint(data: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = [0u8; 10];
+    let mut len = 0;
+    loop {
+        tmp[len] = (n & 0x7F) as u8 | if len > 0 { 0x80 } else { 0 };
+        if n <= 0x7F {
+            break;
+        }
+        n = (n >> 7) - 1;
+        len += 1;
+    }
+    data.extend(tmp[..=len].iter().rev());
+}
+
+/// The amount compression of Bitcoin Core, removing the trailing zeros of the decimal
+/// representation
+fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n % 10 == 0 && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+/// The script compression of Bitcoin Core: pay to public key hash, pay to script hash and pay to
+/// valid public keys use 21 or 33 bytes, other scripts are prefixed with their size plus
+/// [`SPECIAL_SCRIPTS`]
+fn write_compressed_script(data: &mut Vec<u8>, script: &Script) {
+    let bytes = script.as_bytes();
+    if script.is_p2pkh() {
+        data.push(0x00);
+        data.extend(&bytes[3..23]);
+    } else if script.is_p2sh() {
+        data.push(0x01);
+        data.extend(&bytes[2..22]);
+    } else if bytes.len() == 35
+        && bytes[0] == 33
+        && bytes[34] == 0xac
+        && (bytes[1] == 0x02 || bytes[1] == 0x03)
+    {
+        data.extend(&bytes[1..34]);
+    } else if bytes.len() == 67
+        && bytes[0] == 65
+        && bytes[66] == 0xac
+        && bytes[1] == 0x04
+        && PublicKey::from_slice(&bytes[1..66]).is_ok()
+    {
+        data.push(0x04 | (bytes[65] & 0x01));
+        data.extend(&bytes[2..34]);
+    } else {
+        write_varint(data, bytes.len() as u64 + SPECIAL_SCRIPTS);
+        data.extend(bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress_amount, write_compressed_script, write_undo, write_varint, CoinHeights};
+    use crate::bitcoin::consensus::deserialize;
+    use crate::bitcoin::hashes::{sha256d, Hash};
+    use crate::bitcoin::{Block, Network, OutPoint, ScriptBuf, Txid};
+    use crate::block_extra::test::{block_extra, block_extra_from_block, tx};
+    use crate::inner_test::test_conf;
+    use crate::iter;
+    use bitcoin::hashes::hex::FromHex;
+    use test_log::test;
+
+    #[test]
+    fn test_varint() {
+        // the vectors of the serialization unit test of Bitcoin Core
+        let encode = |n| {
+            let mut data = vec![];
+            write_varint(&mut data, n);
+            data
+        };
+        assert_eq!(encode(0), vec![0x00]);
+        assert_eq!(encode(0x7f), vec![0x7f]);
+        assert_eq!(encode(0x80), vec![0x80, 0x00]);
+        assert_eq!(encode(0x1234), vec![0xa3, 0x34]);
+        assert_eq!(encode(0xffff), vec![0x82, 0xfe, 0x7f]);
+        assert_eq!(encode(0x123456), vec![0xc7, 0xe7, 0x56]);
+        assert_eq!(encode(0x80123456), vec![0x86, 0xff, 0xc7, 0xe7, 0x56]);
+        assert_eq!(encode(0xffffffff), vec![0x8e, 0xfe, 0xfe, 0xfe, 0x7f]);
+        assert_eq!(
+            encode(u64::MAX),
+            vec![0x80, 0xfe, 0xfe, 0xfe, 0xfe, 0xfe, 0xfe, 0xfe, 0xfe, 0x7f]
+        );
+    }
+
+    #[test]
+    fn test_compress_amount() {
+        // the vectors of the compress unit test of Bitcoin Core
+        assert_eq!(compress_amount(0), 0x0);
+        assert_eq!(compress_amount(1), 0x1);
+        assert_eq!(compress_amount(1_000_000), 0x7);
+        assert_eq!(compress_amount(100_000_000), 0x9);
+        assert_eq!(compress_amount(5_000_000_000), 0x32);
+        assert_eq!(compress_amount(21_000_000 * 100_000_000), 0x1406f40);
+    }
+
+    #[test]
+    fn test_compressed_script() {
+        let compress = |hex: &str| {
+            let mut data = vec![];
+            write_compressed_script(&mut data, &ScriptBuf::from_hex(hex).unwrap());
+            data
+        };
+        let hash = "1234567890abcdef1234567890abcdef12345678";
+        let p2pkh = compress(&format!("76a914{}88ac", hash));
+        assert_eq!(p2pkh, Vec::<u8>::from_hex(&format!("00{}", hash)).unwrap());
+        let p2sh = compress(&format!("a914{}87", hash));
+        assert_eq!(p2sh, Vec::<u8>::from_hex(&format!("01{}", hash)).unwrap());
+
+        let x = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let y = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+        let compressed = compress(&format!("2102{}ac", x));
+        assert_eq!(
+            compressed,
+            Vec::<u8>::from_hex(&format!("02{}", x)).unwrap()
+        );
+        let uncompressed = compress(&format!("4104{}{}ac", x, y));
+        assert_eq!(
+            uncompressed,
+            Vec::<u8>::from_hex(&format!("04{}", x)).unwrap()
+        );
+
+        // not on the curve, kept whole
+        let invalid = format!("4104{}{}ac", x, x);
+        let mut expected = vec![67 + 6];
+        expected.extend(Vec::<u8>::from_hex(&invalid).unwrap());
+        assert_eq!(compress(&invalid), expected);
+        assert_eq!(compress("6a"), vec![7, 0x6a]);
+    }
+
+    #[test]
+    fn test_coin_heights_unknown_prevout() {
+        let header = block_extra().block().header;
+        let coinbase = tx(vec![OutPoint::null()], 2, 50);
+        let created = OutPoint::new(coinbase.compute_txid(), 1);
+        let mut first = block_extra_from_block(Block {
+            header,
+            txdata: vec![coinbase],
+        });
+        first.height = 1;
+        let mut coin_heights = CoinHeights::new();
+        coin_heights.add(&mut first);
+        assert_eq!(coin_heights.unspent.len(), 2);
+
+        // the unknown prevout comes first, the known one is removed anyway
+        let unknown = OutPoint::new(Txid::all_zeros(), 0);
+        let mut second = block_extra_from_block(Block {
+            header,
+            txdata: vec![
+                tx(vec![OutPoint::null()], 1, 50),
+                tx(vec![unknown, created], 1, 10),
+            ],
+        });
+        second.height = 2;
+        coin_heights.add(&mut second);
+        assert_eq!(second.prevout_codes, None);
+        assert!(!coin_heights.unspent.contains_key(&created));
+        assert_eq!(coin_heights.unspent.len(), 3);
+    }
+
+    #[test]
+    fn test_undo_data() {
+        let mut conf = test_conf();
+        assert!(iter(conf.clone()).all(|b| b.undo_data().is_none()));
+
+        conf.track_undo_data = true;
+        let blocks: Vec<_> = iter(conf).collect();
+        assert_eq!(blocks[0].undo_data(), Some(vec![0]));
+        let spending = blocks
+            .iter()
+            .find(|b| b.block_total_txs > 1)
+            .expect("a block spending outputs");
+        let undo = spending.undo_data().unwrap();
+        let txs = spending.block_total_txs - 1;
+        assert_eq!(undo[0] as usize, txs);
+        // the test blocks spend early coinbase outputs paying to public keys
+        let (outpoint, prevout) = spending.real_prevouts().next().unwrap();
+        let created = blocks
+            .iter()
+            .position(|b| b.txids[0] == outpoint.txid)
+            .unwrap();
+        let inputs = spending.block().txdata[1].input.len();
+        let mut expected = vec![txs as u8, inputs as u8];
+        write_varint(&mut expected, (created as u64) << 1 | 1);
+        expected.push(0);
+        write_varint(&mut expected, compress_amount(prevout.value.to_sat()));
+        write_compressed_script(&mut expected, &prevout.script_pubkey);
+        assert_eq!(undo[..expected.len()], expected[..]);
+
+        let mut written = vec![];
+        write_undo(&mut written, Network::Testnet.magic(), &blocks[0]).unwrap();
+        assert!(written.is_empty());
+        write_undo(&mut written, Network::Testnet.magic(), spending).unwrap();
+        assert_eq!(written[..4], Network::Testnet.magic().to_bytes());
+        let size = deserialize::<u32>(&written[4..8]).unwrap() as usize;
+        assert_eq!(written[8..8 + size], undo[..]);
+        let mut checked = spending
+            .block()
+            .header
+            .prev_blockhash
+            .to_byte_array()
+            .to_vec();
+        checked.extend(&undo);
+        assert_eq!(
+            written[8 + size..],
+            sha256d::Hash::hash(&checked).to_byte_array()[..]
+        );
+
+        let mut unknown = crate::block_extra::test::block_extra();
+        unknown.height = 1;
+        assert!(write_undo(&mut written, Network::Testnet.magic(), &unknown).is_err());
+    }
+}
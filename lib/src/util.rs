@@ -0,0 +1,39 @@
+/// Returns the runs of printable ASCII characters, from space to `~`, at least `min_len` long,
+/// trimmed of surrounding whitespace. Runs made only of whitespace are skipped.
+///
+/// Useful to find the human-readable strings embedded in the chain, like the miner tags in the
+/// coinbase or protocol markers in `OP_RETURN` outputs
+pub fn printable_ascii_runs(bytes: &[u8], min_len: usize) -> Vec<String> {
+    bytes
+        .split(|b| !(b' '..=b'~').contains(b))
+        .filter(|run| run.len() >= min_len.max(1))
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+        .filter(|run| !run.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::printable_ascii_runs;
+    use test_log::test;
+
+    #[test]
+    fn test_printable_ascii_runs() {
+        assert!(printable_ascii_runs(b"", 1).is_empty());
+        assert!(printable_ascii_runs(b"\x03\x10\x27\x00ab\x01", 4).is_empty());
+        assert_eq!(
+            printable_ascii_runs(b"\x03\x10\x27\x00ab\x01", 2),
+            vec!["ab"]
+        );
+        assert_eq!(
+            printable_ascii_runs(b"\x03\xa0\x8c\x0b/ViaBTC/\x00\x01Mined by x\xff", 4),
+            vec!["/ViaBTC/", "Mined by x"]
+        );
+        assert_eq!(
+            printable_ascii_runs(b"\x03\x4f\x52\x0a\x00\x1f/Slush/\x10", 4),
+            vec!["/Slush/"]
+        );
+        assert!(printable_ascii_runs(b"\x04    \x05", 4).is_empty());
+        assert_eq!(printable_ascii_runs(b"\x05 x  \x06", 3), vec!["x"]);
+    }
+}
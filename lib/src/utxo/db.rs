@@ -3,9 +3,9 @@ use crate::bitcoin::{OutPoint, TxOut};
 use crate::utxo::UtxoStore;
 use crate::BlockExtra;
 use bitcoin::consensus::{deserialize, Encodable};
-use log::{debug, info};
+use log::{debug, info, warn};
 use rocksdb::{Options, WriteBatch, DB};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::path::Path;
 
@@ -13,6 +13,30 @@ pub struct DbUtxo {
     db: DB,
     updated_up_to_height: i32,
     inserted_outputs: u64,
+
+    /// The last block added overwrote unspent outputs, see [`UtxoStore::bip30_duplicate`]
+    bip30_duplicate: bool,
+
+    /// Only read the prevouts already stored, the db must be populated up to the iterated heights
+    prevouts_only_replay: bool,
+
+    /// Commit the accumulated writes to the db every `flush_every` blocks
+    flush_every: u32,
+
+    /// Writes of the blocks processed since the last commit
+    batch: WriteBatch,
+
+    /// Outputs in `batch` not yet committed, they could be spent by the following blocks
+    pending_outputs: HashMap<OutPoint, TxOut>,
+
+    /// Outputs in the db whose deletion is in `batch`, not yet committed
+    pending_spent: HashSet<OutPoint>,
+
+    /// Number of blocks in `batch`
+    pending_blocks: u32,
+
+    /// Height of the last block in `batch`
+    pending_height: i32,
 }
 
 /// This prefix contains currently unspent transaction outputs.
@@ -25,7 +49,11 @@ const PREVOUTS_PREFIX: u8 = b'P';
 const HEIGHT_PREFIX: u8 = b'H';
 
 impl DbUtxo {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<DbUtxo, rocksdb::Error> {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        flush_every: u32,
+        prevouts_only_replay: bool,
+    ) -> Result<DbUtxo, rocksdb::Error> {
         let mut options = Options::default();
         options.create_if_missing(true);
         let db = DB::open(&options, path)?;
@@ -42,8 +70,34 @@ impl DbUtxo {
             db,
             updated_up_to_height,
             inserted_outputs: 0,
+            bip30_duplicate: false,
+            prevouts_only_replay,
+            flush_every,
+            batch: WriteBatch::default(),
+            pending_outputs: HashMap::new(),
+            pending_spent: HashSet::new(),
+            pending_blocks: 0,
+            pending_height: updated_up_to_height,
         })
     }
+
+    /// Write the accumulated batch to the db, marking it updated up to `self.pending_height`
+    fn commit(&mut self) {
+        let mut batch = std::mem::take(&mut self.batch);
+        batch.put([HEIGHT_PREFIX], self.pending_height.to_ne_bytes());
+        self.db.write(batch).unwrap(); // TODO unwrap
+        self.pending_outputs.clear();
+        self.pending_spent.clear();
+        self.pending_blocks = 0;
+    }
+}
+
+impl Drop for DbUtxo {
+    fn drop(&mut self) {
+        if self.pending_blocks > 0 {
+            self.commit();
+        }
+    }
 }
 
 fn serialize_outpoint(o: &OutPoint, buffer: &mut [u8; 37]) {
@@ -72,13 +126,16 @@ impl UtxoStore for DbUtxo {
             "height: {} updated_up_to: {}",
             height, self.updated_up_to_height
         );
+        self.bip30_duplicate = false;
         if height > self.updated_up_to_height {
             let block = block_extra.block();
+            let txids = block_extra.txids_or_compute();
+            let coinbase_txid = txids[0];
 
             // since we can spend outputs created in this same block, we first put outputs in memory...
             let total_outputs = block_extra.block_total_outputs();
             let mut block_outputs = HashMap::with_capacity(total_outputs);
-            for (txid, tx) in block_extra.iter_tx() {
+            for (txid, tx) in txids.iter().zip(block.txdata.iter()) {
                 for (i, output) in tx.output.iter().enumerate() {
                     if !output.script_pubkey.is_op_return() {
                         let outpoint = OutPoint::new(*txid, i as u32);
@@ -88,7 +145,6 @@ impl UtxoStore for DbUtxo {
             }
 
             let mut prevouts = Vec::with_capacity(block_extra.block_total_inputs());
-            let mut batch = WriteBatch::default();
             for tx in block.txdata.iter().skip(1) {
                 for input in tx.input.iter() {
                     //...then we first check if inputs spend output created in this block
@@ -99,10 +155,21 @@ impl UtxoStore for DbUtxo {
                         }
                         None => {
                             serialize_outpoint(&input.previous_output, &mut outpoint_buffer);
-                            let tx_out =
-                                deserialize(&self.db.get_pinned(outpoint_buffer).unwrap().unwrap())
-                                    .unwrap();
-                            batch.delete(outpoint_buffer);
+                            let pending = self.pending_outputs.remove(&input.previous_output);
+                            let tx_out = match pending {
+                                // created in a block not yet committed
+                                Some(tx_out) => tx_out,
+                                None => {
+                                    if self.flush_every > 1 {
+                                        self.pending_spent.insert(input.previous_output);
+                                    }
+                                    deserialize(
+                                        &self.db.get_pinned(outpoint_buffer).unwrap().unwrap(),
+                                    )
+                                    .unwrap()
+                                }
+                            };
+                            self.batch.delete(outpoint_buffer);
                             prevouts.push(tx_out);
                         }
                     }
@@ -112,10 +179,18 @@ impl UtxoStore for DbUtxo {
             // and we put all the remaining outputs in db
             for (k, v) in block_outputs.drain() {
                 serialize_outpoint(&k, &mut outpoint_buffer);
+                // only a coinbase can duplicate an unspent output (BIP30), the put overwrites it
+                if k.txid == coinbase_txid
+                    && (self.pending_outputs.contains_key(&k)
+                        || self.db.get_pinned(outpoint_buffer).unwrap().is_some())
+                {
+                    warn!("coinbase output {} overwrites an unspent output", k);
+                    self.bip30_duplicate = true;
+                }
                 if v.script_pubkey.len() <= 10_000 {
                     // max script size for spendable output is 10k https://bitcoin.stackexchange.com/a/35881/6693 ...
                     let used = serialize_txout(&v, &mut txout_buffer);
-                    batch.put(&outpoint_buffer[..], &txout_buffer[..used]);
+                    self.batch.put(&outpoint_buffer[..], &txout_buffer[..used]);
                 } else {
                     // ... however there are bigger unspendable output like testnet 73e64e38faea386c88a578fd1919bcdba3d0b3af7b6302bf6ee1b423dc4e4333:0
                     // this rare case are handled separately here, this is less perfomant because `serialize` allocates a vector
@@ -124,16 +199,23 @@ impl UtxoStore for DbUtxo {
                         v.script_pubkey.len(),
                         k
                     );
-                    batch.put(&outpoint_buffer[..], &serialize(&v));
+                    self.batch.put(&outpoint_buffer[..], &serialize(&v));
+                }
+                if self.flush_every > 1 {
+                    self.pending_outputs.insert(k, v.clone());
                 }
                 self.inserted_outputs += 1;
             }
             if !prevouts.is_empty() {
                 // TODO consider compress this value serialized prevouts
-                batch.put(serialize_prevouts_height(height), serialize(&prevouts));
+                self.batch
+                    .put(serialize_prevouts_height(height), serialize(&prevouts));
+            }
+            self.pending_blocks += 1;
+            self.pending_height = height;
+            if self.pending_blocks >= self.flush_every {
+                self.commit();
             }
-            batch.put([HEIGHT_PREFIX], height.to_ne_bytes());
-            self.db.write(batch).unwrap(); // TODO unwrap
             prevouts
         } else if block_extra.block_total_txs == 1 {
             // avoid hitting disk when we have only the coinbase (no prevouts!)
@@ -153,6 +235,41 @@ impl UtxoStore for DbUtxo {
             self.updated_up_to_height, self.inserted_outputs
         )
     }
+
+    fn updated_up_to_height(&self) -> Option<i32> {
+        Some(self.updated_up_to_height)
+    }
+
+    fn replay_up_to_height(&self) -> Option<i32> {
+        self.prevouts_only_replay
+            .then_some(self.updated_up_to_height)
+    }
+
+    fn bip30_duplicate(&self) -> bool {
+        self.bip30_duplicate
+    }
+
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        if let Some(tx_out) = self.pending_outputs.get(outpoint) {
+            return Some(tx_out.clone());
+        }
+        if self.pending_spent.contains(outpoint) {
+            return None;
+        }
+        let mut outpoint_buffer = [0u8; 37];
+        serialize_outpoint(outpoint, &mut outpoint_buffer);
+        self.db
+            .get_pinned(outpoint_buffer)
+            .unwrap()
+            .map(|e| deserialize(&e).unwrap())
+    }
+
+    fn finalize(&mut self) {
+        if self.pending_blocks > 0 {
+            self.commit();
+        }
+        self.db.flush().unwrap(); // TODO unwrap
+    }
 }
 
 #[cfg(test)]
@@ -173,4 +290,24 @@ mod test {
         }
         assert_eq!(expected, outpoint_buffer);
     }
+
+    #[test]
+    fn test_get_pending() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        // between commits, the outputs spent are deleted only in the pending batch
+        let mut db = DbUtxo::new(tempdir.path(), 7, false).unwrap();
+        let mut spent = 0;
+        for b in crate::iter(crate::inner_test::test_conf()) {
+            db.add_outputs_get_inputs(&b, b.height());
+            let coinbase = &b.block().txdata[0];
+            let outpoint = OutPoint::new(b.txids()[0], 0);
+            assert_eq!(db.get(&outpoint).as_ref(), Some(&coinbase.output[0]));
+            for (outpoint, _) in b.real_prevouts() {
+                assert_eq!(db.get(outpoint), None);
+                spent += 1;
+            }
+        }
+        assert!(spent > 0);
+        assert_eq!(db.get(&OutPoint::default()), None);
+    }
 }
@@ -0,0 +1,145 @@
+use crate::bitcoin::{OutPoint, TxOut};
+use crate::utxo::UtxoStore;
+use crate::BlockExtra;
+use log::error;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+type PrevoutFn = dyn Fn(&OutPoint) -> Option<TxOut> + Send + Sync;
+
+/// A user callback returning the output referenced by an outpoint, see
+/// [`crate::Config::external_prevout`]
+#[derive(Clone)]
+pub struct ExternalPrevout(Arc<PrevoutFn>);
+
+impl ExternalPrevout {
+    /// Wrap the callback `f`, which must return the output for every outpoint spent in the
+    /// iterated blocks, except the ones created in the same block. Returning `None` stops the
+    /// iteration with [`crate::Error::PrevoutCountMismatch`]
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&OutPoint) -> Option<TxOut> + Send + Sync + 'static,
+    {
+        ExternalPrevout(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for ExternalPrevout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ExternalPrevout")
+    }
+}
+
+/// A [`UtxoStore`] not storing anything, prevouts are asked to an [`ExternalPrevout`] callback
+pub struct ExternalUtxo {
+    prevout: ExternalPrevout,
+    requested: u64,
+}
+
+impl ExternalUtxo {
+    pub fn new(prevout: ExternalPrevout) -> Self {
+        ExternalUtxo {
+            prevout,
+            requested: 0,
+        }
+    }
+}
+
+impl UtxoStore for ExternalUtxo {
+    fn add_outputs_get_inputs(&mut self, block_extra: &BlockExtra, _height: u32) -> Vec<TxOut> {
+        // the external source may not know yet the outputs of this block
+        let mut block_outputs = HashMap::with_capacity(block_extra.block_total_outputs());
+        let txids = block_extra.txids_or_compute();
+        for (txid, tx) in txids.iter().zip(block_extra.block().txdata.iter()) {
+            for (i, output) in tx.output.iter().enumerate() {
+                block_outputs.insert(OutPoint::new(*txid, i as u32), output);
+            }
+        }
+
+        let mut prevouts = Vec::with_capacity(block_extra.block_total_inputs());
+        for tx in block_extra.block().txdata.iter().skip(1) {
+            for input in tx.input.iter() {
+                let tx_out = match block_outputs.remove(&input.previous_output) {
+                    Some(tx_out) => tx_out.clone(),
+                    None => {
+                        self.requested += 1;
+                        match (self.prevout.0)(&input.previous_output) {
+                            Some(tx_out) => tx_out,
+                            None => {
+                                // the missing prevouts are reported by the fee stage
+                                error!("external prevout missing {}", input.previous_output);
+                                return prevouts;
+                            }
+                        }
+                    }
+                };
+                prevouts.push(tx_out);
+            }
+        }
+        prevouts
+    }
+
+    fn stat(&self) -> String {
+        format!("requested_prevouts: {}", self.requested)
+    }
+
+    fn bip30_duplicate(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExternalPrevout;
+    use crate::bitcoin::OutPoint;
+    use crate::{inner_test::test_conf, iter, try_iter, Error};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use test_log::test;
+
+    #[test]
+    fn test_blk_testnet_external_prevout() {
+        let mut conf = test_conf();
+        conf.skip_prevout = true;
+        let mut outputs = HashMap::new();
+        for block_extra in iter(conf) {
+            for (txid, tx) in block_extra.iter_tx() {
+                for (i, output) in tx.output.iter().enumerate() {
+                    outputs.insert(OutPoint::new(*txid, i as u32), output.clone());
+                }
+            }
+        }
+
+        let requested = Arc::new(AtomicUsize::new(0));
+        let mut conf = test_conf();
+        conf.external_prevout = Some(ExternalPrevout::new({
+            let requested = requested.clone();
+            move |outpoint| {
+                requested.fetch_add(1, Ordering::Relaxed);
+                outputs.get(outpoint).cloned()
+            }
+        }));
+        let fees: Vec<_> = iter(conf).map(|b| b.fee()).collect();
+        let expected: Vec<_> = iter(test_conf()).map(|b| b.fee()).collect();
+        assert_eq!(fees, expected);
+        assert!(requested.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_external_prevout_missing() {
+        let mut conf = test_conf();
+        conf.external_prevout = Some(ExternalPrevout::new(|_| None));
+        let first_spending = iter(test_conf())
+            .find(|b| b.real_prevouts().count() > 0)
+            .unwrap()
+            .height();
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), first_spending as usize);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::PrevoutCountMismatch { height, got: 0, .. }) if *height == first_spending
+        ));
+    }
+}
@@ -4,12 +4,14 @@ use crate::BlockExtra;
 use bitcoin::hashes::Hash;
 use bitcoin::{Amount, OutPoint, PubkeyHash, ScriptBuf, ScriptHash, TxOut, WPubkeyHash};
 use fxhash::FxHashMap;
+use log::{error, warn};
 use std::collections::HashMap;
 use std::hash::{BuildHasher, Hasher};
 
 pub struct MemUtxo {
     map: TruncMap,
     unspendable: u64,
+    bip30_duplicate: bool,
 }
 
 impl MemUtxo {
@@ -17,18 +19,26 @@ impl MemUtxo {
         MemUtxo {
             map: TruncMap::new(network),
             unspendable: 0,
+            bip30_duplicate: false,
         }
     }
 }
 
 impl MemUtxo {
     fn add_tx_outputs(&mut self, txid: &Txid, tx: &Transaction) {
+        let is_coinbase = tx.is_coinbase();
         for (i, output) in tx.output.iter().enumerate() {
             if output.script_pubkey.is_op_return() {
                 self.unspendable += 1;
                 continue;
             }
-            self.map.insert(OutPoint::new(*txid, i as u32), output);
+            let outpoint = OutPoint::new(*txid, i as u32);
+            // only a coinbase can duplicate an unspent output (BIP30), the previous is overwritten
+            if is_coinbase && self.map.remove(&outpoint).is_some() {
+                warn!("coinbase output {} overwrites an unspent output", outpoint);
+                self.bip30_duplicate = true;
+            }
+            self.map.insert(outpoint, output);
         }
     }
 }
@@ -36,14 +46,22 @@ impl MemUtxo {
 impl UtxoStore for MemUtxo {
     fn add_outputs_get_inputs(&mut self, block_extra: &BlockExtra, _height: u32) -> Vec<TxOut> {
         let block = block_extra.block();
-        for (txid, tx) in block_extra.iter_tx() {
-            self.add_tx_outputs(txid, &tx);
+        self.bip30_duplicate = false;
+        let txids = block_extra.txids_or_compute();
+        for (txid, tx) in txids.iter().zip(block.txdata.iter()) {
+            self.add_tx_outputs(txid, tx);
         }
         let mut prevouts = Vec::with_capacity(block_extra.block_total_inputs());
         for tx in block.txdata.iter().skip(1) {
             for input in tx.input.iter() {
-                let tx_out = self.map.remove(&input.previous_output).unwrap();
-                prevouts.push(tx_out);
+                match self.map.remove(&input.previous_output) {
+                    Some(tx_out) => prevouts.push(tx_out),
+                    None => {
+                        // the missing prevouts are reported by the fee stage
+                        error!("prevout missing {}", input.previous_output);
+                        return prevouts;
+                    }
+                }
             }
         }
         prevouts
@@ -67,6 +85,14 @@ impl UtxoStore for MemUtxo {
             unspendable
         )
     }
+
+    fn size(&self) -> Option<u64> {
+        Some((self.map.trunc.len() + self.map.full.len()) as u64)
+    }
+
+    fn bip30_duplicate(&self) -> bool {
+        self.bip30_duplicate
+    }
 }
 
 /// A map like struct storing truncated keys to save memory, in case of collisions a fallback map
@@ -166,9 +192,11 @@ impl TruncMap {
         let capacity = match network {
             Network::Bitcoin => 98_959_418, // @704065 load:76.1%
             Network::Testnet => 28_038_982, // @2097712 load:93.2%
-            Network::Signet => 1 >> 20,
-            Network::Regtest => 1 >> 10,
-            _ => panic!("unrecognized network"),
+            Network::Testnet4 => 1 << 22,
+            Network::Signet => 1 << 20,
+            Network::Regtest => 1 << 10,
+            // networks added by future versions of `bitcoin`, the map grows as needed
+            _ => 1 << 20,
         };
 
         TruncMap {
@@ -210,10 +238,23 @@ impl Hasher for PassthroughHasher {
 
 #[cfg(test)]
 mod test {
-    use crate::utxo::mem::StackScript;
+    use crate::utxo::mem::{StackScript, TruncMap};
     use crate::FsBlock;
     use bitcoin::hashes::Hash;
-    use bitcoin::{PubkeyHash, PublicKey, ScriptBuf, ScriptHash, WPubkeyHash, WScriptHash};
+    use bitcoin::{
+        Network, PubkeyHash, PublicKey, ScriptBuf, ScriptHash, WPubkeyHash, WScriptHash,
+    };
+
+    #[test]
+    fn test_trunc_map_capacity() {
+        for (network, capacity) in [
+            (Network::Testnet4, 1 << 22),
+            (Network::Signet, 1 << 20),
+            (Network::Regtest, 1 << 10),
+        ] {
+            assert!(TruncMap::new(network).trunc.capacity() >= capacity);
+        }
+    }
 
     #[test]
     fn test_size() {
@@ -225,7 +266,7 @@ mod test {
         assert_eq!(std::mem::size_of::<WScriptHash>(), 32);
         assert_eq!(std::mem::size_of::<Box<[u8]>>(), 16);
         assert_eq!(std::mem::size_of::<(StackScript, u64)>(), 40);
-        assert_eq!(std::mem::size_of::<FsBlock>(), 128);
+        assert_eq!(std::mem::size_of::<FsBlock>(), 144);
     }
 
     #[test]
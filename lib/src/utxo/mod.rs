@@ -1,6 +1,8 @@
-use crate::{bitcoin::TxOut, BlockExtra};
+use crate::{bitcoin::TxOut, BlockExtra, MuHash};
 
+mod external;
 mod mem;
+mod muhash;
 
 #[cfg(feature = "db")]
 mod db;
@@ -8,7 +10,12 @@ mod db;
 #[cfg(feature = "redb")]
 mod redb;
 
+mod wal;
+
+pub use external::{ExternalPrevout, ExternalUtxo};
 pub use mem::MemUtxo;
+pub use muhash::MuHashUtxo;
+pub use wal::{UtxoDelta, WalUtxo};
 
 #[cfg(feature = "redb")]
 pub use redb::RedbUtxo;
@@ -17,7 +24,13 @@ use bitcoin::OutPoint;
 #[cfg(feature = "db")]
 pub use db::DbUtxo;
 
-pub trait UtxoStore {
+/// A store of the unspent outputs, providing the previous outputs of the blocks.
+///
+/// The store is moved to the thread of the fee stage when the iteration starts and it's owned
+/// exclusively by it: blocks are added one at a time in height order and the store is never
+/// accessed concurrently, thus it must be `Send` but not `Sync`. Anything the store shares with
+/// other threads, like the [`ExternalPrevout`] callback, must be `Send + Sync` on its own
+pub trait UtxoStore: Send {
     /// Add all the outputs (except provably unspenof all the transaction in the block in the `UtxoStore`
     /// Return all the prevouts in the block at `height` in the order they are found in the block.
     /// First element in the vector is the prevout of the first input of the first transaction after
@@ -26,6 +39,58 @@ pub trait UtxoStore {
 
     /// return stats about the Utxo
     fn stat(&self) -> String;
+
+    /// The number of unspent outputs in the store, if cheaply known
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    /// For persistent stores, the height up to which the store was populated when opened
+    fn updated_up_to_height(&self) -> Option<i32> {
+        None
+    }
+
+    /// For stores opened with [`crate::Config::prevouts_only_replay`], the height up to which the
+    /// stored prevouts can be replayed, the blocks above it can't be added
+    fn replay_up_to_height(&self) -> Option<i32> {
+        None
+    }
+
+    /// Returns true if the last block added contained outputs with the same outpoint of unspent
+    /// outputs, which happened with the duplicated coinbases before BIP30. As per consensus rules
+    /// the previous outputs are overwritten and become unspendable
+    fn bip30_duplicate(&self) -> bool;
+
+    /// Commit any pending write and flush it to disk, called when the iteration ends, also if it's
+    /// stopped early
+    fn finalize(&mut self) {}
+
+    /// Takes the first error of the writes not stopping the iteration by themselves, like the
+    /// ones of the [`WalUtxo`] file, the fee stage checks it after every block and at the end
+    fn take_error(&mut self) -> Option<crate::Error> {
+        None
+    }
+
+    /// Returns the output at `outpoint` if it's unspent at the height the store is updated to,
+    /// like [`UtxoStore::updated_up_to_height()`] for a store opened on a finished db.
+    ///
+    /// Only the persistent stores support it, `MemUtxo` returns `None` since it indexes outputs
+    /// by a truncated hash of the outpoint and it doesn't outlive the iteration anyway
+    fn get(&self, _outpoint: &OutPoint) -> Option<TxOut> {
+        None
+    }
+
+    /// The accumulator of the hash of the UTXO set, kept when
+    /// [`crate::Config::compute_utxo_hash`] is used
+    fn muhash(&self) -> Option<&MuHash> {
+        None
+    }
+
+    /// Returns the hash of the UTXO set, like the `muhash` of `gettxoutsetinfo` of Bitcoin Core
+    /// but in reverse byte order. `None` if the store doesn't keep it, see [`UtxoStore::muhash()`]
+    fn utxo_set_hash(&self) -> Option<[u8; 32]> {
+        self.muhash().map(MuHash::finalize)
+    }
 }
 
 trait Hash64 {
@@ -36,8 +101,11 @@ pub enum AnyUtxo {
     #[cfg(feature = "db")]
     Db(db::DbUtxo),
     Mem(MemUtxo),
+    External(ExternalUtxo),
     #[cfg(feature = "redb")]
     Redb(redb::RedbUtxo),
+    Wal(Box<WalUtxo<AnyUtxo>>),
+    MuHash(Box<MuHashUtxo<AnyUtxo>>),
 }
 
 impl UtxoStore for AnyUtxo {
@@ -46,8 +114,11 @@ impl UtxoStore for AnyUtxo {
             #[cfg(feature = "db")]
             AnyUtxo::Db(db) => db.add_outputs_get_inputs(block_extra, height),
             AnyUtxo::Mem(mem) => mem.add_outputs_get_inputs(block_extra, height),
+            AnyUtxo::External(external) => external.add_outputs_get_inputs(block_extra, height),
             #[cfg(feature = "redb")]
             AnyUtxo::Redb(db) => db.add_outputs_get_inputs(block_extra, height),
+            AnyUtxo::Wal(wal) => wal.add_outputs_get_inputs(block_extra, height),
+            AnyUtxo::MuHash(muhash) => muhash.add_outputs_get_inputs(block_extra, height),
         }
     }
 
@@ -56,8 +127,109 @@ impl UtxoStore for AnyUtxo {
             #[cfg(feature = "db")]
             AnyUtxo::Db(db) => db.stat(),
             AnyUtxo::Mem(mem) => mem.stat(),
+            AnyUtxo::External(external) => external.stat(),
             #[cfg(feature = "redb")]
             AnyUtxo::Redb(db) => db.stat(),
+            AnyUtxo::Wal(wal) => wal.stat(),
+            AnyUtxo::MuHash(muhash) => muhash.stat(),
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyUtxo::Db(db) => db.size(),
+            AnyUtxo::Mem(mem) => mem.size(),
+            AnyUtxo::External(external) => external.size(),
+            #[cfg(feature = "redb")]
+            AnyUtxo::Redb(db) => db.size(),
+            AnyUtxo::Wal(wal) => wal.size(),
+            AnyUtxo::MuHash(muhash) => muhash.size(),
+        }
+    }
+
+    fn updated_up_to_height(&self) -> Option<i32> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyUtxo::Db(db) => db.updated_up_to_height(),
+            AnyUtxo::Mem(mem) => mem.updated_up_to_height(),
+            AnyUtxo::External(external) => external.updated_up_to_height(),
+            #[cfg(feature = "redb")]
+            AnyUtxo::Redb(db) => db.updated_up_to_height(),
+            AnyUtxo::Wal(wal) => wal.updated_up_to_height(),
+            AnyUtxo::MuHash(muhash) => muhash.updated_up_to_height(),
+        }
+    }
+
+    fn replay_up_to_height(&self) -> Option<i32> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyUtxo::Db(db) => db.replay_up_to_height(),
+            AnyUtxo::Mem(mem) => mem.replay_up_to_height(),
+            AnyUtxo::External(external) => external.replay_up_to_height(),
+            #[cfg(feature = "redb")]
+            AnyUtxo::Redb(db) => db.replay_up_to_height(),
+            AnyUtxo::Wal(wal) => wal.replay_up_to_height(),
+            AnyUtxo::MuHash(muhash) => muhash.replay_up_to_height(),
+        }
+    }
+
+    fn bip30_duplicate(&self) -> bool {
+        match self {
+            #[cfg(feature = "db")]
+            AnyUtxo::Db(db) => db.bip30_duplicate(),
+            AnyUtxo::Mem(mem) => mem.bip30_duplicate(),
+            AnyUtxo::External(external) => external.bip30_duplicate(),
+            #[cfg(feature = "redb")]
+            AnyUtxo::Redb(db) => db.bip30_duplicate(),
+            AnyUtxo::Wal(wal) => wal.bip30_duplicate(),
+            AnyUtxo::MuHash(muhash) => muhash.bip30_duplicate(),
+        }
+    }
+
+    fn finalize(&mut self) {
+        match self {
+            #[cfg(feature = "db")]
+            AnyUtxo::Db(db) => db.finalize(),
+            AnyUtxo::Mem(mem) => mem.finalize(),
+            AnyUtxo::External(external) => external.finalize(),
+            #[cfg(feature = "redb")]
+            AnyUtxo::Redb(db) => db.finalize(),
+            AnyUtxo::Wal(wal) => wal.finalize(),
+            AnyUtxo::MuHash(muhash) => muhash.finalize(),
+        }
+    }
+
+    fn take_error(&mut self) -> Option<crate::Error> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyUtxo::Db(db) => db.take_error(),
+            AnyUtxo::Mem(mem) => mem.take_error(),
+            AnyUtxo::External(external) => external.take_error(),
+            #[cfg(feature = "redb")]
+            AnyUtxo::Redb(db) => db.take_error(),
+            AnyUtxo::Wal(wal) => wal.take_error(),
+            AnyUtxo::MuHash(muhash) => muhash.take_error(),
+        }
+    }
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyUtxo::Db(db) => db.get(outpoint),
+            AnyUtxo::Mem(mem) => mem.get(outpoint),
+            AnyUtxo::External(external) => external.get(outpoint),
+            #[cfg(feature = "redb")]
+            AnyUtxo::Redb(db) => db.get(outpoint),
+            AnyUtxo::Wal(wal) => wal.get(outpoint),
+            AnyUtxo::MuHash(muhash) => muhash.get(outpoint),
+        }
+    }
+
+    fn muhash(&self) -> Option<&MuHash> {
+        match self {
+            AnyUtxo::Wal(wal) => wal.muhash(),
+            AnyUtxo::MuHash(muhash) => muhash.muhash(),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,174 @@
+use crate::bitcoin::consensus::Encodable;
+use crate::bitcoin::{OutPoint, TxOut};
+use crate::utxo::UtxoStore;
+use crate::{BlockExtra, MuHash};
+use fxhash::FxHashMap;
+
+/// Scripts bigger than this are unspendable and not in the UTXO set of Bitcoin Core
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// A [`UtxoStore`] decorator keeping the [`MuHash`] of the UTXO set, see
+/// [`crate::Config::compute_utxo_hash`].
+///
+/// The unspent outputs are hashed like Bitcoin Core does for `gettxoutsetinfo`, including the
+/// height of the block creating them and whether it's a coinbase. The inner store doesn't keep
+/// them, thus they are kept here for every unspent output
+pub struct MuHashUtxo<S: UtxoStore> {
+    inner: S,
+    muhash: MuHash,
+    /// `(height << 1) | is_coinbase` of the unspent outputs
+    codes: FxHashMap<OutPoint, u32>,
+}
+
+impl<S: UtxoStore> MuHashUtxo<S> {
+    pub fn new(inner: S) -> Self {
+        MuHashUtxo {
+            inner,
+            muhash: MuHash::new(),
+            codes: FxHashMap::default(),
+        }
+    }
+}
+
+/// The serialization of an unspent output hashed by Bitcoin Core
+fn serialize_coin(outpoint: &OutPoint, code: u32, tx_out: &TxOut) -> Vec<u8> {
+    let mut data = Vec::with_capacity(36 + 4 + 9 + tx_out.script_pubkey.len());
+    outpoint
+        .consensus_encode(&mut data)
+        .expect("vec doesn't error");
+    code.consensus_encode(&mut data).expect("vec doesn't error");
+    tx_out
+        .consensus_encode(&mut data)
+        .expect("vec doesn't error");
+    data
+}
+
+fn is_unspendable(tx_out: &TxOut) -> bool {
+    tx_out.script_pubkey.is_op_return() || tx_out.script_pubkey.len() > MAX_SCRIPT_SIZE
+}
+
+impl<S: UtxoStore> UtxoStore for MuHashUtxo<S> {
+    fn add_outputs_get_inputs(&mut self, block_extra: &BlockExtra, height: u32) -> Vec<TxOut> {
+        let prevouts = self.inner.add_outputs_get_inputs(block_extra, height);
+        let block = block_extra.block();
+
+        // the outputs of the genesis block aren't in the UTXO set, they can't be spent
+        if height > 0 {
+            let txids = block_extra.txids_or_compute();
+            for (txid, tx) in txids.iter().zip(block.txdata.iter()) {
+                let code = (height << 1) | tx.is_coinbase() as u32;
+                for (vout, tx_out) in tx.output.iter().enumerate() {
+                    if is_unspendable(tx_out) {
+                        continue;
+                    }
+                    let outpoint = OutPoint::new(*txid, vout as u32);
+                    // a coinbase duplicating an unspent output (BIP30) overwrites it, the
+                    // output is the same but the height
+                    if let Some(old) = self.codes.insert(outpoint, code) {
+                        self.muhash.remove(&serialize_coin(&outpoint, old, tx_out));
+                    }
+                    self.muhash.insert(&serialize_coin(&outpoint, code, tx_out));
+                }
+            }
+        }
+
+        let inputs = block.txdata.iter().skip(1).flat_map(|tx| tx.input.iter());
+        for (input, prevout) in inputs.zip(prevouts.iter()) {
+            let outpoint = &input.previous_output;
+            if let Some(code) = self.codes.remove(outpoint) {
+                self.muhash.remove(&serialize_coin(outpoint, code, prevout));
+            }
+        }
+        prevouts
+    }
+
+    fn stat(&self) -> String {
+        self.inner.stat()
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.inner.size()
+    }
+
+    fn updated_up_to_height(&self) -> Option<i32> {
+        self.inner.updated_up_to_height()
+    }
+
+    fn replay_up_to_height(&self) -> Option<i32> {
+        self.inner.replay_up_to_height()
+    }
+
+    fn bip30_duplicate(&self) -> bool {
+        self.inner.bip30_duplicate()
+    }
+
+    fn finalize(&mut self) {
+        self.inner.finalize();
+    }
+
+    fn take_error(&mut self) -> Option<crate::Error> {
+        self.inner.take_error()
+    }
+
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.inner.get(outpoint)
+    }
+
+    fn muhash(&self) -> Option<&MuHash> {
+        Some(&self.muhash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_unspendable, serialize_coin};
+    use crate::inner_test::test_conf;
+    use crate::{iter, try_iter, Error, MuHash};
+    use std::collections::HashMap;
+    use test_log::test;
+
+    #[test]
+    fn test_utxo_set_hash() {
+        let mut conf = test_conf();
+        conf.compute_utxo_hash = true;
+
+        // build the UTXO set from the blocks and hash it from scratch
+        let mut utxos = HashMap::new();
+        let mut last = None;
+        for block_extra in iter(conf.clone()) {
+            let height = block_extra.height();
+            for (txid, tx) in block_extra.iter_tx().filter(|_| height > 0) {
+                let code = (height << 1) | tx.is_coinbase() as u32;
+                for (vout, tx_out) in tx.output.iter().enumerate() {
+                    if !is_unspendable(tx_out) {
+                        let outpoint = crate::bitcoin::OutPoint::new(*txid, vout as u32);
+                        utxos.insert(outpoint, (code, tx_out.clone()));
+                    }
+                }
+            }
+            for (outpoint, _) in block_extra.real_prevouts() {
+                assert!(utxos.remove(outpoint).is_some());
+            }
+            assert!(block_extra.utxo_muhash.is_some());
+            last = Some(block_extra);
+        }
+        let mut expected = MuHash::new();
+        for (outpoint, (code, tx_out)) in utxos.iter() {
+            expected.insert(&serialize_coin(outpoint, *code, tx_out));
+        }
+        let last = last.unwrap();
+        assert_eq!(last.utxo_set_hash(), Some(expected.finalize()));
+        assert_ne!(last.utxo_set_hash(), Some(MuHash::new().finalize()));
+
+        assert!(iter(test_conf()).all(|b| b.utxo_set_hash().is_none()));
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        conf.since_file_state = Some(tempdir.path().join("state"));
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::UtxoHashNotFromGenesis)
+        ));
+    }
+}
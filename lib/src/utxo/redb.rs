@@ -4,14 +4,29 @@ use crate::utxo::UtxoStore;
 use crate::BlockExtra;
 use bitcoin_slices::redb::{self, Database, ReadableTable, TableDefinition};
 use bitcoin_slices::{bsl, Parse};
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread::JoinHandle;
 
 pub struct RedbUtxo {
-    db: Database,
+    db: Arc<Database>,
     updated_up_to_height: i32,
     inserted_outputs: u64,
+
+    /// The last block added overwrote unspent outputs, see [`UtxoStore::bip30_duplicate`]
+    bip30_duplicate: bool,
+
+    /// Only read the prevouts already stored, the db must be populated up to the iterated heights
+    prevouts_only_replay: bool,
+
+    /// Started at the first block replayed when `prevouts_only_replay` is used
+    replay: Option<Replay>,
+
+    /// The last commit was not durable and could be lost if the process is interrupted
+    pending_durable: bool,
 }
 
 /// This table contains currently (up to the height defined in INTS_TABLE) unspent transaction outputs.
@@ -23,9 +38,79 @@ const PREVOUTS_TABLE: TableDefinition<i32, bsl::TxOuts> = TableDefinition::new("
 /// This table contains the height meaning the db updated up to this.
 const INTS_TABLE: TableDefinition<&str, i32> = TableDefinition::new("ints");
 
+/// Streams the stored prevouts in height order, reading them in a single read transaction from a
+/// separate thread instead of opening one for every block
+struct Replay {
+    receiver: Option<Receiver<(i32, Vec<TxOut>)>>,
+
+    /// Received but not requested yet, the heights with only the coinbase have no prevouts stored
+    next: Option<(i32, Vec<TxOut>)>,
+
+    join: Option<JoinHandle<()>>,
+}
+
+impl Replay {
+    fn start(db: Arc<Database>, from: i32, to: i32) -> Self {
+        let (sender, receiver) = sync_channel(1_000);
+        let join = std::thread::spawn(move || {
+            let read = || -> Result<(), redb::Error> {
+                let read_txn = db.begin_read()?;
+                let prevouts_table = read_txn.open_table(PREVOUTS_TABLE)?;
+                for entry in prevouts_table.range(from..=to)? {
+                    let (height, tx_outs) = entry?;
+                    let prevouts = tx_outs.value().iter().map(|e| e.into()).collect();
+                    if sender.send((height.value(), prevouts)).is_err() {
+                        // the store has been dropped
+                        break;
+                    }
+                }
+                Ok(())
+            };
+            if let Err(e) = read() {
+                error!("cannot read the stored prevouts: {}", e);
+            }
+        });
+        Replay {
+            receiver: Some(receiver),
+            next: None,
+            join: Some(join),
+        }
+    }
+
+    /// The prevouts stored at `height`, empty if there aren't any or they can't be read
+    fn prevouts(&mut self, height: i32) -> Vec<TxOut> {
+        loop {
+            if self.next.is_none() {
+                self.next = self.receiver.as_ref().and_then(|r| r.recv().ok());
+            }
+            match self.next.take() {
+                Some((h, prevouts)) if h == height => return prevouts,
+                Some((h, _)) if h < height => continue,
+                next => {
+                    self.next = next;
+                    return Vec::new();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        // dropping the receiver unblocks the thread if it's waiting to send
+        self.receiver.take();
+        if let Some(join) = self.join.take() {
+            let _ = join.join();
+        }
+    }
+}
+
 impl RedbUtxo {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<RedbUtxo, redb::Error> {
-        let db = Database::create(path)?;
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        prevouts_only_replay: bool,
+    ) -> Result<RedbUtxo, redb::Error> {
+        let db = Arc::new(Database::create(path)?);
 
         let tables: Vec<_> = {
             let read_txn = db.begin_read()?;
@@ -52,6 +137,10 @@ impl RedbUtxo {
             db,
             updated_up_to_height,
             inserted_outputs: 0,
+            bip30_duplicate: false,
+            prevouts_only_replay,
+            replay: None,
+            pending_durable: false,
         })
     }
 }
@@ -69,11 +158,13 @@ impl UtxoStore for RedbUtxo {
             "height: {} updated_up_to: {}",
             height, self.updated_up_to_height
         );
+        self.bip30_duplicate = false;
         if height > self.updated_up_to_height {
             // since we can spend outputs created in this same block, we first put outputs in memory...
             let total_outputs = block.txdata.iter().map(|e| e.output.len()).sum();
             let mut block_outputs = HashMap::with_capacity(total_outputs);
-            for (txid, tx) in block_extra.iter_tx() {
+            let txids = block_extra.txids_or_compute();
+            for (txid, tx) in txids.iter().zip(block.txdata.iter()) {
                 for (i, output) in tx.output.iter().enumerate() {
                     if !output.script_pubkey.is_op_return() {
                         let outpoint = OutPoint::new(*txid, i as u32);
@@ -116,7 +207,8 @@ impl UtxoStore for RedbUtxo {
             }
 
             let mut write_txn = self.db.begin_write().unwrap();
-            if height % 10 != 0 {
+            self.pending_durable = height % 10 != 0;
+            if self.pending_durable {
                 write_txn.set_durability(redb::Durability::None);
             }
             {
@@ -136,7 +228,10 @@ impl UtxoStore for RedbUtxo {
                         .unwrap()
                         .parsed_owned();
 
-                    utxos_table.insert(out_point, tx_out).unwrap();
+                    if utxos_table.insert(out_point, tx_out).unwrap().is_some() {
+                        warn!("output {} overwrites an unspent output", k);
+                        self.bip30_duplicate = true;
+                    }
 
                     self.inserted_outputs += 1;
                 }
@@ -158,6 +253,11 @@ impl UtxoStore for RedbUtxo {
         } else if block.txdata.len() == 1 {
             // avoid hitting disk when we have only the coinbase (no prevouts!)
             Vec::new()
+        } else if self.prevouts_only_replay {
+            let (db, to) = (&self.db, self.updated_up_to_height);
+            self.replay
+                .get_or_insert_with(|| Replay::start(db.clone(), height, to))
+                .prevouts(height)
         } else {
             let read_txn = self.db.begin_read().unwrap();
             let prevouts_table = read_txn.open_table(PREVOUTS_TABLE).unwrap();
@@ -172,11 +272,43 @@ impl UtxoStore for RedbUtxo {
             self.updated_up_to_height, self.inserted_outputs
         )
     }
+
+    fn updated_up_to_height(&self) -> Option<i32> {
+        Some(self.updated_up_to_height)
+    }
+
+    fn replay_up_to_height(&self) -> Option<i32> {
+        self.prevouts_only_replay
+            .then_some(self.updated_up_to_height)
+    }
+
+    fn bip30_duplicate(&self) -> bool {
+        self.bip30_duplicate
+    }
+
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        let outpoint_bytes = serialize(outpoint);
+        let outpoint = bsl::OutPoint::parse(&outpoint_bytes).ok()?.parsed_owned();
+        let read_txn = self.db.begin_read().unwrap();
+        let utxos_table = read_txn.open_table(UTXOS_TABLE).unwrap();
+        let tx_out = utxos_table.get(&outpoint).unwrap()?;
+        Some(tx_out.value().into())
+    }
+
+    fn finalize(&mut self) {
+        if self.pending_durable {
+            // a durable commit persists also the previous non-durable ones
+            self.db.begin_write().unwrap().commit().unwrap();
+            self.pending_durable = false;
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{inner_test::test_conf, iter};
+    use crate::bitcoin::OutPoint;
+    use crate::utxo::UtxoStore;
+    use crate::{inner_test::test_conf, iter, try_iter, Error};
     use test_log::test;
 
     #[test]
@@ -202,12 +334,150 @@ mod test {
             }
         }
         assert_eq!(max_height, 400 - conf.max_reorg as u32);
+        assert_ne!(max_height % 10, 0);
+        let db = super::RedbUtxo::new(&path, false).unwrap();
+        assert_eq!(db.updated_up_to_height(), Some(max_height as i32));
+        let mut spent = 0;
+        // the db is open, iterating with the in memory utxo
+        for b in iter(test_conf()).take_while(|b| b.height <= max_height) {
+            if b.height == max_height {
+                // coinbase outputs of the last block can't be spent yet
+                let coinbase = &b.block().txdata[0];
+                let outpoint = OutPoint::new(b.txids()[0], 0);
+                assert_eq!(db.get(&outpoint).as_ref(), Some(&coinbase.output[0]));
+            }
+            for (outpoint, _) in b.real_prevouts() {
+                assert_eq!(db.get(outpoint), None);
+                spent += 1;
+            }
+        }
+        assert!(spent > 0);
+        assert_eq!(db.get(&OutPoint::default()), None);
+        drop(db);
 
         // iterating twice, this time prevouts come directly from db
-        for b in iter(conf) {
+        for b in iter(conf.clone()) {
             if b.height == 394 {
                 assert_eq!(b.fee(), Some(50_000));
             }
         }
+
+        let mut conf = conf;
+        conf.prevouts_only_replay = true;
+        conf.stop_at_height = Some(max_height + 1);
+        assert!(matches!(
+            conf.utxo_manager(),
+            Err(crate::Error::ReplayNotPopulated { .. })
+        ));
+        conf.stop_at_height = Some(max_height);
+        let mut total_fee = 0;
+        for b in iter(conf) {
+            total_fee += b.fee().unwrap();
+        }
+        assert_eq!(total_fee, 450_000);
+    }
+
+    #[test]
+    fn test_replay_not_populated() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut conf = test_conf();
+        conf.utxo_redb = Some(path.to_path_buf());
+        conf.stop_at_height = Some(200);
+        assert_eq!(iter(conf.clone()).count(), 201);
+
+        conf.stop_at_height = None;
+        conf.prevouts_only_replay = true;
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 201);
+        assert!(matches!(
+            blocks.error(),
+            Some(Error::ReplayNotPopulated {
+                populated: 200,
+                required: 201
+            })
+        ));
+    }
+
+    #[test]
+    fn test_prevouts_only_replay() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut conf = test_conf();
+        conf.utxo_redb = Some(path.to_path_buf());
+        conf.stop_at_height = Some(300);
+        let expected: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height(), b.outpoint_values().clone()))
+            .collect();
+
+        conf.prevouts_only_replay = true;
+        conf.start_at_height = 100;
+        let blocks: Vec<_> = iter(conf)
+            .map(|b| (b.height(), b.outpoint_values().clone()))
+            .collect();
+        assert_eq!(blocks, expected[100..]);
+        assert!(blocks.iter().any(|(_, prevouts)| !prevouts.is_empty()));
+
+        // the thread streaming the prevouts ended with the iteration
+        assert!(super::RedbUtxo::new(&path, false).is_ok());
+    }
+
+    #[test]
+    fn test_resume_from_utxo_db() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut conf = test_conf();
+        conf.utxo_redb = Some(tempdir.path().join("utxo.redb"));
+        let expected: Vec<_> = iter(test_conf())
+            .map(|b| (b.height(), b.block_hash(), b.fee()))
+            .collect();
+
+        // an empty db resumes from the genesis
+        conf.resume_from_utxo_db = true;
+        conf.stop_at_height = Some(200);
+        let blocks: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height(), b.block_hash(), b.fee()))
+            .collect();
+        assert_eq!(blocks, expected[..=200]);
+
+        conf.stop_at_height = None;
+        let blocks: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height(), b.block_hash(), b.fee()))
+            .collect();
+        assert_eq!(blocks, expected[201..]);
+        // nothing new to emit
+        assert_eq!(iter(conf.clone()).count(), 0);
+
+        // re-emitted with the stored prevouts
+        conf.start_at_height = 150;
+        let blocks: Vec<_> = iter(conf.clone())
+            .map(|b| (b.height(), b.block_hash(), b.fee()))
+            .collect();
+        assert_eq!(blocks, expected[150..]);
+
+        let mut conf = test_conf();
+        conf.resume_from_utxo_db = true;
+        let mut blocks = crate::try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 0);
+        assert!(matches!(
+            blocks.error(),
+            Some(crate::Error::ResumeWithoutDb)
+        ));
+    }
+
+    #[test]
+    fn test_redb_path() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut conf = test_conf();
+        for invalid in [
+            tempdir.path().join("missing").join("utxo.redb"),
+            tempdir.path().to_path_buf(),
+        ] {
+            conf.utxo_redb = Some(invalid.clone());
+            match conf.utxo_manager() {
+                Err(crate::Error::UtxoDbPath { path, .. }) => assert_eq!(path, invalid),
+                Err(e) => panic!("unexpected {:?}", e),
+                Ok(_) => panic!("unexpected success"),
+            }
+        }
+        conf.utxo_redb = Some(tempdir.path().join("utxo.redb"));
+        assert!(conf.utxo_manager().is_ok());
     }
 }
@@ -0,0 +1,224 @@
+use crate::bitcoin::consensus::{encode, serialize, Decodable, Encodable};
+use crate::bitcoin::{OutPoint, TxOut};
+use crate::utxo::UtxoStore;
+use crate::{BlockExtra, MuHash};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The changes to the UTXO set caused by the block at `height`.
+///
+/// Records are appended to the file specified in [`crate::Config::utxo_wal`], the UTXO set at a
+/// given height is obtained by adding `created` and then removing `spent` of every record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoDelta {
+    /// Height of the block
+    pub height: u32,
+
+    /// Outputs created in the block, excluding provably unspendable ones, in block order
+    pub created: Vec<OutPoint>,
+
+    /// Outputs spent by the inputs of the block (excluding the coinbase), in block order
+    pub spent: Vec<OutPoint>,
+}
+
+/// A [`UtxoStore`] decorator appending an [`UtxoDelta`] record for every processed block to a file
+pub struct WalUtxo<S: UtxoStore> {
+    inner: S,
+    wal: BufWriter<File>,
+
+    /// The first error writing the wal, until it's reported by [`UtxoStore::take_error`]
+    error: Option<io::Error>,
+
+    /// Nothing is written after an error, to not leave a gap in the records
+    failed: bool,
+}
+
+impl<S: UtxoStore> WalUtxo<S> {
+    pub fn new<P: AsRef<Path>>(inner: S, path: P) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(WalUtxo {
+            inner,
+            wal: BufWriter::new(file),
+            error: None,
+            failed: false,
+        })
+    }
+
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.error = Some(e);
+            self.failed = true;
+        }
+    }
+}
+
+impl<S: UtxoStore> UtxoStore for WalUtxo<S> {
+    fn add_outputs_get_inputs(&mut self, block_extra: &BlockExtra, height: u32) -> Vec<TxOut> {
+        let mut created = Vec::with_capacity(block_extra.block_total_outputs());
+        let txids = block_extra.txids_or_compute();
+        for (txid, tx) in txids.iter().zip(block_extra.block().txdata.iter()) {
+            for (i, output) in tx.output.iter().enumerate() {
+                if !output.script_pubkey.is_op_return() {
+                    created.push(OutPoint::new(*txid, i as u32));
+                }
+            }
+        }
+        let spent = block_extra
+            .block()
+            .txdata
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.input.iter().map(|input| input.previous_output))
+            .collect();
+        let delta = UtxoDelta {
+            height,
+            created,
+            spent,
+        };
+        if !self.failed {
+            let result = self.wal.write_all(&serialize(&delta));
+            self.check(result);
+        }
+
+        self.inner.add_outputs_get_inputs(block_extra, height)
+    }
+
+    fn stat(&self) -> String {
+        self.inner.stat()
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.inner.size()
+    }
+
+    fn updated_up_to_height(&self) -> Option<i32> {
+        self.inner.updated_up_to_height()
+    }
+
+    fn replay_up_to_height(&self) -> Option<i32> {
+        self.inner.replay_up_to_height()
+    }
+
+    fn bip30_duplicate(&self) -> bool {
+        self.inner.bip30_duplicate()
+    }
+
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.inner.get(outpoint)
+    }
+
+    fn muhash(&self) -> Option<&MuHash> {
+        self.inner.muhash()
+    }
+
+    fn finalize(&mut self) {
+        if !self.failed {
+            let result = self
+                .wal
+                .flush()
+                .and_then(|_| self.wal.get_ref().sync_data());
+            self.check(result);
+        }
+        self.inner.finalize();
+    }
+
+    fn take_error(&mut self) -> Option<crate::Error> {
+        match self.error.take() {
+            Some(e) => Some(crate::Error::UtxoWal(e)),
+            None => self.inner.take_error(),
+        }
+    }
+}
+
+impl Encodable for UtxoDelta {
+    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let mut written = self.height.consensus_encode(writer)?;
+        for outpoints in [&self.created, &self.spent] {
+            written += (outpoints.len() as u32).consensus_encode(writer)?;
+            for outpoint in outpoints.iter() {
+                written += outpoint.consensus_encode(writer)?;
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Decodable for UtxoDelta {
+    fn consensus_decode<D: bitcoin::io::Read + ?Sized>(d: &mut D) -> Result<Self, encode::Error> {
+        Ok(UtxoDelta {
+            height: Decodable::consensus_decode(d)?,
+            created: decode_outpoints(d)?,
+            spent: decode_outpoints(d)?,
+        })
+    }
+}
+
+fn decode_outpoints<D: bitcoin::io::Read + ?Sized>(
+    d: &mut D,
+) -> Result<Vec<OutPoint>, encode::Error> {
+    let len = u32::consensus_decode(d)?;
+    let mut v = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        v.push(Decodable::consensus_decode(d)?);
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::UtxoDelta;
+    use crate::bitcoin::consensus::Decodable;
+    use crate::{inner_test::test_conf, iter, try_iter};
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::io::BufReader;
+    use test_log::test;
+
+    #[test]
+    fn test_blk_testnet_wal() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let conf = {
+            let mut conf = test_conf();
+            conf.utxo_wal = Some(path.to_path_buf());
+            conf
+        };
+        let blocks = iter(conf).count();
+
+        let mut reader = BufReader::new(File::open(&path).unwrap());
+        let mut utxos = HashSet::new();
+        let mut records = 0;
+        while let Ok(delta) = UtxoDelta::consensus_decode(&mut reader) {
+            assert_eq!(delta.height, records);
+            utxos.extend(delta.created);
+            for spent in delta.spent {
+                assert!(utxos.remove(&spent));
+            }
+            records += 1;
+        }
+        assert_eq!(records as usize, blocks);
+        assert!(!utxos.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wal_write_error() {
+        let mut conf = test_conf();
+        // every write fails with "no space left on device"
+        conf.utxo_wal = Some("/dev/full".into());
+        let mut blocks = try_iter(conf);
+        assert!(blocks.by_ref().count() > 0);
+        assert!(matches!(blocks.error(), Some(crate::Error::UtxoWal(_))));
+
+        let mut conf = test_conf();
+        conf.utxo_wal = Some("/dev/full".into());
+        conf.stop_at_height = Some(1);
+        let mut blocks = try_iter(conf);
+        assert_eq!(blocks.by_ref().count(), 2);
+        // the records are buffered, the error comes when flushing at the end
+        assert!(matches!(blocks.error(), Some(crate::Error::UtxoWal(_))));
+    }
+}